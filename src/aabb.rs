@@ -0,0 +1,261 @@
+use serde::{Serialize, Deserialize};
+use crate::shape::{Ray, Point};
+use crate::vertors::Vector3;
+use crate::transform::Matrix;
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct Aabb {
+    pub min: Point,
+    pub max: Point
+}
+
+impl Aabb {
+    pub fn new(min: Point, max: Point) -> Aabb {
+        Aabb { min, max }
+    }
+
+    pub fn around(center: Point, half_size: Vector3) -> Aabb {
+        Aabb { min: center - half_size, max: center + half_size }
+    }
+
+    pub fn from_points<I: IntoIterator<Item = Point>>(points: I) -> Option<Aabb> {
+        points.into_iter().fold(None, |aabb: Option<Aabb>, point| match aabb {
+            Some(aabb) => Some(aabb.union(&Aabb::new(point, point))),
+            None => Some(Aabb::new(point, point))
+        })
+    }
+
+    // Bounds of a disk from the extent of its circle along each axis
+    pub fn around_disk(center: Point, normal: Vector3, radius: f64) -> Aabb {
+        let normal = normal.normalize();
+        let extent = |component: f64| radius * (1.0 - component * component).max(0.0).sqrt();
+        Aabb::around(center, Vector3::new(extent(normal.x), extent(normal.y), extent(normal.z)))
+    }
+
+    pub fn union(&self, other: &Aabb) -> Aabb {
+        Aabb {
+            min: Vector3::new(self.min.x.min(other.min.x), self.min.y.min(other.min.y), self.min.z.min(other.min.z)),
+            max: Vector3::new(self.max.x.max(other.max.x), self.max.y.max(other.max.y), self.max.z.max(other.max.z))
+        }
+    }
+
+    pub fn intersection(&self, other: &Aabb) -> Option<Aabb> {
+        let min = Vector3::new(self.min.x.max(other.min.x), self.min.y.max(other.min.y), self.min.z.max(other.min.z));
+        let max = Vector3::new(self.max.x.min(other.max.x), self.max.y.min(other.max.y), self.max.z.min(other.max.z));
+        if min.x > max.x || min.y > max.y || min.z > max.z {
+            return None;
+        }
+        Some(Aabb { min, max })
+    }
+
+    pub fn expanded(&self, margin: f64) -> Aabb {
+        let margin = Vector3::new(margin, margin, margin);
+        Aabb { min: self.min - margin, max: self.max + margin }
+    }
+
+    pub fn translated(&self, offset: Vector3) -> Aabb {
+        Aabb { min: self.min + offset, max: self.max + offset }
+    }
+
+    pub fn corners(&self) -> [Point; 8] {
+        let (min, max) = (self.min, self.max);
+        [
+            Vector3::new(min.x, min.y, min.z), Vector3::new(max.x, min.y, min.z),
+            Vector3::new(min.x, max.y, min.z), Vector3::new(max.x, max.y, min.z),
+            Vector3::new(min.x, min.y, max.z), Vector3::new(max.x, min.y, max.z),
+            Vector3::new(min.x, max.y, max.z), Vector3::new(max.x, max.y, max.z)
+        ]
+    }
+
+    pub fn transformed(&self, matrix: &Matrix) -> Aabb {
+        let corners = self.corners();
+        let mut aabb = Aabb::new(matrix.transform_point(corners[0]), matrix.transform_point(corners[0]));
+        for corner in corners.iter().skip(1) {
+            let point = matrix.transform_point(*corner);
+            aabb = aabb.union(&Aabb::new(point, point));
+        }
+        aabb
+    }
+
+    pub fn contains(&self, point: Point) -> bool {
+        point.x >= self.min.x && point.x <= self.max.x
+            && point.y >= self.min.y && point.y <= self.max.y
+            && point.z >= self.min.z && point.z <= self.max.z
+    }
+
+    // Slab test: the ray hits the box when the intervals it spends between each pair of planes overlap inside its range
+    pub fn hit(&self, ray: &Ray) -> bool {
+        self.range(ray).is_some()
+    }
+
+    // Distances at which the ray enters and leaves the box, clipped to its range. The signs of the ray pick the entry and
+    // exit planes without comparing distances. Parallel to an axis, the infinite inverse puts the slab at -inf to inf when the
+    // origin is between its planes and at inf to -inf when outside; on a plane it gives 0 * inf = NaN, which min and max
+    // drop, so rays along a face still graze the box
+    pub fn range(&self, ray: &Ray) -> Option<(f64, f64)> {
+        let bounds = [self.min, self.max];
+        let (origin, inverse, sign) = (ray.origin, ray.inverse_direction, ray.sign);
+        let near_x = (bounds[sign[0]].x - origin.x) * inverse.x;
+        let far_x = (bounds[1 - sign[0]].x - origin.x) * inverse.x;
+        let near_y = (bounds[sign[1]].y - origin.y) * inverse.y;
+        let far_y = (bounds[1 - sign[1]].y - origin.y) * inverse.y;
+        let near_z = (bounds[sign[2]].z - origin.z) * inverse.z;
+        let far_z = (bounds[1 - sign[2]].z - origin.z) * inverse.z;
+        let t_min = ray.t_min.max(near_x).max(near_y).max(near_z);
+        let t_max = ray.t_max.min(far_x).min(far_y).min(far_z);
+        if t_min > t_max {
+            return None;
+        }
+        Some((t_min, t_max))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::random::Rng;
+    use crate::shape::{Shape, Sphere, Triangle, Cylinder, Cone, Torus, Ellipsoid, Capsule, Disk, Quad, Transformed};
+    use crate::traits::Intersectable;
+
+    fn random_point(rng: &mut Rng, center: Point, size: f64) -> Point {
+        center + Vector3::new(rng.next_f64() - 0.5, rng.next_f64() - 0.5, rng.next_f64() - 0.5) * size
+    }
+
+    // Rays from around the shape aimed at points near its box, so that both hits and misses are tried
+    fn assert_hits_are_bounded(name: &str, shape: &dyn Intersectable) {
+        let aabb = shape.bounding_box().unwrap();
+        let center = (aabb.min + aabb.max) * 0.5;
+        let size = (aabb.max - aabb.min).length();
+        let mut rng = Rng::new(263);
+        let mut hits = 0;
+        for _ in 0..4000 {
+            let origin = random_point(&mut rng, center, size * 4.0);
+            let ray = Ray::new(origin, (random_point(&mut rng, center, size * 1.5) - origin).normalize());
+            if let Some(hit) = shape.intersect(&ray) {
+                hits += 1;
+                assert!(aabb.hit(&ray), "{} hit at {} outside its box", name, hit.distance);
+                let (near, far) = aabb.range(&ray).unwrap();
+                assert!(hit.distance >= near - 1e-9 && hit.distance <= far + 1e-9, "{} hit at {} outside {}..{}", name, hit.distance, near, far);
+            }
+        }
+        assert!(hits > 100, "{} was hit {} times", name, hits);
+    }
+
+    #[test]
+    fn rays_hitting_a_sphere_hit_its_box() {
+        assert_hits_are_bounded("sphere", &Sphere::new(Vector3::new(1.0, -2.0, 3.0), 1.5));
+        let sphere = Sphere::new(Vector3::new(0.0, 0.0, 0.0), 1.0);
+        let aabb = sphere.bounding_box().unwrap();
+        // Grazing the sphere along a face of its box
+        let ray = Ray::new(Vector3::new(-5.0, 1.0, 0.0), Vector3::new(1.0, 0.0, 0.0));
+        assert!(aabb.hit(&ray));
+        assert_eq!(aabb.range(&ray), Some((4.0, 6.0)));
+    }
+
+    #[test]
+    fn rays_hitting_any_shape_hit_its_box() {
+        let shapes: Vec<(&str, Box<dyn Intersectable>)> = vec![
+            ("triangle", Box::new(Triangle::new(Vector3::new(0.0, 0.0, 0.0), Vector3::new(2.0, 0.5, 0.0), Vector3::new(0.5, 2.0, 1.0)))),
+            ("cylinder", Box::new(Cylinder::new(Vector3::new(0.0, 0.0, 0.0), Vector3::new(1.0, 1.0, 0.0), 0.5, 2.0))),
+            ("cone", Box::new(Cone::new(Vector3::new(0.0, 1.0, 0.0), Vector3::new(0.0, -1.0, 0.3), 25.0, 1.5))),
+            ("torus", Box::new(Torus::new(Vector3::new(0.0, 0.0, 0.0), Vector3::new(0.3, 1.0, 0.0), 1.0, 0.25))),
+            ("ellipsoid", Box::new(Ellipsoid::new(Vector3::new(0.0, 0.0, 0.0), Vector3::new(2.0, 0.5, 1.0)))),
+            ("capsule", Box::new(Capsule::new(Vector3::new(0.0, 0.0, 0.0), Vector3::new(1.0, 2.0, -1.0), 0.3))),
+            ("disk", Box::new(Disk::new(Vector3::new(0.0, 0.0, 0.0), Vector3::new(1.0, 1.0, 1.0), 1.0))),
+            ("quad", Box::new(Quad::new(Vector3::new(0.0, 0.0, 0.0), Vector3::new(2.0, 0.0, 0.5), Vector3::new(0.0, 1.0, 0.0)))),
+            ("transformed sphere", Box::new(Transformed::new(Vector3::new(1.0, 0.0, 0.0), Vector3::new(30.0, 45.0, 0.0), Vector3::new(2.0, 0.5, 1.0), Shape::SPHERE(Sphere::new(Vector3::new(0.0, 0.0, 0.0), 1.0)))))
+        ];
+        for (name, shape) in shapes.iter() {
+            assert_hits_are_bounded(name, shape.as_ref());
+        }
+    }
+
+    #[test]
+    fn axis_parallel_rays_only_hit_between_their_planes() {
+        let aabb = Aabb::new(Vector3::new(-1.0, -1.0, -1.0), Vector3::new(1.0, 1.0, 1.0));
+        assert!(aabb.hit(&Ray::new(Vector3::new(0.5, 0.5, 5.0), Vector3::new(0.0, 0.0, -1.0))));
+        assert!(!aabb.hit(&Ray::new(Vector3::new(1.5, 0.5, 5.0), Vector3::new(0.0, 0.0, -1.0))));
+        assert!(!aabb.hit(&Ray::new(Vector3::new(0.5, 0.5, 5.0), Vector3::new(0.0, 0.0, 1.0))));
+        assert_eq!(aabb.range(&Ray::new(Vector3::new(0.0, 0.0, 0.0), Vector3::new(0.0, 1.0, 0.0))), Some((0.0, 1.0)));
+        assert!(!aabb.hit(&Ray::new(Vector3::new(0.0, 0.0, 5.0), Vector3::new(0.0, 0.0, -1.0)).with_range(0.0, 3.0)));
+    }
+
+    // The slab test as it was before rays carried their inverse direction, branching on parallel axes and on the order of the planes
+    pub(crate) fn branching_range(aabb: &Aabb, ray: &Ray) -> Option<(f64, f64)> {
+        let mut t_min = ray.t_min;
+        let mut t_max = ray.t_max;
+        for (origin, direction, min, max) in [
+            (ray.origin.x, ray.direction.x, aabb.min.x, aabb.max.x),
+            (ray.origin.y, ray.direction.y, aabb.min.y, aabb.max.y),
+            (ray.origin.z, ray.direction.z, aabb.min.z, aabb.max.z)
+        ] {
+            if direction == 0.0 {
+                if origin < min || origin > max {
+                    return None;
+                }
+                continue;
+            }
+            let mut near = (min - origin) / direction;
+            let mut far = (max - origin) / direction;
+            if near > far {
+                std::mem::swap(&mut near, &mut far);
+            }
+            t_min = t_min.max(near);
+            t_max = t_max.min(far);
+            if t_min > t_max {
+                return None;
+            }
+        }
+        Some((t_min, t_max))
+    }
+
+    #[test]
+    fn rays_along_a_face_graze_the_box_whatever_the_sign_of_their_zeros() {
+        let aabb = Aabb::new(Vector3::new(-1.0, -1.0, -1.0), Vector3::new(1.0, 1.0, 1.0));
+        for zero in [0.0, -0.0] {
+            for x in [-1.0, 1.0] {
+                // In the plane of the face, the distance to it is 0 and its inverse infinite
+                let ray = Ray::new(Vector3::new(x, 0.5, 5.0), Vector3::new(zero, zero, -1.0));
+                assert_eq!(aabb.range(&ray), Some((4.0, 6.0)), "x {} zero {}", x, zero);
+                let edge = Ray::new(Vector3::new(x, 1.0, 5.0), Vector3::new(zero, zero, -1.0));
+                assert_eq!(aabb.range(&edge), Some((4.0, 6.0)), "x {} zero {}", x, zero);
+            }
+            let beside = Ray::new(Vector3::new(1.0 + 1e-12, 0.5, 5.0), Vector3::new(zero, zero, -1.0));
+            assert!(!aabb.hit(&beside));
+            let inside = Ray::new(Vector3::new(0.25, -0.5, 0.0), Vector3::new(zero, 1.0, zero));
+            assert_eq!(aabb.range(&inside), Some((0.0, 1.5)));
+        }
+    }
+
+    #[test]
+    fn rays_starting_inside_leave_through_the_boundary() {
+        let aabb = Aabb::new(Vector3::new(-1.0, -2.0, 0.0), Vector3::new(3.0, 1.0, 0.5));
+        let mut rng = Rng::new(239);
+        let axes = [Vector3::new(1.0, 0.0, 0.0), Vector3::new(0.0, -1.0, 0.0), Vector3::new(0.0, 0.0, 1.0), Vector3::new(-0.0, 0.0, -1.0)];
+        for index in 0..1000 {
+            let origin = aabb.min + Vector3::new(rng.next_f64() * 4.0, rng.next_f64() * 3.0, rng.next_f64() * 0.5);
+            let direction = if index < axes.len() * 10 { axes[index % axes.len()] } else { random_point(&mut rng, Vector3::zero(), 2.0).normalize() };
+            let ray = Ray::new(origin, direction);
+            let (near, far) = aabb.range(&ray).unwrap();
+            assert_eq!(near, 0.0);
+            let exit = origin + direction * far;
+            let on_a_face = [(exit.x, aabb.min.x, aabb.max.x), (exit.y, aabb.min.y, aabb.max.y), (exit.z, aabb.min.z, aabb.max.z)].iter()
+                .any(|&(value, min, max)| (value - min).abs() < 1e-9 || (value - max).abs() < 1e-9);
+            assert!(on_a_face && aabb.expanded(1e-9).contains(exit), "{:?} leaves at {:?}", ray, exit);
+        }
+    }
+
+    #[test]
+    fn the_slab_test_agrees_with_the_branching_one() {
+        let mut rng = Rng::new(2390);
+        for _ in 0..4000 {
+            let aabb = Aabb::around(random_point(&mut rng, Vector3::zero(), 4.0), Vector3::new(rng.next_f64(), rng.next_f64(), rng.next_f64()));
+            let origin = random_point(&mut rng, Vector3::zero(), 8.0);
+            let ray = Ray::new(origin, (random_point(&mut rng, Vector3::zero(), 4.0) - origin).normalize()).with_range(0.0, rng.next_f64() * 10.0);
+            match (aabb.range(&ray), branching_range(&aabb, &ray)) {
+                (Some(found), Some(expected)) => assert!((found.0 - expected.0).abs() < 1e-9 && (found.1 - expected.1).abs() < 1e-9),
+                (found, expected) => assert_eq!(found.is_some(), expected.is_some(), "{:?} instead of {:?}", found, expected)
+            }
+        }
+    }
+}
@@ -0,0 +1,330 @@
+use std::error;
+use std::fmt;
+use std::fs;
+use std::io::ErrorKind;
+use image::{ImageBuffer, RgbaImage};
+use crate::rendering::{self, Scene, View};
+use crate::output;
+use crate::profile::Stage;
+
+const MAGIC: &[u8; 8] = b"RTACCUM3"; // Version 1 summed quantized samples and version 2 did not keep the samples per pixel
+
+#[derive(Debug, Clone)]
+pub struct AccumulationError {
+    pub message: String
+}
+
+impl AccumulationError {
+    pub fn new(message: String) -> AccumulationError {
+        AccumulationError { message }
+    }
+}
+
+impl fmt::Display for AccumulationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl error::Error for AccumulationError {}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Accumulation {
+    pub scene_hash: String,
+    pub width: u32,
+    pub height: u32,
+    pub sampler: String,
+    pub seed: u64,
+    pub samples_per_pixel: u32, // Of every run, each one adds as many to the counts
+    pub counts: Vec<u32>,
+    pub sums: Vec<[f64; 4]> // Linear, exposed but not quantized
+}
+
+fn scene_hash(scene: &Scene) -> String {
+    scene.metadata().into_iter().find(|(key, _)| key == "SceneHash").map(|(_, value)| value).unwrap_or_default()
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    offset: usize
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, length: usize) -> Result<&'a [u8], AccumulationError> {
+        if self.offset + length > self.data.len() {
+            return Err(AccumulationError::new("the accumulation file is truncated".to_string()));
+        }
+        let bytes = &self.data[self.offset..self.offset + length];
+        self.offset += length;
+        Ok(bytes)
+    }
+
+    fn u32(&mut self) -> Result<u32, AccumulationError> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    fn u64(&mut self) -> Result<u64, AccumulationError> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    fn f64(&mut self) -> Result<f64, AccumulationError> {
+        Ok(f64::from_bits(self.u64()?))
+    }
+
+    fn string(&mut self) -> Result<String, AccumulationError> {
+        let length = self.u32()? as usize;
+        Ok(String::from_utf8_lossy(self.take(length)?).to_string())
+    }
+}
+
+fn write_string(data: &mut Vec<u8>, text: &str) {
+    data.extend_from_slice(&(text.len() as u32).to_le_bytes());
+    data.extend_from_slice(text.as_bytes());
+}
+
+impl Accumulation {
+    pub fn new(scene: &Scene) -> Accumulation {
+        let pixels = scene.camera.width as usize * scene.camera.height as usize;
+        Accumulation {
+            scene_hash: scene_hash(scene),
+            width: scene.camera.width,
+            height: scene.camera.height,
+            sampler: serde_json::to_string(&scene.sampler).unwrap_or_default().trim_matches('"').to_string(),
+            seed: scene.seed,
+            samples_per_pixel: scene.samples_per_pixel.max(1),
+            counts: vec![0; pixels],
+            sums: vec![[0.0; 4]; pixels]
+        }
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Accumulation, AccumulationError> {
+        let mut reader = Reader { data, offset: 0 };
+        match reader.take(MAGIC.len())? {
+            magic if magic == MAGIC => (),
+            b"RTACCUM1" | b"RTACCUM2" => return Err(AccumulationError::new("was accumulated by an older version, remove it to start again".to_string())),
+            _ => return Err(AccumulationError::new("not an accumulation file".to_string()))
+        }
+        let scene_hash = reader.string()?;
+        let width = reader.u32()?;
+        let height = reader.u32()?;
+        let sampler = reader.string()?;
+        let seed = reader.u64()?;
+        let samples_per_pixel = reader.u32()?;
+        let pixels = width as usize * height as usize;
+        if data.len() - reader.offset != pixels * 36 {
+            return Err(AccumulationError::new(format!("the accumulation file does not hold {}x{} pixels", width, height)));
+        }
+        let mut counts = Vec::with_capacity(pixels);
+        let mut sums = Vec::with_capacity(pixels);
+        for _ in 0..pixels {
+            counts.push(reader.u32()?);
+            sums.push([reader.f64()?, reader.f64()?, reader.f64()?, reader.f64()?]);
+        }
+        Ok(Accumulation { scene_hash, width, height, sampler, seed, samples_per_pixel, counts, sums })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(64 + self.counts.len() * 36);
+        data.extend_from_slice(MAGIC);
+        write_string(&mut data, &self.scene_hash);
+        data.extend_from_slice(&self.width.to_le_bytes());
+        data.extend_from_slice(&self.height.to_le_bytes());
+        write_string(&mut data, &self.sampler);
+        data.extend_from_slice(&self.seed.to_le_bytes());
+        data.extend_from_slice(&self.samples_per_pixel.to_le_bytes());
+        for (count, sum) in self.counts.iter().zip(self.sums.iter()) {
+            data.extend_from_slice(&count.to_le_bytes());
+            for channel in sum.iter() {
+                data.extend_from_slice(&channel.to_bits().to_le_bytes());
+            }
+        }
+        data
+    }
+
+    // Without a scene to check it against, for merging
+    pub fn read(path: &str) -> Result<Accumulation, AccumulationError> {
+        let data = fs::read(path).map_err(|e| AccumulationError::new(format!("{}: {}", path, e)))?;
+        Accumulation::from_bytes(&data).map_err(|e| AccumulationError::new(format!("{}: {}", path, e)))
+    }
+
+    pub fn load(path: &str, scene: &Scene) -> Result<Accumulation, AccumulationError> {
+        let data = match fs::read(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Accumulation::new(scene)),
+            Err(e) => return Err(AccumulationError::new(format!("{}: {}", path, e)))
+        };
+        let accumulation = Accumulation::from_bytes(&data).map_err(|e| AccumulationError::new(format!("{}: {}", path, e)))?;
+        accumulation.check(scene).map_err(|e| AccumulationError::new(format!("{}: {}", path, e)))?;
+        Ok(accumulation)
+    }
+
+    pub fn check(&self, scene: &Scene) -> Result<(), AccumulationError> {
+        let current = Accumulation::new(scene);
+        if self.width != current.width || self.height != current.height {
+            return Err(AccumulationError::new(format!("was accumulated at {}x{} instead of {}x{}", self.width, self.height, current.width, current.height)));
+        }
+        if self.sampler != current.sampler || self.seed != current.seed {
+            return Err(AccumulationError::new(format!("was accumulated with the {} sampler and seed {} instead of the {} sampler and seed {}", self.sampler, self.seed, current.sampler, current.seed)));
+        }
+        if self.samples_per_pixel != current.samples_per_pixel {
+            return Err(AccumulationError::new(format!("was accumulated with {} samples per pixel per run instead of {}", self.samples_per_pixel, current.samples_per_pixel)));
+        }
+        if self.scene_hash != current.scene_hash {
+            return Err(AccumulationError::new(format!("was accumulated from another scene (hash {} instead of {})", self.scene_hash, current.scene_hash)));
+        }
+        Ok(())
+    }
+
+    // Sums and counts add up, so every input weighs by its samples. The seed and samples per pixel of this state are kept
+    pub fn merge(&mut self, other: &Accumulation) -> Result<(), AccumulationError> {
+        if self.width != other.width || self.height != other.height {
+            return Err(AccumulationError::new(format!("is {}x{} instead of {}x{}", other.width, other.height, self.width, self.height)));
+        }
+        if self.scene_hash != other.scene_hash {
+            return Err(AccumulationError::new(format!("was accumulated from another scene (hash {} instead of {})", other.scene_hash, self.scene_hash)));
+        }
+        for (count, other_count) in self.counts.iter_mut().zip(other.counts.iter()) {
+            *count += other_count;
+        }
+        for (sum, other_sum) in self.sums.iter_mut().zip(other.sums.iter()) {
+            for (total, channel) in sum.iter_mut().zip(other_sum.iter()) {
+                *total += channel;
+            }
+        }
+        Ok(())
+    }
+
+    // Mean of every channel of every pixel quantized once to 16 bits, in the order png files store them
+    pub fn to_samples(&self) -> Vec<u16> {
+        self.sums.iter().zip(self.counts.iter()).flat_map(|(sum, &count)| {
+            let count = count.max(1) as f64;
+            sum.map(|channel| ((channel / count).clamp(0.0, 1.0) * 65535.0).round() as u16)
+        }).collect()
+    }
+
+    pub fn samples(&self) -> u32 {
+        self.counts.iter().copied().min().unwrap_or(0)
+    }
+
+    pub fn add(&mut self, nb_pass: u8, scene: &Scene, view: &View) {
+        let render_start = scene.profile.start();
+        let samples = scene.samples_per_pixel.max(1);
+        for pixel_y in 0..self.height {
+            for pixel_x in 0..self.width {
+                let index = (pixel_y * self.width + pixel_x) as usize;
+                let sum = rendering::sum_samples(nb_pass, scene, view, pixel_x, pixel_y, self.counts[index]);
+                for (total, channel) in self.sums[index].iter_mut().zip(sum.iter()) {
+                    *total += channel;
+                }
+                self.counts[index] += samples;
+            }
+        }
+        scene.profile.stop(Stage::RENDER, render_start);
+    }
+
+    pub fn to_image(&self) -> RgbaImage {
+        ImageBuffer::from_fn(self.width, self.height, |pixel_x, pixel_y| {
+            let index = (pixel_y * self.width + pixel_x) as usize;
+            rendering::average(&self.sums[index], self.counts[index]).to_rgba()
+        })
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), Box<dyn error::Error>> {
+        let temporary = format!("{}.tmp", path);
+        fs::write(&temporary, self.to_bytes())?;
+        fs::rename(&temporary, path)?;
+        Ok(())
+    }
+}
+
+pub fn render(nb_pass: u8, mut scene: Scene, output_path: String, state_path: &str) -> Result<(), Box<dyn error::Error>> {
+    let mut accumulation = Accumulation::load(state_path, &scene)?;
+    let previous = accumulation.samples();
+    rendering::prepare_caustics(nb_pass, &mut scene);
+    accumulation.add(nb_pass, &scene, &View::camera(&scene.camera));
+    accumulation.save(state_path)?;
+    let mut image = accumulation.to_image();
+    rendering::apply_post(&scene, &mut image);
+    let encode_start = scene.profile.start();
+    let total = accumulation.samples();
+    let metadata: Vec<(String, String)> = scene.metadata().into_iter()
+        .map(|(key, value)| if key == "Samples" { (key, total.to_string()) } else { (key, value) })
+        .collect();
+    output::write(&image, &output_path, &scene.output, &metadata)?;
+    scene.profile.stop(Stage::ENCODE, encode_start);
+    println!("Accumulated {} samples per pixel ({} before this run) in {}", total, previous, state_path);
+    scene.profile.report()?;
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::rendering::{AreaLight, Camera, Color, Light, Material, Renderable};
+    use crate::sampler::Sampler;
+    use crate::shape::{Plane, Shape, Sphere};
+    use crate::vertors::Vector3;
+
+    pub(crate) fn scene(sampler: Sampler, samples_per_pixel: u32) -> Scene {
+        let camera = Camera::new(12, 9, 60.0).looking_at(Vector3::new(0.0, 2.0, 4.0), Vector3::zero(), Vector3::new(0.0, 1.0, 0.0));
+        let floor = Renderable::new(Shape::PLANE(Plane::new(Vector3::zero(), Vector3::new(0.0, 1.0, 0.0))), Material::new(Color::white(), 0.8, 0.0));
+        let ball = Renderable::new(Shape::SPHERE(Sphere::new(Vector3::new(0.0, 0.7, 0.0), 0.7)), Material::new(Color::new(200, 80, 80, 255), 0.8, 0.0));
+        let lamp = Light::AREA(AreaLight::new(Vector3::new(-1.0, 3.0, -1.0), Vector3::new(2.0, 0.0, 0.0), Vector3::new(0.0, 0.0, 2.0), 400.0, Color::white(), 1));
+        let mut scene = Scene::new(camera, vec![floor, ball], vec![lamp], Color::new(40, 40, 60, 255));
+        scene.sampler = sampler;
+        scene.samples_per_pixel = samples_per_pixel;
+        scene.seed = 5;
+        scene.prepare().unwrap();
+        scene
+    }
+
+    pub(crate) fn accumulate(scene: &Scene, runs: u32) -> Accumulation {
+        let mut accumulation = Accumulation::new(scene);
+        for _ in 0..runs {
+            accumulation.add(4, scene, &View::camera(&scene.camera));
+        }
+        accumulation
+    }
+
+    fn accumulated(sampler: Sampler, samples_per_pixel: u32, runs: u32) -> Accumulation {
+        accumulate(&scene(sampler, samples_per_pixel), runs)
+    }
+
+    #[test]
+    fn two_runs_of_eight_samples_are_one_run_of_sixteen() {
+        for sampler in [Sampler::RANDOM, Sampler::STRATIFIED, Sampler::HALTON] {
+            let (twice, once) = (accumulated(sampler, 8, 2), accumulated(sampler, 16, 1));
+            assert_eq!(twice.counts, once.counts);
+            for (a, b) in twice.sums.iter().zip(once.sums.iter()) {
+                assert!(a.iter().zip(b.iter()).all(|(a, b)| (a - b).abs() < 1e-9), "{:?}: {:?} instead of {:?}", sampler, a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn bytes_round_trip() {
+        let accumulation = accumulated(Sampler::STRATIFIED, 2, 1);
+        assert_eq!(Accumulation::from_bytes(&accumulation.to_bytes()).unwrap(), accumulation);
+    }
+
+    #[test]
+    fn older_and_truncated_files_are_refused() {
+        let mut data = accumulated(Sampler::RANDOM, 1, 1).to_bytes();
+        data.pop();
+        assert!(Accumulation::from_bytes(&data).unwrap_err().message.contains("does not hold"));
+        data[..8].copy_from_slice(b"RTACCUM1");
+        assert!(Accumulation::from_bytes(&data).unwrap_err().message.contains("older version"));
+    }
+
+    #[test]
+    fn check_refuses_other_samples_per_pixel() {
+        let accumulation = accumulated(Sampler::STRATIFIED, 8, 1);
+        assert!(accumulation.check(&scene(Sampler::STRATIFIED, 8)).is_ok());
+        assert!(accumulation.check(&scene(Sampler::STRATIFIED, 4)).unwrap_err().message.contains("samples per pixel"));
+        assert!(accumulation.check(&scene(Sampler::HALTON, 8)).is_err());
+    }
+}
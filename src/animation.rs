@@ -0,0 +1,112 @@
+use std::error;
+use serde::{Serialize, Deserialize};
+use crate::rendering::{self, Scene, Camera, View};
+use crate::shape::Point;
+use crate::cubemap;
+use crate::output;
+use crate::profile::Stage;
+
+pub const DEFAULT_FPS: f64 = 24.0;
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum Interpolation {
+    #[default]
+    LINEAR,
+    SMOOTHSTEP // Eases in and out of every keyframe
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct CameraKeyframe {
+    pub time: f64, // Seconds
+    pub position: Point,
+    pub look_at: Point,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fov: Option<f64> // Keeps the scene camera fov when missing
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CameraPath {
+    pub keyframes: Vec<CameraKeyframe>,
+    #[serde(default)]
+    pub interpolation: Interpolation
+}
+
+impl CameraPath {
+    pub fn validate(&self, camera: &Camera) -> Result<(), String> {
+        if self.keyframes.is_empty() {
+            return Err("the camera path has no keyframes".to_string());
+        }
+        for (index, pair) in self.keyframes.windows(2).enumerate() {
+            if pair[1].time <= pair[0].time {
+                return Err(format!("camera path keyframe {} at time {} is not after the previous one at {}", index + 1, pair[1].time, pair[0].time));
+            }
+        }
+        for (index, keyframe) in self.keyframes.iter().enumerate() {
+            self.camera_at(camera, keyframe.time).basis().map_err(|e| format!("camera path keyframe {}: {}", index, e))?;
+            if let Some(fov) = keyframe.fov {
+                if !(fov > 0.0 && fov < 180.0) {
+                    return Err(format!("camera path keyframe {} fov must be between 0 and 180 degrees, not {}", index, fov));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn duration(&self) -> f64 {
+        match (self.keyframes.first(), self.keyframes.last()) {
+            (Some(first), Some(last)) => last.time - first.time,
+            _ => 0.0
+        }
+    }
+
+    // Camera at the given time, held at the first and last keyframes outside the path. The basis is rebuilt
+    // from the interpolated position and look_at so that it stays orthonormal
+    pub fn camera_at(&self, camera: &Camera, time: f64) -> Camera {
+        let next = self.keyframes.iter().position(|keyframe| keyframe.time > time).unwrap_or(self.keyframes.len());
+        let (from, to, t) = if next == 0 {
+            (self.keyframes[0], self.keyframes[0], 0.0)
+        } else if next == self.keyframes.len() {
+            (self.keyframes[next - 1], self.keyframes[next - 1], 0.0)
+        } else {
+            let (from, to) = (self.keyframes[next - 1], self.keyframes[next]);
+            (from, to, (time - from.time) / (to.time - from.time))
+        };
+        let t = match self.interpolation {
+            Interpolation::LINEAR => t,
+            Interpolation::SMOOTHSTEP => t * t * (3.0 - 2.0 * t)
+        };
+        let fov = camera.fov;
+        let fov = from.fov.unwrap_or(fov) + (to.fov.unwrap_or(fov) - from.fov.unwrap_or(fov)) * t;
+        let position = from.position + (to.position - from.position) * t;
+        let look_at = from.look_at + (to.look_at - from.look_at) * t;
+        Camera { fov, ..camera.clone().looking_at(position, look_at, camera.up) }
+    }
+}
+
+pub fn frame_path(output_path: &str, frame: u32) -> String {
+    cubemap::face_path(output_path, &format!("{:04}", frame))
+}
+
+// The scene is prepared once, only the camera changes between frames
+pub fn render(nb_pass: u8, mut scene: Scene, output_path: String, frames: Option<u32>, fps: f64) -> Result<(), Box<dyn error::Error>> {
+    let path = scene.camera_path.clone().ok_or("--frames needs a camera_path in the scene")?;
+    let frames = frames.unwrap_or_else(|| (path.duration() * fps).floor() as u32 + 1);
+    let start = path.keyframes[0].time;
+    let camera = scene.camera.clone();
+    rendering::prepare_caustics(nb_pass, &mut scene);
+    for frame in 0..frames {
+        scene.camera = path.camera_at(&camera, start + frame as f64 / fps);
+        scene.camera.prepare()?;
+        scene.focus()?;
+        let mut image = rendering::render_view(nb_pass, &scene, &View::camera(&scene.camera));
+        rendering::apply_post(&scene, &mut image);
+        let encode_start = scene.profile.start();
+        let frame_path = frame_path(&output_path, frame + 1);
+        output::write(&image, &frame_path, &scene.output, &scene.metadata())?;
+        scene.profile.stop(Stage::ENCODE, encode_start);
+        println!("Wrote {}", frame_path);
+    }
+    scene.profile.report()?;
+    Ok(())
+}
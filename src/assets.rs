@@ -0,0 +1,74 @@
+use std::error;
+use std::fmt;
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone)]
+pub struct AssetError {
+    pub errors: Vec<String>
+}
+
+impl AssetError {
+    pub fn new(errors: Vec<String>) -> AssetError {
+        AssetError { errors }
+    }
+}
+
+impl fmt::Display for AssetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} asset(s) failed to load", self.errors.len())?;
+        for e in self.errors.iter() {
+            write!(f, "\n    {}", e)?;
+        }
+        Ok(())
+    }
+}
+
+impl error::Error for AssetError {}
+
+pub trait Asset: Send {
+    fn name(&self) -> &str;
+    fn load(&mut self) -> Result<(), Box<dyn error::Error>>;
+    fn describe(&self) -> String;
+}
+
+impl<T: Asset + ?Sized> Asset for &mut T {
+    fn name(&self) -> &str {
+        (**self).name()
+    }
+
+    fn load(&mut self) -> Result<(), Box<dyn error::Error>> {
+        (**self).load()
+    }
+
+    fn describe(&self) -> String {
+        (**self).describe()
+    }
+}
+
+fn load_one<T: Asset>(asset: &mut T) -> Result<Duration, String> {
+    let start = Instant::now();
+    asset.load().map_err(|e| e.to_string())?;
+    Ok(start.elapsed())
+}
+
+pub fn load_all<T: Asset>(assets: &mut [T]) -> Result<Vec<Duration>, AssetError> {
+    if assets.is_empty() {
+        return Ok(Vec::new());
+    }
+    let threads = thread::available_parallelism().map(|count| count.get()).unwrap_or(1);
+    let chunk_size = assets.len().div_ceil(threads);
+    let results: Vec<Result<Duration, String>> = thread::scope(|scope| {
+        let handles: Vec<_> = assets.chunks_mut(chunk_size)
+            .map(|chunk| scope.spawn(move || chunk.iter_mut().map(load_one).collect::<Vec<_>>()))
+            .collect();
+        handles.into_iter()
+            .flat_map(|handle| handle.join().unwrap_or_else(|_| vec![Err("asset loader panicked".to_string())]))
+            .collect()
+    });
+    let errors: Vec<String> = results.iter().filter_map(|result| result.clone().err()).collect();
+    if !errors.is_empty() {
+        return Err(AssetError::new(errors));
+    }
+    Ok(results.into_iter().filter_map(Result::ok).collect())
+}
@@ -0,0 +1,235 @@
+use std::mem::size_of;
+use crate::aabb::Aabb;
+use crate::shape::{Ray, Point};
+
+// Most leaves end up with 2 to 4 items once halved, 3 on average
+const LEAF_SIZE: usize = 4;
+const AVERAGE_LEAF: u64 = 3;
+
+#[derive(Copy, Clone, Debug)]
+struct Node {
+    aabb: Aabb,
+    first: usize, // First item of a leaf in order, index of the second child of an inner node
+    count: usize // Items of a leaf, 0 for inner nodes whose first child follows them
+}
+
+// Bounding volume hierarchy over items given by their boxes, split at the median of their centers along the longest axis
+#[derive(Clone, Debug, Default)]
+pub struct Bvh {
+    nodes: Vec<Node>,
+    order: Vec<usize>
+}
+
+fn center(aabb: &Aabb) -> Point {
+    (aabb.min + aabb.max) * 0.5
+}
+
+fn axis(point: &Point, axis: usize) -> f64 {
+    match axis {
+        0 => point.x,
+        1 => point.y,
+        _ => point.z
+    }
+}
+
+impl Bvh {
+    pub fn build(boxes: &[Aabb]) -> Bvh {
+        let mut bvh = Bvh { nodes: Vec::with_capacity((boxes.len() as u64 * 2 / AVERAGE_LEAF) as usize + 1), order: (0..boxes.len()).collect() };
+        if !boxes.is_empty() {
+            bvh.build_node(boxes, 0, boxes.len());
+        }
+        bvh
+    }
+
+    fn build_node(&mut self, boxes: &[Aabb], start: usize, end: usize) -> usize {
+        let items = &mut self.order[start..end];
+        let aabb = items.iter().skip(1).fold(boxes[items[0]], |aabb, &item| aabb.union(&boxes[item]));
+        let index = self.nodes.len();
+        self.nodes.push(Node { aabb, first: start, count: end - start });
+        if end - start <= LEAF_SIZE {
+            return index;
+        }
+        let centers = items.iter().skip(1).fold(Aabb::new(center(&boxes[items[0]]), center(&boxes[items[0]])), |centers, &item| {
+            let point = center(&boxes[item]);
+            centers.union(&Aabb::new(point, point))
+        });
+        let extent = centers.max - centers.min;
+        let longest = if extent.x >= extent.y && extent.x >= extent.z { 0 } else if extent.y >= extent.z { 1 } else { 2 };
+        let middle = items.len() / 2;
+        items.select_nth_unstable_by(middle, |a, b| axis(&center(&boxes[*a]), longest).total_cmp(&axis(&center(&boxes[*b]), longest)));
+        self.build_node(boxes, start, start + middle);
+        let second = self.build_node(boxes, start + middle, end);
+        self.nodes[index] = Node { aabb, first: second, count: 0 };
+        index
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn bytes(&self) -> u64 {
+        (self.nodes.len() * size_of::<Node>() + self.order.len() * size_of::<usize>()) as u64
+    }
+
+    pub fn estimated_bytes(items: u64) -> u64 {
+        (items * 2 / AVERAGE_LEAF + 1) * size_of::<Node>() as u64 + items * size_of::<usize>() as u64
+    }
+
+    // Calls visit with every item whose leaf the ray reaches before the nearest distance found so far, nearer children
+    // first. visit returns the distance of a closer hit, which then bounds the rest of the traversal
+    pub fn traverse(&self, ray: &Ray, visit: impl FnMut(usize, f64) -> Option<f64>) {
+        self.traverse_with(ray, Aabb::range, visit)
+    }
+
+    // The box test is a parameter so that the traversal benchmark can compare slab tests
+    fn traverse_with(&self, ray: &Ray, range: impl Fn(&Aabb, &Ray) -> Option<(f64, f64)>, mut visit: impl FnMut(usize, f64) -> Option<f64>) {
+        if self.nodes.is_empty() {
+            return;
+        }
+        let mut t_max = ray.t_max;
+        let mut stack = vec![0];
+        while let Some(index) = stack.pop() {
+            let node = &self.nodes[index];
+            let limited = ray.with_range(ray.t_min, t_max);
+            if range(&node.aabb, &limited).is_none() {
+                continue;
+            }
+            if node.count > 0 {
+                for &item in self.order[node.first..node.first + node.count].iter() {
+                    if let Some(distance) = visit(item, t_max) {
+                        t_max = t_max.min(distance);
+                    }
+                }
+                continue;
+            }
+            let (near, far) = (index + 1, node.first);
+            let entry = |child: usize| range(&self.nodes[child].aabb, &limited).map_or(f64::INFINITY, |(entry, _)| entry);
+            if entry(far) < entry(near) {
+                stack.push(near);
+                stack.push(far);
+            } else {
+                stack.push(far);
+                stack.push(near);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::random::Rng;
+    use crate::vertors::Vector3;
+
+    fn boxes(count: usize) -> Vec<Aabb> {
+        let mut rng = Rng::new(232);
+        (0..count).map(|_| {
+            let center = Vector3::new(rng.next_f64() * 20.0 - 10.0, rng.next_f64() * 20.0 - 10.0, rng.next_f64() * 20.0 - 10.0);
+            Aabb::around(center, Vector3::new(rng.next_f64(), rng.next_f64(), rng.next_f64()) * 0.5)
+        }).collect()
+    }
+
+    // Distance at which the ray enters a box, standing for the hit of its item
+    fn entry(aabb: &Aabb, ray: &Ray, t_max: f64) -> Option<f64> {
+        aabb.range(&ray.with_range(ray.t_min, t_max)).map(|(entry, _)| entry)
+    }
+
+    #[test]
+    fn every_item_is_in_one_leaf_inside_its_parents() {
+        let boxes = boxes(1000);
+        let bvh = Bvh::build(&boxes);
+        let mut seen = bvh.order.clone();
+        seen.sort_unstable();
+        assert_eq!(seen, (0..1000).collect::<Vec<_>>());
+        for (index, node) in bvh.nodes.iter().enumerate() {
+            let children = if node.count > 0 { vec![] } else { vec![index + 1, node.first] };
+            for child in children {
+                let inner = bvh.nodes[child].aabb;
+                assert!(node.aabb.contains(inner.min) && node.aabb.contains(inner.max));
+            }
+            for &item in bvh.order[node.first..node.first + node.count].iter() {
+                assert!(node.aabb.contains(boxes[item].min) && node.aabb.contains(boxes[item].max));
+            }
+        }
+        assert!(bvh.bytes() <= Bvh::estimated_bytes(1000) * 3 / 2 && bvh.bytes() >= Bvh::estimated_bytes(1000) * 2 / 3);
+    }
+
+    #[test]
+    fn traversal_finds_the_nearest_item_like_a_linear_search() {
+        let boxes = boxes(500);
+        let bvh = Bvh::build(&boxes);
+        let mut rng = Rng::new(7);
+        for _ in 0..500 {
+            let origin = Vector3::new(rng.next_f64() * 40.0 - 20.0, rng.next_f64() * 40.0 - 20.0, rng.next_f64() * 40.0 - 20.0);
+            let ray = Ray::new(origin, (Vector3::new(rng.next_f64(), rng.next_f64(), rng.next_f64()) * 10.0 - origin).normalize());
+            let linear = boxes.iter().enumerate().filter_map(|(item, aabb)| entry(aabb, &ray, ray.t_max).map(|distance| (distance, item)))
+                .fold(None, |nearest: Option<(f64, usize)>, found| if nearest.is_none_or(|nearest| found.0 < nearest.0) { Some(found) } else { nearest });
+            let mut nearest: Option<(f64, usize)> = None;
+            let mut visited = 0;
+            bvh.traverse(&ray, |item, t_max| {
+                visited += 1;
+                let distance = entry(&boxes[item], &ray, t_max)?;
+                if nearest.is_none_or(|nearest| distance < nearest.0) {
+                    nearest = Some((distance, item));
+                }
+                Some(distance)
+            });
+            assert_eq!(nearest.map(|(_, item)| item), linear.map(|(_, item)| item));
+            assert!(visited < boxes.len());
+        }
+    }
+
+    #[test]
+    fn empty_and_single_item_hierarchies() {
+        let empty = Bvh::build(&[]);
+        assert_eq!(empty.node_count(), 0);
+        empty.traverse(&Ray::new(Vector3::zero(), Vector3::new(0.0, 0.0, 1.0)), |_, _| panic!("nothing to visit"));
+        let single = Bvh::build(&[Aabb::around(Vector3::new(0.0, 0.0, 5.0), Vector3::new(1.0, 1.0, 1.0))]);
+        let mut visited = Vec::new();
+        single.traverse(&Ray::new(Vector3::zero(), Vector3::new(0.0, 0.0, 1.0)), |item, _| { visited.push(item); None });
+        assert_eq!(visited, vec![0]);
+    }
+
+    // Random sphere stress scene: cargo test --release bvh::tests::traversal_benchmark -- --ignored --nocapture
+    // times the same traversals with the slab test on the precomputed inverse direction and with the branching one
+    #[test]
+    #[ignore]
+    fn traversal_benchmark() {
+        use std::time::Instant;
+        use crate::aabb::tests::branching_range;
+        use crate::shape::Sphere;
+        use crate::traits::Intersectable;
+        let mut rng = Rng::new(239);
+        let spheres: Vec<Sphere> = (0..100_000).map(|_| {
+            Sphere::new(Vector3::new(rng.next_f64() * 200.0 - 100.0, rng.next_f64() * 200.0 - 100.0, rng.next_f64() * 200.0 - 100.0), 0.2 + rng.next_f64() * 0.8)
+        }).collect();
+        let bvh = Bvh::build(&spheres.iter().map(|sphere| sphere.bounding_box().unwrap()).collect::<Vec<_>>());
+        let rays: Vec<Ray> = (0..200_000).map(|index| {
+            let origin = Vector3::new(rng.next_f64() * 240.0 - 120.0, rng.next_f64() * 240.0 - 120.0, rng.next_f64() * 240.0 - 120.0);
+            // One ray in eight is axis aligned, like the rays of orthographic cameras
+            let direction = match index % 8 {
+                0 => [Vector3::new(1.0, 0.0, 0.0), Vector3::new(0.0, -1.0, 0.0), Vector3::new(0.0, 0.0, 1.0)][index / 8 % 3],
+                _ => (Vector3::new(rng.next_f64(), rng.next_f64(), rng.next_f64()) * 200.0 - Vector3::new(100.0, 100.0, 100.0) - origin).normalize()
+            };
+            Ray::new(origin, direction)
+        }).collect();
+        let run = |range: fn(&Aabb, &Ray) -> Option<(f64, f64)>| {
+            let start = Instant::now();
+            let hits: Vec<Option<f64>> = rays.iter().map(|ray| {
+                let mut nearest = None;
+                bvh.traverse_with(ray, range, |item, t_max| {
+                    let hit = spheres[item].intersect(&ray.with_range(ray.t_min, t_max))?;
+                    nearest = Some(hit.distance);
+                    Some(hit.distance)
+                });
+                nearest
+            }).collect();
+            (start.elapsed(), hits)
+        };
+        let (precomputed, found) = run(Aabb::range);
+        let (branching, expected) = run(branching_range);
+        assert_eq!(found, expected);
+        println!("{} rays through {} spheres: {:?} branching, {:?} precomputed, {:.2}x", rays.len(), spheres.len(), branching, precomputed,
+            branching.as_secs_f64() / precomputed.as_secs_f64());
+    }
+}
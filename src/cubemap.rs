@@ -0,0 +1,189 @@
+use std::error;
+use std::path::Path;
+use image::{ImageBuffer, RgbaImage, Rgba, GenericImage};
+use crate::rendering::{self, Scene, View};
+use crate::shape::{Ray, Point};
+use crate::vertors::Vector3;
+use crate::output;
+use crate::profile::Stage;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CubemapLayout {
+    FILES,
+    CROSS
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Face {
+    pub suffix: &'static str,
+    pub forward: Vector3,
+    pub right: Vector3,
+    pub up: Vector3,
+    pub cell: (u32, u32)
+}
+
+pub const FACES: [Face; 6] = [
+    Face { suffix: "px", forward: Vector3 { x: 1.0, y: 0.0, z: 0.0 }, right: Vector3 { x: 0.0, y: 0.0, z: 1.0 }, up: Vector3 { x: 0.0, y: 1.0, z: 0.0 }, cell: (2, 1) },
+    Face { suffix: "nx", forward: Vector3 { x: -1.0, y: 0.0, z: 0.0 }, right: Vector3 { x: 0.0, y: 0.0, z: -1.0 }, up: Vector3 { x: 0.0, y: 1.0, z: 0.0 }, cell: (0, 1) },
+    Face { suffix: "py", forward: Vector3 { x: 0.0, y: 1.0, z: 0.0 }, right: Vector3 { x: 1.0, y: 0.0, z: 0.0 }, up: Vector3 { x: 0.0, y: 0.0, z: 1.0 }, cell: (1, 0) },
+    Face { suffix: "ny", forward: Vector3 { x: 0.0, y: -1.0, z: 0.0 }, right: Vector3 { x: 1.0, y: 0.0, z: 0.0 }, up: Vector3 { x: 0.0, y: 0.0, z: -1.0 }, cell: (1, 2) },
+    Face { suffix: "pz", forward: Vector3 { x: 0.0, y: 0.0, z: 1.0 }, right: Vector3 { x: -1.0, y: 0.0, z: 0.0 }, up: Vector3 { x: 0.0, y: 1.0, z: 0.0 }, cell: (3, 1) },
+    Face { suffix: "nz", forward: Vector3 { x: 0.0, y: 0.0, z: -1.0 }, right: Vector3 { x: 1.0, y: 0.0, z: 0.0 }, up: Vector3 { x: 0.0, y: 1.0, z: 0.0 }, cell: (1, 1) }
+];
+
+impl Face {
+    pub fn view(&self, origin: Point, size: u32, stream: u64) -> View {
+        let face = *self;
+        View::new(size, size, stream, Box::new(move |x, y, offset_x, offset_y| {
+            let u = ((x as f64 + offset_x) / size as f64) * 2.0 - 1.0;
+            let v = 1.0 - ((y as f64 + offset_y) / size as f64) * 2.0;
+            Ray::new(origin, (face.forward + face.right * u + face.up * v).normalize())
+        }))
+    }
+}
+
+pub fn face_path(output_path: &str, suffix: &str) -> String {
+    let path = Path::new(output_path);
+    let stem = path.file_stem().and_then(|stem| stem.to_str()).unwrap_or("output");
+    let name = match path.extension().and_then(|extension| extension.to_str()) {
+        Some(extension) => format!("{}_{}.{}", stem, suffix, extension),
+        None => format!("{}_{}", stem, suffix)
+    };
+    path.with_file_name(name).to_string_lossy().to_string()
+}
+
+pub fn render_faces(nb_pass: u8, scene: &mut Scene) -> Vec<RgbaImage> {
+    rendering::prepare_caustics(nb_pass, scene);
+    let size = scene.camera.height;
+    FACES.iter().enumerate().map(|(index, face)| {
+        let mut image = rendering::render_view(nb_pass, scene, &face.view(scene.camera.origin(), size, index as u64 + 1));
+        rendering::apply_post(scene, &mut image);
+        image
+    }).collect()
+}
+
+pub fn cross(faces: &[RgbaImage]) -> RgbaImage {
+    let size = faces.first().map_or(0, |face| face.height());
+    let mut image: RgbaImage = ImageBuffer::from_pixel(size * 4, size * 3, Rgba([0, 0, 0, 0]));
+    for (face, pixels) in FACES.iter().zip(faces.iter()) {
+        let _ = image.copy_from(pixels, face.cell.0 * size, face.cell.1 * size);
+    }
+    image
+}
+
+pub fn render(nb_pass: u8, mut scene: Scene, output_path: String, layout: CubemapLayout) -> Result<(), Box<dyn error::Error>> {
+    let faces = render_faces(nb_pass, &mut scene);
+    let encode_start = scene.profile.start();
+    let metadata = scene.metadata();
+    match layout {
+        CubemapLayout::CROSS => output::write(&cross(&faces), &output_path, &scene.output, &metadata)?,
+        CubemapLayout::FILES => {
+            for (face, image) in FACES.iter().zip(faces.iter()) {
+                let path = face_path(&output_path, face.suffix);
+                output::write(image, &path, &scene.output, &metadata)?;
+                println!("Wrote {}", path);
+            }
+        }
+    }
+    scene.profile.stop(Stage::ENCODE, encode_start);
+    scene.profile.report()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rendering::{Camera, Color, DirectionalLight, Light, Material, Renderable};
+    use crate::shape::{Shape, Sphere};
+
+    fn direction(face: &Face, u: f64, v: f64) -> Vector3 {
+        (face.forward + face.right * u + face.up * v).normalize()
+    }
+
+    fn same(a: Vector3, b: Vector3) -> bool {
+        (a - b).length() < 1e-12
+    }
+
+    fn face(suffix: &str) -> &'static Face {
+        FACES.iter().find(|face| face.suffix == suffix).unwrap()
+    }
+
+    #[test]
+    fn faces_are_orthonormal_and_cover_every_axis() {
+        for face in FACES.iter() {
+            for axis in [face.forward, face.right, face.up].iter() {
+                assert!((axis.length() - 1.0).abs() < 1e-12);
+            }
+            assert!(face.forward.dot(&face.right).abs() < 1e-12 && face.forward.dot(&face.up).abs() < 1e-12 && face.right.dot(&face.up).abs() < 1e-12);
+            // Seen from inside the cube every face keeps the same handedness
+            assert!(same(face.right.cross(&face.up), -face.forward), "{}", face.suffix);
+        }
+        let total = FACES.iter().fold(Vector3::new(0.0, 0.0, 0.0), |total, face| total + face.forward);
+        assert!(same(total, Vector3::new(0.0, 0.0, 0.0)));
+        let mut cells: Vec<(u32, u32)> = FACES.iter().map(|face| face.cell).collect();
+        cells.sort_unstable();
+        cells.dedup();
+        assert_eq!(cells.len(), 6);
+    }
+
+    #[test]
+    fn neighbour_cells_of_the_cross_share_their_edges() {
+        for t in [-1.0, -0.3, 0.0, 0.7, 1.0].iter() {
+            for (left, right) in [("nx", "nz"), ("nz", "px"), ("px", "pz"), ("pz", "nx")].iter() {
+                assert!(same(direction(face(left), 1.0, *t), direction(face(right), -1.0, *t)), "{} {}", left, right);
+            }
+            assert!(same(direction(face("py"), *t, -1.0), direction(face("nz"), *t, 1.0)));
+            assert!(same(direction(face("ny"), *t, 1.0), direction(face("nz"), *t, -1.0)));
+        }
+    }
+
+    #[test]
+    fn views_cover_ninety_degrees() {
+        let face = face("px");
+        let view = face.view(Point::new(1.0, 2.0, 3.0), 4, 1);
+        let center = (view.ray)(2, 2, 0.0, 0.0);
+        assert!(same(center.origin, Point::new(1.0, 2.0, 3.0)));
+        assert!(same(center.direction, face.forward));
+        let corner = (view.ray)(0, 0, 0.0, 0.0);
+        assert!(same(corner.direction, direction(face, -1.0, 1.0)));
+        assert!((corner.direction.dot(&face.right) / corner.direction.dot(&face.forward) + 1.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn face_files_get_a_suffix() {
+        assert_eq!(face_path("out.png", "px"), "out_px.png");
+        assert_eq!(face_path("renders/sky.jpg", "nz"), "renders/sky_nz.jpg");
+        assert_eq!(face_path("out", "py"), "out_py");
+    }
+
+    #[test]
+    fn faces_are_placed_in_a_cross() {
+        let faces: Vec<RgbaImage> = (0..6).map(|index| ImageBuffer::from_pixel(3, 3, Rgba([index as u8 + 1, 0, 0, 255]))).collect();
+        let image = cross(&faces);
+        assert_eq!(image.dimensions(), (12, 9));
+        for (index, face) in FACES.iter().enumerate() {
+            assert_eq!(image.get_pixel(face.cell.0 * 3 + 1, face.cell.1 * 3 + 1)[0], index as u8 + 1);
+        }
+        assert_eq!(image.get_pixel(0, 0), &Rgba([0, 0, 0, 0]));
+        assert_eq!(image.get_pixel(11, 8), &Rgba([0, 0, 0, 0]));
+    }
+
+    #[test]
+    fn each_face_sees_its_own_direction() {
+        let ball = Renderable::new(Shape::SPHERE(Sphere::new(Vector3::new(0.0, 0.0, -5.0), 1.0)), Material::new(Color::new(255, 0, 0, 255), 1.0, 0.0));
+        let sun = Light::DIRECTIONAL(DirectionalLight::new(Vector3::new(0.0, 0.0, -1.0), 5.0, Color::white()));
+        let mut scene = Scene::new(Camera::new(8, 8, 60.0), vec![ball], vec![sun], Color::new(0, 0, 255, 255));
+        scene.prepare().unwrap();
+        let faces = render_faces(1, &mut scene);
+        assert_eq!(faces.len(), 6);
+        for (face, image) in FACES.iter().zip(faces.iter()) {
+            assert_eq!(image.dimensions(), (8, 8));
+            let center = image.get_pixel(4, 4);
+            if face.suffix == "nz" {
+                assert!(center[0] > 0 && center[2] == 0, "{:?}", center);
+            } else {
+                assert_eq!(center, &Rgba([0, 0, 255, 255]), "{}", face.suffix);
+            }
+        }
+    }
+}
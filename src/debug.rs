@@ -0,0 +1,268 @@
+use std::fmt::Write;
+use serde::Serialize;
+use crate::rendering::{self, Scene, Renderable, Material, Color, View};
+use crate::shape::{Ray, RayType, Hit};
+use crate::traits::Recorder;
+use crate::vertors::Vector3;
+
+pub struct NoRecorder;
+
+impl Recorder for NoRecorder {}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct CandidateEvent {
+    pub element: usize,
+    pub distance: f64
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct HitEvent {
+    pub element: Option<usize>,
+    pub distance: f64,
+    pub point: Vector3,
+    pub normal: Vector3,
+    pub front_face: bool,
+    pub material: Material,
+    pub file_material: bool, // The material comes from the mesh file instead of the element
+    pub vertex_color: Option<Color>,
+    pub base_color: Color, // The texture of the material at the hit
+    pub uv: (f64, f64)
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct LightEvent {
+    pub light: usize,
+    pub direction: Vector3,
+    pub brightness: f64,
+    pub blocked_at: Option<f64>,
+    pub contribution: [f64; 3]
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct RayEvent {
+    pub ray_type: RayType,
+    pub origin: Vector3,
+    pub direction: Vector3,
+    pub candidates: Vec<CandidateEvent>,
+    pub hit: Option<HitEvent>,
+    pub lights: Vec<LightEvent>,
+    pub caustic: Option<[f64; 3]>,
+    pub children: Vec<RayEvent>,
+    pub color: Option<Color>
+}
+
+impl RayEvent {
+    fn new(ray_type: RayType, ray: &Ray) -> RayEvent {
+        RayEvent {
+            ray_type,
+            origin: ray.origin,
+            direction: ray.direction,
+            candidates: Vec::new(),
+            hit: None,
+            lights: Vec::new(),
+            caustic: None,
+            children: Vec::new(),
+            color: None
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct TreeRecorder {
+    stack: Vec<RayEvent>,
+    pub root: Option<RayEvent>
+}
+
+impl Recorder for TreeRecorder {
+    fn is_recording(&self) -> bool {
+        true
+    }
+
+    fn enter(&mut self, ray_type: RayType, ray: &Ray) {
+        self.stack.push(RayEvent::new(ray_type, ray));
+    }
+
+    fn candidate(&mut self, element: usize, hit: &Hit) {
+        if let Some(event) = self.stack.last_mut() {
+            event.candidates.push(CandidateEvent { element, distance: hit.distance });
+        }
+    }
+
+    fn hit(&mut self, renderable: &Renderable, hit: &Hit) {
+        if let Some(event) = self.stack.last_mut() {
+            let element = event.candidates.iter().find(|candidate| candidate.distance == hit.distance).map(|candidate| candidate.element);
+            let material = hit.material.as_ref().unwrap_or(&renderable.material);
+            event.hit = Some(HitEvent { element, distance: hit.distance, point: hit.point, normal: hit.normal, front_face: hit.front_face, material: material.clone(), file_material: hit.material.is_some(), vertex_color: hit.color, base_color: material.base_color.color_at(hit), uv: hit.uv });
+        }
+    }
+
+    fn light(&mut self, light: usize, direction: Vector3, brightness: f64, blocked_at: Option<f64>, contribution: [f64; 3]) {
+        if let Some(event) = self.stack.last_mut() {
+            event.lights.push(LightEvent { light, direction, brightness, blocked_at, contribution });
+        }
+    }
+
+    fn caustic(&mut self, irradiance: [f64; 3]) {
+        if let Some(event) = self.stack.last_mut() {
+            event.caustic = Some(irradiance);
+        }
+    }
+
+    fn leave(&mut self, color: Color) {
+        if let Some(mut event) = self.stack.pop() {
+            event.color = Some(color);
+            match self.stack.last_mut() {
+                Some(parent) => parent.children.push(event),
+                None => self.root = Some(event)
+            }
+        }
+    }
+}
+
+pub fn trace_pixel(nb_pass: u8, scene: &mut Scene, x: u32, y: u32) -> RayEvent {
+    rendering::prepare_caustics(nb_pass, scene);
+    let mut recorder = TreeRecorder::default();
+    rendering::sample_pixel(nb_pass, scene, &View::camera(&scene.camera), x, y, 0, &mut recorder);
+    recorder.root.unwrap_or_else(|| RayEvent::new(RayType::PRIMARY, &Ray::compute_prime_ray(x, y, &scene.camera)))
+}
+
+fn vector(v: &Vector3) -> String {
+    format!("({:.4}, {:.4}, {:.4})", v.x, v.y, v.z)
+}
+
+fn write_event(out: &mut String, event: &RayEvent, depth: usize) {
+    let indent = "  ".repeat(depth);
+    let _ = writeln!(out, "{}{:?} ray origin {} direction {}", indent, event.ray_type, vector(&event.origin), vector(&event.direction));
+    for candidate in event.candidates.iter() {
+        let _ = writeln!(out, "{}  candidate elements[{}] at {:.4}", indent, candidate.element, candidate.distance);
+    }
+    match &event.hit {
+        Some(hit) => {
+            let element = hit.element.map_or("?".to_string(), |element| element.to_string());
+            let side = if hit.front_face { "" } else { " (back face, normal flipped)" };
+            let _ = writeln!(out, "{}  hit elements[{}] at {:.4} point {} normal {}{} uv ({:.4}, {:.4})", indent, element, hit.distance, vector(&hit.point), vector(&hit.normal), side, hit.uv.0, hit.uv.1);
+            let color = hit.base_color;
+            let source = if hit.file_material { " from the mesh file" } else { "" };
+            let _ = writeln!(out, "{}    material{} base_color ({}, {}, {}) albedo {} reflectiveness {}", indent, source, color.r, color.g, color.b, hit.material.albedo, hit.material.reflectiveness);
+            if let Some(color) = hit.vertex_color {
+                let _ = writeln!(out, "{}    vertex color ({}, {}, {}) replaces base_color", indent, color.r, color.g, color.b);
+            }
+        },
+        None if event.candidates.is_empty() => {
+            let _ = writeln!(out, "{}  no hit, sky color", indent);
+        },
+        None => {
+            let _ = writeln!(out, "{}  maximum depth reached", indent);
+        }
+    }
+    for light in event.lights.iter() {
+        let shadow = match light.blocked_at {
+            Some(distance) => format!("blocked at {:.4}", distance),
+            None => "clear".to_string()
+        };
+        let _ = writeln!(out, "{}  lights[{}] direction {} brightness {:.4} shadow {} contribution ({:.4}, {:.4}, {:.4})",
+            indent, light.light, vector(&light.direction), light.brightness, shadow, light.contribution[0], light.contribution[1], light.contribution[2]);
+    }
+    if let Some(caustic) = event.caustic {
+        let _ = writeln!(out, "{}  caustic irradiance ({:.4}, {:.4}, {:.4})", indent, caustic[0], caustic[1], caustic[2]);
+    }
+    for child in event.children.iter() {
+        write_event(out, child, depth + 1);
+    }
+    if let Some(color) = event.color {
+        let _ = writeln!(out, "{}  color ({}, {}, {}, {})", indent, color.r, color.g, color.b, color.a);
+    }
+}
+
+pub fn format_tree(event: &RayEvent) -> String {
+    let mut out = String::new();
+    write_event(&mut out, event, 0);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rendering::{Camera, DirectionalLight, Light};
+    use crate::shape::{Shape, Sphere};
+
+    // A reflective ball in the middle of the image with a second ball above it, between it and the sun
+    fn scene() -> Scene {
+        let ball = Renderable::new(Shape::SPHERE(Sphere::new(Vector3::new(0.0, 0.0, -5.0), 1.0)), Material::new(Color::white(), 0.5, 0.5));
+        let blocker = Renderable::new(Shape::SPHERE(Sphere::new(Vector3::new(0.0, 3.0, -5.0), 1.0)), Material::new(Color::white(), 0.5, 0.0));
+        let sun = Light::DIRECTIONAL(DirectionalLight::new(Vector3::new(0.0, -1.0, 0.0), 5.0, Color::white()));
+        let mut scene = Scene::new(Camera::new(64, 48, 60.0), vec![ball, blocker], vec![sun], Color::new(10, 20, 30, 255));
+        scene.prepare().unwrap();
+        scene
+    }
+
+    fn rendered(scene: &Scene, x: u32, y: u32) -> Color {
+        rendering::sample_pixel(1, scene, &View::camera(&scene.camera), x, y, 0, &mut NoRecorder)
+    }
+
+    fn same_color(a: Color, b: Color) -> bool {
+        (a.r, a.g, a.b, a.a) == (b.r, b.g, b.b, b.a)
+    }
+
+    #[test]
+    fn the_tree_records_the_hit_lights_and_reflection() {
+        let mut scene = scene();
+        let root = trace_pixel(1, &mut scene, 32, 24);
+        assert_eq!(root.ray_type, RayType::PRIMARY);
+        assert_eq!(root.candidates.len(), 1);
+        let hit = root.hit.as_ref().unwrap();
+        assert_eq!(hit.element, Some(0));
+        assert!((hit.distance - 4.0).abs() < 0.01);
+        assert!(hit.normal.z > 0.99);
+        assert_eq!(root.lights.len(), 1);
+        assert_eq!(root.lights[0].light, 0);
+        assert_eq!(root.children.len(), 1);
+        assert_eq!(root.children[0].ray_type, RayType::REFLECTION);
+        assert!(root.children[0].hit.is_none());
+    }
+
+    #[test]
+    fn shadow_rays_report_where_they_are_blocked() {
+        let mut scene = scene();
+        // The top of the ball, right under the blocker
+        let y = (0..24).rev().find(|y| trace_pixel(1, &mut scene, 32, *y).hit.is_none()).unwrap() + 2;
+        let root = trace_pixel(1, &mut scene, 32, y);
+        assert_eq!(root.hit.as_ref().unwrap().element, Some(0));
+        let blocked_at = root.lights[0].blocked_at.unwrap();
+        assert!(blocked_at > 0.0 && blocked_at < 2.0);
+        assert_eq!(root.lights[0].contribution, [0.0; 3]);
+    }
+
+    #[test]
+    fn the_traced_color_is_the_rendered_color() {
+        let mut scene = scene();
+        for &(x, y) in [(32, 24), (0, 0), (40, 20), (32, 5)].iter() {
+            let root = trace_pixel(1, &mut scene, x, y);
+            assert!(same_color(root.color.unwrap(), rendered(&scene, x, y)), "pixel {},{}", x, y);
+        }
+    }
+
+    #[test]
+    fn missed_rays_show_the_sky() {
+        let mut scene = scene();
+        let root = trace_pixel(1, &mut scene, 0, 0);
+        assert!(root.candidates.is_empty() && root.hit.is_none() && root.children.is_empty());
+        assert!(same_color(root.color.unwrap(), Color::new(10, 20, 30, 255)));
+        assert!(format_tree(&root).contains("no hit, sky color"));
+    }
+
+    #[test]
+    fn the_tree_is_printed_indented() {
+        let mut scene = scene();
+        let text = format_tree(&trace_pixel(1, &mut scene, 32, 24));
+        let lines: Vec<&str> = text.lines().collect();
+        assert!(lines[0].starts_with("PRIMARY ray origin"));
+        assert!(lines.iter().any(|line| line.starts_with("  candidate elements[0] at 4.00")));
+        assert!(lines.iter().any(|line| line.starts_with("  hit elements[0] at 4.00")));
+        assert!(lines.iter().any(|line| line.starts_with("  lights[0] direction")));
+        assert!(lines.iter().any(|line| line.starts_with("  REFLECTION ray origin")));
+        assert!(lines.iter().any(|line| line.starts_with("    no hit, sky color")));
+        let json = serde_json::to_value(trace_pixel(1, &mut scene, 32, 24)).unwrap();
+        assert_eq!(json["children"][0]["ray_type"], "REFLECTION");
+    }
+}
@@ -0,0 +1,359 @@
+use std::error;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use serde::{Serialize, Deserialize};
+use image::hdr::HdrDecoder;
+use crate::rendering::{Color, LinearColor, Light, DirectionalLight};
+use crate::vertors::Vector3;
+use crate::assets::Asset;
+
+// Linear radiance of every pixel, row by row from the top of the image
+#[derive(Clone, Debug, Default)]
+pub struct EnvironmentPixels {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<[f32; 3]>
+}
+
+impl EnvironmentPixels {
+    fn pixel(&self, column: usize, row: usize) -> [f32; 3] {
+        self.pixels[row * self.width + column]
+    }
+
+    // Bilinear filtering between the four nearest pixel centers, wrapping around horizontally at the seam
+    fn sample(&self, u: f64, v: f64) -> [f64; 3] {
+        let x = u * self.width as f64 - 0.5;
+        let y = (v * self.height as f64 - 0.5).clamp(0.0, (self.height - 1) as f64);
+        let (left, top) = (x.floor(), y.floor());
+        let (tx, ty) = (x - left, y - top);
+        let left = (left as i64).rem_euclid(self.width as i64) as usize;
+        let right = (left + 1) % self.width;
+        let top = top as usize;
+        let bottom = (top + 1).min(self.height - 1);
+        let mut color = [0.0; 3];
+        for (channel, value) in color.iter_mut().enumerate() {
+            let upper = self.pixel(left, top)[channel] as f64 * (1.0 - tx) + self.pixel(right, top)[channel] as f64 * tx;
+            let lower = self.pixel(left, bottom)[channel] as f64 * (1.0 - tx) + self.pixel(right, bottom)[channel] as f64 * tx;
+            *value = upper * (1.0 - ty) + lower * ty;
+        }
+        color
+    }
+}
+
+fn default_strength() -> f64 {
+    1.0
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct EnvironmentImage {
+    pub file: String,
+    #[serde(default = "default_strength")]
+    pub strength: f64,
+    // Degrees around the vertical axis, turning the image to the left
+    #[serde(default)]
+    pub rotation: f64,
+    // Cosine weighted directions averaged at every hit for the ambient lighting, none leaves it out
+    #[serde(default)]
+    pub ambient_samples: u32,
+    #[serde(skip)]
+    pub image: EnvironmentPixels
+}
+
+impl EnvironmentImage {
+    // Same mapping as the panoramic camera: the center of the image is toward -z and the top row is straight up
+    pub fn radiance(&self, direction: Vector3) -> LinearColor {
+        if self.image.pixels.is_empty() {
+            return LinearColor::new(0.0, 0.0, 0.0, 1.0);
+        }
+        let direction = direction.normalize();
+        let longitude = direction.x.atan2(-direction.z) - self.rotation.to_radians();
+        let latitude = direction.y.clamp(-1.0, 1.0).asin();
+        let u = (longitude / (2.0 * std::f64::consts::PI) + 0.5).rem_euclid(1.0);
+        let v = 0.5 - latitude / std::f64::consts::PI;
+        let [r, g, b] = self.image.sample(u, v);
+        LinearColor::new(r * self.strength, g * self.strength, b * self.strength, 1.0)
+    }
+
+    fn load_pixels(&self) -> Result<EnvironmentPixels, Box<dyn error::Error>> {
+        let is_hdr = Path::new(&self.file).extension().is_some_and(|extension| extension.eq_ignore_ascii_case("hdr"));
+        if is_hdr {
+            let decoder = HdrDecoder::new(BufReader::new(File::open(&self.file)?))?;
+            let metadata = decoder.metadata();
+            let pixels = decoder.read_image_hdr()?.into_iter().map(|pixel| pixel.0).collect();
+            Ok(EnvironmentPixels { width: metadata.width as usize, height: metadata.height as usize, pixels })
+        } else {
+            // Other images are read as they are stored, like the rest of the colors of the scene
+            let image = image::open(&self.file)?.to_rgb();
+            let (width, height) = (image.width() as usize, image.height() as usize);
+            let pixels = image.pixels().map(|pixel| [pixel[0] as f32 / 255.0, pixel[1] as f32 / 255.0, pixel[2] as f32 / 255.0]).collect();
+            Ok(EnvironmentPixels { width, height, pixels })
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct SkyGradient {
+    pub zenith: Color,
+    pub horizon: Color,
+    #[serde(default)]
+    pub ground: Option<Color> // Below the horizon, the horizon color carries on without it
+}
+
+// The sky color is either a single color or a vertical gradient, both forms are read from the same key
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Sky {
+    COLOR(Color),
+    GRADIENT(SkyGradient)
+}
+
+impl Default for Sky {
+    fn default() -> Sky {
+        Sky::COLOR(Color::black())
+    }
+}
+
+fn mix(from: Color, to: Color, t: f64) -> LinearColor {
+    let t = t * t * (3.0 - 2.0 * t);
+    LinearColor::from_color(from) * (1.0 - t) + LinearColor::from_color(to) * t
+}
+
+impl Sky {
+    // Smooth blend from the horizon, toward the zenith straight up and toward the ground straight down
+    pub fn radiance(&self, direction: Vector3) -> LinearColor {
+        match self {
+            Sky::COLOR(color) => LinearColor::from_color(*color),
+            Sky::GRADIENT(gradient) => {
+                let height = direction.normalize().y.clamp(-1.0, 1.0);
+                match gradient.ground {
+                    Some(ground) if height < 0.0 => mix(gradient.horizon, ground, -height),
+                    _ => mix(gradient.horizon, gradient.zenith, height.max(0.0))
+                }
+            }
+        }
+    }
+}
+
+// Preetham's luminances are in kcd/m², scaled so that a clear noon zenith stays well below full intensity
+const LUMINANCE_SCALE: f64 = 0.04;
+// Sunlight above the atmosphere in klx, in the same units as the sky luminance
+const SOLAR_ILLUMINANCE: f64 = 128.0;
+
+fn default_turbidity() -> f64 {
+    3.0
+}
+
+fn default_sun_radius() -> f64 {
+    0.27
+}
+
+fn default_ground_albedo() -> f64 {
+    0.3
+}
+
+// Perez sky distribution: the first factor darkens toward the zenith, the second brightens around the sun
+fn perez(coefficients: [f64; 5], cos_theta: f64, gamma: f64) -> f64 {
+    let [a, b, c, d, e] = coefficients;
+    (1.0 + a * (b / cos_theta.max(1e-3)).exp()) * (1.0 + c * (d * gamma).exp() + e * gamma.cos() * gamma.cos())
+}
+
+fn zenith_chromaticity(turbidity: f64, theta: f64, coefficients: [[f64; 4]; 3]) -> f64 {
+    let angles = [theta * theta * theta, theta * theta, theta, 1.0];
+    let row = |row: [f64; 4]| row.iter().zip(angles.iter()).map(|(coefficient, angle)| coefficient * angle).sum::<f64>();
+    turbidity * turbidity * row(coefficients[0]) + turbidity * row(coefficients[1]) + row(coefficients[2])
+}
+
+fn xyy_to_rgb(x: f64, y: f64, luminance: f64) -> [f64; 3] {
+    let (big_x, big_z) = (x / y * luminance, (1.0 - x - y) / y * luminance);
+    [
+        (3.2406 * big_x - 1.5372 * luminance - 0.4986 * big_z).max(0.0),
+        (-0.9689 * big_x + 1.8758 * luminance + 0.0415 * big_z).max(0.0),
+        (0.0557 * big_x - 0.2040 * luminance + 1.0570 * big_z).max(0.0)
+    ]
+}
+
+// Procedural clear sky from "A Practical Analytic Model for Daylight" (Preetham, Shirley and Smits), with the sun in it
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SunSky {
+    pub elevation: f64, // Degrees above the horizon
+    #[serde(default)]
+    pub azimuth: f64, // Degrees from -z toward +x
+    #[serde(default = "default_turbidity")]
+    pub turbidity: f64, // Haze, from 2 (very clear) to 10 (hazy)
+    #[serde(default = "default_strength")]
+    pub strength: f64,
+    #[serde(default = "default_sun_radius")]
+    pub sun_radius: f64, // Angular radius of the sun disk in degrees
+    #[serde(default = "default_ground_albedo")]
+    pub ground_albedo: f64, // Below the horizon, the horizon color is darkened by the ground
+    #[serde(default)]
+    pub ambient_samples: u32
+}
+
+impl SunSky {
+    pub fn sun_direction(&self) -> Vector3 {
+        let (elevation, azimuth) = (self.elevation.to_radians(), self.azimuth.to_radians());
+        Vector3::new(azimuth.sin() * elevation.cos(), elevation.sin(), -azimuth.cos() * elevation.cos())
+    }
+
+    // Rayleigh and aerosol extinction along the air mass the sunlight goes through, reddening low suns
+    fn sun_transmittance(&self) -> [f64; 3] {
+        let zenith_degrees = 90.0 - self.elevation;
+        let air_mass = 1.0 / (zenith_degrees.to_radians().cos() + 0.15 * (93.885 - zenith_degrees).powf(-1.253));
+        let aerosol = 0.04608 * self.turbidity - 0.04586;
+        let mut transmittance = [0.0; 3];
+        for (value, wavelength) in transmittance.iter_mut().zip([0.68_f64, 0.55, 0.44]) {
+            let rayleigh = 0.008569 * wavelength.powi(-4) * (1.0 + 0.0113 * wavelength.powi(-2) + 0.00013 * wavelength.powi(-4));
+            *value = (-(rayleigh + aerosol * wavelength.powf(-1.3)) * air_mass).exp();
+        }
+        transmittance
+    }
+
+    fn sun_irradiance(&self) -> [f64; 3] {
+        let transmittance = self.sun_transmittance();
+        let illuminance = SOLAR_ILLUMINANCE * LUMINANCE_SCALE * self.strength;
+        [transmittance[0] * illuminance, transmittance[1] * illuminance, transmittance[2] * illuminance]
+    }
+
+    // Directional light standing for the sun disk, its color is normalized and the rest goes in the brightness
+    pub fn sun(&self) -> DirectionalLight {
+        let irradiance = self.sun_irradiance();
+        let brightest = irradiance.iter().cloned().fold(0.0, f64::max);
+        let channel = |value: f64| if brightest > 0.0 { (value / brightest * 255.0).round() as u8 } else { 0 };
+        let color = Color::new(channel(irradiance[0]), channel(irradiance[1]), channel(irradiance[2]), 255);
+        DirectionalLight { angular_radius: self.sun_radius, ..DirectionalLight::new(-self.sun_direction(), brightest, color) }
+    }
+
+    pub fn sky_radiance(&self, direction: Vector3) -> LinearColor {
+        let direction = direction.normalize();
+        if direction.y < 0.0 {
+            let horizon = self.sky_radiance(Vector3::new(direction.x, 0.0, direction.z));
+            return LinearColor { a: 1.0, ..horizon * self.ground_albedo };
+        }
+        let turbidity = self.turbidity;
+        let theta_sun = (90.0 - self.elevation).to_radians();
+        let sun = self.sun_direction();
+        let gamma = direction.dot(&sun).clamp(-1.0, 1.0).acos();
+        let cos_theta = direction.y;
+        let luminance_coefficients = [0.1787 * turbidity - 1.4630, -0.3554 * turbidity + 0.4275, -0.0227 * turbidity + 5.3251, 0.1206 * turbidity - 2.5771, -0.0670 * turbidity + 0.3703];
+        let x_coefficients = [-0.0193 * turbidity - 0.2592, -0.0665 * turbidity + 0.0008, -0.0004 * turbidity + 0.2125, -0.0641 * turbidity - 0.8989, -0.0033 * turbidity + 0.0452];
+        let y_coefficients = [-0.0167 * turbidity - 0.2608, -0.0950 * turbidity + 0.0092, -0.0079 * turbidity + 0.2102, -0.0441 * turbidity - 1.6537, -0.0109 * turbidity + 0.0529];
+        let chi = (4.0 / 9.0 - turbidity / 120.0) * (std::f64::consts::PI - 2.0 * theta_sun);
+        let zenith_luminance = ((4.0453 * turbidity - 4.9710) * chi.tan() - 0.2155 * turbidity + 2.4192).max(0.0);
+        let zenith_x = zenith_chromaticity(turbidity, theta_sun, [[0.00166, -0.00375, 0.00209, 0.0], [-0.02903, 0.06377, -0.03202, 0.00394], [0.11693, -0.21196, 0.06052, 0.25886]]);
+        let zenith_y = zenith_chromaticity(turbidity, theta_sun, [[0.00275, -0.00610, 0.00317, 0.0], [-0.04214, 0.08970, -0.04153, 0.00516], [0.15346, -0.26756, 0.06670, 0.26688]]);
+        let relative = |coefficients: [f64; 5]| perez(coefficients, cos_theta, gamma) / perez(coefficients, 1.0, theta_sun);
+        let luminance = zenith_luminance * relative(luminance_coefficients) * LUMINANCE_SCALE * self.strength;
+        let [r, g, b] = xyy_to_rgb(zenith_x * relative(x_coefficients), zenith_y * relative(y_coefficients), luminance);
+        LinearColor::new(r, g, b, 1.0)
+    }
+
+    // The sun disk spreads the sunlight over its solid angle, so it is as bright as the light it stands for
+    pub fn radiance(&self, direction: Vector3) -> LinearColor {
+        let sky = self.sky_radiance(direction);
+        let radius = self.sun_radius.to_radians();
+        if radius <= 0.0 || direction.normalize().dot(&self.sun_direction()) < radius.cos() {
+            return sky;
+        }
+        let solid_angle = 2.0 * std::f64::consts::PI * (1.0 - radius.cos());
+        let [r, g, b] = self.sun_irradiance();
+        sky + LinearColor::new(r / solid_angle, g / solid_angle, b / solid_angle, 0.0)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if !(0.0..=90.0).contains(&self.elevation) {
+            return Err(format!("sun elevation must be between 0 and 90 degrees, not {}", self.elevation));
+        }
+        if !(1.7..=10.0).contains(&self.turbidity) {
+            return Err(format!("sky turbidity must be between 1.7 and 10, not {}", self.turbidity));
+        }
+        if !(0.0..90.0).contains(&self.sun_radius) {
+            return Err(format!("sun radius must be between 0 and 90 degrees, not {}", self.sun_radius));
+        }
+        if self.strength < 0.0 || self.strength.is_nan() || self.ground_albedo < 0.0 || self.ground_albedo.is_nan() {
+            return Err("sky strength and ground albedo must not be negative".to_string());
+        }
+        Ok(())
+    }
+}
+
+// What rays leaving the scene see, a solid color is the same as the sky color
+#[allow(non_camel_case_types, clippy::upper_case_acronyms)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum Environment {
+    SOLID_COLOR(Color),
+    IMAGE(EnvironmentImage),
+    SUN_SKY(SunSky)
+}
+
+impl Environment {
+    pub fn radiance(&self, direction: Vector3) -> LinearColor {
+        match self {
+            Environment::SOLID_COLOR(color) => LinearColor::from_color(*color),
+            Environment::IMAGE(image) => image.radiance(direction),
+            Environment::SUN_SKY(sky) => sky.radiance(direction)
+        }
+    }
+
+    // Without the sun disk of the sky, its light is already given by the sun
+    pub fn ambient_radiance(&self, direction: Vector3) -> LinearColor {
+        match self {
+            Environment::SUN_SKY(sky) => sky.sky_radiance(direction),
+            environment => environment.radiance(direction)
+        }
+    }
+
+    pub fn ambient_samples(&self) -> u32 {
+        match self {
+            Environment::SOLID_COLOR(_) => 0,
+            Environment::IMAGE(image) => image.ambient_samples,
+            Environment::SUN_SKY(sky) => sky.ambient_samples
+        }
+    }
+
+    pub fn sun(&self) -> Option<Light> {
+        match self {
+            Environment::SUN_SKY(sky) => Some(Light::DIRECTIONAL(sky.sun())),
+            _ => None
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            Environment::IMAGE(image) if image.strength < 0.0 || image.strength.is_nan() => Err(format!("environment strength must not be negative, not {}", image.strength)),
+            Environment::SUN_SKY(sky) => sky.validate(),
+            _ => Ok(())
+        }
+    }
+}
+
+impl Asset for Environment {
+    fn name(&self) -> &str {
+        match self {
+            Environment::SOLID_COLOR(_) => "environment color",
+            Environment::IMAGE(image) => &image.file,
+            Environment::SUN_SKY(_) => "sun and sky"
+        }
+    }
+
+    fn load(&mut self) -> Result<(), Box<dyn error::Error>> {
+        if let Environment::IMAGE(image) = self {
+            let pixels = image.load_pixels()?;
+            if pixels.width == 0 || pixels.height == 0 {
+                return Err(format!("{}: the environment image is empty", image.file).into());
+            }
+            image.image = pixels;
+        }
+        Ok(())
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            Environment::SOLID_COLOR(color) => format!("Using environment color: {} {} {}", color.r, color.g, color.b),
+            Environment::IMAGE(image) => format!("Using environment: {} ({}x{} pixels)", image.file, image.image.width, image.image.height),
+            Environment::SUN_SKY(sky) => format!("Using sun and sky: sun at {} degrees of elevation and {} of azimuth, turbidity {}", sky.elevation, sky.azimuth, sky.turbidity)
+        }
+    }
+}
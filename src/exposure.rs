@@ -0,0 +1,85 @@
+use serde::{Serialize, Deserialize};
+
+// Scales the light reaching the image in stops. The camera settings follow the photometric exposure value
+// EV100 = log2(aperture² / shutter) - log2(iso / 100), scenes are lit for EV100 0 (f/1, 1 second, ISO 100)
+#[derive(Copy, Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Exposure {
+    #[serde(default)]
+    pub ev: f64, // Stops added on top of the camera settings, +1 doubles the light
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub iso: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shutter: Option<f64>, // Seconds, unrelated to the motion blur shutter_open and shutter_close
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub aperture: Option<f64> // f-number
+}
+
+impl Exposure {
+    pub fn validate(&self) -> Result<(), String> {
+        for (name, value) in [("iso", self.iso), ("shutter", self.shutter), ("aperture", self.aperture)].iter() {
+            match value {
+                Some(value) if !(*value > 0.0 && value.is_finite()) => return Err(format!("the camera exposure {} must be positive, not {}", name, value)),
+                _ => ()
+            }
+        }
+        if !self.ev.is_finite() {
+            return Err(format!("the camera exposure ev must be a number, not {}", self.ev));
+        }
+        Ok(())
+    }
+
+    pub fn ev100(&self) -> f64 {
+        let aperture = self.aperture.unwrap_or(1.0);
+        (aperture * aperture / self.shutter.unwrap_or(1.0)).log2() - (self.iso.unwrap_or(100.0) / 100.0).log2()
+    }
+
+    // Factor applied to the linear shading result
+    pub fn scale(&self) -> f64 {
+        (self.ev - self.ev100()).exp2()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rendering::{self, Camera, Color, Light, Material, PointLight, Renderable, Scene, View};
+    use crate::shape::{Shape, Sphere};
+    use crate::vertors::Vector3;
+
+    // Linear light of every pixel of a lit ball in front of the sky
+    fn linear_render(exposure: Exposure) -> Vec<[f64; 4]> {
+        let camera = Camera { exposure: Some(exposure), ..Camera::new(8, 6, 60.0) };
+        let ball = Renderable::new(Shape::SPHERE(Sphere::new(Vector3::new(0.0, 0.0, -4.0), 1.0)), Material::new(Color::new(200, 120, 80, 255), 0.8, 0.2));
+        let lamp = Light::POINT(PointLight::new(Vector3::new(2.0, 3.0, 0.0), 3000.0, Color::white()));
+        let mut scene = Scene::new(camera, vec![ball], vec![lamp], Color::new(40, 60, 90, 255));
+        scene.prepare().unwrap();
+        let view = View::camera(&scene.camera);
+        (0..6).flat_map(|y| (0..8).map(move |x| (x, y))).map(|(x, y)| rendering::sum_samples(4, &scene, &view, x, y, 0)).collect()
+    }
+
+    #[test]
+    fn one_more_stop_doubles_the_linear_light() {
+        let (base, brighter) = (linear_render(Exposure::default()), linear_render(Exposure { ev: 1.0, ..Exposure::default() }));
+        assert!(base.iter().any(|pixel| pixel[0] > 0.0));
+        for (base, brighter) in base.iter().zip(brighter.iter()) {
+            for channel in 0..3 {
+                assert_eq!(brighter[channel], 2.0 * base[channel]);
+            }
+            // The alpha is coverage, not light
+            assert_eq!(brighter[3], base[3]);
+        }
+    }
+
+    #[test]
+    fn camera_settings_give_their_exposure_value() {
+        // Sunny 16: f/16 at 1/125 s and ISO 100
+        let sunny = Exposure { aperture: Some(16.0), shutter: Some(1.0 / 125.0), iso: Some(100.0), ..Exposure::default() };
+        assert!((sunny.ev100() - 14.965784284662087).abs() < 1e-12);
+        let indoors = Exposure { aperture: Some(2.8), shutter: Some(1.0 / 60.0), iso: Some(400.0), ..Exposure::default() };
+        assert!((indoors.ev100() - 6.877744249949002).abs() < 1e-12);
+        assert_eq!(Exposure::default().ev100(), 0.0);
+        // Every stop of exposure value takes half the light, the ev offset gives it back
+        assert!((sunny.scale() - 1.0 / 32000.0).abs() < 1e-18);
+        assert!((Exposure { ev: 1.0, ..indoors }.scale() / indoors.scale() - 2.0).abs() < 1e-12);
+    }
+}
@@ -0,0 +1,551 @@
+use std::convert::TryInto;
+use std::error;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use serde::Deserialize;
+use crate::shape::{Shape, Point};
+use crate::vertors::Vector3;
+use crate::transform::Matrix;
+use crate::mesh::Mesh;
+use crate::rendering::{Scene, Camera, Renderable, Material, Color, Light, PointLight, DirectionalLight, SpotLight};
+
+const GLB_JSON_CHUNK: u32 = 0x4E4F_534A;
+const GLB_BINARY_CHUNK: u32 = 0x004E_4942;
+
+#[derive(Debug, Clone)]
+pub struct GltfError {
+    pub file: String,
+    pub message: String
+}
+
+impl GltfError {
+    pub fn new(file: &str, message: String) -> GltfError {
+        GltfError { file: file.to_string(), message }
+    }
+}
+
+impl fmt::Display for GltfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.file, self.message)
+    }
+}
+
+impl error::Error for GltfError {}
+
+// Only the parts of the JSON that are imported, anything else is ignored
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+struct Document {
+    scene: Option<usize>,
+    scenes: Vec<DocumentScene>,
+    nodes: Vec<DocumentNode>,
+    meshes: Vec<DocumentMesh>,
+    materials: Vec<DocumentMaterial>,
+    accessors: Vec<Accessor>,
+    buffer_views: Vec<BufferView>,
+    buffers: Vec<Buffer>,
+    cameras: Vec<DocumentCamera>,
+    skins: Vec<serde_json::Value>,
+    animations: Vec<serde_json::Value>,
+    extensions: DocumentExtensions
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct DocumentScene {
+    nodes: Vec<usize>
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct DocumentNode {
+    children: Vec<usize>,
+    mesh: Option<usize>,
+    camera: Option<usize>,
+    matrix: Option<[f64; 16]>,
+    translation: Option<[f64; 3]>,
+    rotation: Option<[f64; 4]>,
+    scale: Option<[f64; 3]>,
+    extensions: NodeExtensions
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct DocumentMesh {
+    primitives: Vec<DocumentPrimitive>
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct DocumentPrimitive {
+    attributes: std::collections::HashMap<String, usize>,
+    indices: Option<usize>,
+    material: Option<usize>,
+    mode: Option<u32>
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+struct DocumentMaterial {
+    pbr_metallic_roughness: Option<PbrMetallicRoughness>
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PbrMetallicRoughness {
+    #[serde(default = "white")]
+    base_color_factor: [f64; 4]
+}
+
+fn white() -> [f64; 4] {
+    [1.0; 4]
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Accessor {
+    buffer_view: Option<usize>,
+    #[serde(default)]
+    byte_offset: usize,
+    component_type: u32,
+    #[serde(default)]
+    normalized: bool,
+    count: usize,
+    #[serde(rename = "type")]
+    kind: String,
+    sparse: Option<serde_json::Value>
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BufferView {
+    buffer: usize,
+    #[serde(default)]
+    byte_offset: usize,
+    byte_length: usize,
+    byte_stride: Option<usize>
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Buffer {
+    uri: Option<String>,
+    byte_length: usize
+}
+
+#[derive(Debug, Deserialize)]
+struct DocumentCamera {
+    #[serde(rename = "type")]
+    kind: String,
+    perspective: Option<Perspective>
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Perspective {
+    yfov: f64,
+    aspect_ratio: Option<f64>
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct DocumentExtensions {
+    #[serde(rename = "KHR_lights_punctual")]
+    lights_punctual: Option<PunctualLights>
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct PunctualLights {
+    lights: Vec<PunctualLight>
+}
+
+#[derive(Debug, Deserialize)]
+struct PunctualLight {
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default = "white_light")]
+    color: [f64; 3],
+    #[serde(default = "unit_intensity")]
+    intensity: f64,
+    #[serde(default)]
+    spot: PunctualSpot
+}
+
+// Half angles in radians
+#[derive(Debug, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct PunctualSpot {
+    inner_cone_angle: f64,
+    outer_cone_angle: f64
+}
+
+impl Default for PunctualSpot {
+    fn default() -> Self {
+        PunctualSpot { inner_cone_angle: 0.0, outer_cone_angle: std::f64::consts::FRAC_PI_4 }
+    }
+}
+
+fn white_light() -> [f64; 3] {
+    [1.0; 3]
+}
+
+fn unit_intensity() -> f64 {
+    1.0
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct NodeExtensions {
+    #[serde(rename = "KHR_lights_punctual")]
+    light: Option<NodeLight>
+}
+
+#[derive(Debug, Deserialize)]
+struct NodeLight {
+    light: usize
+}
+
+impl DocumentNode {
+    // Matrices are stored column by column, TRS nodes apply the scale first and the translation last
+    fn local_matrix(&self) -> Matrix {
+        if let Some(values) = self.matrix {
+            let mut matrix = Matrix::identity();
+            for (index, value) in values.iter().enumerate() {
+                matrix.m[index % 4][index / 4] = *value;
+            }
+            return matrix;
+        }
+        let [tx, ty, tz] = self.translation.unwrap_or([0.0; 3]);
+        let [sx, sy, sz] = self.scale.unwrap_or([1.0; 3]);
+        let [x, y, z, w] = self.rotation.unwrap_or([0.0, 0.0, 0.0, 1.0]);
+        let rotation = Matrix::new([
+            [1.0 - 2.0 * (y * y + z * z), 2.0 * (x * y - z * w), 2.0 * (x * z + y * w), 0.0],
+            [2.0 * (x * y + z * w), 1.0 - 2.0 * (x * x + z * z), 2.0 * (y * z - x * w), 0.0],
+            [2.0 * (x * z - y * w), 2.0 * (y * z + x * w), 1.0 - 2.0 * (x * x + y * y), 0.0],
+            [0.0, 0.0, 0.0, 1.0]
+        ]);
+        Matrix::translation(Vector3::new(tx, ty, tz)) * rotation * Matrix::scaling(Vector3::new(sx, sy, sz))
+    }
+}
+
+fn to_color(channels: &[f64]) -> Color {
+    let channel = |value: f64| (value * 255.0).round().clamp(0.0, 255.0) as u8;
+    Color::new(channel(channels[0]), channel(channels[1]), channel(channels[2]), channels.get(3).map_or(255, |&alpha| channel(alpha)))
+}
+
+// Triangles of one primitive with the transform of its node applied
+#[derive(Clone, Debug, Default)]
+pub struct GltfPrimitive {
+    pub vertices: Vec<Point>,
+    pub indices: Vec<[usize; 3]>,
+    pub normals: Vec<Vector3>, // One per vertex when the file has them
+    pub base_color: Option<Color>
+}
+
+#[derive(Clone, Debug)]
+pub struct GltfCamera {
+    pub fov: f64, // Vertical, in degrees
+    pub aspect_ratio: Option<f64>,
+    pub to_world: Matrix
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Gltf {
+    pub primitives: Vec<GltfPrimitive>,
+    pub camera: Option<GltfCamera>,
+    pub lights: Vec<Light>
+}
+
+impl Gltf {
+    pub fn load(file: &str) -> Result<Gltf, GltfError> {
+        let content = fs::read(file).map_err(|e| GltfError::new(file, e.to_string()))?;
+        Gltf::parse(&content, file)
+    }
+
+    // Binary .glb files embed the JSON and its first buffer, .gltf files are the JSON alone
+    pub fn parse(content: &[u8], file: &str) -> Result<Gltf, GltfError> {
+        let (json, binary) = if content.starts_with(b"glTF") { split_glb(content, file)? } else { (content, None) };
+        let document: Document = serde_json::from_slice(json).map_err(|e| GltfError::new(file, e.to_string()))?;
+        if !document.skins.is_empty() || !document.animations.is_empty() {
+            eprintln!("warning: {}: ignoring {} skin(s) and {} animation(s), meshes are imported in their rest pose", file, document.skins.len(), document.animations.len());
+        }
+        let buffers = document.buffers.iter().enumerate()
+            .map(|(index, buffer)| load_buffer(buffer, index, binary, file))
+            .collect::<Result<Vec<_>, _>>()?;
+        let importer = Importer { document: &document, buffers, file };
+
+        // Without a scene every node that is nobody's child is a root
+        let roots = match document.scene.or(if document.scenes.is_empty() { None } else { Some(0) }) {
+            Some(scene) => document.scenes.get(scene).ok_or_else(|| importer.error(format!("scene {} does not exist", scene)))?.nodes.clone(),
+            None => (0..document.nodes.len()).filter(|index| !document.nodes.iter().any(|node| node.children.contains(index))).collect()
+        };
+        let mut gltf = Gltf::default();
+        for root in roots {
+            importer.visit(root, &Matrix::identity(), 0, &mut gltf)?;
+        }
+        if gltf.primitives.is_empty() {
+            return Err(GltfError::new(file, "no triangles".to_string()));
+        }
+        Ok(gltf)
+    }
+}
+
+fn split_glb<'a>(content: &'a [u8], file: &str) -> Result<(&'a [u8], Option<&'a [u8]>), GltfError> {
+    let word = |offset: usize| content.get(offset..offset + 4).map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()) as usize);
+    match word(4) {
+        Some(2) => {},
+        Some(version) => return Err(GltfError::new(file, format!("unsupported glTF version {}", version))),
+        None => return Err(GltfError::new(file, "truncated header".to_string()))
+    }
+    let length = word(8).unwrap_or(0).min(content.len());
+    let (mut json, mut binary) = (None, None);
+    let mut offset = 12;
+    while offset + 8 <= length {
+        let (chunk_length, kind) = (word(offset).unwrap_or(0), word(offset + 4).unwrap_or(0));
+        let data = content.get(offset + 8..offset + 8 + chunk_length).ok_or_else(|| GltfError::new(file, "truncated chunk".to_string()))?;
+        match kind as u32 {
+            GLB_JSON_CHUNK => json = json.or(Some(data)),
+            GLB_BINARY_CHUNK => binary = binary.or(Some(data)),
+            _ => {} // Unknown chunks must be ignored
+        }
+        offset += 8 + chunk_length;
+    }
+    Ok((json.ok_or_else(|| GltfError::new(file, "missing JSON chunk".to_string()))?, binary))
+}
+
+// Buffers are the binary chunk, a base64 data URI or a file next to the glTF one
+fn load_buffer(buffer: &Buffer, index: usize, binary: Option<&[u8]>, file: &str) -> Result<Vec<u8>, GltfError> {
+    let data = match &buffer.uri {
+        None => binary.ok_or_else(|| GltfError::new(file, format!("buffer {} has no uri outside of a .glb file", index)))?.to_vec(),
+        Some(uri) if uri.starts_with("data:") => {
+            let encoded = uri.find(";base64,").map(|start| &uri[start + 8..])
+                .ok_or_else(|| GltfError::new(file, format!("buffer {} is not a base64 data URI", index)))?;
+            decode_base64(encoded).ok_or_else(|| GltfError::new(file, format!("buffer {} has invalid base64 data", index)))?
+        },
+        Some(uri) => {
+            let path = Path::new(file).parent().unwrap_or_else(|| Path::new("")).join(uri);
+            fs::read(&path).map_err(|e| GltfError::new(file, format!("{}: {}", path.display(), e)))?
+        }
+    };
+    if data.len() < buffer.byte_length {
+        return Err(GltfError::new(file, format!("buffer {} has {} bytes, {} expected", index, data.len(), buffer.byte_length)));
+    }
+    Ok(data)
+}
+
+fn decode_base64(text: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(text.len() * 3 / 4);
+    let (mut bits, mut count) = (0u32, 0);
+    for character in text.bytes().filter(|&character| character != b'=') {
+        let value = match character {
+            b'A'..=b'Z' => character - b'A',
+            b'a'..=b'z' => character - b'a' + 26,
+            b'0'..=b'9' => character - b'0' + 52,
+            b'+' | b'-' => 62,
+            b'/' | b'_' => 63,
+            _ => return None
+        };
+        bits = (bits << 6) | value as u32;
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            bytes.push((bits >> count) as u8);
+            bits &= (1 << count) - 1;
+        }
+    }
+    Some(bytes)
+}
+
+struct Importer<'a> {
+    document: &'a Document,
+    buffers: Vec<Vec<u8>>,
+    file: &'a str
+}
+
+impl<'a> Importer<'a> {
+    fn error(&self, message: String) -> GltfError {
+        GltfError::new(self.file, message)
+    }
+
+    fn visit(&self, index: usize, parent: &Matrix, depth: usize, gltf: &mut Gltf) -> Result<(), GltfError> {
+        if depth > self.document.nodes.len() {
+            return Err(self.error(format!("node {} is its own ancestor", index)));
+        }
+        let node = self.document.nodes.get(index).ok_or_else(|| self.error(format!("node {} does not exist", index)))?;
+        let to_world = *parent * node.local_matrix();
+        if let Some(mesh) = node.mesh {
+            let mesh = self.document.meshes.get(mesh).ok_or_else(|| self.error(format!("mesh {} does not exist", mesh)))?;
+            for primitive in mesh.primitives.iter() {
+                if let Some(primitive) = self.primitive(primitive, &to_world)? {
+                    gltf.primitives.push(primitive);
+                }
+            }
+        }
+        if let (Some(camera), None) = (node.camera, &gltf.camera) {
+            let camera = self.document.cameras.get(camera).ok_or_else(|| self.error(format!("camera {} does not exist", camera)))?;
+            match &camera.perspective {
+                Some(perspective) => gltf.camera = Some(GltfCamera { fov: perspective.yfov.to_degrees(), aspect_ratio: perspective.aspect_ratio, to_world }),
+                None => eprintln!("warning: {}: skipping {} camera, only perspective ones are supported", self.file, camera.kind)
+            }
+        }
+        if let Some(light) = &node.extensions.light {
+            let lights = self.document.extensions.lights_punctual.as_ref().map_or(&[][..], |lights| &lights.lights[..]);
+            let light = lights.get(light.light).ok_or_else(|| self.error(format!("light {} does not exist", light.light)))?;
+            // Point intensities are in candela, spread over the whole sphere like the crate's brightness
+            match light.kind.as_str() {
+                "point" => gltf.lights.push(Light::POINT(PointLight::new(to_world.transform_point(Vector3::zero()), light.intensity * 4.0 * std::f64::consts::PI, to_color(&light.color)))),
+                "directional" => gltf.lights.push(Light::DIRECTIONAL(DirectionalLight::new(to_world.transform_vector(Vector3::new(0.0, 0.0, -1.0)).normalize(), light.intensity, to_color(&light.color)))),
+                "spot" => gltf.lights.push(Light::SPOT(SpotLight::new(
+                    to_world.transform_point(Vector3::zero()),
+                    to_world.transform_vector(Vector3::new(0.0, 0.0, -1.0)).normalize(),
+                    light.spot.inner_cone_angle.to_degrees(),
+                    light.spot.outer_cone_angle.to_degrees(),
+                    light.intensity * 4.0 * std::f64::consts::PI,
+                    to_color(&light.color)
+                ))),
+                kind => eprintln!("warning: {}: skipping {} light, only point, directional and spot ones are supported", self.file, kind)
+            }
+        }
+        for &child in node.children.iter() {
+            self.visit(child, &to_world, depth + 1, gltf)?;
+        }
+        Ok(())
+    }
+
+    fn primitive(&self, primitive: &DocumentPrimitive, to_world: &Matrix) -> Result<Option<GltfPrimitive>, GltfError> {
+        let mode = primitive.mode.unwrap_or(4);
+        let position = match (mode, primitive.attributes.get("POSITION")) {
+            (4..=6, Some(&position)) => position,
+            (4..=6, None) => return Ok(None), // Allowed by the format, there is nothing to draw
+            _ => {
+                eprintln!("warning: {}: skipping a primitive of points or lines (mode {})", self.file, mode);
+                return Ok(None);
+            }
+        };
+        let vertices: Vec<Point> = self.accessor(position, "VEC3")?.chunks_exact(3)
+            .map(|values| to_world.transform_point(Vector3::new(values[0], values[1], values[2])))
+            .collect();
+        let order: Vec<usize> = match primitive.indices {
+            Some(indices) => self.accessor(indices, "SCALAR")?.into_iter().map(|index| index as usize).collect(),
+            None => (0..vertices.len()).collect()
+        };
+        // Strips alternate their winding, fans turn around their first vertex
+        let indices: Vec<[usize; 3]> = match mode {
+            4 => order.chunks_exact(3).map(|corners| [corners[0], corners[1], corners[2]]).collect(),
+            5 => (0..order.len().saturating_sub(2)).map(|i| if i % 2 == 0 { [order[i], order[i + 1], order[i + 2]] } else { [order[i + 1], order[i], order[i + 2]] }).collect(),
+            _ => (1..order.len().saturating_sub(1)).map(|i| [order[0], order[i], order[i + 1]]).collect()
+        };
+        if let Some(index) = indices.iter().flatten().find(|&&index| index >= vertices.len()) {
+            return Err(self.error(format!("vertex index {} is out of range ({} defined)", index, vertices.len())));
+        }
+        // Normals follow the inverse transpose so that non-uniform scales keep them perpendicular
+        let normals = match primitive.attributes.get("NORMAL") {
+            Some(&normal) => {
+                let normal_matrix = to_world.inverse().map_or(*to_world, |inverse| inverse.transpose());
+                let normals: Vec<Vector3> = self.accessor(normal, "VEC3")?.chunks_exact(3)
+                    .map(|values| normal_matrix.transform_vector(Vector3::new(values[0], values[1], values[2])).normalize())
+                    .collect();
+                if normals.len() != vertices.len() {
+                    return Err(self.error(format!("{} normals for {} vertices", normals.len(), vertices.len())));
+                }
+                normals
+            },
+            None => Vec::new()
+        };
+        let base_color = primitive.material
+            .and_then(|material| self.document.materials.get(material))
+            .map(|material| material.pbr_metallic_roughness.as_ref().map_or(white(), |pbr| pbr.base_color_factor))
+            .map(|factor| to_color(&factor));
+        Ok(Some(GltfPrimitive { vertices, indices, normals, base_color }))
+    }
+
+    // Values of an accessor converted to f64, one after the other
+    fn accessor(&self, index: usize, kind: &str) -> Result<Vec<f64>, GltfError> {
+        let accessor = self.document.accessors.get(index).ok_or_else(|| self.error(format!("accessor {} does not exist", index)))?;
+        if accessor.kind != kind {
+            return Err(self.error(format!("accessor {} is a {}, expected a {}", index, accessor.kind, kind)));
+        }
+        if accessor.sparse.is_some() {
+            return Err(self.error(format!("accessor {} is sparse, which is not supported", index)));
+        }
+        let components = if kind == "VEC3" { 3 } else { 1 };
+        let (size, range) = match accessor.component_type {
+            5120 => (1, 127.0),
+            5121 => (1, 255.0),
+            5122 => (2, 32767.0),
+            5123 => (2, 65535.0),
+            5125 => (4, 4294967295.0),
+            5126 => (4, 1.0),
+            other => return Err(self.error(format!("accessor {} has unknown component type {}", index, other)))
+        };
+        let view = match accessor.buffer_view {
+            Some(view) => self.document.buffer_views.get(view).ok_or_else(|| self.error(format!("buffer view {} does not exist", view)))?,
+            None => return Ok(vec![0.0; accessor.count * components]) // Accessors without data are zeros
+        };
+        let buffer = self.buffers.get(view.buffer).ok_or_else(|| self.error(format!("buffer {} does not exist", view.buffer)))?;
+        let stride = view.byte_stride.unwrap_or(size * components);
+        let start = view.byte_offset + accessor.byte_offset;
+        let end = start + stride * accessor.count.saturating_sub(1) + size * components;
+        if accessor.count > 0 && (end > view.byte_offset + view.byte_length || end > buffer.len()) {
+            return Err(self.error(format!("accessor {} reads past the end of its buffer view", index)));
+        }
+        let decode = |bytes: &[u8]| match accessor.component_type {
+            5120 => bytes[0] as i8 as f64,
+            5121 => bytes[0] as f64,
+            5122 => i16::from_le_bytes(bytes.try_into().unwrap()) as f64,
+            5123 => u16::from_le_bytes(bytes.try_into().unwrap()) as f64,
+            5125 => u32::from_le_bytes(bytes.try_into().unwrap()) as f64,
+            _ => f32::from_le_bytes(bytes.try_into().unwrap()) as f64
+        };
+        let mut values = Vec::with_capacity(accessor.count * components);
+        for element in 0..accessor.count {
+            for component in 0..components {
+                let offset = start + element * stride + component * size;
+                let value = decode(&buffer[offset..offset + size]);
+                // Normalized integers map onto [0, 1] or [-1, 1]
+                values.push(if accessor.normalized { (value / range).max(-1.0) } else { value });
+            }
+        }
+        Ok(values)
+    }
+}
+
+pub fn is_gltf(file: &str) -> bool {
+    matches!(file.rsplit('.').next().unwrap_or("").to_lowercase().as_str(), "gltf" | "glb")
+}
+
+// The crate's camera stays at the origin looking down -z, so the whole file is moved in front of it instead
+pub fn load_scene(file: &str) -> Result<Scene, GltfError> {
+    let gltf = Gltf::load(file)?;
+    let (camera, to_view) = match &gltf.camera {
+        Some(camera) => {
+            let height = 600;
+            let aspect_ratio = camera.aspect_ratio.unwrap_or(4.0 / 3.0);
+            let width = (height as f64 * aspect_ratio).round().max(1.0) as u32;
+            // glTF gives the vertical fov, the crate's fov spans the shorter side
+            let fov = if width < height { 2.0 * ((camera.fov.to_radians() / 2.0).tan() * aspect_ratio).atan().to_degrees() } else { camera.fov };
+            let to_view = camera.to_world.inverse().ok_or_else(|| GltfError::new(file, "the camera transform cannot be inverted".to_string()))?;
+            (Camera::new(width, height, fov), to_view)
+        },
+        None => {
+            eprintln!("warning: {}: no camera, looking down -z from the origin", file);
+            (Camera::new(800, 600, 60.0), Matrix::identity())
+        }
+    };
+    // Each primitive is its own mesh so that it keeps its base color, glTF has no albedo or reflectiveness
+    let elements = gltf.primitives.iter().enumerate().map(|(index, primitive)| {
+        let mut mesh = Mesh::new(file.to_string(), 1.0, Vector3::zero());
+        mesh.part = Some(index);
+        mesh.smooth = !primitive.normals.is_empty();
+        let material = Material::new(primitive.base_color.unwrap_or_else(|| Color::new(255, 255, 255, 255)), 0.9, 0.0);
+        Renderable::new(Shape::MESH(mesh.transformed(&to_view)), material)
+    }).collect();
+    let mut lights: Vec<Light> = gltf.lights.iter().map(|light| light.transformed(&to_view)).collect();
+    if lights.is_empty() {
+        eprintln!("warning: {}: no lights, adding a directional one from behind the camera", file);
+        lights.push(Light::DIRECTIONAL(DirectionalLight::new(Vector3::new(0.3, -0.5, -1.0), 3.5, Color::new(255, 255, 255, 255))));
+    }
+    Ok(Scene::new(camera, elements, lights, Color::new(40, 40, 50, 255)))
+}
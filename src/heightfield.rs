@@ -0,0 +1,200 @@
+use std::error;
+use serde::{Serialize, Deserialize};
+use image::{DynamicImage, GenericImageView};
+use crate::shape::{Ray, Hit, Point, Triangle, intersect_in_object_space};
+use crate::vertors::Vector3;
+use crate::traits::Intersectable;
+use crate::transform::Matrix;
+use crate::assets::Asset;
+use crate::aabb::Aabb;
+
+// Heights of the image in grid space: one unit between neighboring pixels, from 0 (black) to 1 (white)
+#[derive(Clone, Debug, Default)]
+pub struct HeightGrid {
+    pub columns: usize,
+    pub rows: usize,
+    pub heights: Vec<f64>,
+    pub lowest: f64,
+    pub highest: f64
+}
+
+impl HeightGrid {
+    pub fn new(columns: usize, rows: usize, heights: Vec<f64>) -> HeightGrid {
+        let lowest = heights.iter().cloned().fold(f64::INFINITY, f64::min);
+        let highest = heights.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+        HeightGrid { columns, rows, heights, lowest, highest }
+    }
+
+    fn point(&self, column: usize, row: usize) -> Point {
+        Vector3::new(column as f64, self.height(column, row), row as f64)
+    }
+
+    fn height(&self, column: usize, row: usize) -> f64 {
+        self.heights[row * self.columns + column]
+    }
+
+    // Slope from the neighboring heights, one sided on the borders
+    fn vertex_normal(&self, column: usize, row: usize) -> Vector3 {
+        let (left, right) = (column.saturating_sub(1), (column + 1).min(self.columns - 1));
+        let (front, back) = (row.saturating_sub(1), (row + 1).min(self.rows - 1));
+        let slope_x = (self.height(right, row) - self.height(left, row)) / (right - left) as f64;
+        let slope_z = (self.height(column, back) - self.height(column, front)) / (back - front) as f64;
+        Vector3::new(-slope_x, 1.0, -slope_z)
+    }
+
+    // Bilinear blend of the corner normals at a point of the cell
+    fn normal(&self, column: usize, row: usize, point: Point) -> Vector3 {
+        let (u, v) = ((point.x - column as f64).clamp(0.0, 1.0), (point.z - row as f64).clamp(0.0, 1.0));
+        let front = self.vertex_normal(column, row) * (1.0 - u) + self.vertex_normal(column + 1, row) * u;
+        let back = self.vertex_normal(column, row + 1) * (1.0 - u) + self.vertex_normal(column + 1, row + 1) * u;
+        (front * (1.0 - v) + back * v).normalize()
+    }
+
+    fn bounds(&self) -> Aabb {
+        Aabb::new(Vector3::new(0.0, self.lowest, 0.0), Vector3::new((self.columns - 1) as f64, self.highest, (self.rows - 1) as f64))
+    }
+
+    // Each cell is split in two triangles, the ray is only tested against them when it passes at their height,
+    // the normal is interpolated so the terrain looks smooth
+    fn intersect_cell(&self, column: usize, row: usize, ray: &Ray, enter: f64, exit: f64) -> Option<Hit> {
+        let corners = [self.point(column, row), self.point(column + 1, row), self.point(column, row + 1), self.point(column + 1, row + 1)];
+        let lowest = corners.iter().map(|corner| corner.y).fold(f64::INFINITY, f64::min);
+        let highest = corners.iter().map(|corner| corner.y).fold(f64::NEG_INFINITY, f64::max);
+        let (enter_height, exit_height) = (ray.origin.y + ray.direction.y * enter, ray.origin.y + ray.direction.y * exit);
+        if enter_height.min(exit_height) > highest + 1e-9 || enter_height.max(exit_height) < lowest - 1e-9 {
+            return None;
+        }
+        let first = Triangle::new(corners[0], corners[2], corners[1]).intersect(ray);
+        let remaining = ray.with_range(ray.t_min, first.as_ref().map_or(ray.t_max, |hit| hit.distance));
+        let hit = Triangle::new(corners[1], corners[2], corners[3]).intersect(&remaining).or(first)?;
+        let normal = self.normal(column, row, hit.point);
+        // Texture coordinates span the whole image, u along x and v along z
+        let uv = (hit.point.x / (self.columns - 1) as f64, hit.point.z / (self.rows - 1) as f64);
+        let tangents = Some((Vector3::new(1.0, 0.0, 0.0), Vector3::new(0.0, 0.0, 1.0)));
+        // Both triangles turn counterclockwise seen from above, so the terrain faces up and is seen from below as a back face
+        Some(Hit { normal, uv, tangents, ..hit })
+    }
+}
+
+// Distance to the first cell boundary crossed along one axis and the distance between two boundaries
+fn boundaries(origin: f64, direction: f64, cell: usize) -> (f64, f64) {
+    if direction > 0.0 {
+        ((cell as f64 + 1.0 - origin) / direction, 1.0 / direction)
+    } else if direction < 0.0 {
+        ((cell as f64 - origin) / direction, -1.0 / direction)
+    } else {
+        (f64::INFINITY, f64::INFINITY)
+    }
+}
+
+impl Intersectable for HeightGrid {
+    // Cells are walked in the order the ray crosses them (2D DDA), so the first hit found is the nearest one
+    fn intersect(&self, ray: &Ray) -> Option<Hit> {
+        let (enter, exit) = self.bounds().range(ray)?;
+        let start = ray.origin + ray.direction * enter;
+        let (last_column, last_row) = (self.columns - 2, self.rows - 2);
+        let mut column = (start.x.floor().max(0.0) as usize).min(last_column);
+        let mut row = (start.z.floor().max(0.0) as usize).min(last_row);
+        let (mut next_column, column_step) = boundaries(ray.origin.x, ray.direction.x, column);
+        let (mut next_row, row_step) = boundaries(ray.origin.z, ray.direction.z, row);
+        let range = ray.with_range(enter, exit);
+        let mut cell_enter = enter;
+        loop {
+            let cell_exit = next_column.min(next_row).min(exit);
+            if let Some(hit) = self.intersect_cell(column, row, &range, cell_enter, cell_exit) {
+                return Some(hit);
+            }
+            if cell_exit >= exit {
+                return None;
+            }
+            cell_enter = cell_exit;
+            if next_column < next_row {
+                if (ray.direction.x > 0.0 && column == last_column) || (ray.direction.x < 0.0 && column == 0) {
+                    return None;
+                }
+                column = if ray.direction.x > 0.0 { column + 1 } else { column - 1 };
+                next_column += column_step;
+            } else {
+                if (ray.direction.z > 0.0 && row == last_row) || (ray.direction.z < 0.0 && row == 0) {
+                    return None;
+                }
+                row = if ray.direction.z > 0.0 { row + 1 } else { row - 1 };
+                next_row += row_step;
+            }
+        }
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        Some(self.bounds())
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Heightfield {
+    pub file: String,
+    #[serde(default = "Vector3::zero")]
+    pub corner: Point,
+    pub width: f64,
+    pub depth: f64,
+    pub height: f64,
+    #[serde(skip, default = "Matrix::identity")]
+    pub placement: Matrix,
+    #[serde(skip)]
+    pub grid: HeightGrid,
+    #[serde(skip)]
+    pub to_object: Option<Matrix>
+}
+
+impl Heightfield {
+    // The image spans width along x and depth along z from the corner, a white pixel is height above it
+    pub fn to_world(&self) -> Matrix {
+        let cell = Vector3::new(self.width / (self.grid.columns - 1) as f64, self.height, self.depth / (self.grid.rows - 1) as f64);
+        self.placement * Matrix::translation(self.corner) * Matrix::scaling(cell)
+    }
+
+    pub fn transformed(&self, matrix: &Matrix) -> Heightfield {
+        let mut heightfield = self.clone();
+        heightfield.placement = *matrix * self.placement;
+        if self.to_object.is_some() {
+            heightfield.to_object = heightfield.to_world().inverse();
+        }
+        heightfield
+    }
+}
+
+impl Asset for Heightfield {
+    fn name(&self) -> &str {
+        &self.file
+    }
+
+    fn load(&mut self) -> Result<(), Box<dyn error::Error>> {
+        // 16 bit images keep their precision, anything else is converted to 8 bit gray
+        let image = image::open(&self.file)?;
+        let (columns, rows) = (image.width() as usize, image.height() as usize);
+        if columns < 2 || rows < 2 {
+            return Err(format!("{}: a heightfield needs at least 2x2 pixels, found {}x{}", self.file, columns, rows).into());
+        }
+        let heights = match image {
+            DynamicImage::ImageLuma16(gray) => gray.pixels().map(|pixel| pixel[0] as f64 / u16::MAX as f64).collect(),
+            image => image.to_luma().pixels().map(|pixel| pixel[0] as f64 / u8::MAX as f64).collect()
+        };
+        self.grid = HeightGrid::new(columns, rows, heights);
+        self.to_object = Some(self.to_world().inverse().ok_or_else(|| format!("{}: the heightfield is flat in one direction", self.file))?);
+        Ok(())
+    }
+
+    fn describe(&self) -> String {
+        format!("Using heightfield: {} ({}x{} pixels, {} triangles)", self.file, self.grid.columns, self.grid.rows, self.grid.columns.saturating_sub(1) * self.grid.rows.saturating_sub(1) * 2)
+    }
+}
+
+impl Intersectable for Heightfield {
+    fn intersect(&self, ray: &Ray) -> Option<Hit> {
+        intersect_in_object_space(&self.grid, self.to_object.as_ref()?, ray)
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        self.to_object?;
+        Some(self.grid.bounds().transformed(&self.to_world()))
+    }
+}
@@ -0,0 +1,250 @@
+use std::error;
+use std::fmt;
+use std::fs;
+use crate::assets::Asset;
+
+#[derive(Debug, Clone)]
+pub struct IesError {
+    pub file: String,
+    pub message: String
+}
+
+impl IesError {
+    pub fn new(file: &str, message: String) -> IesError {
+        IesError { file: file.to_string(), message }
+    }
+}
+
+impl fmt::Display for IesError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.file, self.message)
+    }
+}
+
+impl error::Error for IesError {}
+
+// Candela table of an IES LM-63 file with type C photometry: vertical angles go from the nadir (0) to the zenith (180),
+// horizontal angles turn around the vertical axis
+#[derive(Clone, Debug)]
+pub struct IesProfile {
+    pub keywords: Vec<(String, String)>, // Header lines like [MANUFAC] Acme, in the order of the file
+    pub lumens_per_lamp: f64, // -1 for absolute photometry
+    pub multiplier: f64, // Already applied to the candela values
+    pub input_watts: f64,
+    pub vertical_angles: Vec<f64>,
+    pub horizontal_angles: Vec<f64>,
+    pub candela: Vec<Vec<f64>>, // One row of vertical values per horizontal angle
+    pub peak: f64
+}
+
+// Position of the value between two entries of a sorted table, None outside of it
+fn locate(angles: &[f64], angle: f64) -> Option<(usize, usize, f64)> {
+    if angles.len() == 1 {
+        return if (angle - angles[0]).abs() < 1e-9 { Some((0, 0, 0.0)) } else { None };
+    }
+    if angle < angles[0] || angle > angles[angles.len() - 1] {
+        return None;
+    }
+    let upper = angles.partition_point(|candidate| *candidate < angle).clamp(1, angles.len() - 1);
+    let lower = upper - 1;
+    let span = angles[upper] - angles[lower];
+    Some((lower, upper, if span > 0.0 { (angle - angles[lower]) / span } else { 0.0 }))
+}
+
+impl IesProfile {
+    pub fn parse(file: &str, text: &str) -> Result<IesProfile, IesError> {
+        let mut lines = text.lines();
+        // The header keywords end at the TILT line, the numbers after it can be laid out on any number of lines
+        let mut keywords = Vec::new();
+        let tilt = loop {
+            let line = lines.next().ok_or_else(|| IesError::new(file, "missing TILT line".to_string()))?.trim();
+            if line.starts_with("TILT=") {
+                break line;
+            }
+            if let Some((keyword, value)) = line.strip_prefix('[').and_then(|rest| rest.split_once(']')) {
+                keywords.push((keyword.to_string(), value.trim().to_string()));
+            }
+        };
+        let mut numbers = lines.flat_map(|line| line.split(|c: char| c.is_whitespace() || c == ','))
+            .filter(|token| !token.is_empty())
+            .map(|token| token.parse::<f64>().map_err(|_| IesError::new(file, format!("invalid number \"{}\"", token))));
+        let mut next = |what: &str| numbers.next().unwrap_or_else(|| Err(IesError::new(file, format!("missing {}", what))));
+        if tilt == "TILT=INCLUDE" {
+            next("lamp to luminaire geometry")?;
+            let pairs = next("number of tilt angles")? as usize;
+            for _ in 0..pairs * 2 {
+                next("tilt angles and factors")?;
+            }
+        }
+        let _lamps = next("number of lamps")?;
+        let lumens_per_lamp = next("lumens per lamp")?;
+        let multiplier = next("candela multiplier")?;
+        let vertical_count = next("number of vertical angles")? as usize;
+        let horizontal_count = next("number of horizontal angles")? as usize;
+        let photometric_type = next("photometric type")?;
+        for what in ["units type", "width", "length", "height", "ballast factor", "future use"].iter() {
+            next(what)?;
+        }
+        let input_watts = next("input watts")?;
+        if photometric_type != 1.0 {
+            return Err(IesError::new(file, format!("only type C photometry is supported, not type {}", photometric_type)));
+        }
+        if vertical_count == 0 || horizontal_count == 0 {
+            return Err(IesError::new(file, "the candela table is empty".to_string()));
+        }
+        let vertical_angles = (0..vertical_count).map(|_| next("vertical angles")).collect::<Result<Vec<f64>, IesError>>()?;
+        let horizontal_angles = (0..horizontal_count).map(|_| next("horizontal angles")).collect::<Result<Vec<f64>, IesError>>()?;
+        let candela = (0..horizontal_count)
+            .map(|_| (0..vertical_count).map(|_| next("candela values").map(|value| value * multiplier)).collect::<Result<Vec<f64>, IesError>>())
+            .collect::<Result<Vec<Vec<f64>>, IesError>>()?;
+        if vertical_angles.windows(2).any(|pair| pair[0] >= pair[1]) || horizontal_angles.windows(2).any(|pair| pair[0] >= pair[1]) {
+            return Err(IesError::new(file, "the angles must be increasing".to_string()));
+        }
+        let peak = candela.iter().flatten().cloned().fold(0.0, f64::max);
+        if peak <= 0.0 {
+            return Err(IesError::new(file, "every candela value is zero".to_string()));
+        }
+        Ok(IesProfile { keywords, lumens_per_lamp, multiplier, input_watts, vertical_angles, horizontal_angles, candela, peak })
+    }
+
+    pub fn load(file: &str) -> Result<IesProfile, IesError> {
+        let text = fs::read(file).map_err(|e| IesError::new(file, e.to_string()))?;
+        IesProfile::parse(file, &String::from_utf8_lossy(&text))
+    }
+
+    // Horizontal angle folded into the range covered by the table, following the symmetry its last angle implies
+    fn fold_horizontal(&self, angle: f64) -> f64 {
+        let angle = angle.rem_euclid(360.0);
+        match self.horizontal_angles[self.horizontal_angles.len() - 1] {
+            last if last <= 0.0 => 0.0,
+            last if last <= 90.0 => {
+                let half = angle % 180.0;
+                if half > 90.0 { 180.0 - half } else { half }
+            },
+            last if last <= 180.0 => if angle > 180.0 { 360.0 - angle } else { angle },
+            _ => angle
+        }
+    }
+
+    // Intensity relative to the brightest direction, bilinear between the entries of the table and 0 outside of it
+    pub fn intensity(&self, vertical: f64, horizontal: f64) -> f64 {
+        let (top, bottom, t) = match locate(&self.vertical_angles, vertical) {
+            Some(found) => found,
+            None => return 0.0
+        };
+        let column = |row: &Vec<f64>| row[top] * (1.0 - t) + row[bottom] * t;
+        let horizontal = self.fold_horizontal(horizontal);
+        let value = match locate(&self.horizontal_angles, horizontal) {
+            Some((left, right, u)) => column(&self.candela[left]) * (1.0 - u) + column(&self.candela[right]) * u,
+            // Between the last angle and a full turn, the table wraps around to its first angle
+            None if self.horizontal_angles.len() > 1 => {
+                let (first, last) = (self.horizontal_angles[0], self.horizontal_angles[self.horizontal_angles.len() - 1]);
+                let gap = first + 360.0 - last;
+                let u = if gap > 0.0 { (horizontal - last).rem_euclid(360.0) / gap } else { 0.0 };
+                column(&self.candela[self.candela.len() - 1]) * (1.0 - u) + column(&self.candela[0]) * u
+            },
+            None => column(&self.candela[0])
+        };
+        value / self.peak
+    }
+}
+
+// An IES file loaded once and shared by every light using it
+pub struct IesFile {
+    pub file: String,
+    pub profile: Option<IesProfile>
+}
+
+impl IesFile {
+    pub fn new(file: &str) -> IesFile {
+        IesFile { file: file.to_string(), profile: None }
+    }
+}
+
+impl Asset for IesFile {
+    fn name(&self) -> &str {
+        &self.file
+    }
+
+    fn load(&mut self) -> Result<(), Box<dyn error::Error>> {
+        self.profile = Some(IesProfile::load(&self.file)?);
+        Ok(())
+    }
+
+    fn describe(&self) -> String {
+        match &self.profile {
+            Some(profile) => format!("Using IES profile: {} ({} vertical and {} horizontal angles, peak {} cd)", self.file, profile.vertical_angles.len(), profile.horizontal_angles.len(), profile.peak),
+            None => format!("Using IES profile: {} (not loaded)", self.file)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keyword<'a>(profile: &'a IesProfile, name: &str) -> Option<&'a str> {
+        profile.keywords.iter().find(|(keyword, _)| keyword == name).map(|(_, value)| value.as_str())
+    }
+
+    #[test]
+    fn parses_a_rotationally_symmetric_downlight() {
+        let profile = IesProfile::load("test_scene/ies/downlight.ies").unwrap();
+        assert_eq!(keyword(&profile, "LUMCAT"), Some("WASH-1"));
+        assert_eq!(keyword(&profile, "LAMP"), Some("LED module 1000 lm"));
+        assert_eq!(profile.keywords.len(), 5);
+        assert_eq!((profile.lumens_per_lamp, profile.multiplier, profile.input_watts), (1000.0, 1.0, 12.0));
+        assert_eq!(profile.vertical_angles, (0..19).map(|step| step as f64 * 5.0).collect::<Vec<f64>>());
+        assert_eq!(profile.horizontal_angles, vec![0.0]);
+        assert_eq!(profile.peak, 1124.5);
+        // One horizontal angle: the same everywhere around the vertical axis
+        let between = (1124.5 + 797.1) / 2.0 / 1124.5;
+        for horizontal in [0.0, 90.0, 217.0] {
+            assert!((profile.intensity(32.5, horizontal) - between).abs() < 1e-12);
+        }
+        assert!((profile.intensity(0.0, 0.0) - 300.4 / 1124.5).abs() < 1e-12);
+        assert_eq!(profile.intensity(120.0, 0.0), 0.0);
+    }
+
+    #[test]
+    fn parses_a_linear_fixture_with_tilt_data_and_mirrors_its_half_table() {
+        let profile = IesProfile::load("test_scene/ies/linear.ies").unwrap();
+        assert_eq!(keyword(&profile, "LUMINAIRE"), Some("Linear fixture, wide across its length"));
+        assert_eq!((profile.lumens_per_lamp, profile.multiplier, profile.input_watts), (2000.0, 1.0, 30.0));
+        assert_eq!(profile.vertical_angles.len(), 10);
+        assert_eq!(profile.horizontal_angles, vec![0.0, 45.0, 90.0, 135.0, 180.0]);
+        assert_eq!(profile.candela[2][3], 720.7);
+        assert_eq!(profile.peak, 1000.0);
+        // Halfway between 40 and 50 degrees and between the 0 and 45 degree planes
+        let expected = ((59.2 + 11.8) / 2.0 + (466.7 + 296.3) / 2.0) / 2.0 / 1000.0;
+        assert!((profile.intensity(45.0, 22.5) - expected).abs() < 1e-12);
+        // The table stops at 180 degrees, the other half mirrors it
+        assert!((profile.intensity(63.0, 270.0) - profile.intensity(63.0, 90.0)).abs() < 1e-12);
+        assert!((profile.intensity(63.0, 200.0) - profile.intensity(63.0, 160.0)).abs() < 1e-12);
+    }
+
+    #[test]
+    fn parses_comma_separated_wrapped_rows_with_absolute_photometry() {
+        let profile = IesProfile::load("test_scene/ies/wallwash.ies").unwrap();
+        assert_eq!(keyword(&profile, "ISSUEDATE"), Some("2026-10-15"));
+        assert_eq!(profile.keywords.len(), 7);
+        assert_eq!((profile.lumens_per_lamp, profile.multiplier, profile.input_watts), (-1.0, 2.0, 18.0));
+        assert_eq!(profile.vertical_angles, vec![0.0, 22.5, 45.0, 67.5, 90.0]);
+        assert_eq!(profile.candela[1], vec![800.0, 720.0, 520.0, 220.0, 20.0]);
+        assert_eq!(profile.peak, 800.0);
+        // The table stops at 90 degrees, each quadrant mirrors it
+        assert!((profile.intensity(22.5, 135.0) - 720.0 / 800.0).abs() < 1e-12);
+        assert!((profile.intensity(45.0, 300.0) - (520.0 * 2.0 / 3.0 + 440.0 / 3.0) / 800.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn malformed_files_are_errors() {
+        let truncated = "IESNA:LM-63-2002\nTILT=NONE\n1 1000 1 3 1 1 2 0.1 0.1 0\n1 1 12\n0 45 90\n0\n100 50\n";
+        assert_eq!(IesProfile::parse("truncated.ies", truncated).unwrap_err().to_string(), "truncated.ies: missing candela values");
+        let garbled = "TILT=NONE\n1 1000 1 1 1 1 2 0.1 0.1 0\n1 1 twelve\n";
+        assert_eq!(IesProfile::parse("garbled.ies", garbled).unwrap_err().to_string(), "garbled.ies: invalid number \"twelve\"");
+        assert_eq!(IesProfile::parse("empty.ies", "IESNA:LM-63-2002\n[TEST] nothing\n").unwrap_err().message, "missing TILT line");
+        let type_b = "TILT=NONE\n1 1000 1 1 1 2 2 0.1 0.1 0\n1 1 12\n0\n0\n100\n";
+        assert!(IesProfile::parse("type_b.ies", type_b).unwrap_err().message.contains("type C"));
+    }
+}
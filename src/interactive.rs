@@ -0,0 +1,233 @@
+use std::error;
+use std::sync::Arc;
+use std::io::{self, BufRead, Write};
+use serde_json::Value;
+use image::RgbaImage;
+use crate::rendering::{self, Scene};
+use crate::output;
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Debug, PartialEq)]
+pub enum Command {
+    SET(String, String),
+    RENDER,
+    SAVE(String),
+    HELP,
+    QUIT
+}
+
+pub fn parse_command(line: &str) -> Result<Command, String> {
+    let line = line.trim();
+    let (name, arguments) = match line.find(char::is_whitespace) {
+        Some(index) => (&line[..index], line[index..].trim()),
+        None => (line, "")
+    };
+    match name {
+        "set" => {
+            let (path, value) = match arguments.find(char::is_whitespace) {
+                Some(index) => (&arguments[..index], arguments[index..].trim()),
+                None => return Err("usage: set <path> <value>".to_string())
+            };
+            Ok(Command::SET(path.to_string(), value.to_string()))
+        },
+        "render" if arguments.is_empty() => Ok(Command::RENDER),
+        "save" if !arguments.is_empty() => Ok(Command::SAVE(arguments.to_string())),
+        "save" => Err("usage: save <file>".to_string()),
+        "help" => Ok(Command::HELP),
+        "quit" | "exit" => Ok(Command::QUIT),
+        _ => Err(format!("unknown command \"{}\", type help to list the commands", line))
+    }
+}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Debug, PartialEq)]
+pub enum PathSegment {
+    FIELD(String),
+    INDEX(usize)
+}
+
+pub fn parse_path(path: &str) -> Result<Vec<PathSegment>, String> {
+    let mut segments = Vec::new();
+    for part in path.split('.') {
+        let (field, mut indices) = match part.find('[') {
+            Some(index) => (&part[..index], &part[index..]),
+            None => (part, "")
+        };
+        if field.is_empty() {
+            return Err(format!("empty field name in \"{}\"", path));
+        }
+        segments.push(PathSegment::FIELD(field.to_string()));
+        while !indices.is_empty() {
+            let end = match (indices.starts_with('['), indices.find(']')) {
+                (true, Some(end)) => end,
+                _ => return Err(format!("malformed index in \"{}\"", path))
+            };
+            let index = indices[1..end].parse().map_err(|_| format!("invalid index \"{}\" in \"{}\"", &indices[1..end], path))?;
+            segments.push(PathSegment::INDEX(index));
+            indices = &indices[end + 1..];
+        }
+    }
+    Ok(segments)
+}
+
+// The last field is added when it is missing, the optional ones are left out of scene files
+pub fn set_path(root: &mut Value, path: &str, new_value: Value) -> Result<(), String> {
+    let segments = parse_path(path)?;
+    let last = segments.len() - 1;
+    let mut current = root;
+    for (position, segment) in segments.into_iter().enumerate() {
+        current = match segment {
+            PathSegment::FIELD(field) => match current {
+                Value::Object(object) => if position == last {
+                    object.entry(field).or_insert(Value::Null)
+                } else {
+                    object.get_mut(&field).ok_or_else(|| format!("no field \"{}\" in \"{}\"", field, path))?
+                },
+                _ => return Err(format!("\"{}\" is not an object in \"{}\"", field, path))
+            },
+            PathSegment::INDEX(index) => match current {
+                Value::Array(array) => {
+                    let len = array.len();
+                    array.get_mut(index).ok_or_else(|| format!("index {} out of range (length {}) in \"{}\"", index, len, path))?
+                },
+                _ => return Err(format!("[{}] used on something that is not an array in \"{}\"", index, path))
+            }
+        };
+    }
+    *current = new_value;
+    Ok(())
+}
+
+fn parse_value(text: &str) -> Value {
+    serde_json::from_str(text).unwrap_or_else(|_| Value::String(text.to_string()))
+}
+
+// Edits the scene as it was before being prepared, so material names, nodes and the other values prepare resolves
+// are prepared again from what the file had. The settings given on the command line and the loaded textures are kept
+fn apply_set(source: &Value, previous: &Scene, path: &str, value: &str) -> Result<(Value, Scene), String> {
+    let mut root = source.clone();
+    set_path(&mut root, path, parse_value(value))?;
+    let mut updated: Scene = serde_json::from_value(root.clone()).map_err(|e| format!("invalid value for \"{}\": {}", path, e))?;
+    previous.limits.check_scene(&updated).map_err(|e| e.to_string())?;
+    updated.limits = previous.limits;
+    updated.verbose = previous.verbose;
+    updated.textures = Arc::clone(&previous.textures);
+    updated.prepare().map_err(|e| e.to_string())?;
+    Ok((root, updated))
+}
+
+fn print_help() {
+    println!("set <path> <value>  change a scene value, e.g. set camera.fov 70 or set elements[0].material.reflectiveness 0.8");
+    println!("render              render the scene again and write it to the output file");
+    println!("save <file>         write the last render to another file");
+    println!("quit                leave the interactive mode");
+}
+
+pub fn run(nb_pass: u8, mut source: Value, mut scene: Scene, output_path: String) -> Result<(), Box<dyn error::Error>> {
+    let mut image: RgbaImage = rendering::render_image(nb_pass, &mut scene);
+    output::write(&image, &output_path, &scene.output, &scene.metadata())?;
+    println!("Rendered to {}, type help to list the commands", output_path);
+
+    let stdin = io::stdin();
+    print!("> ");
+    io::stdout().flush()?;
+    for line in stdin.lock().lines() {
+        let line = line?;
+        if !line.trim().is_empty() {
+            match parse_command(&line) {
+                Ok(Command::SET(path, value)) => match apply_set(&source, &scene, &path, &value) {
+                    Ok((edited, updated)) => {
+                        source = edited;
+                        scene = updated;
+                    },
+                    Err(e) => eprintln!("error: {}", e)
+                },
+                Ok(Command::RENDER) => {
+                    image = rendering::render_image(nb_pass, &mut scene);
+                    match output::write(&image, &output_path, &scene.output, &scene.metadata()) {
+                        Ok(()) => println!("Rendered to {}", output_path),
+                        Err(e) => eprintln!("error: {}", e)
+                    }
+                },
+                Ok(Command::SAVE(path)) => match output::write(&image, &path, &scene.output, &scene.metadata()) {
+                    Ok(()) => println!("Saved to {}", path),
+                    Err(e) => eprintln!("error: {}", e)
+                },
+                Ok(Command::HELP) => print_help(),
+                Ok(Command::QUIT) => return Ok(()),
+                Err(e) => eprintln!("error: {}", e)
+            }
+        }
+        print!("> ");
+        io::stdout().flush()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn materials_scene() -> Value {
+        serde_json::from_str(include_str!("../test_scene/materials.json")).unwrap()
+    }
+
+    #[test]
+    fn commands_are_parsed() {
+        assert_eq!(parse_command("set camera.fov 70"), Ok(Command::SET("camera.fov".to_string(), "70".to_string())));
+        assert_eq!(parse_command("  set camera.position {\"x\": 1, \"y\": 2, \"z\": 3} "), Ok(Command::SET("camera.position".to_string(), "{\"x\": 1, \"y\": 2, \"z\": 3}".to_string())));
+        assert_eq!(parse_command("render"), Ok(Command::RENDER));
+        assert_eq!(parse_command("save other.png"), Ok(Command::SAVE("other.png".to_string())));
+        assert_eq!(parse_command("help"), Ok(Command::HELP));
+        assert_eq!(parse_command("quit"), Ok(Command::QUIT));
+        assert_eq!(parse_command("exit"), Ok(Command::QUIT));
+        assert!(parse_command("set camera.fov").is_err());
+        assert!(parse_command("save").is_err());
+        assert!(parse_command("render now").is_err());
+        assert!(parse_command("draw").unwrap_err().contains("unknown command"));
+    }
+
+    #[test]
+    fn paths_are_split_in_fields_and_indices() {
+        assert_eq!(parse_path("elements[2].material.albedo"), Ok(vec![
+            PathSegment::FIELD("elements".to_string()),
+            PathSegment::INDEX(2),
+            PathSegment::FIELD("material".to_string()),
+            PathSegment::FIELD("albedo".to_string())
+        ]));
+        assert_eq!(parse_path("grid[1][0]"), Ok(vec![PathSegment::FIELD("grid".to_string()), PathSegment::INDEX(1), PathSegment::INDEX(0)]));
+        for path in ["", "camera..fov", "[0]", "elements[x]", "elements[1", "elements]0["] {
+            assert!(parse_path(path).is_err(), "{}", path);
+        }
+    }
+
+    #[test]
+    fn values_are_set_at_their_path() {
+        let mut root = json!({"camera": {"fov": 60}, "elements": [{"radius": 1}]});
+        set_path(&mut root, "camera.fov", json!(70)).unwrap();
+        set_path(&mut root, "elements[0].radius", json!(2)).unwrap();
+        set_path(&mut root, "camera.aperture", json!(0.1)).unwrap();
+        assert_eq!(root, json!({"camera": {"fov": 70, "aperture": 0.1}, "elements": [{"radius": 2}]}));
+        assert!(set_path(&mut root, "elements[1].radius", json!(3)).unwrap_err().contains("out of range"));
+        assert!(set_path(&mut root, "camera.stereo.layout", json!("ANAGLYPH")).unwrap_err().contains("no field \"stereo\""));
+        assert!(set_path(&mut root, "camera[0]", json!(1)).is_err());
+        assert!(set_path(&mut root, "camera.fov.degrees", json!(1)).is_err());
+        assert_eq!(root, json!({"camera": {"fov": 70, "aperture": 0.1}, "elements": [{"radius": 2}]}));
+    }
+
+    #[test]
+    fn edits_keep_the_scene_materials() {
+        let source = materials_scene();
+        let previous: Scene = serde_json::from_value(source.clone()).unwrap();
+        let (edited, scene) = apply_set(&source, &previous, "camera.fov", "70").unwrap();
+        assert!(Arc::ptr_eq(&scene.textures, &previous.textures));
+        assert_eq!(edited["elements"][1]["material"], json!("chrome"));
+        assert_eq!(scene.elements[1].material.name.as_deref(), Some("chrome"));
+        let (edited, scene) = apply_set(&edited, &previous, "materials.chrome.reflectiveness", "0.1").unwrap();
+        assert_eq!(scene.camera.fov, 70.0);
+        assert_eq!(scene.flat_elements[1].material.reflectiveness, 0.1);
+        assert_eq!(edited["materials"]["chrome"]["reflectiveness"], json!(0.1));
+        assert!(apply_set(&edited, &previous, "camera.fov", "\"wide\"").unwrap_err().contains("invalid value"));
+    }
+}
@@ -0,0 +1,214 @@
+use serde::{Serialize, Deserialize};
+use crate::rendering::{Camera, Projection, Scene};
+use crate::shape::{Ray, RayType, Point};
+use crate::vertors::Vector3;
+
+// Thin lens: the rays of a pixel leave from a disk of the aperture around the camera and meet on the plane in focus,
+// which stays sharp while nearer and farther elements blur
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct DepthOfField {
+    pub aperture: f64, // Diameter of the lens in scene units, 0 keeps everything sharp
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub focus_distance: Option<f64>, // Along the view direction, also where a focus_point that misses falls back to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub focus_on: Option<String>, // Name of an element, focused at the center of its bounds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub focus_point: Option<[f64; 2]>, // Image position from the top left corner, from 0 to 1, focused where its ray hits
+    #[serde(skip)]
+    pub focus: Option<f64> // Distance resolved by Scene::prepare
+}
+
+impl DepthOfField {
+    pub fn validate(&self, camera: &Camera) -> Result<(), String> {
+        if !(self.aperture >= 0.0 && self.aperture.is_finite()) {
+            return Err(format!("the camera depth_of_field aperture must not be negative, not {}", self.aperture));
+        }
+        if camera.projection != Projection::PERSPECTIVE {
+            return Err("the camera depth_of_field needs the PERSPECTIVE projection".to_string());
+        }
+        match self.focus_distance {
+            Some(distance) if !(distance > 0.0 && distance.is_finite()) => return Err(format!("the camera focus_distance must be positive, not {}", distance)),
+            _ => ()
+        }
+        if self.focus_on.is_some() && self.focus_point.is_some() {
+            return Err("the camera cannot have both focus_on and focus_point".to_string());
+        }
+        match self.focus_point {
+            Some(point) if point.iter().any(|value| !(0.0..=1.0).contains(value)) => Err(format!("the camera focus_point must be between 0 and 1, not {:?}", point)),
+            _ => Ok(())
+        }
+    }
+
+    // Distance along the view direction of the plane in focus
+    pub fn resolve(&self, scene: &Scene) -> Result<f64, String> {
+        let camera = &scene.camera;
+        let (_, _, forward) = camera.basis()?;
+        if let Some(name) = &self.focus_on {
+            let element = scene.flat_elements.iter().find(|renderable| renderable.name.as_deref() == Some(name.as_str()))
+                .ok_or_else(|| format!("the camera focuses on \"{}\" that is not the name of an element", name))?;
+            let bounds = element.bounding_box().ok_or_else(|| format!("the camera cannot focus on \"{}\", it has no bounds", name))?;
+            let distance = ((bounds.min + bounds.max) * 0.5 - camera.origin()).dot(&forward);
+            if distance <= 0.0 {
+                return Err(format!("the camera focuses on \"{}\" that is behind it", name));
+            }
+            return Ok(distance);
+        }
+        if let Some([x, y]) = self.focus_point {
+            let (pixel_x, offset_x) = pixel(x, camera.width);
+            let (pixel_y, offset_y) = pixel(y, camera.height);
+            let ray = camera.compute_sample_ray(pixel_x, pixel_y, offset_x, offset_y).with_time(camera.shutter_open);
+            if let Some((_, hit)) = scene.trace(&ray, RayType::PRIMARY) {
+                return Ok(hit.distance * ray.direction.dot(&forward));
+            }
+            return match self.focus_distance {
+                Some(distance) => {
+                    eprintln!("warning: the camera focus_point [{}, {}] misses every element, focusing at the focus_distance {}", x, y, distance);
+                    Ok(distance)
+                },
+                None => Err(format!("the camera focus_point [{}, {}] misses every element and there is no focus_distance to fall back to", x, y))
+            };
+        }
+        self.focus_distance.ok_or_else(|| "the camera depth_of_field needs a focus_distance, focus_on or focus_point".to_string())
+    }
+}
+
+// Pixel and offset inside it of a position from 0 to 1 across the image
+fn pixel(position: f64, size: u32) -> (u32, f64) {
+    let scaled = position * size as f64;
+    let pixel = (scaled.floor() as u32).min(size.saturating_sub(1));
+    (pixel, scaled - pixel as f64)
+}
+
+// Lens of a prepared camera, moving the origin of its rays across the aperture
+#[derive(Copy, Clone, Debug)]
+pub struct Lens {
+    pub radius: f64,
+    pub focus: f64,
+    pub right: Vector3,
+    pub up: Vector3,
+    pub forward: Vector3
+}
+
+impl Lens {
+    // u and v pick the point of the aperture, uniformly over the disk
+    pub fn ray(&self, ray: Ray, u: f64, v: f64) -> Ray {
+        let focus: Point = ray.origin + ray.direction * (self.focus / ray.direction.dot(&self.forward));
+        let (sin, cos) = (v * 2.0 * std::f64::consts::PI).sin_cos();
+        let radius = self.radius * u.sqrt();
+        let origin = ray.origin + self.right * (radius * cos) + self.up * (radius * sin);
+        Ray::new(origin, (focus - origin).normalize()).with_range(ray.t_min, ray.t_max).with_time(ray.time)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use crate::rendering::{self, Color, Material, Renderable};
+    use crate::shape::{Shape, Sphere};
+
+    fn balls(depth_of_field: DepthOfField) -> Scene {
+        let mut camera = Camera::new(64, 48, 60.0);
+        camera.depth_of_field = Some(depth_of_field);
+        let ball = |name: &str, z: f64| Renderable { name: Some(name.to_string()), ..Renderable::new(Shape::SPHERE(Sphere::new(Vector3::new(0.0, 0.0, z), 1.0)), Material::new(Color::new(200, 200, 200, 255), 0.5, 0.0)) };
+        Scene::new(camera, vec![ball("near", -4.0), ball("far", -10.0)], Vec::new(), Color::new(10, 20, 30, 255))
+    }
+
+    fn focus(scene: &Scene) -> f64 {
+        scene.camera.depth_of_field.as_ref().and_then(|depth_of_field| depth_of_field.focus).unwrap()
+    }
+
+    #[test]
+    fn focuses_on_named_elements() {
+        let mut scene = balls(DepthOfField { aperture: 0.2, focus_on: Some("far".to_string()), ..DepthOfField::default() });
+        scene.prepare().unwrap();
+        assert!((focus(&scene) - 10.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn focuses_where_the_focus_point_hits() {
+        let mut scene = balls(DepthOfField { aperture: 0.2, focus_point: Some([0.5, 0.5]), ..DepthOfField::default() });
+        scene.prepare().unwrap();
+        assert!((focus(&scene) - 3.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn a_focus_point_missing_everything_falls_back_to_the_focus_distance() {
+        let mut scene = balls(DepthOfField { aperture: 0.2, focus_point: Some([0.0, 0.0]), focus_distance: Some(7.0), ..DepthOfField::default() });
+        scene.prepare().unwrap();
+        assert_eq!(focus(&scene), 7.0);
+        let mut scene = balls(DepthOfField { aperture: 0.2, focus_point: Some([0.0, 0.0]), ..DepthOfField::default() });
+        assert!(scene.prepare().unwrap_err().to_string().contains("no focus_distance to fall back to"));
+    }
+
+    #[test]
+    fn unknown_names_and_bad_settings_are_errors() {
+        let mut unknown = balls(DepthOfField { aperture: 0.2, focus_on: Some("hero".to_string()), ..DepthOfField::default() });
+        assert!(unknown.prepare().unwrap_err().to_string().contains("\"hero\" that is not the name of an element"));
+        let mut unfocused = balls(DepthOfField { aperture: 0.2, ..DepthOfField::default() });
+        assert!(unfocused.prepare().is_err());
+        let mut both = balls(DepthOfField { aperture: 0.2, focus_on: Some("far".to_string()), focus_point: Some([0.5, 0.5]), ..DepthOfField::default() });
+        assert!(both.prepare().is_err());
+        let mut outside = balls(DepthOfField { aperture: 0.2, focus_point: Some([1.5, 0.5]), ..DepthOfField::default() });
+        assert!(outside.prepare().is_err());
+    }
+
+    #[test]
+    fn rays_through_the_lens_meet_on_the_plane_in_focus() {
+        let lens = Lens { radius: 0.5, focus: 5.0, right: Vector3::new(1.0, 0.0, 0.0), up: Vector3::new(0.0, 1.0, 0.0), forward: Vector3::new(0.0, 0.0, -1.0) };
+        let ray = Ray::new(Vector3::zero(), Vector3::new(0.3, -0.2, -1.0).normalize());
+        let expected = ray.origin + ray.direction * (5.0 / ray.direction.dot(&lens.forward));
+        for (u, v) in [(0.0, 0.0), (1.0, 0.25), (0.5, 0.6), (0.9, 0.9)].iter() {
+            let through = lens.ray(ray, *u, *v);
+            assert!((through.origin - ray.origin).length() <= 0.5 + 1e-12);
+            assert!(through.origin.z.abs() < 1e-12);
+            let at_focus = through.origin + through.direction * ((expected.z - through.origin.z) / through.direction.z);
+            assert!((at_focus - expected).length() < 1e-9);
+        }
+    }
+
+    fn render(focus_on: &str, aperture: f64) -> (Scene, image::RgbaImage) {
+        let mut scene = crate::parse_scene(include_str!("../test_scene/depth_of_field.json"), &HashMap::new(), None).unwrap();
+        scene.camera.width = 64;
+        scene.camera.height = 36;
+        scene.samples_per_pixel = 8;
+        scene.camera.depth_of_field = Some(DepthOfField { aperture, focus_on: Some(focus_on.to_string()), ..DepthOfField::default() });
+        scene.prepare().unwrap();
+        let image = rendering::render_image(1, &mut scene);
+        (scene, image)
+    }
+
+    // Difference with the sharp render over the pixels showing the element
+    fn blur(element: &str, sharp: &(Scene, image::RgbaImage), blurred: &image::RgbaImage) -> u64 {
+        let (scene, image) = sharp;
+        let mut blur = 0;
+        for (x, y, pixel) in image.enumerate_pixels() {
+            let ray = scene.camera.compute_prime_ray(x, y);
+            if scene.trace(&ray, RayType::PRIMARY).is_some_and(|(renderable, _)| renderable.name.as_deref() == Some(element)) {
+                blur += pixel.0.iter().zip(blurred.get_pixel(x, y).0.iter()).map(|(a, b)| (*a as i64 - *b as i64).unsigned_abs()).sum::<u64>();
+            }
+        }
+        blur
+    }
+
+    #[test]
+    fn only_the_plane_in_focus_stays_sharp() {
+        let sharp = render("middle", 0.0);
+        let (_, near) = render("near", 0.4);
+        let (_, far) = render("far", 0.4);
+        assert!(blur("near", &sharp, &near) * 2 < blur("near", &sharp, &far));
+        assert!(blur("far", &sharp, &far) * 2 < blur("far", &sharp, &near));
+    }
+
+    #[test]
+    fn no_aperture_renders_like_a_pinhole() {
+        let (_, sharp) = render("near", 0.0);
+        let mut pinhole = crate::parse_scene(include_str!("../test_scene/depth_of_field.json"), &HashMap::new(), None).unwrap();
+        pinhole.camera.width = 64;
+        pinhole.camera.height = 36;
+        pinhole.samples_per_pixel = 8;
+        pinhole.camera.depth_of_field = None;
+        pinhole.prepare().unwrap();
+        assert_eq!(rendering::render_image(1, &mut pinhole).into_raw(), sharp.into_raw());
+    }
+}
@@ -1,17 +1,137 @@
+//! Ray tracer rendering the JSON scenes described in the README.
+//!
+//! Scenes can also be built in code, with shapes of your own: implement
+//! [`traits::Intersectable`] and wrap the shape in [`shape::Shape::CUSTOM`].
+//! Custom shapes cannot be written to or read from a scene file.
+//!
+//! ```no_run
+//! use rust_raytracer::aabb::Aabb;
+//! use rust_raytracer::rendering::{self, Camera, Color, Light, Material, PointLight, Renderable, Scene};
+//! use rust_raytracer::shape::{CustomShape, Hit, Point, Ray, Shape};
+//! use rust_raytracer::traits::Intersectable;
+//! use rust_raytracer::vertors::Vector3;
+//!
+//! // A floating cube, the hits must stay in the ray range
+//! struct Cube {
+//!     center: Point,
+//!     half_size: f64
+//! }
+//!
+//! impl Intersectable for Cube {
+//!     fn intersect(&self, ray: &Ray) -> Option<Hit> {
+//!         let bounds = self.bounding_box()?;
+//!         let (enter, _) = bounds.range(ray)?;
+//!         let point = ray.origin + ray.direction * enter;
+//!         let offset = point - self.center;
+//!         let normal = if offset.x.abs() >= offset.y.abs() && offset.x.abs() >= offset.z.abs() {
+//!             Vector3::new(offset.x.signum(), 0.0, 0.0)
+//!         } else if offset.y.abs() >= offset.z.abs() {
+//!             Vector3::new(0.0, offset.y.signum(), 0.0)
+//!         } else {
+//!             Vector3::new(0.0, 0.0, offset.z.signum())
+//!         };
+//!         Some(Hit::new(enter, point, normal))
+//!     }
+//!
+//!     fn bounding_box(&self) -> Option<Aabb> {
+//!         Some(Aabb::around(self.center, Vector3::new(self.half_size, self.half_size, self.half_size)))
+//!     }
+//! }
+//!
+//! let cube = Cube { center: Vector3::new(0.0, 0.0, -5.0), half_size: 1.0 };
+//! let elements = vec![Renderable::new(Shape::CUSTOM(CustomShape::new(cube)), Material::new(Color::new(200, 80, 40, 255), 0.9, 0.0))];
+//! let lights = vec![Light::POINT(PointLight::new(Vector3::new(3.0, 4.0, 0.0), 2000.0, Color::new(255, 255, 255, 255)))];
+//! let scene = Scene::new(Camera::new(320, 240, 90.0), elements, lights, Color::new(20, 20, 30, 255));
+//! rendering::render(1, scene, "cube.png".to_string()).unwrap();
+//! ```
+
+// serde_derive 1.0.105 puts its impls in named constants, attributes on the derived items do not reach them
+#![allow(non_local_definitions)]
+
 use std::error;
-use std::fs;
-use crate::rendering::{Scene, Camera, Renderable, Material, Color, Light};
-use crate::shape::{Shape, Sphere, Point};
+use std::collections::HashMap;
+use std::sync::Arc;
+use crate::rendering::Scene;
+use crate::profile::{Profile, Stage};
+use crate::post::PostEffect;
+use crate::memory::MemoryEstimate;
+use crate::texture_cache::TextureCache;
+use crate::stereo::Stereo;
+pub use crate::output::{Channels, RenderingIntent};
+pub use crate::limits::Limits;
+pub use crate::memory::parse_size;
+pub use crate::cubemap::CubemapLayout;
+pub use crate::stereo::StereoLayout;
 
-mod shape;
-mod vertors;
-mod rendering;
-mod traits;
+pub mod shape;
+pub mod vertors;
+mod polynomial;
+mod noise;
+mod mesh;
+mod stl;
+mod ply;
+mod gltf;
+mod heightfield;
+mod sdf;
+mod metaballs;
+pub mod aabb;
+pub mod rendering;
+pub mod traits;
+mod random;
+mod photon;
+mod light_sampling;
+mod transform;
+mod node;
+mod template;
+mod variables;
+mod profile;
+mod interactive;
+mod post;
+mod output;
+mod lint;
+mod limits;
+mod assets;
+mod bvh;
+mod texture_cache;
+mod memory;
+mod debug;
+mod sampler;
+mod merge;
+mod cubemap;
+mod stereo;
+mod exposure;
+mod lens;
+mod environment;
+mod ies;
+pub mod texture;
+mod uv_projection;
+mod animation;
+mod accumulate;
 
 pub struct Config {
     pub scene_path: String,
     pub output_path: String,
-    pub nb_pass: u8
+    pub nb_pass: u8,
+    pub variables: HashMap<String, String>,
+    pub profile: bool,
+    pub profile_json: Option<String>,
+    pub verbose: bool,
+    pub interactive: bool,
+    pub luts: Vec<String>,
+    pub channels: Option<Channels>,
+    pub bit_depth: Option<u8>,
+    pub srgb_intent: Option<RenderingIntent>,
+    pub limits: Limits,
+    pub max_memory: Option<u64>,
+    pub texture_budget: Option<u64>,
+    pub debug_pixel: Option<(u32, u32)>,
+    pub debug_json: bool,
+    pub cubemap: Option<CubemapLayout>,
+    pub stereo: Option<StereoLayout>,
+    pub camera: Option<String>,
+    pub accumulate: Option<String>,
+    pub frames: Option<u32>,
+    pub fps: f64
 }
 
 impl Config {
@@ -19,24 +139,211 @@ impl Config {
         Config {
             scene_path,
             output_path,
-            nb_pass
+            nb_pass,
+            variables: HashMap::new(),
+            profile: false,
+            profile_json: None,
+            verbose: false,
+            interactive: false,
+            luts: Vec::new(),
+            channels: None,
+            bit_depth: None,
+            srgb_intent: None,
+            limits: Limits::default(),
+            max_memory: None,
+            texture_budget: None,
+            debug_pixel: None,
+            debug_json: false,
+            cubemap: None,
+            stereo: None,
+            camera: None,
+            accumulate: None,
+            frames: None,
+            fps: animation::DEFAULT_FPS
+        }
+    }
+}
+
+// Scenes with several viewpoints name them in "cameras", the selected one (or "default", or the first by name) becomes the scene camera
+fn select_camera(value: &mut serde_json::Value, name: Option<&str>) -> Result<(), String> {
+    let cameras = match value.as_object_mut().and_then(|scene| scene.remove("cameras")) {
+        Some(serde_json::Value::Object(cameras)) => cameras,
+        Some(_) => return Err("cameras must map names to cameras".to_string()),
+        None => return match name {
+            Some(name) => Err(format!("no camera named \"{}\", the scene has a single camera", name)),
+            None => Ok(())
+        }
+    };
+    if value.get("camera").is_some() {
+        return Err("a scene has either a camera or cameras, not both".to_string());
+    }
+    let selected = match name {
+        Some(name) => name,
+        None if cameras.contains_key("default") => "default",
+        None => cameras.keys().next().map(|name| name.as_str()).ok_or_else(|| "cameras is empty".to_string())?
+    };
+    let camera = cameras.get(selected).ok_or_else(|| {
+        let names: Vec<&str> = cameras.keys().map(|name| name.as_str()).collect();
+        format!("no camera named \"{}\", the scene has {}", selected, names.join(", "))
+    })?;
+    value["camera"] = camera.clone();
+    Ok(())
+}
+
+fn parse_scene(content: &str, variables: &HashMap<String, String>, camera: Option<&str>) -> Result<Scene, Box<dyn error::Error>> {
+    let substitution = variables::substitute(content, variables)?;
+    let located = |e: serde_json::Error| -> Box<dyn error::Error> {
+        if e.line() == 0 {
+            return Box::new(e);
         }
+        let (line, column) = substitution.source_position(content, e.line(), e.column());
+        let mut message = e.to_string();
+        if let Some(index) = message.rfind(" at line ") {
+            message.truncate(index);
+        }
+        format!("{} at line {} column {}", message, line, column).into()
+    };
+    let mut value: serde_json::Value = serde_json::from_str(&substitution.text).map_err(located)?;
+    if value.get("templates").is_none() && value.get("cameras").is_none() && camera.is_none() {
+        return serde_json::from_str(&substitution.text).map_err(located);
     }
+    select_camera(&mut value, camera)?;
+    template::expand_templates(&mut value)?;
+    Ok(serde_json::from_value(value)?)
+}
+
+fn load_scene(config: &Config) -> Result<(String, Scene), Box<dyn error::Error>> {
+    // glTF files are rendered directly, the scene made from them stands in for the file content
+    if gltf::is_gltf(&config.scene_path) {
+        let mut scene = gltf::load_scene(&config.scene_path)?;
+        config.limits.check_scene(&scene)?;
+        scene.limits = config.limits;
+        return Ok((serde_json::to_string_pretty(&scene)?, scene));
+    }
+    let file_content = config.limits.read_scene(&config.scene_path)?;
+    let mut scene = parse_scene(&file_content, &config.variables, config.camera.as_deref())?;
+    config.limits.check_scene(&scene)?;
+    scene.limits = config.limits;
+    Ok((file_content, scene))
 }
 
 pub fn run(config: Config) -> Result<(), Box<dyn error::Error>> {
-    println!("Using scene: {}", config.scene_path);
-    println!("Writing to {}", config.output_path);
-    println!("Number of passes: {}", config.nb_pass);
+    if config.debug_pixel.is_none() {
+        println!("Using scene: {}", config.scene_path);
+        println!("Writing to {}", config.output_path);
+        println!("Number of passes: {}", config.nb_pass);
+    }
 
-    let file_content = fs::read_to_string(config.scene_path)?;
+    let profile = Profile::new(config.profile || config.profile_json.is_some(), config.profile_json.clone());
+    let load_start = profile.start();
+    let (_, mut scene) = load_scene(&config)?;
+    profile.stop(Stage::LOAD, load_start);
+    scene.profile = profile;
+    scene.verbose = config.verbose;
+    scene.textures = Arc::new(TextureCache::new(config.texture_budget));
+    scene.profile.textures = Some(Arc::clone(&scene.textures));
 
-    let scene: Scene = match serde_json::from_str(&file_content) {
-        Ok(parsed) => parsed,
-        Err(e) => return Err(Box::new(e))
-    };
-    if let Err(e) = rendering::render(config.nb_pass, scene, config.output_path) {
-        return Err(Box::new(e));
+    if config.channels.is_some() {
+        scene.output.channels = config.channels;
+    }
+    if let Some(bit_depth) = config.bit_depth {
+        scene.output.bit_depth = bit_depth;
+    }
+    if config.srgb_intent.is_some() {
+        scene.output.srgb_intent = config.srgb_intent;
+    }
+    if let Some(layout) = config.stereo {
+        scene.camera.stereo = Some(Stereo { layout, ..scene.camera.stereo.unwrap_or_default() });
+    }
+    scene.output.validate(&config.output_path)?;
+    for file in config.luts {
+        scene.post.push(PostEffect::LUT { file, lut: None });
+    }
+
+    // Estimated before any file is loaded, so that --max-memory refuses the scene first
+    let estimate = MemoryEstimate::of(&scene, &config.output_path, config.accumulate.is_some(), config.cubemap);
+    if let Some(max_memory) = config.max_memory {
+        estimate.check(max_memory)?;
+    }
+    scene.profile.memory = estimate;
+    let source = if config.interactive { Some(serde_json::to_value(&scene)?) } else { None };
+    let prepare_start = scene.profile.start();
+    scene.prepare()?;
+    scene.profile.stop(Stage::PREPARE, prepare_start);
+    if let Some((x, y)) = config.debug_pixel {
+        if x >= scene.camera.width || y >= scene.camera.height {
+            return Err(format!("debug pixel {},{} is outside the {}x{} image", x, y, scene.camera.width, scene.camera.height).into());
+        }
+        let tree = debug::trace_pixel(config.nb_pass, &mut scene, x, y);
+        if config.debug_json {
+            println!("{}", serde_json::to_string_pretty(&tree)?);
+        } else {
+            print!("{}", debug::format_tree(&tree));
+        }
+        return Ok(());
+    }
+    if config.frames.is_some() || scene.camera_path.is_some() {
+        if config.accumulate.is_some() || config.cubemap.is_some() || config.interactive || scene.camera.stereo.is_some() {
+            return Err("an animation cannot be combined with --accumulate, --cubemap, --interactive or a stereo camera".into());
+        }
+        return animation::render(config.nb_pass, scene, config.output_path, config.frames, config.fps);
+    }
+    if let Some(state_path) = &config.accumulate {
+        if config.cubemap.is_some() || config.interactive || scene.camera.stereo.is_some() {
+            return Err("--accumulate cannot be combined with --cubemap, --interactive or a stereo camera".into());
+        }
+        return accumulate::render(config.nb_pass, scene, config.output_path.clone(), state_path);
+    }
+    if let Some(layout) = config.cubemap {
+        if scene.camera.stereo.is_some() {
+            return Err("--cubemap cannot be combined with a stereo camera".into());
+        }
+        return cubemap::render(config.nb_pass, scene, config.output_path, layout);
+    }
+    if scene.camera.stereo.is_some() && !config.interactive {
+        return stereo::render(config.nb_pass, scene, config.output_path);
+    }
+    if let Some(source) = source {
+        return interactive::run(config.nb_pass, source, scene, config.output_path);
+    }
+    rendering::render(config.nb_pass, scene, config.output_path)
+}
+
+fn lint_scene(config: &Config) -> Result<Vec<lint::Finding>, Box<dyn error::Error>> {
+    let (file_content, scene) = load_scene(config)?;
+    let raw: serde_json::Value = serde_json::from_str(&variables::substitute(&file_content, &config.variables)?.text)?;
+    Ok(lint::lint(&raw, scene))
+}
+
+pub fn lint(config: Config, json: bool) -> Result<(), Box<dyn error::Error>> {
+    let findings = lint_scene(&config)?;
+    lint::print(&findings, json)?;
+    if !json {
+        println!("{}: {} finding(s)", config.scene_path, findings.len());
+    }
+    Ok(())
+}
+
+pub fn validate(config: Config, strict: bool) -> Result<(), Box<dyn error::Error>> {
+    let (_, mut scene) = load_scene(&config)?;
+    scene.output.validate(&config.output_path)?;
+    scene.prepare()?;
+    if strict {
+        let findings = lint_scene(&config)?;
+        for finding in findings.iter() {
+            eprintln!("warning: {}", finding);
+        }
+        if !findings.is_empty() {
+            return Err(format!("{}: {} lint warning(s)", config.scene_path, findings.len()).into());
+        }
+    }
+    println!("{} is valid", config.scene_path);
+    if let Some(bounds) = scene.bounding_box() {
+        println!("Scene bounds: ({:.3}, {:.3}, {:.3}) to ({:.3}, {:.3}, {:.3})", bounds.min.x, bounds.min.y, bounds.min.z, bounds.max.x, bounds.max.y, bounds.max.z);
     }
     Ok(())
 }
+
+pub fn merge(inputs: &[String], output_path: &str, state_path: Option<&str>) -> Result<(), Box<dyn error::Error>> {
+    merge::merge(inputs, output_path, state_path)
+}
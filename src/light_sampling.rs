@@ -0,0 +1,218 @@
+use serde::{Serialize, Deserialize};
+use crate::rendering::Light;
+use crate::traits::{LightEmitter, SampleSource};
+use crate::shape::Point;
+use crate::aabb::Aabb;
+
+#[allow(non_camel_case_types, clippy::upper_case_acronyms)]
+#[derive(Copy, Clone, Debug, Default, Serialize, Deserialize)]
+pub enum LightSampling {
+    #[default]
+    ALL,
+    UNIFORM_ONE,
+    POWER_WEIGHTED
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct LightSubset {
+    pub threshold: usize,
+    pub size: usize
+}
+
+impl LightSubset {
+    pub fn new(threshold: usize, size: usize) -> LightSubset {
+        LightSubset { threshold, size }
+    }
+}
+
+fn color_weight(light: &Light) -> f64 {
+    let color = light.get_color();
+    ((color.r as f64) + (color.g as f64) + (color.b as f64)) / (3.0 * 255.0)
+}
+
+// Directional lights give an irradiance, the power they send is what falls on the disk the scene shows them,
+// π·r² for the bounding sphere of the scene. Scenes without bounded elements use a unit sphere
+pub fn light_powers(lights: &[Light], bounds: Option<Aabb>) -> Vec<f64> {
+    let radius = bounds.map_or(1.0, |bounds| (bounds.max - bounds.min).length() / 2.0).max(f64::EPSILON);
+    lights.iter().map(|light| {
+        let power = match light {
+            Light::DIRECTIONAL(_) => std::f64::consts::PI * radius * radius * light.get_power(),
+            _ => light.get_power()
+        };
+        power * color_weight(light)
+    }).collect()
+}
+
+fn select_subset<'a>(lights: &'a [Light], point: Point, size: usize, rng: &mut dyn SampleSource) -> Vec<(&'a Light, f64)> {
+    let contributions: Vec<f64> = lights.iter().map(|light| light.get_brightness(point) * color_weight(light)).collect();
+    let total: f64 = contributions.iter().sum();
+    if total <= 0.0 {
+        return Vec::new();
+    }
+    let mut picks = vec![0u32; lights.len()];
+    let mut index = 0;
+    let mut accumulated = contributions[0];
+    for stratum in 0..size {
+        let target = ((stratum as f64) + rng.next_f64()) / (size as f64) * total;
+        while target >= accumulated && index + 1 < lights.len() {
+            index += 1;
+            accumulated += contributions[index];
+        }
+        picks[index] += 1;
+    }
+    lights.iter().zip(contributions.iter()).zip(picks.iter())
+        .filter(|((_, &contribution), &count)| count > 0 && contribution > 0.0)
+        .map(|((light, &contribution), &count)| (light, (count as f64) * total / (contribution * size as f64)))
+        .collect()
+}
+
+impl LightSampling {
+    // The powers come from light_powers, they are computed here for scenes that were not prepared
+    pub fn select<'a>(&self, lights: &'a [Light], powers: &[f64], point: Point, subset: &Option<LightSubset>, rng: &mut dyn SampleSource) -> Vec<(&'a Light, f64)> {
+        if lights.is_empty() {
+            return Vec::new();
+        }
+        match self {
+            LightSampling::ALL => match subset {
+                Some(subset) if lights.len() > subset.threshold && subset.size > 0 => select_subset(lights, point, subset.size, rng),
+                _ => lights.iter().map(|light| (light, 1.0)).collect()
+            },
+            LightSampling::UNIFORM_ONE => {
+                let index = ((rng.next_f64() * lights.len() as f64) as usize).min(lights.len() - 1);
+                vec![(&lights[index], lights.len() as f64)]
+            },
+            LightSampling::POWER_WEIGHTED => {
+                let computed;
+                let powers = if powers.len() == lights.len() {
+                    powers
+                } else {
+                    computed = light_powers(lights, None);
+                    &computed
+                };
+                let total_power: f64 = powers.iter().sum();
+                if total_power <= 0.0 {
+                    return LightSampling::UNIFORM_ONE.select(lights, powers, point, subset, rng);
+                }
+                let target = rng.next_f64() * total_power;
+                let mut accumulated = 0.0;
+                for (light, &power) in lights.iter().zip(powers) {
+                    accumulated += power;
+                    if power > 0.0 && target < accumulated {
+                        return vec![(light, total_power / power)];
+                    }
+                }
+                let (light, power) = lights.iter().zip(powers).rev().find(|(_, &power)| power > 0.0).unwrap();
+                vec![(light, total_power / power)]
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::random::Rng;
+    use crate::rendering::{self, Camera, Color, DirectionalLight, Material, PointLight, Renderable, Scene, View};
+    use crate::shape::{Plane, Shape, Sphere};
+    use crate::traits::Recorder;
+    use crate::vertors::Vector3;
+
+    #[test]
+    fn directional_power_grows_with_the_scene() {
+        let lights = [Light::DIRECTIONAL(DirectionalLight::new(Vector3::new(0.0, -1.0, 0.0), 2.0, Color::white()))];
+        let small = light_powers(&lights, Some(Aabb::around(Vector3::zero(), Vector3::new(1.0, 1.0, 1.0))));
+        let large = light_powers(&lights, Some(Aabb::around(Vector3::zero(), Vector3::new(10.0, 10.0, 10.0))));
+        assert!((small[0] - std::f64::consts::PI * 3.0 * 2.0).abs() < 1e-9);
+        assert!((large[0] / small[0] - 100.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn point_power_is_its_brightness() {
+        let lights = [Light::POINT(PointLight::new(Vector3::zero(), 500.0, Color::white()))];
+        assert_eq!(light_powers(&lights, Some(Aabb::around(Vector3::zero(), Vector3::new(100.0, 100.0, 100.0)))), vec![500.0]);
+    }
+
+    // A sun of irradiance 1 over a scene of radius 10 sends about 314, a lamp of 100 should be picked about 1 in 4.14
+    #[test]
+    fn power_weighted_picks_directional_lights_by_what_they_send() {
+        let lights = [
+            Light::DIRECTIONAL(DirectionalLight::new(Vector3::new(0.0, -1.0, 0.0), 1.0, Color::white())),
+            Light::POINT(PointLight::new(Vector3::zero(), 100.0, Color::white()))
+        ];
+        let powers = light_powers(&lights, Some(Aabb::around(Vector3::zero(), Vector3::new(10.0, 0.0, 0.0))));
+        let mut rng = Rng::new(7);
+        let (mut point_picks, mut estimate) = (0, [0.0, 0.0]);
+        let draws = 20000;
+        for _ in 0..draws {
+            for (light, weight) in LightSampling::POWER_WEIGHTED.select(&lights, &powers, Vector3::zero(), &None, &mut rng) {
+                let index = if std::ptr::eq(light, &lights[0]) { 0 } else { point_picks += 1; 1 };
+                estimate[index] += weight / draws as f64;
+            }
+        }
+        let expected = 100.0 / (100.0 + 100.0 * std::f64::consts::PI);
+        assert!((point_picks as f64 / draws as f64 - expected).abs() < 0.01);
+        // Every light is counted once on average, whatever its share of the picks
+        assert!((estimate[0] - 1.0).abs() < 0.05 && (estimate[1] - 1.0).abs() < 0.05, "{:?}", estimate);
+    }
+
+    // Point lights trace a single shadow ray each, every light shaded is one of them
+    #[derive(Default)]
+    struct ShadowRays(usize);
+
+    impl Recorder for ShadowRays {
+        fn is_recording(&self) -> bool {
+            true
+        }
+        fn light(&mut self, _light: usize, _direction: Vector3, _brightness: f64, _blocked_at: Option<f64>, _contribution: [f64; 3]) {
+            self.0 += 1;
+        }
+    }
+
+    // A ball on a floor inside a ring of 100 lamps
+    fn ring_scene(subset: Option<LightSubset>) -> Scene {
+        let camera = Camera::new(24, 18, 60.0).looking_at(Vector3::new(0.0, 2.0, 6.0), Vector3::zero(), Vector3::new(0.0, 1.0, 0.0));
+        let floor = Renderable::new(Shape::PLANE(Plane::new(Vector3::new(0.0, -1.0, 0.0), Vector3::new(0.0, 1.0, 0.0))), Material::new(Color::white(), 0.8, 0.0));
+        let ball = Renderable::new(Shape::SPHERE(Sphere::new(Vector3::zero(), 1.0)), Material::new(Color::new(200, 120, 80, 255), 0.8, 0.0));
+        let lamps = (0..100).map(|index| {
+            let angle = index as f64 / 100.0 * 2.0 * std::f64::consts::PI;
+            Light::POINT(PointLight::new(Vector3::new(3.0 * angle.cos(), 1.5, 3.0 * angle.sin()), 20.0, Color::white()))
+        }).collect();
+        let mut scene = Scene::new(camera, vec![floor, ball], lamps, Color::black());
+        scene.light_subset = subset;
+        scene.samples_per_pixel = 16;
+        scene.seed = 3;
+        scene.prepare().unwrap();
+        scene
+    }
+
+    fn render_counted(scene: &Scene) -> (Vec<f64>, usize) {
+        let view = View::camera(&scene.camera);
+        let mut shadow_rays = ShadowRays::default();
+        let mut image = Vec::new();
+        for y in 0..view.height {
+            for x in 0..view.width {
+                let mut sum = 0.0;
+                for index in 0..scene.samples_per_pixel {
+                    let color = rendering::sample_radiance(4, scene, &view, x, y, index, &mut shadow_rays);
+                    sum += color.r + color.g + color.b;
+                }
+                image.push(sum / scene.samples_per_pixel as f64);
+            }
+        }
+        (image, shadow_rays.0)
+    }
+
+    #[test]
+    fn a_subset_of_the_ring_matches_the_full_loop_with_fewer_shadow_rays() {
+        let (reference, all_rays) = render_counted(&ring_scene(None));
+        let (sampled, subset_rays) = render_counted(&ring_scene(Some(LightSubset::new(16, 8))));
+        let mean = reference.iter().sum::<f64>() / reference.len() as f64;
+        let error = reference.iter().zip(sampled.iter()).map(|(expected, found)| (expected - found).abs()).sum::<f64>() / reference.len() as f64;
+        assert!(mean > 0.1, "{}", mean);
+        assert!(error < mean * 0.05, "off by {} on a mean of {}", error, mean);
+        // The bias would show as a shift of the mean, the noise averages out over the image
+        let sampled_mean = sampled.iter().sum::<f64>() / sampled.len() as f64;
+        assert!((sampled_mean - mean).abs() < mean * 0.01, "{} against {}", sampled_mean, mean);
+        assert!(subset_rays * 10 < all_rays, "{} shadow rays against {}", subset_rays, all_rays);
+    }
+}
@@ -0,0 +1,321 @@
+use std::error;
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use crate::rendering::Scene;
+use crate::node::Node;
+use crate::shape::Shape;
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Limit {
+    SIZE,
+    ELEMENTS,
+    LIGHTS,
+    DEPTH,
+    PIXELS,
+    PHOTONS,
+    TRIANGLES,
+    TEXTURE,
+    CSG
+}
+
+const LIMITS: [Limit; 9] = [Limit::SIZE, Limit::ELEMENTS, Limit::LIGHTS, Limit::DEPTH, Limit::PIXELS, Limit::PHOTONS, Limit::TRIANGLES, Limit::TEXTURE, Limit::CSG];
+
+impl Limit {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Limit::SIZE => "size",
+            Limit::ELEMENTS => "elements",
+            Limit::LIGHTS => "lights",
+            Limit::DEPTH => "depth",
+            Limit::PIXELS => "pixels",
+            Limit::PHOTONS => "photons",
+            Limit::TRIANGLES => "triangles",
+            Limit::TEXTURE => "texture",
+            Limit::CSG => "csg"
+        }
+    }
+
+    fn description(&self) -> &'static str {
+        match self {
+            Limit::SIZE => "scene file size in bytes",
+            Limit::ELEMENTS => "number of elements",
+            Limit::LIGHTS => "number of lights",
+            Limit::DEPTH => "node nesting depth",
+            Limit::PIXELS => "number of pixels",
+            Limit::PHOTONS => "number of caustic photons",
+            Limit::TRIANGLES => "number of triangles of a mesh",
+            Limit::TEXTURE => "number of pixels of an image file",
+            Limit::CSG => "CSG nesting depth"
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Limit> {
+        LIMITS.iter().copied().find(|limit| limit.name() == name)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LimitError {
+    pub limit: Limit,
+    pub value: u64,
+    pub max: u64
+}
+
+impl LimitError {
+    pub fn new(limit: Limit, value: u64, max: u64) -> LimitError {
+        LimitError { limit, value, max }
+    }
+}
+
+impl fmt::Display for LimitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} exceeds the limit: {} > {} (raise it with --limit {}=N)", self.limit.description(), self.value, self.max, self.limit.name())
+    }
+}
+
+impl error::Error for LimitError {}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Limits {
+    pub size: u64,
+    pub elements: u64,
+    pub lights: u64,
+    pub depth: u64,
+    pub pixels: u64,
+    pub photons: u64,
+    pub triangles: u64,
+    pub texture: u64,
+    pub csg: u64
+}
+
+// CSG operations inside each other, through the transforms and groups between them
+fn csg_depth(shape: &Shape) -> u64 {
+    match shape {
+        Shape::CSG(c) => 1 + csg_depth(&c.left).max(csg_depth(&c.right)),
+        Shape::TRANSFORM(t) => csg_depth(&t.description.shape),
+        Shape::GROUP(g) => g.iter().map(csg_depth).max().unwrap_or(0),
+        _ => 0
+    }
+}
+
+impl Limits {
+
+    pub fn get(&self, limit: Limit) -> u64 {
+        match limit {
+            Limit::SIZE => self.size,
+            Limit::ELEMENTS => self.elements,
+            Limit::LIGHTS => self.lights,
+            Limit::DEPTH => self.depth,
+            Limit::PIXELS => self.pixels,
+            Limit::PHOTONS => self.photons,
+            Limit::TRIANGLES => self.triangles,
+            Limit::TEXTURE => self.texture,
+            Limit::CSG => self.csg
+        }
+    }
+
+    pub fn set(&mut self, name: &str, value: &str) -> Result<(), String> {
+        let limit = Limit::from_name(name).ok_or_else(|| {
+            let names: Vec<&str> = LIMITS.iter().map(|limit| limit.name()).collect();
+            format!("unknown limit \"{}\", expected one of {}", name, names.join(", "))
+        })?;
+        let value = value.parse().map_err(|_| format!("limit {} expect a number, found \"{}\"", name, value))?;
+        match limit {
+            Limit::SIZE => self.size = value,
+            Limit::ELEMENTS => self.elements = value,
+            Limit::LIGHTS => self.lights = value,
+            Limit::DEPTH => self.depth = value,
+            Limit::PIXELS => self.pixels = value,
+            Limit::PHOTONS => self.photons = value,
+            Limit::TRIANGLES => self.triangles = value,
+            Limit::TEXTURE => self.texture = value,
+            Limit::CSG => self.csg = value
+        }
+        Ok(())
+    }
+
+    pub fn check(&self, limit: Limit, value: u64) -> Result<(), LimitError> {
+        if value > self.get(limit) {
+            return Err(LimitError::new(limit, value, self.get(limit)));
+        }
+        Ok(())
+    }
+
+    pub fn read_scene(&self, path: &str) -> Result<String, Box<dyn error::Error>> {
+        let file = File::open(path)?;
+        self.check(Limit::SIZE, file.metadata()?.len())?;
+        let mut content = String::new();
+        let read = file.take(self.size.saturating_add(1)).read_to_string(&mut content)?;
+        self.check(Limit::SIZE, read as u64)?;
+        Ok(content)
+    }
+
+    // Only the header is read, before the pixels are decoded. Unreadable files are left to the loader to report
+    pub fn check_image(&self, file: &str) -> Result<(), Box<dyn error::Error>> {
+        if let Ok((width, height)) = image::image_dimensions(file) {
+            self.check(Limit::TEXTURE, width as u64 * height as u64).map_err(|e| format!("{}: {}", file, e))?;
+        }
+        Ok(())
+    }
+
+    fn check_nodes(&self, nodes: &[Node], depth: u64, elements: &mut u64, lights: &mut u64) -> Result<(), LimitError> {
+        if !nodes.is_empty() {
+            self.check(Limit::DEPTH, depth)?;
+        }
+        for node in nodes {
+            if let Some(renderable) = &node.renderable {
+                self.check(Limit::CSG, csg_depth(&renderable.shape))?;
+            }
+            *elements += node.renderable.is_some() as u64;
+            *lights += node.lights.len() as u64;
+            self.check_nodes(&node.children, depth + 1, elements, lights)?;
+        }
+        Ok(())
+    }
+
+    pub fn check_scene(&self, scene: &Scene) -> Result<(), LimitError> {
+        let mut elements = scene.elements.len() as u64;
+        let mut lights = scene.lights.len() as u64;
+        for shape in scene.elements.iter().map(|renderable| &renderable.shape).chain(scene.geometries.values()) {
+            self.check(Limit::CSG, csg_depth(shape))?;
+        }
+        self.check_nodes(&scene.nodes, 1, &mut elements, &mut lights)?;
+        self.check(Limit::ELEMENTS, elements)?;
+        self.check(Limit::LIGHTS, lights)?;
+        self.check(Limit::PIXELS, scene.camera.width as u64 * scene.camera.height as u64)?;
+        if scene.caustics.enabled {
+            self.check(Limit::PHOTONS, scene.caustics.photon_count as u64)?;
+        }
+        Ok(())
+    }
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            size: 64 * 1024 * 1024,
+            elements: 1_000_000,
+            lights: 100_000,
+            depth: 32,
+            pixels: 16384 * 16384,
+            photons: 50_000_000,
+            triangles: 50_000_000,
+            texture: 16384 * 16384,
+            csg: 64
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use serde_json::json;
+    use crate::rendering::{Camera, Color, Material, Renderable};
+    use crate::shape::{Csg, CsgOperation, Sphere};
+    use crate::transform::Transform;
+    use crate::vertors::Vector3;
+
+    fn ball() -> Shape {
+        Shape::SPHERE(Sphere::new(Vector3::new(0.0, 0.0, -5.0), 1.0))
+    }
+
+    fn scene(shapes: Vec<Shape>) -> Scene {
+        let elements = shapes.into_iter().map(|shape| Renderable::new(shape, Material::new(Color::white(), 0.5, 0.0))).collect();
+        Scene::new(Camera::new(64, 48, 60.0), elements, Vec::new(), Color::black())
+    }
+
+    fn limits(name: &str, value: u64) -> Limits {
+        let mut limits = Limits::default();
+        limits.set(name, &value.to_string()).unwrap();
+        limits
+    }
+
+    fn exceeded(result: Result<(), LimitError>) -> Option<(Limit, u64)> {
+        result.err().map(|e| (e.limit, e.value))
+    }
+
+    #[test]
+    fn limits_are_set_by_name() {
+        let mut limits = Limits::default();
+        for (index, limit) in LIMITS.iter().enumerate() {
+            limits.set(limit.name(), &index.to_string()).unwrap();
+            assert_eq!(limits.get(*limit), index as u64);
+            assert_eq!(Limit::from_name(limit.name()), Some(*limit));
+        }
+        assert!(limits.set("faces", "3").unwrap_err().contains("triangles, texture, csg"));
+        assert!(limits.set("csg", "deep").unwrap_err().contains("expect a number"));
+    }
+
+    #[test]
+    fn counts_just_over_the_limit_are_refused() {
+        let three = scene(vec![ball(), ball(), ball()]);
+        assert!(limits("elements", 3).check_scene(&three).is_ok());
+        assert_eq!(exceeded(limits("elements", 2).check_scene(&three)), Some((Limit::ELEMENTS, 3)));
+        assert!(limits("pixels", 64 * 48).check_scene(&three).is_ok());
+        assert_eq!(exceeded(limits("pixels", 64 * 48 - 1).check_scene(&three)), Some((Limit::PIXELS, 64 * 48)));
+        let error = limits("elements", 2).check_scene(&three).unwrap_err();
+        assert_eq!(error.to_string(), "number of elements exceeds the limit: 3 > 2 (raise it with --limit elements=N)");
+    }
+
+    #[test]
+    fn nodes_count_their_depth_and_their_elements() {
+        let leaf = Node::new(Transform::default(), Vec::new(), Some(Renderable::new(ball(), Material::new(Color::white(), 0.5, 0.0))), Vec::new());
+        let mut nested = scene(vec![ball()]);
+        nested.nodes = vec![Node::new(Transform::default(), vec![Node::new(Transform::default(), vec![leaf], None, Vec::new())], None, Vec::new())];
+        assert!(limits("depth", 3).check_scene(&nested).is_ok());
+        assert_eq!(exceeded(limits("depth", 2).check_scene(&nested)), Some((Limit::DEPTH, 3)));
+        assert_eq!(exceeded(limits("elements", 1).check_scene(&nested)), Some((Limit::ELEMENTS, 2)));
+    }
+
+    #[test]
+    fn csg_nesting_is_counted_through_groups() {
+        let mut shape = ball();
+        for _ in 0..3 {
+            shape = Shape::GROUP(vec![ball(), Shape::CSG(Csg::new(CsgOperation::UNION, shape, ball()))]);
+        }
+        let deep = scene(vec![ball(), shape]);
+        assert!(limits("csg", 3).check_scene(&deep).is_ok());
+        assert_eq!(exceeded(limits("csg", 2).check_scene(&deep)), Some((Limit::CSG, 3)));
+        let mut shared = scene(Vec::new());
+        shared.geometries.insert("deep".to_string(), deep.elements[1].shape.clone());
+        assert_eq!(exceeded(limits("csg", 2).check_scene(&shared)), Some((Limit::CSG, 3)));
+    }
+
+    #[test]
+    fn scene_files_just_over_the_size_are_refused() {
+        let path = env::temp_dir().join(format!("rust_raytracer_{}_limits.json", std::process::id())).to_string_lossy().to_string();
+        fs::write(&path, "{\"elements\": []}").unwrap();
+        assert_eq!(limits("size", 16).read_scene(&path).unwrap(), "{\"elements\": []}");
+        let error = limits("size", 15).read_scene(&path).unwrap_err();
+        fs::remove_file(&path).unwrap();
+        assert!(error.to_string().contains("16 > 15"));
+    }
+
+    #[test]
+    fn images_are_checked_from_their_header() {
+        assert!(limits("texture", 512 * 256).check_image("test_scene/textures/planet.png").is_ok());
+        assert!(limits("texture", 512 * 256 - 1).check_image("test_scene/textures/planet.png").unwrap_err().to_string().contains("planet.png: number of pixels"));
+        assert!(limits("texture", 1).check_image("test_scene/textures/sky.hdr").is_err());
+        assert!(limits("texture", 1).check_image("test_scene/textures/missing.png").is_ok());
+        let mut textured = scene(vec![ball()]);
+        textured.environment = Some(serde_json::from_value(json!({"IMAGE": {"file": "test_scene/textures/planet.png"}})).unwrap());
+        textured.limits = limits("texture", 1000);
+        assert!(textured.prepare().unwrap_err().to_string().contains("planet.png"));
+    }
+
+    #[test]
+    fn meshes_just_over_the_triangles_are_refused() {
+        let cube: Shape = serde_json::from_value(json!({"MESH": {"file": "test_scene/models/cube.obj"}})).unwrap();
+        let mut meshes = scene(vec![cube]);
+        meshes.limits = limits("triangles", 12);
+        assert!(meshes.prepare().is_ok());
+        let cube: Shape = serde_json::from_value(json!({"MESH": {"file": "test_scene/models/cube.obj"}})).unwrap();
+        let mut meshes = scene(vec![cube]);
+        meshes.limits = limits("triangles", 11);
+        assert!(meshes.prepare().unwrap_err().to_string().contains("cube.obj: number of triangles of a mesh exceeds the limit: 12 > 11"));
+    }
+}
@@ -0,0 +1,401 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use serde::Serialize;
+use serde_json::Value;
+use crate::rendering::{Scene, Renderable, Light, Projection};
+use crate::aabb::Aabb;
+use crate::shape::{Ray, RayType, Hit, Shape, Sphere, Triangle};
+use crate::traits::{Intersectable, LightEmitter};
+use crate::transform::Matrix;
+use crate::vertors::Vector3;
+
+const SAMPLE_COLUMNS: u32 = 32;
+const SAMPLE_ROWS: u32 = 24;
+const NEGLIGIBLE_CONTRIBUTION: f64 = 0.5 / 255.0;
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Finding {
+    pub rule: &'static str,
+    pub path: String,
+    pub message: String,
+    pub suggestion: String
+}
+
+impl Finding {
+    pub fn new(rule: &'static str, path: String, message: String, suggestion: String) -> Finding {
+        Finding { rule, path, message, suggestion }
+    }
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {} [{}]\n    fix: {}", self.path, self.message, self.rule, self.suggestion)
+    }
+}
+
+fn flatten_with_paths(scene: &mut Scene) -> (Vec<String>, Vec<String>) {
+    let mut element_paths: Vec<String> = (0..scene.elements.len()).map(|index| format!("elements[{}]", index)).collect();
+    let mut light_paths: Vec<String> = (0..scene.lights.len()).map(|index| format!("lights[{}]", index)).collect();
+    scene.flat_elements = scene.elements.clone();
+    scene.flat_lights = scene.lights.clone();
+    for (index, node) in scene.nodes.iter().enumerate() {
+        let mut elements = Vec::new();
+        let mut lights = Vec::new();
+        node.flatten(&Matrix::identity(), &mut elements, &mut lights);
+        element_paths.extend((0..elements.len()).map(|element| format!("nodes[{}] (renderable {})", index, element)));
+        light_paths.extend((0..lights.len()).map(|light| format!("nodes[{}] (light {})", index, light)));
+        scene.flat_elements.extend(elements);
+        scene.flat_lights.extend(lights);
+    }
+    (element_paths, light_paths)
+}
+
+fn sample_rays(scene: &Scene) -> Vec<Ray> {
+    let mut rays = Vec::new();
+    for row in 0..SAMPLE_ROWS {
+        for column in 0..SAMPLE_COLUMNS {
+            let x = (column * 2 + 1) * scene.camera.width / (SAMPLE_COLUMNS * 2);
+            let y = (row * 2 + 1) * scene.camera.height / (SAMPLE_ROWS * 2);
+            rays.push(scene.camera.compute_prime_ray(x, y));
+        }
+    }
+    rays
+}
+
+fn light_contribution(scene: &Scene, light: &Light, renderable: &Renderable, hit: &Hit) -> f64 {
+    let light_direction = light.get_direction(hit.point);
+    let facing = hit.normal.dot(&light_direction);
+    if facing <= 0.0 || !renderable.is_lit_by(light) {
+        return 0.0;
+    }
+    let light_ray = Ray::new(hit.shadow_origin(), light_direction).with_range(0.0, light.get_distance(hit.point));
+    if light.casts_shadows() && scene.trace(&light_ray, RayType::SHADOW).is_some() {
+        return 0.0;
+    }
+    let color = light.get_color();
+    let base_color = renderable.material.base_color.color_at(hit);
+    let albedo = renderable.material.albedo.0;
+    let channels = [(color.r, base_color.r, albedo[0]), (color.g, base_color.g, albedo[1]), (color.b, base_color.b, albedo[2])];
+    channels.iter()
+        .map(|&(light_channel, base_channel, albedo)| (light_channel as f64 / 255.0) * light.get_brightness(hit.point) * facing * (albedo / std::f64::consts::PI) * (base_channel as f64 / 255.0) * scene.camera.exposure_scale())
+        .fold(0.0, f64::max)
+}
+
+fn lint_lights(scene: &Scene, light_paths: &[String], samples: &[(&Renderable, Hit)], findings: &mut Vec<Finding>) {
+    if samples.is_empty() {
+        return;
+    }
+    for (light, path) in scene.flat_lights.iter().zip(light_paths.iter()) {
+        let lit = samples.iter().any(|(renderable, hit)| light_contribution(scene, light, renderable, hit) >= NEGLIGIBLE_CONTRIBUTION);
+        if !lit {
+            findings.push(Finding::new(
+                "negligible-light",
+                path.clone(),
+                format!("contributes less than one color step at all {} surface points sampled from the camera", samples.len()),
+                "move the light out of the geometry occluding it, raise its brightness or remove it".to_string()
+            ));
+        }
+    }
+}
+
+struct Frustum {
+    origin: Vector3,
+    forward: Vector3,
+    planes: Vec<Vector3>
+}
+
+fn frustum(scene: &Scene) -> Frustum {
+    let camera = &scene.camera;
+    let corners = [
+        camera.compute_prime_ray(0, 0).direction,
+        camera.compute_prime_ray(camera.width - 1, 0).direction,
+        camera.compute_prime_ray(camera.width - 1, camera.height - 1).direction,
+        camera.compute_prime_ray(0, camera.height - 1).direction
+    ];
+    let center = camera.compute_prime_ray(camera.width / 2, camera.height / 2);
+    let mut planes = Vec::new();
+    for index in 0..corners.len() {
+        let mut normal = corners[index].cross(&corners[(index + 1) % corners.len()]).normalize();
+        if normal.dot(&center.direction) < 0.0 {
+            normal = -normal;
+        }
+        planes.push(normal);
+    }
+    Frustum { origin: center.origin, forward: center.direction, planes }
+}
+
+fn sphere_outside_frustum(frustum: &Frustum, sphere: &Sphere) -> Option<&'static str> {
+    let to_center = sphere.origin - frustum.origin;
+    if to_center.dot(&frustum.forward) < -sphere.radius {
+        return Some("is entirely behind the camera");
+    }
+    if frustum.planes.iter().any(|normal| to_center.dot(normal) < -sphere.radius) {
+        return Some("is entirely outside the camera field of view");
+    }
+    None
+}
+
+fn triangle_outside_frustum(frustum: &Frustum, triangle: &Triangle) -> Option<&'static str> {
+    let vertices = [triangle.a - frustum.origin, triangle.b - frustum.origin, triangle.c - frustum.origin];
+    if vertices.iter().all(|vertex| vertex.dot(&frustum.forward) < 0.0) {
+        return Some("is entirely behind the camera");
+    }
+    if frustum.planes.iter().any(|normal| vertices.iter().all(|vertex| vertex.dot(normal) < 0.0)) {
+        return Some("is entirely outside the camera field of view");
+    }
+    None
+}
+
+fn aabb_outside_frustum(frustum: &Frustum, aabb: &Aabb) -> Option<&'static str> {
+    let corners: Vec<Vector3> = aabb.corners().iter().map(|corner| *corner - frustum.origin).collect();
+    if corners.iter().all(|corner| corner.dot(&frustum.forward) < 0.0) {
+        return Some("is entirely behind the camera");
+    }
+    if frustum.planes.iter().any(|normal| corners.iter().all(|corner| corner.dot(normal) < 0.0)) {
+        return Some("is entirely outside the camera field of view");
+    }
+    None
+}
+
+fn lint_frustum(scene: &Scene, element_paths: &[String], rays: &[Ray], findings: &mut Vec<Finding>) {
+    let frustum = frustum(scene);
+    for (renderable, path) in scene.flat_elements.iter().zip(element_paths.iter()) {
+        if !renderable.visible_to_camera {
+            continue;
+        }
+        let outside = match &renderable.shape {
+            _ if renderable.motion.is_some() => renderable.bounding_box().and_then(|aabb| aabb_outside_frustum(&frustum, &aabb)),
+            Shape::SPHERE(sphere) => sphere_outside_frustum(&frustum, sphere),
+            Shape::PLANE(_) if rays.iter().all(|ray| renderable.shape.intersect(ray).is_none()) => Some("faces away from the camera or is outside its field of view"),
+            Shape::PLANE(_) | Shape::INFINITE_CYLINDER(_) => None,
+            Shape::TRIANGLE(triangle) => triangle_outside_frustum(&frustum, triangle),
+            Shape::CYLINDER(cylinder) => {
+                let axis = cylinder.axis.normalize() * cylinder.height;
+                let bounds = Sphere::new(cylinder.base + axis * 0.5, (cylinder.radius * cylinder.radius + axis.length_sq() * 0.25).sqrt());
+                sphere_outside_frustum(&frustum, &bounds)
+            },
+            Shape::CONE(cone) => {
+                let axis = cone.axis.normalize() * cone.height;
+                let radius = cone.height * cone.half_angle.to_radians().tan();
+                let bounds = Sphere::new(cone.apex + axis * 0.5, (radius * radius + axis.length_sq() * 0.25).sqrt());
+                sphere_outside_frustum(&frustum, &bounds)
+            },
+            Shape::TORUS(torus) => sphere_outside_frustum(&frustum, &Sphere::new(torus.center, torus.major_radius + torus.minor_radius)),
+            Shape::DISK(disk) => sphere_outside_frustum(&frustum, &Sphere::new(disk.center, disk.radius)),
+            Shape::QUAD(quad) => {
+                let far_corner = quad.corner + quad.u + quad.v;
+                let first = triangle_outside_frustum(&frustum, &Triangle::new(quad.corner, quad.corner + quad.u, far_corner));
+                let second = triangle_outside_frustum(&frustum, &Triangle::new(quad.corner, far_corner, quad.corner + quad.v));
+                if first.is_some() && second.is_some() { first } else { None }
+            },
+            Shape::ORIENTED_BOX(oriented) => sphere_outside_frustum(&frustum, &Sphere::new(oriented.center, oriented.half_size.length())),
+            Shape::MESH(_) | Shape::HEIGHTFIELD(_) => None, // The file is only read when the scene is prepared
+            Shape::ELLIPSOID(ellipsoid) => sphere_outside_frustum(&frustum, &Sphere::new(ellipsoid.center, ellipsoid.radii.x.max(ellipsoid.radii.y).max(ellipsoid.radii.z))),
+            Shape::CAPSULE(capsule) => {
+                let bounds = Sphere::new((capsule.a + capsule.b) * 0.5, (capsule.b - capsule.a).length() * 0.5 + capsule.radius);
+                sphere_outside_frustum(&frustum, &bounds)
+            },
+            Shape::INSTANCE(_) => None, // The geometry is only shared when the scene is prepared
+            Shape::POLYGON(_) | Shape::CONVEX_POLYHEDRON(_) | Shape::SDF(_) | Shape::METABALLS(_) | Shape::CSG(_) | Shape::TRANSFORM(_) | Shape::GROUP(_) | Shape::CUSTOM(_) => renderable.shape.bounding_box().and_then(|aabb| aabb_outside_frustum(&frustum, &aabb))
+        };
+        if let Some(reason) = outside {
+            let suggestion = if renderable.visible_in_reflections || renderable.casts_shadows {
+                "move it in front of the camera, or set visible_to_camera to false if it is only meant for reflections and shadows"
+            } else {
+                "move it in front of the camera or remove it"
+            };
+            findings.push(Finding::new("outside-frustum", path.clone(), reason.to_string(), suggestion.to_string()));
+        }
+    }
+}
+
+fn lint_duplicates(scene: &Scene, element_paths: &[String], findings: &mut Vec<Finding>) {
+    let mut seen: HashMap<String, &String> = HashMap::new();
+    for (renderable, path) in scene.flat_elements.iter().zip(element_paths.iter()) {
+        let key = match serde_json::to_string(&renderable.shape) {
+            Ok(key) => key,
+            Err(_) => continue
+        };
+        match seen.get(&key) {
+            Some(first) => findings.push(Finding::new(
+                "duplicate-element",
+                path.clone(),
+                format!("has the same shape and position as {}", first),
+                "remove one of the two elements".to_string()
+            )),
+            None => {
+                seen.insert(key, path);
+            }
+        }
+    }
+}
+
+fn lint_templates(raw: &Value, findings: &mut Vec<Finding>) {
+    let templates = match raw.get("templates").and_then(Value::as_object) {
+        Some(templates) => templates,
+        None => return
+    };
+    let used: HashSet<&str> = raw.get("elements").and_then(Value::as_array).into_iter().flatten()
+        .filter_map(|element| element.get("template").and_then(Value::as_str))
+        .collect();
+    for name in templates.keys().filter(|name| !used.contains(name.as_str())) {
+        findings.push(Finding::new(
+            "unused-template",
+            format!("templates.{}", name),
+            "is never referenced by an element".to_string(),
+            "reference it from an element with \"template\" or remove it".to_string()
+        ));
+    }
+}
+
+fn lint_panorama(scene: &Scene, findings: &mut Vec<Finding>) {
+    if scene.camera.width != scene.camera.height * 2 {
+        findings.push(Finding::new(
+            "panorama-aspect",
+            "camera".to_string(),
+            format!("is {}x{}, equirectangular panoramas are twice as wide as high", scene.camera.width, scene.camera.height),
+            format!("set the size to {}x{}", scene.camera.height * 2, scene.camera.height)
+        ));
+    }
+}
+
+fn is_empty_node(node: &Value) -> bool {
+    let renderable = node.get("renderable").is_some_and(|renderable| !renderable.is_null());
+    let lights = node.get("lights").and_then(Value::as_array).is_some_and(|lights| !lights.is_empty());
+    let children = node.get("children").and_then(Value::as_array);
+    !renderable && !lights && children.is_none_or(|children| children.iter().all(is_empty_node))
+}
+
+fn lint_nodes(nodes: &Value, path: &str, findings: &mut Vec<Finding>) {
+    for (index, node) in nodes.as_array().into_iter().flatten().enumerate() {
+        let node_path = format!("{}[{}]", path, index);
+        if is_empty_node(node) {
+            findings.push(Finding::new(
+                "empty-node",
+                node_path,
+                "contains no renderable and no light, directly or through its children".to_string(),
+                "remove the node".to_string()
+            ));
+        } else if let Some(children) = node.get("children") {
+            lint_nodes(children, &format!("{}.children", node_path), findings);
+        }
+    }
+}
+
+pub fn lint(raw: &Value, mut scene: Scene) -> Vec<Finding> {
+    let mut findings = Vec::new();
+    let (element_paths, light_paths) = flatten_with_paths(&mut scene);
+    let rays = sample_rays(&scene);
+    let samples: Vec<(&Renderable, Hit)> = rays.iter().filter_map(|ray| scene.trace(ray, RayType::PRIMARY)).collect();
+
+    lint_lights(&scene, &light_paths, &samples, &mut findings);
+    match scene.camera.projection {
+        Projection::PERSPECTIVE => lint_frustum(&scene, &element_paths, &rays, &mut findings),
+        Projection::PANORAMIC => lint_panorama(&scene, &mut findings) // Every direction is in view
+    }
+    lint_duplicates(&scene, &element_paths, &mut findings);
+    lint_templates(raw, &mut findings);
+    if let Some(nodes) = raw.get("nodes") {
+        lint_nodes(nodes, "nodes", &mut findings);
+    }
+    findings
+}
+
+pub fn print(findings: &[Finding], json: bool) -> Result<(), serde_json::Error> {
+    if json {
+        println!("{}", serde_json::to_string_pretty(findings)?);
+        return Ok(());
+    }
+    for finding in findings {
+        println!("{}", finding);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use serde_json::json;
+    use crate::rendering::{Camera, Color, DirectionalLight, Material, PointLight};
+
+    fn ball(x: f64, z: f64) -> Renderable {
+        Renderable::new(Shape::SPHERE(Sphere::new(Vector3::new(x, 0.0, z), 1.0)), Material::new(Color::white(), 0.5, 0.0))
+    }
+
+    fn scene(elements: Vec<Renderable>, lights: Vec<Light>) -> Scene {
+        Scene::new(Camera::new(64, 48, 60.0), elements, lights, Color::black())
+    }
+
+    fn sun() -> Light {
+        Light::DIRECTIONAL(DirectionalLight::new(Vector3::new(0.0, 0.0, -1.0), 5.0, Color::white()))
+    }
+
+    fn rules(findings: &[Finding]) -> Vec<(&'static str, &str)> {
+        findings.iter().map(|finding| (finding.rule, finding.path.as_str())).collect()
+    }
+
+    #[test]
+    fn a_lit_ball_in_view_has_no_findings() {
+        assert!(lint(&json!({}), scene(vec![ball(0.0, -5.0)], vec![sun()])).is_empty());
+    }
+
+    #[test]
+    fn lights_that_reach_nothing_in_view_are_reported() {
+        let behind = Light::DIRECTIONAL(DirectionalLight::new(Vector3::new(0.0, 0.0, 1.0), 5.0, Color::white()));
+        let dim = Light::POINT(PointLight::new(Vector3::new(0.0, 0.0, -2.0), 1e-6, Color::white()));
+        let findings = lint(&json!({}), scene(vec![ball(0.0, -5.0)], vec![sun(), behind, dim]));
+        assert_eq!(rules(&findings), vec![("negligible-light", "lights[1]"), ("negligible-light", "lights[2]")]);
+    }
+
+    #[test]
+    fn elements_out_of_view_are_reported() {
+        let mut hidden = ball(-3.0, 5.0);
+        hidden.visible_to_camera = false;
+        let findings = lint(&json!({}), scene(vec![ball(0.0, -5.0), ball(3.0, 5.0), ball(40.0, -5.0), hidden], vec![sun()]));
+        assert_eq!(rules(&findings), vec![("outside-frustum", "elements[1]"), ("outside-frustum", "elements[2]")]);
+        assert_eq!(findings[0].message, "is entirely behind the camera");
+        assert_eq!(findings[1].message, "is entirely outside the camera field of view");
+    }
+
+    #[test]
+    fn duplicated_elements_are_reported_once() {
+        let findings = lint(&json!({}), scene(vec![ball(0.0, -5.0), ball(0.0, -5.0), ball(1.0, -5.0)], vec![sun()]));
+        assert_eq!(rules(&findings), vec![("duplicate-element", "elements[1]")]);
+        assert!(findings[0].message.contains("elements[0]"));
+    }
+
+    #[test]
+    fn unused_templates_and_empty_nodes_are_reported() {
+        let raw = json!({
+            "templates": {"used": {}, "unused": {}},
+            "elements": [{"template": "used"}],
+            "nodes": [
+                {"children": [{"children": []}]},
+                {"children": [{"renderable": {}}, {"lights": []}]}
+            ]
+        });
+        let findings = lint(&raw, scene(vec![ball(0.0, -5.0)], vec![sun()]));
+        assert_eq!(rules(&findings), vec![("unused-template", "templates.unused"), ("empty-node", "nodes[0]"), ("empty-node", "nodes[1].children[1]")]);
+    }
+
+    #[test]
+    fn panoramas_must_be_twice_as_wide_as_high() {
+        let mut panorama = scene(vec![ball(0.0, -5.0)], vec![sun()]);
+        panorama.camera.projection = Projection::PANORAMIC;
+        let findings = lint(&json!({}), panorama);
+        assert_eq!(rules(&findings), vec![("panorama-aspect", "camera")]);
+        assert_eq!(findings[0].suggestion, "set the size to 96x48");
+    }
+
+    #[test]
+    fn the_example_scene_has_one_finding_of_every_kind() {
+        let content = include_str!("../test_scene/lint.json");
+        let scene = crate::parse_scene(content, &HashMap::new(), None).unwrap();
+        let findings = lint(&serde_json::from_str(content).unwrap(), scene);
+        let mut kinds: Vec<&str> = findings.iter().map(|finding| finding.rule).collect();
+        kinds.dedup();
+        assert_eq!(kinds, vec!["negligible-light", "outside-frustum", "duplicate-element", "unused-template", "empty-node"]);
+        assert_eq!(findings.len(), 7);
+    }
+}
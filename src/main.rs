@@ -1,6 +1,6 @@
 use std::process;
-use rust_raytracer;
-use clap::{App, Arg};
+use clap::{App, Arg, SubCommand};
+use rust_raytracer::{Channels, RenderingIntent, CubemapLayout, StereoLayout};
 
 fn main() {
     let matches = App::new("rust_raytracer")
@@ -10,7 +10,7 @@ fn main() {
         .arg(Arg::with_name("scene")
             .short("s")
             .long("scene")
-            .help("Sets the json scene file to use. Will assume scene.json by default")
+            .help("Sets the json scene file to use, or a glTF file (.gltf or .glb) to render directly. Will assume scene.json by default")
             .takes_value(true))
         .arg(Arg::with_name("output")
             .short("o")
@@ -22,6 +22,131 @@ fn main() {
             .long("pass")
             .help("Sets the number of passes to compute for a ray. Will assume 3 by default")
             .takes_value(true))
+        .arg(Arg::with_name("set")
+            .long("set")
+            .help("Sets a scene variable used by ${name} placeholders, as name=value. Can be repeated")
+            .takes_value(true)
+            .multiple(true))
+        .arg(Arg::with_name("profile")
+            .long("profile")
+            .help("Prints the time spent in each stage of the render"))
+        .arg(Arg::with_name("profile-json")
+            .long("profile-json")
+            .help("Writes the stage timings to the given json file (implies --profile)")
+            .takes_value(true))
+        .arg(Arg::with_name("verbose")
+            .short("v")
+            .long("verbose")
+            .help("Prints every loaded asset (meshes, textures, LUTs...), with its load time when profiling, and the photons stored for caustics"))
+        .arg(Arg::with_name("interactive")
+            .short("i")
+            .long("interactive")
+            .help("Keeps running after the first render and reads commands (set, render, save, quit) from the standard input"))
+        .arg(Arg::with_name("lut")
+            .long("lut")
+            .help("Applies a 3D LUT (.cube file) to the rendered image, after the scene post effects. Can be repeated")
+            .takes_value(true)
+            .multiple(true))
+        .arg(Arg::with_name("channels")
+            .long("channels")
+            .help("Sets the channel layout of the output file. Will assume RGBA (RGB for jpeg) by default")
+            .takes_value(true)
+            .possible_values(&["RGB", "RGBA"]))
+        .arg(Arg::with_name("bit-depth")
+            .long("bit-depth")
+            .help("Sets the bit depth of the output file (16 is png only). Will assume 8 by default")
+            .takes_value(true)
+            .possible_values(&["8", "16"]))
+        .arg(Arg::with_name("srgb-intent")
+            .long("srgb-intent")
+            .help("Embeds sRGB and gAMA chunks with the given rendering intent in png outputs")
+            .takes_value(true)
+            .possible_values(&["PERCEPTUAL", "RELATIVE", "SATURATION", "ABSOLUTE"]))
+        .arg(Arg::with_name("limit")
+            .long("limit")
+            .help("Sets a resource limit checked while loading the scene, as name=value: size (bytes), elements, lights, depth (node nesting), pixels, photons, triangles (of a mesh), texture (pixels of an image file) or csg (nesting depth). Can be repeated")
+            .takes_value(true)
+            .multiple(true))
+        .arg(Arg::with_name("max-memory")
+            .long("max-memory")
+            .help("Refuses to render when the estimated memory use is above the given size, e.g. 512M or 2G")
+            .takes_value(true))
+        .arg(Arg::with_name("texture-budget")
+            .long("texture-budget")
+            .help("Keeps the decoded image textures under the given size, e.g. 256M, by dropping the least recently sampled ones")
+            .takes_value(true))
+        .arg(Arg::with_name("debug-pixel")
+            .long("debug-pixel")
+            .help("Traces only the pixel X,Y and prints every ray, intersection, light and reflection involved instead of rendering")
+            .takes_value(true))
+        .arg(Arg::with_name("debug-json")
+            .long("debug-json")
+            .help("Prints the --debug-pixel tree as json"))
+        .arg(Arg::with_name("cubemap")
+            .long("cubemap")
+            .help("Renders the six 90 degree faces around the camera instead of the camera view, as six files suffixed _px, _nx, _py, _ny, _pz and _nz or as one horizontal cross image")
+            .takes_value(true)
+            .possible_values(&["files", "cross"]))
+        .arg(Arg::with_name("camera")
+            .long("camera")
+            .help("Selects a camera by name in the scene cameras. Will assume the one named default, or else the first by name")
+            .takes_value(true))
+        .arg(Arg::with_name("stereo")
+            .long("stereo")
+            .help("Renders the camera view for a left and a right eye, side by side in a double width image or as a red and cyan anaglyph. The eye separation and convergence come from the camera stereo settings")
+            .takes_value(true)
+            .possible_values(&["side-by-side", "anaglyph"]))
+        .arg(Arg::with_name("accumulate")
+            .long("accumulate")
+            .help("Adds this render's samples to the per-pixel sums stored in the given state file (created if missing) and writes the output from all the samples accumulated so far")
+            .takes_value(true))
+        .arg(Arg::with_name("frames")
+            .long("frames")
+            .help("Renders this many frames along the scene camera_path to numbered files (output_0001.png, ...). Will assume the whole path by default")
+            .takes_value(true))
+        .arg(Arg::with_name("fps")
+            .long("fps")
+            .help("Sets the frames per second of the camera_path animation. Will assume 24 by default")
+            .takes_value(true))
+        .subcommand(SubCommand::with_name("lint")
+            .about("Reports likely mistakes in a scene: negligible lights, elements out of the camera view, duplicates, unused templates and empty nodes")
+            .arg(Arg::with_name("scene")
+                .short("s")
+                .long("scene")
+                .help("Sets the json scene file to lint. Will assume scene.json by default")
+                .takes_value(true))
+            .arg(Arg::with_name("format")
+                .long("format")
+                .help("Sets the format of the findings. Will assume text by default")
+                .takes_value(true)
+                .possible_values(&["text", "json"])))
+        .subcommand(SubCommand::with_name("merge")
+            .about("Averages the accumulation states of renders of the same scene made with different seeds, weighted by their samples per pixel")
+            .arg(Arg::with_name("average")
+                .long("average")
+                .help("Sets the accumulation state files (written by --accumulate) to average")
+                .takes_value(true)
+                .multiple(true)
+                .required(true))
+            .arg(Arg::with_name("output")
+                .short("o")
+                .long("output")
+                .help("Sets the png file to write the merged render to. Will assume output.png by default")
+                .takes_value(true))
+            .arg(Arg::with_name("state")
+                .long("state")
+                .help("Also saves the merged accumulation state to this file, to keep accumulating into it")
+                .takes_value(true)))
+        .subcommand(SubCommand::with_name("validate")
+            .about("Checks that a scene loads and can be rendered to the output file without rendering it")
+            .arg(Arg::with_name("scene")
+                .short("s")
+                .long("scene")
+                .help("Sets the json scene file to validate. Will assume scene.json by default")
+                .takes_value(true))
+            .arg(Arg::with_name("strict")
+                .long("strict")
+                .help("Also runs the lint checks and fails on any finding")))
         .get_matches();
 
     let nb_pass = matches.value_of("pass").unwrap_or("3").parse().unwrap_or_else(|_| {
@@ -29,13 +154,125 @@ fn main() {
         process::exit(1);
     });
 
-    let config = rust_raytracer::Config::new(
+    let mut config = rust_raytracer::Config::new(
         matches.value_of("scene").unwrap_or("scene.json").to_string(),
         matches.value_of("output").unwrap_or("output.png").to_string(),
         nb_pass
     );
+    config.profile = matches.is_present("profile");
+    config.verbose = matches.is_present("verbose");
+    config.interactive = matches.is_present("interactive");
+    if let Some(files) = matches.values_of("lut") {
+        config.luts = files.iter().map(|file| file.to_string()).collect();
+    }
+    config.profile_json = matches.value_of("profile-json").map(|path| path.to_string());
+    config.channels = match matches.value_of("channels") {
+        Some("RGB") => Some(Channels::RGB),
+        Some("RGBA") => Some(Channels::RGBA),
+        _ => None
+    };
+    config.bit_depth = matches.value_of("bit-depth").map(|depth| depth.parse().unwrap_or(8));
+    config.srgb_intent = match matches.value_of("srgb-intent") {
+        Some("PERCEPTUAL") => Some(RenderingIntent::PERCEPTUAL),
+        Some("RELATIVE") => Some(RenderingIntent::RELATIVE),
+        Some("SATURATION") => Some(RenderingIntent::SATURATION),
+        Some("ABSOLUTE") => Some(RenderingIntent::ABSOLUTE),
+        _ => None
+    };
+    if let Some(values) = matches.values_of("set") {
+        for value in values {
+            let mut split = value.splitn(2, '=');
+            match (split.next(), split.next()) {
+                (Some(name), Some(value)) if !name.is_empty() => {
+                    config.variables.insert(name.to_string(), value.to_string());
+                },
+                _ => {
+                    eprintln!("set argument expect name=value");
+                    process::exit(1);
+                }
+            }
+        }
+    }
+    if let Some(pixel) = matches.value_of("debug-pixel") {
+        let mut split = pixel.splitn(2, ',');
+        config.debug_pixel = match (split.next().map(|x| x.trim().parse()), split.next().map(|y| y.trim().parse())) {
+            (Some(Ok(x)), Some(Ok(y))) => Some((x, y)),
+            _ => {
+                eprintln!("debug-pixel argument expect X,Y");
+                process::exit(1);
+            }
+        };
+    }
+    config.debug_json = matches.is_present("debug-json");
+    config.accumulate = matches.value_of("accumulate").map(|path| path.to_string());
+    config.cubemap = match matches.value_of("cubemap") {
+        Some("files") => Some(CubemapLayout::FILES),
+        Some("cross") => Some(CubemapLayout::CROSS),
+        _ => None
+    };
+    if let Some(frames) = matches.value_of("frames") {
+        config.frames = Some(frames.parse().unwrap_or_else(|_| {
+            eprintln!("frames argument expect a number");
+            process::exit(1);
+        }));
+    }
+    if let Some(fps) = matches.value_of("fps") {
+        config.fps = match fps.parse() {
+            Ok(fps) if fps > 0.0 => fps,
+            _ => {
+                eprintln!("fps argument expect a positive number");
+                process::exit(1);
+            }
+        };
+    }
+    config.camera = matches.value_of("camera").map(|name| name.to_string());
+    config.stereo = match matches.value_of("stereo") {
+        Some("side-by-side") => Some(StereoLayout::SIDE_BY_SIDE),
+        Some("anaglyph") => Some(StereoLayout::ANAGLYPH),
+        _ => None
+    };
+    if let Some(size) = matches.value_of("max-memory") {
+        config.max_memory = Some(rust_raytracer::parse_size(size).unwrap_or_else(|e| {
+            eprintln!("max-memory argument: {}", e);
+            process::exit(1);
+        }));
+    }
+    if let Some(size) = matches.value_of("texture-budget") {
+        config.texture_budget = Some(rust_raytracer::parse_size(size).unwrap_or_else(|e| {
+            eprintln!("texture-budget argument: {}", e);
+            process::exit(1);
+        }));
+    }
+    if let Some(values) = matches.values_of("limit") {
+        for value in values {
+            let mut split = value.splitn(2, '=');
+            let result = match (split.next(), split.next()) {
+                (Some(name), Some(value)) => config.limits.set(name, value),
+                _ => Err("limit argument expect name=value".to_string())
+            };
+            if let Err(e) = result {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+        }
+    }
 
-    if let Err(e) = rust_raytracer::run(config) {
+    let result = match matches.subcommand() {
+        ("lint", Some(sub_matches)) => {
+            config.scene_path = sub_matches.value_of("scene").unwrap_or(&config.scene_path).to_string();
+            rust_raytracer::lint(config, sub_matches.value_of("format") == Some("json"))
+        },
+        ("merge", Some(sub_matches)) => {
+            let inputs: Vec<String> = sub_matches.values_of("average").unwrap_or_default().iter().map(|path| path.to_string()).collect();
+            rust_raytracer::merge(&inputs, sub_matches.value_of("output").unwrap_or(&config.output_path), sub_matches.value_of("state"))
+        },
+        ("validate", Some(sub_matches)) => {
+            config.scene_path = sub_matches.value_of("scene").unwrap_or(&config.scene_path).to_string();
+            rust_raytracer::validate(config, sub_matches.is_present("strict"))
+        },
+        _ => rust_raytracer::run(config)
+    };
+    if let Err(e) = result {
         eprintln!("Application error: {}", e);
         process::exit(1);
     }
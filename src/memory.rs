@@ -0,0 +1,350 @@
+use std::error;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Read};
+use std::mem::size_of;
+use std::path::Path;
+use std::collections::BTreeSet;
+use crate::rendering::{Scene, Renderable, Light, Color, Material};
+use crate::shape::{Shape, Point};
+use crate::vertors::Vector3;
+use crate::photon::Photon;
+use crate::post::PostEffect;
+use crate::output::{Format, Channels};
+use crate::environment::Environment;
+use crate::node::Node;
+use crate::mesh::Mesh;
+use crate::bvh::Bvh;
+use crate::cubemap::CubemapLayout;
+use crate::stereo::StereoLayout;
+
+// Average size in a file of the triangles whose count is not in a header, with their share of the vertices
+const OBJ_BYTES_PER_TRIANGLE: u64 = 30;
+const GLTF_BYTES_PER_TRIANGLE: u64 = 26;
+const ASCII_STL_BYTES_PER_TRIANGLE: u64 = 200;
+
+#[derive(Clone, Debug, Default)]
+pub struct MemoryEstimate {
+    pub items: Vec<(&'static str, u64)>
+}
+
+fn image_pixels(file: &str) -> u64 {
+    image::image_dimensions(file).map_or(0, |(width, height)| width as u64 * height as u64)
+}
+
+fn header_lines(file: &str) -> impl Iterator<Item = String> {
+    File::open(file).into_iter().flat_map(|file| BufReader::new(file).lines().map_while(Result::ok))
+}
+
+// Triangles, vertices and whether the vertices have colors, from the header when the file has one and from its size
+// otherwise, with about half a vertex per triangle
+fn mesh_counts(file: &str) -> (u64, u64, bool) {
+    let size = fs::metadata(file).map_or(0, |metadata| metadata.len());
+    let extension = Path::new(file).extension().and_then(|extension| extension.to_str()).unwrap_or("").to_ascii_lowercase();
+    match extension.as_str() {
+        "stl" => {
+            let mut header = [0; 84];
+            let count = File::open(file).and_then(|mut file| file.read_exact(&mut header)).map(|_| u32::from_le_bytes([header[80], header[81], header[82], header[83]]) as u64);
+            let triangles = match count {
+                Ok(count) if 84 + count * 50 == size => count,
+                _ => size / ASCII_STL_BYTES_PER_TRIANGLE
+            };
+            (triangles, triangles / 2, false)
+        },
+        "ply" => {
+            let header: Vec<String> = header_lines(file).take_while(|line| line.trim() != "end_header").collect();
+            let count = |element: &str| header.iter().find_map(|line| line.trim().strip_prefix(element).and_then(|count| count.trim().parse::<u64>().ok())).unwrap_or(0);
+            let (faces, vertices) = (count("element face "), count("element vertex "));
+            // Faces may be quads, which a closed mesh has about as many as vertices
+            (faces.max((2 * vertices).saturating_sub(4)), vertices, header.iter().any(|line| line.starts_with("property ") && line.trim().ends_with(" red")))
+        },
+        "glb" | "gltf" => (size / GLTF_BYTES_PER_TRIANGLE, size / GLTF_BYTES_PER_TRIANGLE / 2, false),
+        _ => (size / OBJ_BYTES_PER_TRIANGLE, size / OBJ_BYTES_PER_TRIANGLE / 2, false)
+    }
+}
+
+// Indices of the triangles and the vertices, with their normals on smooth meshes and the hierarchy over the triangles
+fn mesh_bytes(mesh: &Mesh) -> u64 {
+    if !mesh.indices.is_empty() {
+        let normals = (mesh.face_normals.len() + mesh.vertex_normals.len()) * size_of::<Vector3>() + mesh.normal_indices.len() * size_of::<[usize; 3]>();
+        let textures = mesh.texture_coordinates.len() * size_of::<(f64, f64)>() + mesh.texture_indices.len() * size_of::<[usize; 3]>();
+        let materials = mesh.materials.len() * size_of::<Material>() + mesh.face_materials.len() * size_of::<Option<usize>>();
+        let bvh = mesh.bvh.as_ref().map_or(0, Bvh::bytes);
+        return (mesh.vertices.len() * size_of::<Point>() + mesh.indices.len() * size_of::<[usize; 3]>() + normals + textures + mesh.colors.len() * size_of::<Color>() + materials) as u64 + bvh;
+    }
+    let (triangles, vertices, colors) = mesh_counts(&mesh.file);
+    let vertex = size_of::<Point>() + if mesh.smooth { size_of::<Vector3>() } else { 0 } + if colors { size_of::<Color>() } else { 0 };
+    triangles * size_of::<[usize; 3]>() as u64 + vertices * vertex as u64 + Bvh::estimated_bytes(triangles)
+}
+
+fn lut_entries(file: &str) -> u64 {
+    header_lines(file)
+        .find_map(|line| line.trim().strip_prefix("LUT_3D_SIZE").and_then(|size| size.trim().parse::<u64>().ok()))
+        .map_or(0, |size| size * size * size)
+}
+
+fn node_contents<'a>(nodes: &'a [Node], renderables: &mut Vec<&'a Renderable>, lights: &mut u64) {
+    for node in nodes {
+        renderables.extend(node.renderable.as_ref());
+        *lights += node.lights.len() as u64;
+        node_contents(&node.children, renderables, lights);
+    }
+}
+
+impl MemoryEstimate {
+    // Made from the scene before it is prepared, files are only measured from their size or their header
+    pub fn of(scene: &Scene, output_path: &str, accumulate: bool, cubemap: Option<CubemapLayout>) -> MemoryEstimate {
+        let pixels = scene.camera.width as u64 * scene.camera.height as u64;
+        let face_pixels = scene.camera.height as u64 * scene.camera.height as u64;
+        // Pixels of the rendered views, of the image combining them and of each image written
+        let (views, combined, written) = match (cubemap, scene.camera.stereo.map(|stereo| stereo.layout)) {
+            (Some(CubemapLayout::FILES), _) => (6 * face_pixels, 0, face_pixels),
+            (Some(CubemapLayout::CROSS), _) => (6 * face_pixels, 12 * face_pixels, 12 * face_pixels),
+            (None, Some(StereoLayout::SIDE_BY_SIDE)) => (2 * pixels, 2 * pixels, 2 * pixels),
+            (None, Some(StereoLayout::ANAGLYPH)) => (2 * pixels, pixels, pixels),
+            (None, None) => (pixels, 0, pixels)
+        };
+        let format = Format::from_path(output_path);
+        let channels = match scene.output.channels_for(format) {
+            Channels::RGB => 3,
+            Channels::RGBA => 4
+        };
+        let mut output = if channels == 3 { written * 3 } else { 0 };
+        if format == Format::PNG {
+            // Widened to 16 bits, then narrowed again for 8 bit files
+            output += written * channels * 2 + if scene.output.bit_depth == 16 { written * channels * 2 } else { written * channels };
+        }
+        let accumulation = if accumulate { pixels * (size_of::<u32>() + size_of::<[f64; 4]>()) as u64 * 2 } else { 0 };
+        let photons = if scene.caustics.enabled { scene.caustics.photon_count as u64 * size_of::<Photon>() as u64 } else { 0 };
+
+        let mut renderables: Vec<&Renderable> = scene.elements.iter().collect();
+        let mut lights = scene.lights.len() as u64;
+        node_contents(&scene.nodes, &mut renderables, &mut lights);
+        // Instances share the geometries, which are counted once
+        let shapes: Vec<&Shape> = renderables.iter().map(|renderable| &renderable.shape).chain(scene.geometries.values()).collect();
+        let meshes = shapes.iter().flat_map(|shape| shape.meshes()).map(mesh_bytes).sum();
+        let heightfields = shapes.iter().flat_map(|shape| shape.heightfields())
+            .map(|heightfield| image_pixels(&heightfield.file) * size_of::<f64>() as u64)
+            .sum();
+        // Each image file is loaded once, for every material using it
+        let images: BTreeSet<&str> = renderables.iter()
+            .map(|renderable| renderable.material.name.as_ref().and_then(|name| scene.materials.get(name)).unwrap_or(&renderable.material))
+            .flat_map(|material| material.images())
+            .map(|image| image.file.as_str())
+            .collect();
+        let texture_sizes: Vec<u64> = images.iter().map(|file| image_pixels(file) * size_of::<[u8; 4]>() as u64).collect();
+        // The cache keeps them under its budget, or keeps the largest one when it is alone above it
+        let textures = match scene.textures.budget() {
+            Some(budget) => texture_sizes.iter().sum::<u64>().min(budget.max(texture_sizes.iter().copied().max().unwrap_or(0))),
+            None => texture_sizes.iter().sum()
+        };
+        let luts = scene.post.iter().map(|effect| match effect {
+            PostEffect::LUT { lut: Some(lut), .. } => (lut.table.len() * size_of::<[f64; 3]>()) as u64,
+            PostEffect::LUT { file, lut: None } => lut_entries(file) * size_of::<[f64; 3]>() as u64,
+            _ => 0
+        }).sum();
+        let environment = match &scene.environment {
+            Some(Environment::IMAGE(image)) => image_pixels(&image.file) * size_of::<[f32; 3]>() as u64,
+            _ => 0
+        };
+        MemoryEstimate {
+            items: vec![
+                ("framebuffer", (views + combined) * 4),
+                ("output buffer", output),
+                ("accumulation", accumulation),
+                ("elements", renderables.len() as u64 * size_of::<Renderable>() as u64),
+                ("meshes", meshes),
+                ("textures", textures),
+                ("heightfields", heightfields),
+                ("lights", lights * size_of::<Light>() as u64),
+                ("photon map", photons),
+                ("luts", luts),
+                ("environment map", environment)
+            ]
+        }
+    }
+
+    pub fn get(&self, name: &str) -> u64 {
+        self.items.iter().find(|(item, _)| *item == name).map_or(0, |(_, bytes)| *bytes)
+    }
+
+    pub fn total(&self) -> u64 {
+        self.items.iter().map(|(_, bytes)| bytes).sum()
+    }
+
+    pub fn check(&self, max: u64) -> Result<(), MemoryError> {
+        if self.total() > max {
+            return Err(MemoryError::new(self.clone(), max));
+        }
+        Ok(())
+    }
+}
+
+pub fn format_size(bytes: u64) -> String {
+    if bytes >= 1024 * 1024 {
+        format!("{:.1} MiB", bytes as f64 / (1024.0 * 1024.0))
+    } else if bytes >= 1024 {
+        format!("{:.1} KiB", bytes as f64 / 1024.0)
+    } else {
+        format!("{} B", bytes)
+    }
+}
+
+pub fn parse_size(text: &str) -> Result<u64, String> {
+    let text = text.trim();
+    let (number, unit) = match text.find(|c: char| c.is_ascii_alphabetic()) {
+        Some(index) => (&text[..index], &text[index..]),
+        None => (text, "")
+    };
+    let multiplier: u64 = match unit.to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" | "KIB" => 1024,
+        "M" | "MB" | "MIB" => 1024 * 1024,
+        "G" | "GB" | "GIB" => 1024 * 1024 * 1024,
+        _ => return Err(format!("unknown size unit \"{}\", expected K, M or G", unit))
+    };
+    let number: f64 = number.trim().parse().map_err(|_| format!("invalid size \"{}\"", text))?;
+    if number < 0.0 {
+        return Err(format!("invalid size \"{}\"", text));
+    }
+    Ok((number * multiplier as f64) as u64)
+}
+
+#[derive(Debug, Clone)]
+pub struct MemoryError {
+    pub estimate: MemoryEstimate,
+    pub max: u64
+}
+
+impl MemoryError {
+    pub fn new(estimate: MemoryEstimate, max: u64) -> MemoryError {
+        MemoryError { estimate, max }
+    }
+}
+
+impl fmt::Display for MemoryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "estimated memory {} exceeds --max-memory {}", format_size(self.estimate.total()), format_size(self.max))?;
+        let mut items = self.estimate.items.clone();
+        items.sort_by_key(|(_, bytes)| std::cmp::Reverse(*bytes));
+        for (name, bytes) in items.iter().filter(|(_, bytes)| *bytes > 0) {
+            write!(f, "\n    {:<14} {:>12}", name, format_size(*bytes))?;
+        }
+        Ok(())
+    }
+}
+
+impl error::Error for MemoryError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use serde_json::json;
+    use crate::assets::Asset;
+    use crate::rendering::Camera;
+    use crate::stereo::Stereo;
+
+    fn scene(file: &str) -> Scene {
+        crate::parse_scene(&fs::read_to_string(file).unwrap(), &HashMap::new(), None).unwrap()
+    }
+
+    fn estimate(scene: &Scene) -> MemoryEstimate {
+        MemoryEstimate::of(scene, "output.png", false, None)
+    }
+
+    #[test]
+    fn images_are_measured_before_they_are_loaded() {
+        let mut textured = scene("test_scene/normal_map.json");
+        let estimated = estimate(&textured);
+        textured.prepare().unwrap();
+        assert_eq!(textured.textures.stats().bytes, 0);
+        // Sampling every image once loads it in the cache
+        let hit = crate::shape::Hit::new(1.0, Vector3::zero(), Vector3::new(0.0, 0.0, 1.0));
+        for image in textured.flat_elements.iter().flat_map(|renderable| renderable.material.images()) {
+            image.color_at(&hit);
+        }
+        assert!(textured.textures.stats().bytes > 0);
+        assert_eq!(estimated.get("textures"), textured.textures.stats().bytes);
+
+        let mut budgeted = scene("test_scene/normal_map.json");
+        budgeted.textures = Arc::new(crate::texture_cache::TextureCache::new(Some(1000)));
+        let largest = budgeted.elements.iter().flat_map(|renderable| renderable.material.images()).map(|image| image_pixels(&image.file) * 4).max().unwrap();
+        assert_eq!(estimate(&budgeted).get("textures"), largest);
+
+        let mut environment = scene("test_scene/environment.json");
+        let estimated = estimate(&environment);
+        environment.prepare().unwrap();
+        match &environment.environment {
+            Some(Environment::IMAGE(image)) => assert_eq!(estimated.get("environment map"), (image.image.pixels.len() * size_of::<[f32; 3]>()) as u64),
+            _ => panic!("the scene has an environment image")
+        }
+        assert!(estimated.get("environment map") > 0);
+    }
+
+    #[test]
+    fn heightfields_are_measured_from_their_image() {
+        let mut terrain = scene("test_scene/heightfield.json");
+        let estimated = estimate(&terrain).get("heightfields");
+        terrain.prepare().unwrap();
+        let loaded: usize = terrain.flat_elements.iter().flat_map(|renderable| renderable.shape.heightfields()).map(|heightfield| heightfield.grid.heights.len() * size_of::<f64>()).sum();
+        assert!(loaded > 0);
+        assert_eq!(estimated, loaded as u64);
+    }
+
+    #[test]
+    fn mesh_estimates_are_close_to_the_loaded_meshes() {
+        // Without material libraries, whose tables are not estimated
+        for file in ["cube.obj", "pyramid.obj", "sphere.obj", "sphere_normals.obj", "icosahedron.stl", "star.stl", "sphere_colors.ply", "torus_colors.ply", "blocks.glb"] {
+            for smooth in [false, true] {
+                let mut mesh = Mesh::new(format!("test_scene/models/{}", file), 1.0, Vector3::zero());
+                mesh.smooth = smooth;
+                let estimated = mesh_bytes(&mesh) as f64;
+                mesh.load().unwrap();
+                let loaded = mesh_bytes(&mesh) as f64;
+                assert!(estimated > loaded * 0.5 && estimated < loaded * 2.0, "{} smooth {}: {} estimated for {}", file, smooth, estimated, loaded);
+            }
+        }
+        assert_eq!(mesh_counts("test_scene/models/torus_colors.ply"), (1532, 768, true));
+        assert_eq!(mesh_counts("test_scene/models/missing.obj"), (0, 0, false));
+    }
+
+    #[test]
+    fn framebuffers_follow_the_output_layout() {
+        let mut view = Scene::new(Camera::new(40, 30, 60.0), Vec::new(), Vec::new(), Color::black());
+        view.output.channels = Some(Channels::RGBA);
+        let single = estimate(&view).get("framebuffer");
+        assert_eq!(single, 40 * 30 * 4);
+        assert_eq!(MemoryEstimate::of(&view, "output.png", true, None).get("accumulation"), 40 * 30 * 36 * 2);
+        assert_eq!(MemoryEstimate::of(&view, "output.png", false, Some(CubemapLayout::FILES)).get("framebuffer"), 6 * 30 * 30 * 4);
+        assert_eq!(MemoryEstimate::of(&view, "output.png", false, Some(CubemapLayout::CROSS)).get("framebuffer"), 18 * 30 * 30 * 4);
+        view.camera.stereo = Some(Stereo { layout: StereoLayout::SIDE_BY_SIDE, ..Stereo::default() });
+        assert_eq!(estimate(&view).get("framebuffer"), 4 * single);
+        assert_eq!(estimate(&view).get("output buffer"), 2 * 40 * 30 * 4 * 3);
+        view.camera.stereo = Some(Stereo { layout: StereoLayout::ANAGLYPH, ..Stereo::default() });
+        assert_eq!(estimate(&view).get("framebuffer"), 3 * single);
+    }
+
+    #[test]
+    fn scenes_just_over_the_maximum_are_refused() {
+        let mut view = Scene::new(Camera::new(40, 30, 60.0), Vec::new(), Vec::new(), Color::black());
+        view.post.push(serde_json::from_value(json!({"LUT": {"file": "missing.cube"}})).unwrap());
+        let estimated = estimate(&view);
+        assert_eq!(estimated.get("luts"), 0);
+        assert!(estimated.check(estimated.total()).is_ok());
+        let error = estimated.check(estimated.total() - 1).unwrap_err();
+        assert!(error.to_string().starts_with("estimated memory"));
+    }
+
+    #[test]
+    fn sizes_are_parsed_with_their_unit() {
+        assert_eq!(parse_size("512"), Ok(512));
+        assert_eq!(parse_size("1.5K"), Ok(1536));
+        assert_eq!(parse_size("2 MiB"), Ok(2 * 1024 * 1024));
+        assert_eq!(parse_size("1g"), Ok(1024 * 1024 * 1024));
+        assert!(parse_size("2T").is_err());
+        assert!(parse_size("-1M").is_err());
+        assert_eq!(format_size(1536), "1.5 KiB");
+    }
+}
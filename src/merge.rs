@@ -0,0 +1,121 @@
+use std::error;
+use std::fmt;
+use crate::accumulate::Accumulation;
+use crate::output::{self, Channels, OutputConfig, Format};
+use crate::rendering::Color;
+
+#[derive(Debug, Clone)]
+pub struct MergeError {
+    pub message: String
+}
+
+impl MergeError {
+    pub fn new(message: String) -> MergeError {
+        MergeError { message }
+    }
+}
+
+impl fmt::Display for MergeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl error::Error for MergeError {}
+
+// Accumulation states keep the linear sums of the samples, so merging them is the same as having rendered every
+// sample in one run. Renders from the same seed hold the same samples and only repeat them
+pub fn average(inputs: &[(String, Accumulation)]) -> Result<Accumulation, MergeError> {
+    let ((first_path, first), rest) = inputs.split_first().ok_or_else(|| MergeError::new("nothing to merge".to_string()))?;
+    let mut merged = first.clone();
+    for (path, input) in rest {
+        merged.merge(input).map_err(|e| MergeError::new(format!("{} {} ({})", path, e, first_path)))?;
+    }
+    if merged.samples() == 0 {
+        return Err(MergeError::new("the inputs have no samples".to_string()));
+    }
+    Ok(merged)
+}
+
+pub fn merge(paths: &[String], output_path: &str, state_path: Option<&str>) -> Result<(), Box<dyn error::Error>> {
+    if Format::from_path(output_path) != Format::PNG {
+        return Err(MergeError::new(format!("{}: merged renders can only be written as png", output_path)).into());
+    }
+    let inputs = paths.iter()
+        .map(|path| Accumulation::read(path).map(|accumulation| (path.clone(), accumulation)))
+        .collect::<Result<Vec<_>, _>>()?;
+    for (index, (path, input)) in inputs.iter().enumerate() {
+        if let Some((other, _)) = inputs[..index].iter().find(|(_, other)| other.seed == input.seed && other.sampler == input.sampler) {
+            eprintln!("warning: {} and {} were rendered with the same seed {}, their samples are the same", other, path, input.seed);
+        }
+    }
+    let merged = average(&inputs)?;
+    let metadata = vec![
+        ("Software".to_string(), "rust_raytracer".to_string()),
+        ("Samples".to_string(), merged.samples().to_string()),
+        ("SceneHash".to_string(), merged.scene_hash.clone()),
+        ("Merged".to_string(), inputs.len().to_string())
+    ];
+    let config = OutputConfig::new(Some(Channels::RGBA), 16, None, Color::black());
+    output::write_png(&merged.to_samples(), merged.width, merged.height, Channels::RGBA, &config, &metadata, output_path)?;
+    if let Some(state_path) = state_path {
+        merged.save(state_path)?;
+    }
+    println!("Merged {} renders ({} samples per pixel) into {}", inputs.len(), merged.samples(), output_path);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::accumulate::tests::{accumulate, scene};
+    use crate::sampler::Sampler;
+
+    fn inputs(accumulations: Vec<Accumulation>) -> Vec<(String, Accumulation)> {
+        accumulations.into_iter().enumerate().map(|(index, accumulation)| (format!("{}.state", index), accumulation)).collect()
+    }
+
+    fn seeded(seed: u64, samples_per_pixel: u32) -> Accumulation {
+        let mut scene = scene(Sampler::RANDOM, samples_per_pixel);
+        scene.seed = seed;
+        accumulate(&scene, 1)
+    }
+
+    #[test]
+    fn inputs_weigh_by_their_samples_per_pixel() {
+        let (few, many) = (seeded(1, 4), seeded(2, 12));
+        let merged = average(&inputs(vec![few.clone(), many.clone()])).unwrap();
+        assert_eq!(merged.samples(), 16);
+        for index in 0..merged.sums.len() {
+            for channel in 0..4 {
+                let mean = merged.sums[index][channel] / merged.counts[index] as f64;
+                let weighted = (few.sums[index][channel] + many.sums[index][channel]) / 16.0;
+                let unweighted = (few.sums[index][channel] / 4.0 + many.sums[index][channel] / 12.0) / 2.0;
+                assert!((mean - weighted).abs() < 1e-12);
+                if (weighted - unweighted).abs() > 1e-6 {
+                    assert!((mean - unweighted).abs() > 1e-9);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn inputs_of_another_size_or_scene_are_refused() {
+        let mut smaller = seeded(2, 1);
+        smaller.width -= 1;
+        assert!(average(&inputs(vec![seeded(1, 1), smaller])).unwrap_err().message.contains("instead of 12x9"));
+        let mut other = seeded(2, 1);
+        other.scene_hash = "0".to_string();
+        assert!(average(&inputs(vec![seeded(1, 1), other])).unwrap_err().message.contains("another scene"));
+        assert!(average(&[]).is_err());
+    }
+
+    #[test]
+    fn renders_from_the_same_seed_are_reproduced() {
+        let render = seeded(3, 4);
+        assert_eq!(seeded(3, 4).to_bytes(), render.to_bytes());
+        let merged = average(&inputs(vec![render.clone(), seeded(3, 4)])).unwrap();
+        assert_eq!(merged.samples(), 8);
+        assert_eq!(merged.to_samples(), render.to_samples());
+    }
+}
@@ -0,0 +1,747 @@
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use serde::{Serialize, Deserialize};
+use crate::shape::{Ray, Hit, Point, Triangle};
+use crate::vertors::Vector3;
+use crate::traits::Intersectable;
+use crate::transform::Matrix;
+use crate::assets::Asset;
+use crate::aabb::Aabb;
+use crate::bvh::Bvh;
+use crate::stl::Stl;
+use crate::ply::Ply;
+use crate::gltf::{Gltf, GltfError};
+use crate::rendering::{Color, Material};
+
+#[derive(Debug, Clone)]
+pub struct ObjError {
+    pub file: String,
+    pub line: usize,
+    pub message: String
+}
+
+impl ObjError {
+    pub fn new(file: &str, line: usize, message: String) -> ObjError {
+        ObjError { file: file.to_string(), line, message }
+    }
+}
+
+impl fmt::Display for ObjError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.line > 0 {
+            write!(f, "{}:{}: {}", self.file, self.line, self.message)
+        } else {
+            write!(f, "{}: {}", self.file, self.message)
+        }
+    }
+}
+
+impl error::Error for ObjError {}
+
+#[derive(Clone, Debug, Default)]
+pub struct Obj {
+    pub vertices: Vec<Point>,
+    pub indices: Vec<[usize; 3]>,
+    pub normals: Vec<Vector3>,
+    pub normal_indices: Vec<[usize; 3]>, // Empty unless every face corner has a normal
+    pub texture_coordinates: Vec<(f64, f64)>,
+    pub texture_indices: Vec<[usize; 3]>, // Empty unless every face corner has texture coordinates
+    pub materials: Vec<Material>,
+    pub face_materials: Vec<Option<usize>> // Empty unless a face uses a material found in the mtllib files
+}
+
+// OBJ indices start at 1, negative ones count back from the last element read so far
+fn resolve_index(token: &str, count: usize, kind: &str, file: &str, line: usize) -> Result<usize, ObjError> {
+    let index: i64 = token.parse().map_err(|_| ObjError::new(file, line, format!("invalid {} index \"{}\"", kind, token)))?;
+    let resolved = if index > 0 { index - 1 } else { count as i64 + index };
+    if index == 0 || resolved < 0 || resolved >= count as i64 {
+        return Err(ObjError::new(file, line, format!("{} index {} is out of range ({} defined)", kind, index, count)));
+    }
+    Ok(resolved as usize)
+}
+
+fn parse_coordinates(parts: &[&str], file: &str, line: usize) -> Result<Vector3, ObjError> {
+    if parts.len() < 3 {
+        return Err(ObjError::new(file, line, format!("expected 3 coordinates, found {}", parts.len())));
+    }
+    let mut values = [0.0; 3];
+    for (value, part) in values.iter_mut().zip(parts.iter()) {
+        *value = part.parse().map_err(|_| ObjError::new(file, line, format!("invalid number \"{}\"", part)))?;
+    }
+    Ok(Vector3::new(values[0], values[1], values[2]))
+}
+
+// Keywords that are understood but have no equivalent in the materials of the crate
+const IGNORED_MTL_KEYWORDS: [&str; 23] = [
+    "Ka", "Ns", "Ni", "d", "Tr", "Tf", "sharpness", "Pr", "Ps", "Pc", "Pcr", "aniso", "anisor",
+    "map_Ka", "map_Kd", "map_Ks", "map_Ke", "map_Ns", "map_d", "bump", "map_bump", "disp", "norm"
+];
+
+fn mtl_color(parts: &[&str]) -> Option<[f64; 3]> {
+    let values: Vec<f64> = parts.iter().map(|part| part.parse()).collect::<Result<_, _>>().ok()?;
+    match values.len() {
+        1 => Some([values[0]; 3]), // A single value is a gray
+        3 => Some([values[0], values[1], values[2]]),
+        _ => None
+    }
+}
+
+// The diffuse color becomes the base color and mirror hints (a ray traced illum model with Ks, or Pm) the
+// reflectiveness. MTL has no albedo, the one of the test scenes is used
+fn parse_mtl(content: &str, file: &str) -> Vec<(String, Material)> {
+    let mut materials: Vec<(String, Material)> = Vec::new();
+    let mut specular = [0.0; 3];
+    let mut mirror = false;
+    let mut warned: Vec<&str> = Vec::new();
+    let finish = |materials: &mut Vec<(String, Material)>, specular: [f64; 3], mirror: bool| {
+        if let Some((_, material)) = materials.last_mut() {
+            if mirror && material.reflectiveness == 0.0 {
+                material.reflectiveness = ((specular[0] + specular[1] + specular[2]) / 3.0).clamp(0.0, 1.0);
+            }
+        }
+    };
+    for (index, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        let warn = |message: String| eprintln!("warning: {}:{}: {}", file, index + 1, message);
+        match parts[0] {
+            "newmtl" => {
+                finish(&mut materials, specular, mirror);
+                specular = [0.0; 3];
+                mirror = false;
+                materials.push((parts[1..].join(" "), Material::new(Color::new(204, 204, 204, 255), 0.9, 0.0)));
+            },
+            _ if materials.is_empty() => warn(format!("\"{}\" before any newmtl is skipped", parts[0])),
+            "Kd" => match mtl_color(&parts[1..]) {
+                Some(color) => {
+                    let channel = |value: f64| (value * 255.0).round().clamp(0.0, 255.0) as u8;
+                    materials.last_mut().unwrap().1.base_color = Color::new(channel(color[0]), channel(color[1]), channel(color[2]), 255).into();
+                },
+                None => warn(format!("unsupported diffuse color \"{}\"", line))
+            },
+            "Ke" => match mtl_color(&parts[1..]) {
+                // Values above 1 become the strength of the brightest channel
+                Some(color) => {
+                    let strength = color[0].max(color[1]).max(color[2]).max(0.0);
+                    let channel = |value: f64| (value / strength.max(1e-12) * 255.0).round().clamp(0.0, 255.0) as u8;
+                    let material = &mut materials.last_mut().unwrap().1;
+                    material.emissive = Color::new(channel(color[0]), channel(color[1]), channel(color[2]), 255);
+                    material.emissive_strength = strength;
+                },
+                None => warn(format!("unsupported emissive color \"{}\"", line))
+            },
+            "Ks" => match mtl_color(&parts[1..]) {
+                Some(color) => specular = color,
+                None => warn(format!("unsupported specular color \"{}\"", line))
+            },
+            "illum" => mirror = matches!(parts.get(1).and_then(|model| model.parse::<u32>().ok()), Some(3..=7)),
+            "Pm" => match parts.get(1).and_then(|value| value.parse::<f64>().ok()) {
+                Some(metallic) => materials.last_mut().unwrap().1.reflectiveness = metallic.clamp(0.0, 1.0),
+                None => warn(format!("invalid metallic value \"{}\"", line))
+            },
+            keyword if IGNORED_MTL_KEYWORDS.contains(&keyword) || keyword.starts_with("map_") => {},
+            keyword => {
+                if !warned.contains(&keyword) {
+                    warn(format!("unknown keyword \"{}\" is skipped", keyword));
+                    warned.push(keyword);
+                }
+            }
+        }
+    }
+    finish(&mut materials, specular, mirror);
+    materials
+}
+
+// Libraries are next to the OBJ file, the ones that cannot be read only lose their materials
+fn load_materials(libraries: &[String], file: &str) -> HashMap<String, Material> {
+    let mut materials = HashMap::new();
+    for library in libraries {
+        let path = Path::new(file).parent().unwrap_or_else(|| Path::new("")).join(library);
+        match fs::read_to_string(&path) {
+            Ok(content) => materials.extend(parse_mtl(&content, &path.to_string_lossy())),
+            Err(e) => eprintln!("warning: {}: cannot read material library {}: {}", file, path.display(), e)
+        }
+    }
+    materials
+}
+
+impl Obj {
+    pub fn load(file: &str) -> Result<Obj, ObjError> {
+        let content = fs::read_to_string(file).map_err(|e| ObjError::new(file, 0, e.to_string()))?;
+        Obj::parse(&content, file)
+    }
+
+    pub fn parse(content: &str, file: &str) -> Result<Obj, ObjError> {
+        let mut obj = Obj::default();
+        let mut every_corner_has_texture = true;
+        let mut every_corner_has_normal = true;
+        let mut libraries: Vec<String> = Vec::new();
+        let mut material_names: Vec<String> = Vec::new();
+        let mut current_material: Option<usize> = None;
+        let mut face_material_names: Vec<Option<usize>> = Vec::new();
+
+        for (index, line) in content.lines().enumerate() {
+            let line_number = index + 1;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            match parts[0] {
+                "v" => obj.vertices.push(parse_coordinates(&parts[1..], file, line_number)?),
+                "vt" => {
+                    // The v coordinate is optional, 1D textures only have u
+                    let mut values = [0.0; 2];
+                    for (value, part) in values.iter_mut().zip(parts[1..].iter()) {
+                        *value = part.parse().map_err(|_| ObjError::new(file, line_number, format!("invalid number \"{}\"", part)))?;
+                    }
+                    if parts.len() < 2 {
+                        return Err(ObjError::new(file, line_number, "expected texture coordinates".to_string()));
+                    }
+                    obj.texture_coordinates.push((values[0], values[1]));
+                },
+                "vn" => obj.normals.push(parse_coordinates(&parts[1..], file, line_number)?),
+                "f" => {
+                    if parts.len() < 4 {
+                        return Err(ObjError::new(file, line_number, format!("a face needs at least 3 vertices, found {}", parts.len() - 1)));
+                    }
+                    let mut face = Vec::with_capacity(parts.len() - 1);
+                    let mut face_textures = Vec::with_capacity(parts.len() - 1);
+                    let mut face_normals = Vec::with_capacity(parts.len() - 1);
+                    for corner in parts[1..].iter() {
+                        let mut references = corner.split('/');
+                        face.push(resolve_index(references.next().unwrap_or(""), obj.vertices.len(), "vertex", file, line_number)?);
+                        match references.next().filter(|texture| !texture.is_empty()) {
+                            Some(texture) => face_textures.push(resolve_index(texture, obj.texture_coordinates.len(), "texture coordinate", file, line_number)?),
+                            None => every_corner_has_texture = false
+                        }
+                        match references.next().filter(|normal| !normal.is_empty()) {
+                            Some(normal) => face_normals.push(resolve_index(normal, obj.normals.len(), "normal", file, line_number)?),
+                            None => every_corner_has_normal = false
+                        }
+                    }
+                    // Quads and larger polygons are split in a fan around their first vertex
+                    for corner in 1..face.len() - 1 {
+                        obj.indices.push([face[0], face[corner], face[corner + 1]]);
+                        face_material_names.push(current_material);
+                        if every_corner_has_texture {
+                            obj.texture_indices.push([face_textures[0], face_textures[corner], face_textures[corner + 1]]);
+                        }
+                        if every_corner_has_normal {
+                            obj.normal_indices.push([face_normals[0], face_normals[corner], face_normals[corner + 1]]);
+                        }
+                    }
+                },
+                "mtllib" => libraries.extend(parts[1..].iter().map(|library| library.to_string())),
+                "usemtl" => {
+                    let name = parts[1..].join(" ");
+                    current_material = Some(material_names.iter().position(|known| *known == name).unwrap_or_else(|| {
+                        material_names.push(name);
+                        material_names.len() - 1
+                    }));
+                },
+                _ => {} // Groups, smoothing, lines and points do not change the geometry
+            }
+        }
+
+        if obj.indices.is_empty() {
+            return Err(ObjError::new(file, 0, "no faces".to_string()));
+        }
+        if !every_corner_has_texture {
+            obj.texture_indices.clear();
+        }
+        if !every_corner_has_normal {
+            obj.normal_indices.clear();
+        }
+        if !material_names.is_empty() {
+            let available = load_materials(&libraries, file);
+            let resolved: Vec<Option<usize>> = material_names.iter().map(|name| match available.get(name) {
+                Some(material) => {
+                    obj.materials.push(material.clone());
+                    Some(obj.materials.len() - 1)
+                },
+                None => {
+                    eprintln!("warning: {}: material \"{}\" is not in any mtllib file, its faces keep the element material", file, name);
+                    None
+                }
+            }).collect();
+            if !obj.materials.is_empty() {
+                obj.face_materials = face_material_names.iter().map(|name| name.and_then(|name| resolved[name])).collect();
+            }
+        }
+        Ok(obj)
+    }
+}
+
+fn default_scale() -> f64 {
+    1.0
+}
+
+fn default_terminator_offset() -> bool {
+    true
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Mesh {
+    pub file: String,
+    #[serde(default = "default_scale")]
+    pub scale: f64,
+    #[serde(default = "Vector3::zero")]
+    pub translate: Vector3,
+    #[serde(default)]
+    pub smooth: bool,
+    #[serde(default = "default_terminator_offset")]
+    pub terminator_offset: bool, // Lifts the shadow rays of smooth meshes to the surface their normals describe
+    #[serde(default)]
+    pub part: Option<usize>, // Primitive of a glTF file in the order of its nodes, all of them when omitted
+    #[serde(skip, default = "Matrix::identity")]
+    pub placement: Matrix,
+    #[serde(skip)]
+    pub vertices: Vec<Point>,
+    #[serde(skip)]
+    pub indices: Vec<[usize; 3]>,
+    #[serde(skip)]
+    pub face_normals: Vec<Vector3>, // One per triangle when the file has them
+    #[serde(skip)]
+    pub vertex_normals: Vec<Vector3>, // Only for smooth meshes
+    #[serde(skip)]
+    pub normal_indices: Vec<[usize; 3]>, // Corners of each triangle in vertex_normals, the vertex indices when empty
+    #[serde(skip)]
+    pub colors: Vec<Color>, // One per vertex when the file has them
+    #[serde(skip)]
+    pub texture_coordinates: Vec<(f64, f64)>,
+    #[serde(skip)]
+    pub texture_indices: Vec<[usize; 3]>, // Corners of each triangle in texture_coordinates when the file has them
+    #[serde(skip)]
+    pub materials: Vec<Material>,
+    #[serde(skip)]
+    pub face_materials: Vec<Option<usize>>, // Material of each triangle in materials when the file has them, the element one for None
+    #[serde(skip)]
+    pub bvh: Option<Bvh> // Built over the triangles once they are loaded and placed
+}
+
+impl Mesh {
+    pub fn new(file: String, scale: f64, translate: Vector3) -> Mesh {
+        Mesh { file, scale, translate, smooth: false, terminator_offset: true, part: None, placement: Matrix::identity(), vertices: Vec::new(), indices: Vec::new(), face_normals: Vec::new(), vertex_normals: Vec::new(), normal_indices: Vec::new(), colors: Vec::new(), texture_coordinates: Vec::new(), texture_indices: Vec::new(), materials: Vec::new(), face_materials: Vec::new(), bvh: None }
+    }
+
+    // Node and template transforms are applied before the file is loaded, so they are kept to be applied after it
+    pub fn transformed(&self, matrix: &Matrix) -> Mesh {
+        let mut mesh = self.clone();
+        mesh.placement = *matrix * self.placement;
+        mesh.transform_loaded(matrix);
+        if mesh.bvh.is_some() {
+            mesh.build_bvh();
+        }
+        mesh
+    }
+
+    // Triangle boxes get a margin so that rays grazing an edge or a flat box still reach the triangle
+    pub fn build_bvh(&mut self) {
+        let boxes: Vec<Aabb> = self.triangles()
+            .map(|triangle| Aabb::new(triangle.a, triangle.a).union(&Aabb::new(triangle.b, triangle.b)).union(&Aabb::new(triangle.c, triangle.c)))
+            .map(|aabb| aabb.expanded((aabb.max - aabb.min).length() * 1e-9 + 1e-12))
+            .collect();
+        self.bvh = Some(Bvh::build(&boxes));
+    }
+
+    // Placements only rotate and scale uniformly, so normals follow the matrix like directions
+    fn transform_loaded(&mut self, matrix: &Matrix) {
+        for vertex in self.vertices.iter_mut() {
+            *vertex = matrix.transform_point(*vertex);
+        }
+        for normal in self.face_normals.iter_mut().chain(self.vertex_normals.iter_mut()) {
+            *normal = matrix.transform_vector(*normal).normalize();
+        }
+    }
+
+    // Each vertex gets the sum of the normals of its triangles weighted by their area
+    fn compute_vertex_normals(&mut self) {
+        let mut normals = vec![Vector3::zero(); self.vertices.len()];
+        for (&[a, b, c], triangle) in self.indices.iter().zip(self.triangles()) {
+            let weighted = (triangle.b - triangle.a).cross(&(triangle.c - triangle.a)); // Its length is twice the area
+            for index in [a, b, c] {
+                normals[index] = normals[index] + weighted;
+            }
+        }
+        self.vertex_normals = normals.into_iter().map(|normal| normal.normalize()).collect();
+        self.normal_indices.clear();
+    }
+
+    pub fn triangles(&self) -> impl Iterator<Item = Triangle> + '_ {
+        (0..self.indices.len()).map(move |index| self.triangle(index))
+    }
+
+    fn triangle(&self, index: usize) -> Triangle {
+        let [a, b, c] = self.indices[index];
+        Triangle::new(self.vertices[a], self.vertices[b], self.vertices[c])
+    }
+
+    // Vertex normals blended with the barycentric weights of the point
+    fn normal_at(&self, index: usize, weights: &[f64; 3]) -> Vector3 {
+        let corners = self.normal_indices.get(index).unwrap_or(&self.indices[index]);
+        corners.iter().zip(weights.iter())
+            .fold(Vector3::zero(), |normal, (&corner, weight)| normal + self.vertex_normals[corner] * *weight)
+            .normalize()
+    }
+
+    // Hanika's shadow terminator fix: below the tangent plane of a corner, the point is moved up to it along the corner normal,
+    // the moves are blended with the barycentric weights and kept along the triangle normal. Without it shadow rays leaving
+    // a flat triangle near the terminator hit the neighboring ones and the smooth shading shows bands of the facets
+    fn terminator_offset_at(&self, index: usize, point: Point, normal: Vector3, weights: &[f64; 3]) -> f64 {
+        let corners = self.normal_indices.get(index).unwrap_or(&self.indices[index]);
+        self.indices[index].iter().zip(corners.iter()).zip(weights.iter()).map(|((&vertex, &corner), weight)| {
+            let corner_normal = self.vertex_normals[corner];
+            let corner_normal = if corner_normal.dot(&normal) < 0.0 { -corner_normal } else { corner_normal };
+            let below = (point - self.vertices[vertex]).dot(&corner_normal).min(0.0);
+            -below * corner_normal.dot(&normal) * weight
+        }).sum()
+    }
+
+    fn uv_at(&self, index: usize, weights: &[f64; 3]) -> (f64, f64) {
+        self.texture_indices[index].iter().zip(weights.iter()).fold((0.0, 0.0), |(u, v), (&corner, weight)| {
+            let (corner_u, corner_v) = self.texture_coordinates[corner];
+            (u + corner_u * weight, v + corner_v * weight)
+        })
+    }
+
+    // Directions the texture coordinates of the file grow along on the triangle, None when they do not span it
+    fn tangents_at(&self, index: usize, triangle: &Triangle) -> Option<(Vector3, Vector3)> {
+        let [a, b, c] = self.texture_indices[index].map(|corner| self.texture_coordinates[corner]);
+        let (edge_1, edge_2) = (triangle.b - triangle.a, triangle.c - triangle.a);
+        let (du_1, dv_1, du_2, dv_2) = (b.0 - a.0, b.1 - a.1, c.0 - a.0, c.1 - a.1);
+        let determinant = du_1 * dv_2 - du_2 * dv_1;
+        if determinant.abs() < 1e-12 {
+            return None;
+        }
+        Some(((edge_1 * dv_2 - edge_2 * dv_1) * (1.0 / determinant), (edge_2 * du_1 - edge_1 * du_2) * (1.0 / determinant)))
+    }
+
+    // Vertex colors blended with the barycentric weights of the point
+    fn color_at(&self, indices: [usize; 3], weights: &[f64; 3]) -> Color {
+        let channel = |get: fn(&Color) -> u8| {
+            let value: f64 = indices.iter().zip(weights.iter()).map(|(&index, weight)| get(&self.colors[index]) as f64 * weight).sum();
+            value.round().clamp(0.0, 255.0) as u8
+        };
+        Color::new(channel(|color| color.r), channel(|color| color.g), channel(|color| color.b), 255)
+    }
+}
+
+impl Asset for Mesh {
+    fn name(&self) -> &str {
+        &self.file
+    }
+
+    // STL, PLY and glTF files are recognized by their extension, anything else is read as OBJ
+    fn load(&mut self) -> Result<(), Box<dyn error::Error>> {
+        let extension = self.file.rsplit('.').next().unwrap_or("").to_lowercase();
+        match extension.as_str() {
+            "stl" => {
+                let stl = Stl::load(&self.file)?;
+                self.vertices = stl.vertices;
+                self.indices = stl.indices;
+                self.face_normals = stl.normals;
+            },
+            "ply" => {
+                let ply = Ply::load(&self.file)?;
+                self.vertices = ply.vertices;
+                self.indices = ply.indices;
+                self.colors = ply.colors;
+            },
+            "gltf" | "glb" => {
+                let mut primitives = Gltf::load(&self.file)?.primitives;
+                if let Some(part) = self.part {
+                    if part >= primitives.len() {
+                        return Err(GltfError::new(&self.file, format!("part {} is out of range ({} primitives)", part, primitives.len())).into());
+                    }
+                    primitives = vec![primitives.swap_remove(part)];
+                }
+                let every_primitive_has_normals = primitives.iter().all(|primitive| !primitive.normals.is_empty());
+                for primitive in primitives {
+                    let offset = self.vertices.len();
+                    self.indices.extend(primitive.indices.iter().map(|&[a, b, c]| [a + offset, b + offset, c + offset]));
+                    self.vertices.extend(primitive.vertices);
+                    if self.smooth && every_primitive_has_normals {
+                        self.vertex_normals.extend(primitive.normals);
+                    }
+                }
+            },
+            _ => {
+                let obj = Obj::load(&self.file)?;
+                self.vertices = obj.vertices;
+                self.indices = obj.indices;
+                if !obj.texture_indices.is_empty() {
+                    self.texture_coordinates = obj.texture_coordinates;
+                    self.texture_indices = obj.texture_indices;
+                }
+                if self.smooth && !obj.normal_indices.is_empty() {
+                    self.vertex_normals = obj.normals;
+                    self.normal_indices = obj.normal_indices;
+                }
+                self.materials = obj.materials;
+                self.face_materials = obj.face_materials;
+            }
+        }
+        self.transform_loaded(&(self.placement * Matrix::translation(self.translate) * Matrix::scaling(Vector3::new(self.scale, self.scale, self.scale))));
+        // Files without normals for every vertex get them from their triangles
+        if self.smooth && self.vertex_normals.is_empty() {
+            self.compute_vertex_normals();
+        }
+        // Meshes are loaded on several threads, so each one builds its own hierarchy on the thread loading it
+        self.build_bvh();
+        Ok(())
+    }
+
+    fn describe(&self) -> String {
+        let part = self.part.map_or(String::new(), |part| format!(" part {}", part));
+        let materials = if self.materials.is_empty() { String::new() } else { format!(", {} materials", self.materials.len()) };
+        format!("Using mesh: {}{} ({} vertices, {} triangles{})", self.file, part, self.vertices.len(), self.indices.len(), materials)
+    }
+}
+
+impl Intersectable for Mesh {
+    fn intersect(&self, ray: &Ray) -> Option<Hit> {
+        // Only the closest triangle gets its file attributes and material, the others are just distances
+        let mut nearest: Option<(usize, Triangle, Hit)> = None;
+        let mut test = |index: usize, t_max: f64| {
+            let triangle = self.triangle(index);
+            let hit = triangle.intersect(&ray.with_range(ray.t_min, t_max))?;
+            let distance = hit.distance;
+            // Triangles sharing an edge are met at the same distance, the last one in the file wins whatever the visit order
+            if !matches!(&nearest, Some((best, _, best_hit)) if best_hit.distance == distance && *best > index) {
+                nearest = Some((index, triangle, hit));
+            }
+            Some(distance)
+        };
+        match &self.bvh {
+            Some(bvh) => bvh.traverse(ray, test),
+            None => {
+                let mut t_max = ray.t_max;
+                for index in 0..self.indices.len() {
+                    t_max = test(index, t_max).unwrap_or(t_max);
+                }
+            }
+        }
+        let (index, triangle, mut hit) = nearest?;
+        let weights = [1.0 - hit.uv.0 - hit.uv.1, hit.uv.0, hit.uv.1];
+        // File normals are kept on the outer side the winding order gives, even when the file disagrees
+        let normal = if !self.vertex_normals.is_empty() {
+            Some(self.normal_at(index, &weights))
+        } else {
+            self.face_normals.get(index).copied()
+        };
+        if let Some(normal) = normal {
+            hit.normal = if normal.dot(&hit.geometric_normal) < 0.0 { -normal } else { normal };
+        }
+        if self.terminator_offset && !self.vertex_normals.is_empty() {
+            hit.shadow_offset = self.terminator_offset_at(index, hit.point, hit.geometric_normal, &weights);
+        }
+        if !self.colors.is_empty() {
+            hit.color = Some(self.color_at(self.indices[index], &weights));
+        }
+        hit.material = self.face_materials.get(index).copied().flatten().map(|material| self.materials[material].clone());
+        // Without texture coordinates in the file the triangle ones are kept
+        if !self.texture_indices.is_empty() {
+            hit.uv = self.uv_at(index, &weights);
+            hit.tangents = self.tangents_at(index, &triangle);
+        }
+        Some(hit)
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        Aabb::from_points(self.vertices.iter().copied())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shape::Sphere;
+
+    // Smooth closed sphere of unit radius around the origin, every triangle turning counterclockwise seen from outside
+    pub(crate) fn sphere_mesh(rings: usize, segments: usize) -> Mesh {
+        let mut mesh = Mesh::new("sphere.obj".to_string(), 1.0, Vector3::zero());
+        mesh.vertices.push(Vector3::new(0.0, 1.0, 0.0));
+        for ring in 1..rings {
+            let polar = std::f64::consts::PI * ring as f64 / rings as f64;
+            for segment in 0..segments {
+                let azimuth = 2.0 * std::f64::consts::PI * segment as f64 / segments as f64;
+                mesh.vertices.push(Vector3::new(polar.sin() * azimuth.cos(), polar.cos(), polar.sin() * azimuth.sin()));
+            }
+        }
+        mesh.vertices.push(Vector3::new(0.0, -1.0, 0.0));
+        let bottom = mesh.vertices.len() - 1;
+        let at = |ring: usize, segment: usize| 1 + (ring - 1) * segments + segment % segments;
+        for segment in 0..segments {
+            mesh.indices.push([0, at(1, segment + 1), at(1, segment)]);
+            mesh.indices.push([bottom, at(rings - 1, segment), at(rings - 1, segment + 1)]);
+            for ring in 1..rings - 1 {
+                mesh.indices.push([at(ring, segment), at(ring, segment + 1), at(ring + 1, segment)]);
+                mesh.indices.push([at(ring, segment + 1), at(ring + 1, segment + 1), at(ring + 1, segment)]);
+            }
+        }
+        mesh.smooth = true;
+        mesh.compute_vertex_normals();
+        mesh
+    }
+
+    #[test]
+    fn sphere_mesh_winds_outward() {
+        let mesh = sphere_mesh(8, 16);
+        for triangle in mesh.triangles() {
+            let normal = (triangle.b - triangle.a).cross(&(triangle.c - triangle.a));
+            assert!(normal.dot(&(triangle.a + triangle.b + triangle.c)) > 0.0);
+        }
+    }
+
+    // Direction a ray leaves a glass shape with after refracting in and out, None when it is reflected inside
+    fn through_glass(shape: &dyn Intersectable, ray: Ray) -> Option<Vector3> {
+        let entry = shape.intersect(&ray).unwrap().facing(ray.direction);
+        assert!(entry.front_face);
+        let inside = Ray::compute_refraction_ray(&entry, ray.direction, 1.5).unwrap();
+        let exit = shape.intersect(&inside).unwrap().facing(inside.direction);
+        assert!(!exit.front_face);
+        Ray::compute_refraction_ray(&exit, inside.direction, 1.5).map(|ray| ray.direction.normalize())
+    }
+
+    #[test]
+    fn glass_mesh_sphere_refracts_like_the_analytic_sphere() {
+        let mesh = sphere_mesh(64, 128);
+        let sphere = Sphere::new(Vector3::zero(), 1.0);
+        for offset in [0.0, 0.2, 0.45, 0.7, 0.85] {
+            let ray = Ray::new(Vector3::new(offset, 0.1, 5.0), Vector3::new(0.0, 0.0, -1.0));
+            let expected = through_glass(&sphere, ray).unwrap();
+            let found = through_glass(&mesh, ray).unwrap();
+            assert!(found.dot(&expected) > 0.999, "offset {}: {:?} instead of {:?}", offset, found, expected);
+        }
+    }
+
+    #[test]
+    fn glass_mesh_sphere_reflects_grazing_rays_inside() {
+        let mesh = sphere_mesh(64, 128);
+        let sphere = Sphere::new(Vector3::zero(), 1.0);
+        // Past the critical angle of glass from inside, about 42 degrees
+        let ray = Ray::new(Vector3::new(0.0, 0.9, 0.0), Vector3::new(1.0, 0.0, 0.0));
+        for shape in [&sphere as &dyn Intersectable, &mesh] {
+            let exit = shape.intersect(&ray).unwrap().facing(ray.direction);
+            assert!(!exit.front_face);
+            assert!(Ray::compute_refraction_ray(&exit, ray.direction, 1.5).is_none());
+        }
+    }
+
+    // One element of the shadow terminator scene alone, moved to the middle of the image
+    fn terminator_render(name: &str, x: f64) -> (crate::rendering::Scene, image::RgbaImage) {
+        let mut scene = crate::parse_scene(include_str!("../test_scene/shadow_terminator.json"), &HashMap::new(), None).unwrap();
+        let element = scene.elements.iter().find(|renderable| renderable.name.as_deref() == Some(name)).unwrap();
+        scene.elements = vec![element.transformed(&Matrix::translation(Vector3::new(-x, 0.0, 0.0)))];
+        scene.camera.width = 160;
+        scene.camera.height = 160;
+        scene.camera.fov = 22.0;
+        scene.prepare().unwrap();
+        let image = crate::rendering::render_image(1, &mut scene);
+        (scene, image)
+    }
+
+    // Pixels where both spheres are seen and the mesh is in the dark while the analytic sphere is lit
+    fn terminator_error(analytic: &(crate::rendering::Scene, image::RgbaImage), name: &str, x: f64) -> usize {
+        let (scene, image) = terminator_render(name, x);
+        image.enumerate_pixels().filter(|(x, y, pixel)| {
+            let ray = scene.camera.compute_prime_ray(*x, *y);
+            let expected = analytic.1.get_pixel(*x, *y);
+            scene.trace(&ray, crate::shape::RayType::PRIMARY).is_some() && analytic.0.trace(&ray, crate::shape::RayType::PRIMARY).is_some()
+                && pixel.0[..3].iter().zip(expected.0[..3].iter()).map(|(a, b)| *b as i64 - *a as i64).sum::<i64>() > 15
+        }).count()
+    }
+
+    #[test]
+    fn lifted_shadow_rays_remove_the_terminator_bands_of_low_poly_spheres() {
+        let analytic = terminator_render("analytic", 2.4);
+        let faceted = terminator_error(&analytic, "faceted", -2.4);
+        let lifted = terminator_error(&analytic, "lifted", 0.0);
+        assert!(lifted * 3 < faceted, "{} against {} without the offset", lifted, faceted);
+    }
+
+    #[test]
+    fn the_terminator_offset_only_lifts_points_below_the_corner_planes() {
+        let mesh = sphere_mesh(8, 16);
+        // A ray straight at a vertex meets the surface where the normal is exact, a triangle center lies below every corner plane
+        let at_vertex = mesh.intersect(&Ray::new(Vector3::new(0.0, 5.0, 0.0), Vector3::new(0.0, -1.0, 0.0))).unwrap();
+        assert!(at_vertex.shadow_offset.abs() < 1e-9);
+        let triangle = mesh.triangle(20);
+        let center = (triangle.a + triangle.b + triangle.c) * (1.0 / 3.0);
+        let hit = mesh.intersect(&Ray::new(center * 3.0, -center.normalize())).unwrap();
+        assert!(hit.shadow_offset > 0.0 && hit.shadow_offset < 0.1, "{}", hit.shadow_offset);
+        let mut flat = mesh.clone();
+        flat.terminator_offset = false;
+        assert_eq!(flat.intersect(&Ray::new(center * 3.0, -center.normalize())).unwrap().shadow_offset, 0.0);
+    }
+
+    #[test]
+    fn the_hierarchy_finds_the_same_hits_as_every_triangle() {
+        let linear = sphere_mesh(32, 64);
+        let mut indexed = linear.clone();
+        indexed.build_bvh();
+        assert!(indexed.bvh.as_ref().unwrap().node_count() > 1);
+        let mut rng = crate::random::Rng::new(232);
+        for _ in 0..1000 {
+            let origin = Vector3::new(rng.next_f64() * 6.0 - 3.0, rng.next_f64() * 6.0 - 3.0, rng.next_f64() * 6.0 - 3.0);
+            let ray = Ray::new(origin, (Vector3::new(rng.next_f64() - 0.5, rng.next_f64() - 0.5, rng.next_f64() - 0.5) - origin).normalize());
+            match (linear.intersect(&ray), indexed.intersect(&ray)) {
+                (Some(expected), Some(found)) => {
+                    assert_eq!(expected.distance, found.distance);
+                    assert!((expected.normal - found.normal).length() < 1e-12);
+                },
+                (None, None) => {},
+                (expected, found) => panic!("{:?} instead of {:?}", found.map(|hit| hit.distance), expected.map(|hit| hit.distance))
+            }
+        }
+    }
+
+    #[test]
+    fn loaded_and_placed_meshes_keep_their_hierarchy_up_to_date() {
+        let mut mesh = Mesh::new("test_scene/models/cube.obj".to_string(), 1.0, Vector3::zero());
+        mesh.load().unwrap();
+        assert!(mesh.bvh.is_some());
+        let moved = mesh.transformed(&Matrix::translation(Vector3::new(10.0, 0.0, 0.0)));
+        let ray = Ray::new(Vector3::new(10.0, 0.0, 10.0), Vector3::new(0.0, 0.0, -1.0));
+        assert!(moved.intersect(&ray).is_some());
+        assert!(mesh.intersect(&ray).is_none());
+    }
+
+    #[test]
+    fn rays_from_inside_a_closed_mesh_see_back_faces() {
+        for smooth in [true, false] {
+            let mut mesh = sphere_mesh(16, 32);
+            mesh.smooth = smooth;
+            for direction in [Vector3::new(1.0, 0.0, 0.0), Vector3::new(0.0, -1.0, 0.0), Vector3::new(1.0, 1.0, -1.0).normalize()] {
+                let ray = Ray::new(Vector3::new(0.1, 0.2, 0.0), direction);
+                let hit = mesh.intersect(&ray).unwrap().facing(ray.direction);
+                assert!(!hit.front_face);
+                assert!(hit.normal.dot(&direction) < 0.0);
+            }
+        }
+    }
+
+    #[test]
+    fn closest_face_gives_the_material() {
+        let mut mesh = Mesh::new("layers.obj".to_string(), 1.0, Vector3::zero());
+        for z in [0.0, 1.0, -1.0] {
+            mesh.vertices.extend([Vector3::new(-1.0, -1.0, z), Vector3::new(1.0, -1.0, z), Vector3::new(0.0, 1.0, z)]);
+        }
+        mesh.indices = vec![[0, 1, 2], [3, 4, 5], [6, 7, 8]];
+        mesh.materials = vec![Material::new(Color::black(), 0.1, 0.0), Material::new(Color::black(), 0.2, 0.0), Material::new(Color::black(), 0.3, 0.0)];
+        mesh.face_materials = vec![Some(0), Some(1), Some(2)];
+        for (z, albedo) in [(5.0, 0.2), (-5.0, 0.3)] {
+            let hit = mesh.intersect(&Ray::new(Vector3::new(0.0, 0.0, z), Vector3::new(0.0, 0.0, -z.signum()))).unwrap();
+            assert!((hit.distance - 4.0).abs() < 1e-9);
+            assert_eq!(hit.material.unwrap().albedo.0[0], albedo);
+        }
+    }
+}
+
@@ -0,0 +1,134 @@
+use serde::{Serialize, Deserialize};
+use crate::shape::{Ray, Hit, Point};
+use crate::vertors::Vector3;
+use crate::traits::Intersectable;
+use crate::transform::Matrix;
+use crate::aabb::Aabb;
+
+// Samples taken along the ray for each radius of the smallest blob, thinner features than that can be missed
+const SAMPLES_PER_RADIUS: f64 = 16.0;
+const REFINE_STEPS: u32 = 48;
+
+fn default_strength() -> f64 {
+    1.0
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct Blob {
+    pub center: Point,
+    pub radius: f64,
+    #[serde(default = "default_strength")]
+    pub strength: f64 // Negative blobs dig into the others
+}
+
+impl Blob {
+    // Smooth falloff reaching 0 at the radius, so that far blobs cost nothing
+    fn field(&self, point: Point) -> f64 {
+        let falloff = 1.0 - (point - self.center).length_sq() / (self.radius * self.radius);
+        if falloff <= 0.0 { 0.0 } else { self.strength * falloff * falloff }
+    }
+
+    fn gradient(&self, point: Point) -> Vector3 {
+        let offset = point - self.center;
+        let falloff = 1.0 - offset.length_sq() / (self.radius * self.radius);
+        if falloff <= 0.0 { Vector3::zero() } else { offset * (-4.0 * self.strength * falloff / (self.radius * self.radius)) }
+    }
+
+    // Distances along the ray inside the sphere where the blob has an influence
+    fn span(&self, ray: &Ray) -> Option<(f64, f64)> {
+        let to_center = self.center - ray.origin;
+        let projection = to_center.dot(&ray.direction);
+        let squared = self.radius * self.radius - (to_center.length_sq() - projection * projection);
+        if squared <= 0.0 {
+            return None;
+        }
+        let thickness = squared.sqrt();
+        Some((projection - thickness, projection + thickness))
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Metaballs {
+    pub blobs: Vec<Blob>,
+    pub threshold: f64 // Points where the summed field reaches it are inside, it must be above 0
+}
+
+impl Metaballs {
+    pub fn transformed(&self, matrix: &Matrix) -> Metaballs {
+        let scale = matrix.uniform_scale();
+        let blobs = self.blobs.iter().map(|blob| Blob { center: matrix.transform_point(blob.center), radius: blob.radius * scale, strength: blob.strength }).collect();
+        Metaballs { blobs, threshold: self.threshold }
+    }
+
+    pub fn field(&self, point: Point) -> f64 {
+        self.blobs.iter().map(|blob| blob.field(point)).sum()
+    }
+
+    // The field decreases outward, so the surface normal points against its gradient
+    pub fn normal(&self, point: Point) -> Vector3 {
+        -self.blobs.iter().fold(Vector3::zero(), |gradient, blob| gradient + blob.gradient(point)).normalize()
+    }
+
+    // Only blobs adding to the field can raise it to the threshold, the spans of the others are skipped
+    fn spans(&self, ray: &Ray) -> Vec<(f64, f64)> {
+        let mut spans: Vec<(f64, f64)> = self.blobs.iter()
+            .filter(|blob| blob.strength > 0.0)
+            .filter_map(|blob| blob.span(ray))
+            .map(|(enter, exit)| (enter.max(ray.t_min), exit.min(ray.t_max)))
+            .filter(|(enter, exit)| enter < exit)
+            .collect();
+        spans.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal));
+        let mut merged: Vec<(f64, f64)> = Vec::with_capacity(spans.len());
+        for (enter, exit) in spans {
+            match merged.last_mut() {
+                Some(last) if enter <= last.1 => last.1 = last.1.max(exit),
+                _ => merged.push((enter, exit))
+            }
+        }
+        merged
+    }
+
+    // Bisection between the last sample on each side of the threshold
+    fn refine(&self, ray: &Ray, mut before: f64, mut after: f64, inside_before: bool) -> f64 {
+        for _ in 0..REFINE_STEPS {
+            let middle = (before + after) * 0.5;
+            if (self.field(ray.origin + ray.direction * middle) >= self.threshold) == inside_before {
+                before = middle;
+            } else {
+                after = middle;
+            }
+        }
+        (before + after) * 0.5
+    }
+}
+
+impl Intersectable for Metaballs {
+    // Fixed steps inside the spans of the blobs, rays that only come close to the threshold run out of spans
+    fn intersect(&self, ray: &Ray) -> Option<Hit> {
+        let smallest = self.blobs.iter().filter(|blob| blob.strength > 0.0).map(|blob| blob.radius).fold(f64::INFINITY, f64::min);
+        let step = smallest / SAMPLES_PER_RADIUS;
+        for (enter, exit) in self.spans(ray) {
+            let mut distance = enter;
+            let mut inside = self.field(ray.origin + ray.direction * distance) >= self.threshold;
+            while distance < exit {
+                let next = (distance + step).min(exit);
+                let next_inside = self.field(ray.origin + ray.direction * next) >= self.threshold;
+                if next_inside != inside {
+                    let distance = self.refine(ray, distance, next, inside);
+                    let point = ray.origin + ray.direction * distance;
+                    return Some(Hit::new(distance, point, self.normal(point)));
+                }
+                distance = next;
+                inside = next_inside;
+            }
+        }
+        None
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        self.blobs.iter()
+            .filter(|blob| blob.strength > 0.0)
+            .map(|blob| Aabb::around(blob.center, Vector3::new(blob.radius, blob.radius, blob.radius)))
+            .fold(None, |bounds: Option<Aabb>, aabb| Some(bounds.map_or(aabb, |bounds| bounds.union(&aabb))))
+    }
+}
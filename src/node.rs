@@ -0,0 +1,81 @@
+use serde::{Serialize, Deserialize};
+use crate::rendering::{Renderable, Light};
+use crate::transform::{Transform, Matrix};
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Node {
+    #[serde(default)]
+    pub transform: Transform,
+    #[serde(default)]
+    pub children: Vec<Node>,
+    #[serde(default)]
+    pub renderable: Option<Renderable>,
+    #[serde(default)]
+    pub lights: Vec<Light>
+}
+
+impl Node {
+    pub fn new(transform: Transform, children: Vec<Node>, renderable: Option<Renderable>, lights: Vec<Light>) -> Node {
+        Node { transform, children, renderable, lights }
+    }
+
+    pub fn flatten(&self, parent: &Matrix, elements: &mut Vec<Renderable>, lights: &mut Vec<Light>) {
+        let world = *parent * self.transform.to_matrix();
+        if let Some(renderable) = &self.renderable {
+            elements.push(renderable.transformed(&world));
+        }
+        for light in self.lights.iter() {
+            lights.push(light.transformed(&world));
+        }
+        for child in self.children.iter() {
+            child.flatten(&world, elements, lights);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use crate::rendering::{self, Scene};
+
+    fn snowman() -> Scene {
+        let mut scene = crate::parse_scene(include_str!("../test_scene/snowman.json"), &HashMap::new(), None).unwrap();
+        scene.camera.width = 64;
+        scene.camera.height = 48;
+        scene
+    }
+
+    // The floor is a horizontal plane and the sun a directional light, so sliding the snowman and the camera
+    // together along x gives back the same image
+    fn render(node_offset: f64, camera_offset: f64) -> Vec<u8> {
+        let mut scene = snowman();
+        scene.nodes[0].transform.translate.x += node_offset;
+        scene.camera.position.x += camera_offset;
+        scene.prepare().unwrap();
+        rendering::render_image(1, &mut scene).into_raw()
+    }
+
+    #[test]
+    fn moving_a_parent_moves_all_of_its_descendants() {
+        let still = render(0.0, 0.0);
+        let followed = render(1.5, 1.5);
+        assert!(still.iter().zip(followed.iter()).all(|(a, b)| (*a as i16 - *b as i16).abs() <= 1));
+        let moved = render(1.5, 0.0);
+        assert!(still.iter().zip(moved.iter()).filter(|(a, b)| (**a as i16 - **b as i16).abs() > 8).count() > 100);
+    }
+
+    #[test]
+    fn prepared_scenes_serialize_back_to_their_hierarchy() {
+        let loaded = snowman();
+        let written = serde_json::to_value(&loaded).unwrap();
+        let mut prepared = snowman();
+        prepared.prepare().unwrap();
+        assert_eq!(prepared.flat_elements.len(), 5);
+        assert_eq!(prepared.flat_lights.len(), 2);
+        let saved = serde_json::to_value(&prepared).unwrap();
+        assert_eq!(saved, written);
+        assert_eq!(saved["elements"].as_array().unwrap().len(), 1);
+        let reloaded: Scene = serde_json::from_value(saved).unwrap();
+        assert_eq!(serde_json::to_value(&reloaded).unwrap(), written);
+    }
+}
@@ -0,0 +1,58 @@
+use crate::shape::Point;
+use crate::random::Rng;
+
+// Directions toward the middle of the edges of a cube, Ken Perlin's gradients for improved noise
+const GRADIENTS: [[f64; 3]; 12] = [
+    [1.0, 1.0, 0.0], [-1.0, 1.0, 0.0], [1.0, -1.0, 0.0], [-1.0, -1.0, 0.0],
+    [1.0, 0.0, 1.0], [-1.0, 0.0, 1.0], [1.0, 0.0, -1.0], [-1.0, 0.0, -1.0],
+    [0.0, 1.0, 1.0], [0.0, -1.0, 1.0], [0.0, 1.0, -1.0], [0.0, -1.0, -1.0]
+];
+
+// Random bits of a lattice cell, the same for the same seed on every run and every machine
+fn hash(cell: [i64; 3], seed: u64) -> u64 {
+    let mixed = (cell[0] as u64).wrapping_mul(0x8DA6_B343)
+        ^ (cell[1] as u64).wrapping_mul(0xD816_3841)
+        ^ (cell[2] as u64).wrapping_mul(0xCB1A_B31F);
+    Rng::new(mixed ^ seed).next_u64()
+}
+
+// Its first and second derivatives are 0 at both ends, so the noise stays smooth across the cells
+fn fade(t: f64) -> f64 {
+    t * t * t * (t * (t * 6.0 - 15.0) + 10.0)
+}
+
+fn lerp(t: f64, a: f64, b: f64) -> f64 {
+    a + (b - a) * t
+}
+
+// Gradient noise, about between -1 and 1 and 0 on every lattice point
+pub fn perlin(point: Point, seed: u64) -> f64 {
+    let cell = [point.x.floor(), point.y.floor(), point.z.floor()];
+    let offset = [point.x - cell[0], point.y - cell[1], point.z - cell[2]];
+    let cell = cell.map(|coordinate| coordinate as i64);
+    let corner = |dx: i64, dy: i64, dz: i64| {
+        let gradient = GRADIENTS[(hash([cell[0] + dx, cell[1] + dy, cell[2] + dz], seed) % 12) as usize];
+        gradient[0] * (offset[0] - dx as f64) + gradient[1] * (offset[1] - dy as f64) + gradient[2] * (offset[2] - dz as f64)
+    };
+    let (u, v, w) = (fade(offset[0]), fade(offset[1]), fade(offset[2]));
+    lerp(w,
+        lerp(v, lerp(u, corner(0, 0, 0), corner(1, 0, 0)), lerp(u, corner(0, 1, 0), corner(1, 1, 0))),
+        lerp(v, lerp(u, corner(0, 0, 1), corner(1, 0, 1)), lerp(u, corner(0, 1, 1), corner(1, 1, 1))))
+}
+
+// Cellular noise: the distance to the nearest of the feature points scattered one per lattice cell, mostly below 1
+pub fn worley(point: Point, seed: u64) -> f64 {
+    let cell = [point.x.floor() as i64, point.y.floor() as i64, point.z.floor() as i64];
+    let mut nearest = f64::INFINITY;
+    for dx in -1..=1 {
+        for dy in -1..=1 {
+            for dz in -1..=1 {
+                let neighbor = [cell[0] + dx, cell[1] + dy, cell[2] + dz];
+                let mut rng = Rng::new(hash(neighbor, seed));
+                let feature = Point::new(neighbor[0] as f64 + rng.next_f64(), neighbor[1] as f64 + rng.next_f64(), neighbor[2] as f64 + rng.next_f64());
+                nearest = nearest.min((feature - point).length_sq());
+            }
+        }
+    }
+    nearest.sqrt()
+}
@@ -0,0 +1,266 @@
+use std::error;
+use std::fmt;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+use serde::{Serialize, Deserialize};
+use image::{DynamicImage, RgbaImage, RgbImage};
+use crate::rendering::Color;
+
+#[derive(Debug, Clone)]
+pub struct OutputError {
+    pub message: String
+}
+
+impl OutputError {
+    pub fn new(message: String) -> OutputError {
+        OutputError { message }
+    }
+}
+
+impl fmt::Display for OutputError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl error::Error for OutputError {}
+
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Channels {
+    RGB,
+    RGBA
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum RenderingIntent {
+    PERCEPTUAL,
+    RELATIVE,
+    SATURATION,
+    ABSOLUTE
+}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Format {
+    PNG,
+    JPEG,
+    OTHER
+}
+
+impl Format {
+    pub fn from_path(path: &str) -> Format {
+        let extension = Path::new(path).extension().and_then(|e| e.to_str()).map(|e| e.to_ascii_lowercase());
+        match extension.as_deref() {
+            Some("png") => Format::PNG,
+            Some("jpg") | Some("jpeg") => Format::JPEG,
+            _ => Format::OTHER
+        }
+    }
+}
+
+fn default_bit_depth() -> u8 {
+    8
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct OutputConfig {
+    #[serde(default)]
+    pub channels: Option<Channels>,
+    #[serde(default = "default_bit_depth")]
+    pub bit_depth: u8,
+    #[serde(default)]
+    pub srgb_intent: Option<RenderingIntent>,
+    #[serde(default = "Color::black")]
+    pub background: Color
+}
+
+impl OutputConfig {
+    pub fn new(channels: Option<Channels>, bit_depth: u8, srgb_intent: Option<RenderingIntent>, background: Color) -> OutputConfig {
+        OutputConfig { channels, bit_depth, srgb_intent, background }
+    }
+
+    pub fn channels_for(&self, format: Format) -> Channels {
+        match (self.channels, format) {
+            (Some(channels), _) => channels,
+            (None, Format::JPEG) => Channels::RGB,
+            (None, _) => Channels::RGBA
+        }
+    }
+
+    pub fn validate(&self, path: &str) -> Result<(), OutputError> {
+        let format = Format::from_path(path);
+        if self.bit_depth != 8 && self.bit_depth != 16 {
+            return Err(OutputError::new(format!("unsupported bit depth {}, expected 8 or 16", self.bit_depth)));
+        }
+        if self.bit_depth == 16 && format != Format::PNG {
+            return Err(OutputError::new(format!("16 bit output is only supported for png files, not {}", path)));
+        }
+        if self.srgb_intent.is_some() && format != Format::PNG {
+            return Err(OutputError::new(format!("the sRGB chunk can only be embedded in png files, not {}", path)));
+        }
+        if format == Format::JPEG && self.channels_for(format) == Channels::RGBA {
+            return Err(OutputError::new(format!("jpeg files have no alpha channel, use RGB channels for {}", path)));
+        }
+        Ok(())
+    }
+}
+
+impl Default for OutputConfig {
+    fn default() -> OutputConfig {
+        OutputConfig { channels: None, bit_depth: default_bit_depth(), srgb_intent: None, background: Color::black() }
+    }
+}
+
+fn composite(image: &RgbaImage, background: Color) -> RgbImage {
+    let background = [background.r, background.g, background.b];
+    RgbImage::from_fn(image.width(), image.height(), |x, y| {
+        let pixel = image.get_pixel(x, y);
+        let alpha = pixel[3] as f64 / 255.0;
+        let mut composited = [0u8; 3];
+        for (channel, value) in composited.iter_mut().enumerate() {
+            *value = (pixel[channel] as f64 * alpha + background[channel] as f64 * (1.0 - alpha)).round() as u8;
+        }
+        image::Rgb(composited)
+    })
+}
+
+pub fn write_png(samples: &[u16], width: u32, height: u32, channels: Channels, config: &OutputConfig, metadata: &[(String, String)], path: &str) -> Result<(), Box<dyn error::Error>> {
+    let file = BufWriter::new(File::create(path)?);
+    let mut encoder = png::Encoder::new(file, width, height);
+    encoder.set_color(match channels {
+        Channels::RGB => png::ColorType::RGB,
+        Channels::RGBA => png::ColorType::RGBA
+    });
+    encoder.set_depth(if config.bit_depth == 16 { png::BitDepth::Sixteen } else { png::BitDepth::Eight });
+    let mut writer = encoder.write_header()?;
+    if let Some(intent) = config.srgb_intent {
+        writer.write_chunk(*b"sRGB", &[intent as u8])?;
+        writer.write_chunk(*b"gAMA", &45455u32.to_be_bytes())?;
+    }
+    for (keyword, text) in metadata {
+        let mut chunk = keyword.as_bytes().to_vec();
+        chunk.push(0);
+        chunk.extend_from_slice(text.as_bytes());
+        writer.write_chunk(*b"tEXt", &chunk)?;
+    }
+    if config.bit_depth == 16 {
+        let wide: Vec<u8> = samples.iter().flat_map(|sample| sample.to_be_bytes().to_vec()).collect();
+        writer.write_image_data(&wide)?;
+    } else {
+        let narrow: Vec<u8> = samples.iter().map(|sample| (sample >> 8) as u8).collect();
+        writer.write_image_data(&narrow)?;
+    }
+    Ok(())
+}
+
+fn widen(samples: &[u8]) -> Vec<u16> {
+    samples.iter().map(|&sample| sample as u16 * 257).collect()
+}
+
+pub fn write(image: &RgbaImage, path: &str, config: &OutputConfig, metadata: &[(String, String)]) -> Result<(), Box<dyn error::Error>> {
+    config.validate(path)?;
+    let format = Format::from_path(path);
+    let channels = config.channels_for(format);
+    match (format, channels) {
+        (Format::PNG, Channels::RGBA) => write_png(&widen(image), image.width(), image.height(), channels, config, metadata, path),
+        (Format::PNG, Channels::RGB) => write_png(&widen(&composite(image, config.background)), image.width(), image.height(), channels, config, metadata, path),
+        (_, Channels::RGBA) => Ok(image.save(path)?),
+        (_, Channels::RGB) => Ok(DynamicImage::ImageRgb8(composite(image, config.background)).save(path)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use image::Rgba;
+
+    fn image() -> RgbaImage {
+        RgbaImage::from_fn(5, 3, |x, y| Rgba([(x * 60) as u8, (y * 120) as u8, 7, if x == 0 { 0 } else { 255 - (y * 100) as u8 }]))
+    }
+
+    fn temporary(name: &str) -> String {
+        env::temp_dir().join(format!("rust_raytracer_{}_{}", std::process::id(), name)).to_string_lossy().to_string()
+    }
+
+    // Samples widened to 16 bits, with the color type, the bit depth and the whole file
+    fn decode(path: &str) -> (png::ColorType, png::BitDepth, Vec<u16>, Vec<u8>) {
+        let data = fs::read(path).unwrap();
+        fs::remove_file(path).unwrap();
+        let mut decoder = png::Decoder::new(data.as_slice());
+        decoder.set_transformations(png::Transformations::IDENTITY);
+        let (info, mut reader) = decoder.read_info().unwrap();
+        let mut buffer = vec![0; info.buffer_size()];
+        reader.next_frame(&mut buffer).unwrap();
+        let samples = match info.bit_depth {
+            png::BitDepth::Sixteen => buffer.chunks(2).map(|pair| u16::from_be_bytes([pair[0], pair[1]])).collect(),
+            _ => buffer.iter().map(|&sample| sample as u16).collect()
+        };
+        (info.color_type, info.bit_depth, samples, data)
+    }
+
+    fn contains(data: &[u8], bytes: &[u8]) -> bool {
+        data.windows(bytes.len()).any(|window| window == bytes)
+    }
+
+    #[test]
+    fn png_files_decode_to_what_was_written() {
+        let background = Color::new(0, 0, 255, 255);
+        let rgb: Vec<u8> = composite(&image(), background).into_raw();
+        assert_eq!(&rgb[..3], &[0, 0, 255]);
+        let cases = [
+            (Channels::RGBA, 8, png::ColorType::RGBA, png::BitDepth::Eight, image().into_raw(), 1),
+            (Channels::RGBA, 16, png::ColorType::RGBA, png::BitDepth::Sixteen, image().into_raw(), 257),
+            (Channels::RGB, 8, png::ColorType::RGB, png::BitDepth::Eight, rgb.clone(), 1),
+            (Channels::RGB, 16, png::ColorType::RGB, png::BitDepth::Sixteen, rgb, 257)
+        ];
+        for (index, (channels, bit_depth, color_type, depth, expected, scale)) in cases.iter().enumerate() {
+            let path = temporary(&format!("layout_{}.png", index));
+            let config = OutputConfig::new(Some(*channels), *bit_depth, None, background);
+            write(&image(), &path, &config, &[]).unwrap();
+            let (decoded_type, decoded_depth, samples, _) = decode(&path);
+            assert_eq!((decoded_type, decoded_depth), (*color_type, *depth), "{:?} {}", channels, bit_depth);
+            assert_eq!(samples, expected.iter().map(|&sample| sample as u16 * scale).collect::<Vec<_>>(), "{:?} {}", channels, bit_depth);
+        }
+    }
+
+    #[test]
+    fn png_files_carry_the_srgb_chunk_and_the_metadata() {
+        let path = temporary("chunks.png");
+        let config = OutputConfig::new(None, 8, Some(RenderingIntent::RELATIVE), Color::black());
+        write(&image(), &path, &config, &[("Samples".to_string(), "16".to_string())]).unwrap();
+        let (_, _, _, data) = decode(&path);
+        assert!(contains(&data, &[b's', b'R', b'G', b'B', RenderingIntent::RELATIVE as u8]));
+        assert!(contains(&data, b"gAMA"));
+        assert!(contains(&data, b"tEXtSamples\x0016"));
+        let path = temporary("plain.png");
+        write(&image(), &path, &OutputConfig::default(), &[]).unwrap();
+        let (color_type, _, _, data) = decode(&path);
+        assert_eq!(color_type, png::ColorType::RGBA);
+        assert!(!contains(&data, b"sRGB") && !contains(&data, b"tEXt"));
+    }
+
+    #[test]
+    fn jpeg_files_are_composited_over_the_background() {
+        let path = temporary("composite.jpg");
+        let transparent = RgbaImage::from_pixel(16, 8, Rgba([20, 20, 20, 0]));
+        write(&transparent, &path, &OutputConfig::new(None, 8, None, Color::white()), &[]).unwrap();
+        let decoded = image::open(&path).unwrap().to_rgb();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(decoded.dimensions(), (16, 8));
+        assert!(decoded.pixels().all(|pixel| pixel.0.iter().all(|&channel| channel > 250)));
+    }
+
+    #[test]
+    fn unsupported_layouts_are_refused() {
+        assert!(OutputConfig::new(None, 12, None, Color::black()).validate("a.png").unwrap_err().message.contains("bit depth 12"));
+        assert!(OutputConfig::new(None, 16, None, Color::black()).validate("a.jpg").unwrap_err().message.contains("only supported for png"));
+        assert!(OutputConfig::new(None, 8, Some(RenderingIntent::PERCEPTUAL), Color::black()).validate("a.bmp").is_err());
+        assert!(OutputConfig::new(Some(Channels::RGBA), 8, None, Color::black()).validate("a.jpeg").unwrap_err().message.contains("no alpha"));
+        assert_eq!(OutputConfig::default().channels_for(Format::JPEG), Channels::RGB);
+        assert_eq!(OutputConfig::default().channels_for(Format::PNG), Channels::RGBA);
+        assert_eq!(Format::from_path("render.PNG"), Format::PNG);
+    }
+}
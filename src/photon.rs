@@ -0,0 +1,251 @@
+use serde::{Serialize, Deserialize};
+use crate::shape::{Ray, RayType, Point};
+use crate::vertors::Vector3;
+use crate::rendering::{Scene, Light, PointLight, SHADOW_BIAS};
+use crate::random::Rng;
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Caustics {
+    pub enabled: bool,
+    pub photon_count: u32,
+    pub gather_radius: f64
+}
+
+impl Caustics {
+    pub fn new(enabled: bool, photon_count: u32, gather_radius: f64) -> Caustics {
+        Caustics { enabled, photon_count, gather_radius }
+    }
+}
+
+impl Default for Caustics {
+    fn default() -> Caustics {
+        Caustics { enabled: false, photon_count: 100000, gather_radius: 0.1 }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Photon {
+    pub position: Point,
+    pub direction: Vector3,
+    pub power: [f64; 3]
+}
+
+impl Photon {
+    pub fn new(position: Point, direction: Vector3, power: [f64; 3]) -> Photon {
+        Photon { position, direction, power }
+    }
+}
+
+fn axis_value(point: &Point, axis: usize) -> f64 {
+    match axis {
+        0 => point.x,
+        1 => point.y,
+        _ => point.z
+    }
+}
+
+fn build_kd_tree(photons: &mut [Photon], depth: usize) {
+    if photons.len() <= 1 {
+        return;
+    }
+    let axis = depth % 3;
+    photons.sort_by(|a, b| {
+        axis_value(&a.position, axis).partial_cmp(&axis_value(&b.position, axis)).unwrap_or(std::cmp::Ordering::Equal)
+    });
+    let median = photons.len() / 2;
+    let (left, right) = photons.split_at_mut(median);
+    build_kd_tree(left, depth + 1);
+    build_kd_tree(&mut right[1..], depth + 1);
+}
+
+fn gather_kd_tree<F: FnMut(&Photon)>(photons: &[Photon], depth: usize, point: &Point, radius_sq: f64, callback: &mut F) {
+    if photons.is_empty() {
+        return;
+    }
+    let axis = depth % 3;
+    let median = photons.len() / 2;
+    let photon = &photons[median];
+    if (photon.position - *point).length_sq() <= radius_sq {
+        callback(photon);
+    }
+    let delta = axis_value(point, axis) - axis_value(&photon.position, axis);
+    let (near, far) = if delta < 0.0 {
+        (&photons[..median], &photons[median + 1..])
+    } else {
+        (&photons[median + 1..], &photons[..median])
+    };
+    gather_kd_tree(near, depth + 1, point, radius_sq, callback);
+    if delta * delta <= radius_sq {
+        gather_kd_tree(far, depth + 1, point, radius_sq, callback);
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct PhotonMap {
+    photons: Vec<Photon>,
+    gather_radius: f64
+}
+
+impl PhotonMap {
+    pub fn new(mut photons: Vec<Photon>, gather_radius: f64) -> PhotonMap {
+        build_kd_tree(&mut photons, 0);
+        PhotonMap { photons, gather_radius }
+    }
+
+    pub fn photon_count(&self) -> usize {
+        self.photons.len()
+    }
+
+    pub fn irradiance(&self, point: Point, normal: Vector3) -> [f64; 3] {
+        let mut irradiance = [0.0, 0.0, 0.0];
+        let radius_sq = self.gather_radius * self.gather_radius;
+        gather_kd_tree(&self.photons, 0, &point, radius_sq, &mut |photon: &Photon| {
+            if photon.direction.dot(&normal) < 0.0 {
+                irradiance[0] += photon.power[0];
+                irradiance[1] += photon.power[1];
+                irradiance[2] += photon.power[2];
+            }
+        });
+        let area = std::f64::consts::PI * radius_sq;
+        [irradiance[0] / area, irradiance[1] / area, irradiance[2] / area]
+    }
+}
+
+fn random_sphere_direction(rng: &mut Rng) -> Vector3 {
+    let z = 1.0 - 2.0 * rng.next_f64();
+    let r = (1.0 - z * z).max(0.0).sqrt();
+    let phi = 2.0 * std::f64::consts::PI * rng.next_f64();
+    Vector3::new(r * phi.cos(), r * phi.sin(), z)
+}
+
+// Photons split at a surface like the rays of the renderer: the uncovered share of a partly opaque surface lets them
+// through, the rest is reflected by the Fresnel reflectance and refracted by the remaining transparency. One of the
+// possible paths is followed, picked in proportion to its share so the power carried stays the same on average
+fn trace_photon(scene: &Scene, mut ray: Ray, mut power: [f64; 3], max_depth: u8, rng: &mut Rng, photons: &mut Vec<Photon>) {
+    let mut specular = false;
+    for _ in 0..max_depth {
+        let (renderable, hit) = match scene.trace(&ray, RayType::SHADOW) {
+            Some(obj) => obj,
+            None => return
+        };
+        let material = hit.material.as_ref().unwrap_or(&renderable.material);
+        let reflectance = material.reflectance(ray.direction, &hit);
+        // The shading of the surface takes its own share of the photons it is lit by
+        if specular && reflectance < 1.0 && material.transparency < 1.0 && material.opacity > 0.0 {
+            photons.push(Photon::new(hit.point, ray.direction, power));
+        }
+        let tint = material.reflection_tint(material.color_at(&hit));
+        let transmission = material.transmission(&hit);
+        let covered = material.opacity;
+        let mut paths: Vec<(Ray, [f64; 3], bool)> = Vec::with_capacity(3);
+        if covered < 1.0 {
+            paths.push((Ray::compute_pass_through_ray(&hit, ray.direction), [1.0 - covered; 3], specular));
+        }
+        if reflectance > 0.0 {
+            paths.push((Ray::compute_reflection_ray(&hit, ray.direction), tint.map(|channel| covered * reflectance * channel), true));
+        }
+        if material.transparency > 0.0 && reflectance < 1.0 {
+            // Total internal reflection keeps the photon inside the material
+            let transmitted = Ray::compute_refraction_ray(&hit, ray.direction, material.ior).unwrap_or_else(|| Ray::compute_reflection_ray(&hit, ray.direction));
+            paths.push((transmitted, transmission.map(|channel| covered * (1.0 - reflectance) * channel), true));
+        }
+        let share = |weights: &[f64; 3]| (weights[0] + weights[1] + weights[2]) / 3.0;
+        let total: f64 = paths.iter().map(|(_, weights, _)| share(weights)).sum();
+        if total <= 0.0 {
+            return;
+        }
+        let mut target = if paths.len() > 1 { rng.next_f64() * total } else { 0.0 };
+        let (next, weights, next_specular) = paths.iter().copied()
+            .find(|(_, weights, _)| {
+                target -= share(weights);
+                target < 0.0 && share(weights) > 0.0
+            })
+            .unwrap_or_else(|| *paths.iter().rev().find(|(_, weights, _)| share(weights) > 0.0).unwrap());
+        let probability = share(&weights) / total;
+        power = [power[0] * weights[0] / probability, power[1] * weights[1] / probability, power[2] * weights[2] / probability];
+        ray = next.with_time(ray.time);
+        specular = next_specular;
+    }
+}
+
+pub fn build_photon_map(scene: &Scene, max_depth: u8) -> PhotonMap {
+    // Sphere lights emit their photons from the center
+    let point_lights: Vec<PointLight> = scene.flat_lights.iter().filter_map(|light| match light {
+        Light::POINT(l) => Some(l.clone()),
+        Light::SPHERE(l) => Some(PointLight::new(l.center, l.brightness, l.color)),
+        _ => None
+    }).collect();
+    let total_brightness: f64 = point_lights.iter().map(|l| l.brightness).sum();
+    let mut photons = Vec::new();
+    if total_brightness <= 0.0 {
+        return PhotonMap::new(photons, scene.caustics.gather_radius);
+    }
+
+    let mut rng = Rng::new(scene.seed);
+    for light in point_lights {
+        let emitted = ((scene.caustics.photon_count as f64) * light.brightness / total_brightness).round() as u32;
+        if emitted == 0 {
+            continue;
+        }
+        let photon_power = light.brightness / (emitted as f64);
+        let power = [
+            photon_power * (light.color.r as f64) / 255.0,
+            photon_power * (light.color.g as f64) / 255.0,
+            photon_power * (light.color.b as f64) / 255.0
+        ];
+        for _ in 0..emitted {
+            let direction = random_sphere_direction(&mut rng);
+            // The map is built once, moving elements cast their caustics where they are when the shutter opens
+            let ray = Ray::new(light.position + direction * SHADOW_BIAS, direction).with_time(scene.camera.shutter_open);
+            trace_photon(scene, ray, power, max_depth, &mut rng, &mut photons);
+        }
+    }
+    PhotonMap::new(photons, scene.caustics.gather_radius)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rendering::{Camera, Color, Fresnel, Material, Renderable};
+    use crate::shape::{Plane, Shape, Sphere};
+
+    // A ball a little above a white floor, under a lamp high over it
+    fn scene(ball: Material) -> Scene {
+        let floor = Renderable::new(Shape::PLANE(Plane::new(Vector3::zero(), Vector3::new(0.0, 1.0, 0.0))), Material::new(Color::white(), 0.8, 0.0));
+        let ball = Renderable::new(Shape::SPHERE(Sphere::new(Vector3::new(0.0, 2.0, 0.0), 1.0)), ball);
+        let lamp = Light::POINT(PointLight::new(Vector3::new(0.0, 10.0, 0.0), 1000.0, Color::white()));
+        let mut scene = Scene::new(Camera::new(8, 8, 60.0), vec![floor, ball], vec![lamp], Color::black());
+        scene.caustics = Caustics::new(true, 200000, 0.25);
+        scene.prepare().unwrap();
+        scene
+    }
+
+    fn glass() -> Material {
+        Material { transparency: 1.0, ior: 1.5, fresnel: Fresnel::DIELECTRIC, ..Material::new(Color::white(), 0.0, 0.0) }
+    }
+
+    #[test]
+    fn glass_ball_focuses_photons_under_it() {
+        let map = build_photon_map(&scene(glass()), 8);
+        let up = Vector3::new(0.0, 1.0, 0.0);
+        let focus = map.irradiance(Vector3::new(0.0, 0.0, 0.0), up)[0];
+        let aside = map.irradiance(Vector3::new(1.5, 0.0, 0.0), up)[0];
+        assert!(focus > 10.0 * aside, "focus {} aside {}", focus, aside);
+    }
+
+    #[test]
+    fn opaque_ball_leaves_no_caustic() {
+        let map = build_photon_map(&scene(Material::new(Color::white(), 0.8, 0.0)), 8);
+        assert_eq!(map.photon_count(), 0);
+    }
+
+    #[test]
+    fn single_path_keeps_the_power_deterministic() {
+        let mirror = scene(Material::new(Color::white(), 0.8, 0.5));
+        let (first, second) = (build_photon_map(&mirror, 8), build_photon_map(&mirror, 8));
+        assert!(first.photon_count() > 0);
+        assert!(first.photons.iter().all(|photon| (photon.power[0] - first.photons[0].power[0]).abs() < 1e-12));
+        assert_eq!(first.photon_count(), second.photon_count());
+    }
+}
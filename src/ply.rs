@@ -0,0 +1,248 @@
+use std::convert::TryInto;
+use std::error;
+use std::fmt;
+use std::fs;
+use crate::shape::Point;
+use crate::vertors::Vector3;
+use crate::rendering::Color;
+
+#[derive(Debug, Clone)]
+pub struct PlyError {
+    pub file: String,
+    pub message: String
+}
+
+impl PlyError {
+    pub fn new(file: &str, message: String) -> PlyError {
+        PlyError { file: file.to_string(), message }
+    }
+}
+
+impl fmt::Display for PlyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.file, self.message)
+    }
+}
+
+impl error::Error for PlyError {}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Scalar {
+    I8,
+    U8,
+    I16,
+    U16,
+    I32,
+    U32,
+    F32,
+    F64
+}
+
+impl Scalar {
+    fn parse(name: &str) -> Option<Scalar> {
+        match name {
+            "char" | "int8" => Some(Scalar::I8),
+            "uchar" | "uint8" => Some(Scalar::U8),
+            "short" | "int16" => Some(Scalar::I16),
+            "ushort" | "uint16" => Some(Scalar::U16),
+            "int" | "int32" => Some(Scalar::I32),
+            "uint" | "uint32" => Some(Scalar::U32),
+            "float" | "float32" => Some(Scalar::F32),
+            "double" | "float64" => Some(Scalar::F64),
+            _ => None
+        }
+    }
+
+    fn size(&self) -> usize {
+        match self {
+            Scalar::I8 | Scalar::U8 => 1,
+            Scalar::I16 | Scalar::U16 => 2,
+            Scalar::I32 | Scalar::U32 | Scalar::F32 => 4,
+            Scalar::F64 => 8
+        }
+    }
+
+    fn decode(&self, bytes: &[u8]) -> f64 {
+        match self {
+            Scalar::I8 => bytes[0] as i8 as f64,
+            Scalar::U8 => bytes[0] as f64,
+            Scalar::I16 => i16::from_le_bytes(bytes.try_into().unwrap()) as f64,
+            Scalar::U16 => u16::from_le_bytes(bytes.try_into().unwrap()) as f64,
+            Scalar::I32 => i32::from_le_bytes(bytes.try_into().unwrap()) as f64,
+            Scalar::U32 => u32::from_le_bytes(bytes.try_into().unwrap()) as f64,
+            Scalar::F32 => f32::from_le_bytes(bytes.try_into().unwrap()) as f64,
+            Scalar::F64 => f64::from_le_bytes(bytes.try_into().unwrap())
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Kind {
+    Scalar(Scalar),
+    List(Scalar, Scalar),
+    Unknown // Only skippable in ASCII files, where it is a single value
+}
+
+#[derive(Clone, Debug)]
+struct Property {
+    name: String,
+    kind: Kind
+}
+
+#[derive(Clone, Debug)]
+struct Element {
+    name: String,
+    count: usize,
+    properties: Vec<Property>
+}
+
+// Values of the body, one token or binary value at a time
+enum Reader<'a> {
+    Ascii(std::str::SplitWhitespace<'a>),
+    Binary(&'a [u8])
+}
+
+impl<'a> Reader<'a> {
+    fn read(&mut self, scalar: Scalar, file: &str) -> Result<f64, PlyError> {
+        match self {
+            Reader::Ascii(tokens) => {
+                let token = tokens.next().ok_or_else(|| PlyError::new(file, "unexpected end of file".to_string()))?;
+                token.parse().map_err(|_| PlyError::new(file, format!("invalid number \"{}\"", token)))
+            },
+            Reader::Binary(bytes) => {
+                if bytes.len() < scalar.size() {
+                    return Err(PlyError::new(file, "unexpected end of file".to_string()));
+                }
+                let (value, rest) = bytes.split_at(scalar.size());
+                *bytes = rest;
+                Ok(scalar.decode(value))
+            }
+        }
+    }
+
+    fn read_property(&mut self, property: &Property, file: &str) -> Result<Vec<f64>, PlyError> {
+        match (&property.kind, self) {
+            (Kind::Scalar(scalar), reader) => Ok(vec![reader.read(*scalar, file)?]),
+            (Kind::List(count, item), reader) => {
+                let count = reader.read(*count, file)? as usize;
+                (0..count).map(|_| reader.read(*item, file)).collect()
+            },
+            (Kind::Unknown, Reader::Ascii(tokens)) => {
+                tokens.next();
+                Ok(Vec::new())
+            },
+            (Kind::Unknown, Reader::Binary(_)) => Err(PlyError::new(file, format!("cannot skip property \"{}\" of unknown type in a binary file", property.name)))
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Ply {
+    pub vertices: Vec<Point>,
+    pub indices: Vec<[usize; 3]>,
+    pub colors: Vec<Color>
+}
+
+impl Ply {
+    pub fn load(file: &str) -> Result<Ply, PlyError> {
+        let content = fs::read(file).map_err(|e| PlyError::new(file, e.to_string()))?;
+        Ply::parse(&content, file)
+    }
+
+    pub fn parse(content: &[u8], file: &str) -> Result<Ply, PlyError> {
+        // The body starts after the line break ending the header, which may be a Windows one
+        let header_end = content.windows(10).position(|window| window == b"end_header")
+            .ok_or_else(|| PlyError::new(file, "missing end_header".to_string()))?;
+        let header = std::str::from_utf8(&content[..header_end]).map_err(|_| PlyError::new(file, "header is not valid UTF-8".to_string()))?;
+        let body_start = content[header_end..].iter().position(|&byte| byte == b'\n').map_or(content.len(), |end| header_end + end + 1);
+        let body = &content[body_start..];
+
+        let mut lines = header.lines().map(|line| line.trim()).filter(|line| !line.is_empty());
+        if lines.next() != Some("ply") {
+            return Err(PlyError::new(file, "not a PLY file".to_string()));
+        }
+        let mut binary = None;
+        let mut elements: Vec<Element> = Vec::new();
+        for line in lines {
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            match parts[0] {
+                "format" => binary = Some(match parts.get(1) {
+                    Some(&"ascii") => false,
+                    Some(&"binary_little_endian") => true,
+                    format => return Err(PlyError::new(file, format!("unsupported format {}", format.unwrap_or(&"")))),
+                }),
+                "element" => {
+                    let count = parts.get(2).and_then(|count| count.parse().ok())
+                        .ok_or_else(|| PlyError::new(file, format!("invalid element \"{}\"", line)))?;
+                    elements.push(Element { name: parts.get(1).unwrap_or(&"").to_string(), count, properties: Vec::new() });
+                },
+                "property" => {
+                    let element = elements.last_mut().ok_or_else(|| PlyError::new(file, "property before any element".to_string()))?;
+                    let (kind, name) = if parts.get(1) == Some(&"list") && parts.len() >= 5 {
+                        match (Scalar::parse(parts[2]), Scalar::parse(parts[3])) {
+                            (Some(count), Some(item)) => (Kind::List(count, item), parts[4]),
+                            _ => (Kind::Unknown, parts[4])
+                        }
+                    } else if parts.len() >= 3 {
+                        (Scalar::parse(parts[1]).map_or(Kind::Unknown, Kind::Scalar), parts[2])
+                    } else {
+                        return Err(PlyError::new(file, format!("invalid property \"{}\"", line)));
+                    };
+                    if let Kind::Unknown = kind {
+                        eprintln!("warning: {}: skipping property \"{}\" of unsupported type", file, name);
+                    }
+                    element.properties.push(Property { name: name.to_string(), kind });
+                },
+                _ => {} // Comments and obj_info
+            }
+        }
+        let mut reader = match binary {
+            Some(true) => Reader::Binary(body),
+            Some(false) => Reader::Ascii(std::str::from_utf8(body).map_err(|_| PlyError::new(file, "body is not valid UTF-8".to_string()))?.split_whitespace()),
+            None => return Err(PlyError::new(file, "missing format".to_string()))
+        };
+
+        let mut ply = Ply::default();
+        for element in elements.iter() {
+            match element.name.as_str() {
+                "vertex" | "face" => {},
+                name => eprintln!("warning: {}: skipping {} element(s) \"{}\"", file, element.count, name)
+            }
+            let position = |name: &str| element.properties.iter().position(|property| property.name == name);
+            let coordinates = [position("x"), position("y"), position("z")];
+            let channels = [position("red").or_else(|| position("diffuse_red")), position("green").or_else(|| position("diffuse_green")), position("blue").or_else(|| position("diffuse_blue"))];
+            let has_colors = channels.iter().all(Option::is_some);
+            // Integer channels go up to 255, floating point ones up to 1
+            let color_scale = match channels[0].map(|index| &element.properties[index].kind) {
+                Some(Kind::Scalar(Scalar::F32)) | Some(Kind::Scalar(Scalar::F64)) => 255.0,
+                _ => 1.0
+            };
+            let corners = position("vertex_indices").or_else(|| position("vertex_index"));
+            for _ in 0..element.count {
+                let values = element.properties.iter().map(|property| reader.read_property(property, file)).collect::<Result<Vec<_>, _>>()?;
+                let value = |index: Option<usize>| index.and_then(|index| values[index].first().copied()).unwrap_or(0.0);
+                if element.name == "vertex" {
+                    ply.vertices.push(Vector3::new(value(coordinates[0]), value(coordinates[1]), value(coordinates[2])));
+                    if has_colors {
+                        let channel = |index: Option<usize>| (value(index) * color_scale).round().clamp(0.0, 255.0) as u8;
+                        ply.colors.push(Color::new(channel(channels[0]), channel(channels[1]), channel(channels[2]), 255));
+                    }
+                } else if let (true, Some(corners)) = (element.name == "face", corners) {
+                    let face = &values[corners];
+                    // Polygons are split in a fan around their first vertex like in OBJ files
+                    for corner in 1..face.len().saturating_sub(1) {
+                        ply.indices.push([face[0] as usize, face[corner] as usize, face[corner + 1] as usize]);
+                    }
+                }
+            }
+        }
+
+        if let Some(index) = ply.indices.iter().flatten().find(|&&index| index >= ply.vertices.len()) {
+            return Err(PlyError::new(file, format!("vertex index {} is out of range ({} defined)", index, ply.vertices.len())));
+        }
+        if ply.indices.is_empty() {
+            return Err(PlyError::new(file, "no faces".to_string()));
+        }
+        Ok(ply)
+    }
+}
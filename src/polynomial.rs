@@ -0,0 +1,172 @@
+use std::f64::consts::PI;
+
+const EPSILON: f64 = 1e-9;
+
+fn is_zero(value: f64) -> bool {
+    value.abs() < EPSILON
+}
+
+// Real roots of c[0] + c[1] x + c[2] x^2, following Jochen Schwarze's solvers from Graphics Gems
+pub fn solve_quadratic(c: [f64; 3]) -> Vec<f64> {
+    if is_zero(c[2]) {
+        return if is_zero(c[1]) { Vec::new() } else { vec![-c[0] / c[1]] };
+    }
+    let p = c[1] / (2.0 * c[2]);
+    let q = c[0] / c[2];
+    let discriminant = p * p - q;
+    if is_zero(discriminant) {
+        vec![-p]
+    } else if discriminant < 0.0 {
+        Vec::new()
+    } else {
+        let root = discriminant.sqrt();
+        vec![root - p, -root - p]
+    }
+}
+
+pub fn solve_cubic(c: [f64; 4]) -> Vec<f64> {
+    if is_zero(c[3]) {
+        return solve_quadratic([c[0], c[1], c[2]]);
+    }
+    let a = c[2] / c[3];
+    let b = c[1] / c[3];
+    let constant = c[0] / c[3];
+    let a_squared = a * a;
+    let p = (-a_squared / 3.0 + b) / 3.0;
+    let q = (2.0 / 27.0 * a * a_squared - a * b / 3.0 + constant) / 2.0;
+    let p_cubed = p * p * p;
+    let discriminant = q * q + p_cubed;
+    let mut roots = if is_zero(discriminant) {
+        if is_zero(q) {
+            vec![0.0]
+        } else {
+            let u = (-q).cbrt();
+            vec![2.0 * u, -u]
+        }
+    } else if discriminant < 0.0 {
+        let phi = (-q / (-p_cubed).sqrt()).clamp(-1.0, 1.0).acos() / 3.0;
+        let t = 2.0 * (-p).sqrt();
+        vec![t * phi.cos(), -t * (phi + PI / 3.0).cos(), -t * (phi - PI / 3.0).cos()]
+    } else {
+        let root = discriminant.sqrt();
+        vec![(root - q).cbrt() - (root + q).cbrt()]
+    };
+    for root in roots.iter_mut() {
+        *root -= a / 3.0;
+    }
+    roots
+}
+
+fn evaluate(c: &[f64; 5], x: f64) -> (f64, f64) {
+    let value = (((c[4] * x + c[3]) * x + c[2]) * x + c[1]) * x + c[0];
+    let derivative = ((4.0 * c[4] * x + 3.0 * c[3]) * x + 2.0 * c[2]) * x + c[1];
+    (value, derivative)
+}
+
+// Ferrari's method, each root is then polished with a few Newton steps on the original polynomial
+pub fn solve_quartic(c: [f64; 5]) -> Vec<f64> {
+    if is_zero(c[4]) {
+        return solve_cubic([c[0], c[1], c[2], c[3]]);
+    }
+    let a = c[3] / c[4];
+    let b = c[2] / c[4];
+    let linear = c[1] / c[4];
+    let constant = c[0] / c[4];
+    let a_squared = a * a;
+    let p = -3.0 / 8.0 * a_squared + b;
+    let q = a_squared * a / 8.0 - a * b / 2.0 + linear;
+    let r = -3.0 / 256.0 * a_squared * a_squared + a_squared * b / 16.0 - a * linear / 4.0 + constant;
+    let mut roots = if is_zero(r) {
+        let mut roots = solve_cubic([q, p, 0.0, 1.0]);
+        roots.push(0.0);
+        roots
+    } else {
+        let z = solve_cubic([r * p / 2.0 - q * q / 8.0, -r, -p / 2.0, 1.0])[0];
+        let u = z * z - r;
+        let v = 2.0 * z - p;
+        let u = if is_zero(u) { 0.0 } else if u > 0.0 { u.sqrt() } else { return Vec::new() };
+        let v = if is_zero(v) { 0.0 } else if v > 0.0 { v.sqrt() } else { return Vec::new() };
+        let mut roots = solve_quadratic([z - u, if q < 0.0 { -v } else { v }, 1.0]);
+        roots.extend(solve_quadratic([z + u, if q < 0.0 { v } else { -v }, 1.0]));
+        roots
+    };
+    for root in roots.iter_mut() {
+        *root -= a / 4.0;
+        for _ in 0..3 {
+            let (value, derivative) = evaluate(&c, *root);
+            if derivative.abs() < EPSILON {
+                break;
+            }
+            *root -= value / derivative;
+        }
+    }
+    roots.retain(|root| root.is_finite());
+    roots
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Coefficients of (x - r0)(x - r1)(x - r2)(x - r3), lowest degree first
+    fn with_roots(roots: [f64; 4]) -> [f64; 5] {
+        let mut c = [1.0, 0.0, 0.0, 0.0, 0.0];
+        for (degree, root) in roots.iter().enumerate() {
+            for index in (0..=degree + 1).rev() {
+                c[index] = if index > 0 { c[index - 1] } else { 0.0 } - root * c[index];
+            }
+        }
+        c
+    }
+
+    fn assert_roots(c: [f64; 5], expected: &[f64], tolerance: f64) {
+        let found = solve_quartic(c);
+        assert!(found.iter().all(|root| root.is_finite()), "{:?}", found);
+        for root in expected {
+            assert!(found.iter().any(|found| (found - root).abs() < tolerance), "{} missing from {:?}", root, found);
+        }
+        for root in found.iter() {
+            assert!(expected.iter().any(|expected| (expected - root).abs() < tolerance), "{} is not a root of {:?}", root, c);
+        }
+    }
+
+    #[test]
+    fn expanded_roots_give_the_coefficients() {
+        assert_eq!(with_roots([1.0, 2.0, 3.0, 4.0]), [24.0, -50.0, 35.0, -10.0, 1.0]);
+    }
+
+    #[test]
+    fn quartics_find_their_distinct_roots() {
+        assert_roots(with_roots([1.0, 2.0, 3.0, 4.0]), &[1.0, 2.0, 3.0, 4.0], 1e-9);
+        assert_roots(with_roots([-7.5, -0.25, 0.5, 12.0]), &[-7.5, -0.25, 0.5, 12.0], 1e-9);
+        // Scaled coefficients have the same roots
+        assert_roots(with_roots([1.0, 2.0, 3.0, 4.0]).map(|c| c * 1e-3), &[1.0, 2.0, 3.0, 4.0], 1e-9);
+    }
+
+    #[test]
+    fn quartics_find_their_repeated_roots() {
+        assert_roots(with_roots([1.0, 1.0, 3.0, 4.0]), &[1.0, 3.0, 4.0], 1e-6);
+        assert_roots(with_roots([-1.0, -1.0, 2.0, 2.0]), &[-1.0, 2.0], 1e-6);
+        assert_roots(with_roots([0.0, 0.0, 0.0, 5.0]), &[0.0, 5.0], 1e-4);
+    }
+
+    // Roots 1e-6 apart move by the square root of the rounding, they must still be found and not dropped as complex
+    #[test]
+    fn quartics_find_their_near_double_roots() {
+        assert_roots(with_roots([1.0, 1.0 + 1e-6, 3.0, 5.0]), &[1.0, 1.0 + 1e-6, 3.0, 5.0], 1e-5);
+        assert_roots(with_roots([-2.0, 0.5, 0.5 + 1e-7, 4.0]), &[-2.0, 0.5, 4.0], 1e-5);
+    }
+
+    #[test]
+    fn quartics_without_real_roots_give_none() {
+        // (x² + 1)(x² + 4) and (x² + 1)(x - 2)(x - 5)
+        assert!(solve_quartic([4.0, 0.0, 5.0, 0.0, 1.0]).is_empty());
+        assert_roots([10.0, -7.0, 11.0, -7.0, 1.0], &[2.0, 5.0], 1e-9);
+    }
+
+    #[test]
+    fn lower_degrees_fall_back_to_their_solvers() {
+        assert_roots([-6.0, 11.0, -6.0, 1.0, 0.0], &[1.0, 2.0, 3.0], 1e-9);
+        assert_roots([-4.0, 0.0, 1.0, 0.0, 0.0], &[-2.0, 2.0], 1e-9);
+    }
+}
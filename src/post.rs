@@ -0,0 +1,342 @@
+use std::error;
+use std::fmt;
+use std::fs;
+use serde::{Serialize, Deserialize};
+use image::RgbaImage;
+use crate::assets::Asset;
+
+#[derive(Debug, Clone)]
+pub struct LutError {
+    pub file: String,
+    pub line: usize,
+    pub message: String
+}
+
+impl LutError {
+    pub fn new(file: &str, line: usize, message: String) -> LutError {
+        LutError { file: file.to_string(), line, message }
+    }
+}
+
+impl fmt::Display for LutError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.line > 0 {
+            write!(f, "{}:{}: {}", self.file, self.line, self.message)
+        } else {
+            write!(f, "{}: {}", self.file, self.message)
+        }
+    }
+}
+
+impl error::Error for LutError {}
+
+#[derive(Clone, Debug)]
+pub struct Lut {
+    pub title: Option<String>,
+    pub size: usize,
+    pub domain_min: [f64; 3],
+    pub domain_max: [f64; 3],
+    pub table: Vec<[f64; 3]>
+}
+
+fn parse_triplet(parts: &[&str], file: &str, line: usize) -> Result<[f64; 3], LutError> {
+    if parts.len() != 3 {
+        return Err(LutError::new(file, line, format!("expected 3 values, found {}", parts.len())));
+    }
+    let mut values = [0.0; 3];
+    for (value, part) in values.iter_mut().zip(parts.iter()) {
+        *value = part.parse().map_err(|_| LutError::new(file, line, format!("invalid number \"{}\"", part)))?;
+    }
+    Ok(values)
+}
+
+impl Lut {
+    pub fn parse(content: &str, file: &str) -> Result<Lut, LutError> {
+        let mut title = None;
+        let mut size = 0;
+        let mut domain_min = [0.0; 3];
+        let mut domain_max = [1.0; 3];
+        let mut table = Vec::new();
+
+        for (index, line) in content.lines().enumerate() {
+            let line_number = index + 1;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            match parts[0] {
+                "TITLE" => title = Some(line["TITLE".len()..].trim().trim_matches('"').to_string()),
+                "LUT_3D_SIZE" => {
+                    if parts.len() != 2 {
+                        return Err(LutError::new(file, line_number, "LUT_3D_SIZE expects one value".to_string()));
+                    }
+                    size = parts[1].parse().map_err(|_| LutError::new(file, line_number, format!("invalid LUT_3D_SIZE \"{}\"", parts[1])))?;
+                    if !(2..=256).contains(&size) {
+                        return Err(LutError::new(file, line_number, format!("LUT_3D_SIZE must be between 2 and 256, found {}", size)));
+                    }
+                },
+                "DOMAIN_MIN" => domain_min = parse_triplet(&parts[1..], file, line_number)?,
+                "DOMAIN_MAX" => domain_max = parse_triplet(&parts[1..], file, line_number)?,
+                "LUT_1D_SIZE" => return Err(LutError::new(file, line_number, "1D LUTs are not supported".to_string())),
+                keyword if keyword.starts_with(|c: char| c.is_ascii_alphabetic()) => {
+                    return Err(LutError::new(file, line_number, format!("unknown keyword \"{}\"", keyword)));
+                },
+                _ => {
+                    if size == 0 {
+                        return Err(LutError::new(file, line_number, "table data before LUT_3D_SIZE".to_string()));
+                    }
+                    if table.len() == size * size * size {
+                        return Err(LutError::new(file, line_number, format!("more than {} table entries", size * size * size)));
+                    }
+                    table.push(parse_triplet(&parts, file, line_number)?);
+                }
+            }
+        }
+
+        if size == 0 {
+            return Err(LutError::new(file, 0, "missing LUT_3D_SIZE".to_string()));
+        }
+        if table.len() != size * size * size {
+            return Err(LutError::new(file, 0, format!("expected {} table entries, found {}", size * size * size, table.len())));
+        }
+        for axis in 0..3 {
+            if domain_max[axis] <= domain_min[axis] {
+                return Err(LutError::new(file, 0, "DOMAIN_MAX must be greater than DOMAIN_MIN".to_string()));
+            }
+        }
+        Ok(Lut { title, size, domain_min, domain_max, table })
+    }
+
+    pub fn load(file: &str) -> Result<Lut, LutError> {
+        let content = fs::read_to_string(file).map_err(|e| LutError::new(file, 0, e.to_string()))?;
+        Lut::parse(&content, file)
+    }
+
+    fn entry(&self, r: usize, g: usize, b: usize) -> [f64; 3] {
+        self.table[r + self.size * (g + self.size * b)]
+    }
+
+    pub fn apply(&self, color: [f64; 3]) -> [f64; 3] {
+        let max_index = (self.size - 1) as f64;
+        let mut base = [0; 3];
+        let mut fraction = [0.0; 3];
+        for axis in 0..3 {
+            let normalized = ((color[axis] - self.domain_min[axis]) / (self.domain_max[axis] - self.domain_min[axis])).clamp(0.0, 1.0);
+            let position = normalized * max_index;
+            base[axis] = (position.floor() as usize).min(self.size - 2);
+            fraction[axis] = position - base[axis] as f64;
+        }
+        let mut result = [0.0; 3];
+        for corner in 0..8 {
+            let offset = [corner & 1, (corner >> 1) & 1, (corner >> 2) & 1];
+            let mut weight = 1.0;
+            for axis in 0..3 {
+                weight *= if offset[axis] == 1 { fraction[axis] } else { 1.0 - fraction[axis] };
+            }
+            if weight == 0.0 {
+                continue;
+            }
+            let value = self.entry(base[0] + offset[0], base[1] + offset[1], base[2] + offset[2]);
+            for axis in 0..3 {
+                result[axis] += value[axis] * weight;
+            }
+        }
+        result
+    }
+}
+
+fn default_one() -> f64 {
+    1.0
+}
+
+const MIDDLE_GRAY: f64 = 0.18;
+
+pub fn grade(color: [f64; 3], brightness: f64, contrast: f64, saturation: f64) -> [f64; 3] {
+    let mut graded = [0.0; 3];
+    for channel in 0..3 {
+        graded[channel] = (color[channel] + brightness - MIDDLE_GRAY) * contrast + MIDDLE_GRAY;
+    }
+    let luminance = 0.2126 * graded[0] + 0.7152 * graded[1] + 0.0722 * graded[2];
+    for value in graded.iter_mut() {
+        *value = luminance + (*value - luminance) * saturation;
+    }
+    graded
+}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum PostEffect {
+    LUT {
+        file: String,
+        #[serde(skip)]
+        lut: Option<Lut>
+    },
+    GRADE {
+        #[serde(default)]
+        brightness: f64,
+        #[serde(default = "default_one")]
+        contrast: f64,
+        #[serde(default = "default_one")]
+        saturation: f64
+    }
+}
+
+impl Asset for PostEffect {
+    fn name(&self) -> &str {
+        match self {
+            PostEffect::LUT { file, .. } => file,
+            PostEffect::GRADE { .. } => "color grade"
+        }
+    }
+
+    fn load(&mut self) -> Result<(), Box<dyn error::Error>> {
+        match self {
+            PostEffect::LUT { file, lut } => *lut = Some(Lut::load(file)?),
+            PostEffect::GRADE { .. } => {}
+        }
+        Ok(())
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            PostEffect::LUT { file, lut: Some(lut) } => format!("Using LUT: {} ({}x{}x{})", lut.title.as_deref().unwrap_or(file), lut.size, lut.size, lut.size),
+            PostEffect::LUT { file, lut: None } => format!("Using LUT: {} (not loaded)", file),
+            PostEffect::GRADE { brightness, contrast, saturation } => format!("Using color grade: brightness {}, contrast {}, saturation {}", brightness, contrast, saturation)
+        }
+    }
+}
+
+impl PostEffect {
+    pub fn apply(&self, image: &mut RgbaImage) {
+        match self {
+            PostEffect::LUT { lut: Some(lut), .. } => {
+                for pixel in image.pixels_mut() {
+                    let graded = lut.apply([pixel[0] as f64 / 255.0, pixel[1] as f64 / 255.0, pixel[2] as f64 / 255.0]);
+                    for channel in 0..3 {
+                        pixel[channel] = (graded[channel] * 255.0).round().clamp(0.0, 255.0) as u8;
+                    }
+                }
+            },
+            PostEffect::LUT { lut: None, .. } => {},
+            PostEffect::GRADE { brightness, contrast, saturation } => {
+                if *brightness == 0.0 && *contrast == 1.0 && *saturation == 1.0 {
+                    return;
+                }
+                for pixel in image.pixels_mut() {
+                    let graded = grade([pixel[0] as f64 / 255.0, pixel[1] as f64 / 255.0, pixel[2] as f64 / 255.0], *brightness, *contrast, *saturation);
+                    for channel in 0..3 {
+                        pixel[channel] = (graded[channel] * 255.0).round().clamp(0.0, 255.0) as u8;
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgba;
+
+    fn cube(size: usize, map: impl Fn([f64; 3]) -> [f64; 3]) -> String {
+        let mut content = format!("TITLE \"test\"\n# red changes fastest\nLUT_3D_SIZE {}\n", size);
+        for b in 0..size {
+            for g in 0..size {
+                for r in 0..size {
+                    let value = map([r as f64 / (size - 1) as f64, g as f64 / (size - 1) as f64, b as f64 / (size - 1) as f64]);
+                    content.push_str(&format!("{} {} {}\n", value[0], value[1], value[2]));
+                }
+            }
+        }
+        content
+    }
+
+    fn every_color() -> RgbaImage {
+        RgbaImage::from_fn(256, 256, |x, y| Rgba([x as u8, y as u8, (x * 7 + y * 13) as u8, (x ^ y) as u8]))
+    }
+
+    fn lut_effect(content: &str) -> PostEffect {
+        PostEffect::LUT { file: "test.cube".to_string(), lut: Some(Lut::parse(content, "test.cube").unwrap()) }
+    }
+
+    #[test]
+    fn identity_luts_leave_images_unchanged() {
+        for size in [2, 17, 33] {
+            let mut image = every_color();
+            lut_effect(&cube(size, |color| color)).apply(&mut image);
+            assert_eq!(image.into_raw(), every_color().into_raw(), "size {}", size);
+        }
+    }
+
+    #[test]
+    fn colors_between_entries_are_interpolated() {
+        let lut = Lut::parse(&cube(2, |[r, g, b]| [1.0 - r, g * 0.5, b]), "invert.cube").unwrap();
+        assert_eq!(lut.title.as_deref(), Some("test"));
+        let graded = lut.apply([0.25, 0.5, 1.0]);
+        assert!((graded[0] - 0.75).abs() < 1e-12 && (graded[1] - 0.25).abs() < 1e-12 && (graded[2] - 1.0).abs() < 1e-12);
+        let mut image = RgbaImage::from_pixel(1, 1, Rgba([255, 200, 10, 77]));
+        lut_effect(&cube(2, |[r, g, b]| [1.0 - r, g * 0.5, b])).apply(&mut image);
+        assert_eq!(image.get_pixel(0, 0), &Rgba([0, 100, 10, 77]));
+    }
+
+    #[test]
+    fn colors_are_placed_in_the_domain() {
+        let content = cube(2, |color| color).replace("LUT_3D_SIZE 2", "LUT_3D_SIZE 2\nDOMAIN_MIN 0 0 0\nDOMAIN_MAX 2 2 2");
+        let lut = Lut::parse(&content, "domain.cube").unwrap();
+        assert_eq!(lut.apply([1.0, 0.5, 4.0]), [0.5, 0.25, 1.0]);
+    }
+
+    #[test]
+    fn malformed_files_are_refused_with_their_line() {
+        let identity = cube(2, |color| color);
+        let cases = [
+            ("LUT_1D_SIZE 2", 1, "not supported"),
+            ("LUT_3D_SIZE 1", 1, "between 2 and 256"),
+            ("LUT_3D_SIZE x", 1, "invalid LUT_3D_SIZE"),
+            ("0 0 0", 1, "before LUT_3D_SIZE"),
+            ("TITLE \"a\"\nLUT_3D_SIZE 2\n0 0", 3, "expected 3 values"),
+            ("LUT_3D_SIZE 2\n0 0 zero", 2, "invalid number"),
+            ("LUT_3D_SIZE 2\nGAMMA 2.2", 2, "unknown keyword"),
+            ("LUT_3D_SIZE 2\n0 0 0", 0, "expected 8 table entries, found 1"),
+            ("TITLE \"empty\"", 0, "missing LUT_3D_SIZE")
+        ];
+        for (content, line, message) in cases.iter() {
+            let error = Lut::parse(content, "bad.cube").unwrap_err();
+            assert_eq!(error.line, *line, "{}", content);
+            assert!(error.message.contains(message), "{}: {}", content, error);
+        }
+        let error = Lut::parse(&format!("{}1 1 1\n", identity), "long.cube").unwrap_err();
+        assert!(error.message.contains("more than 8"));
+        let error = Lut::parse(&identity.replace("LUT_3D_SIZE 2", "LUT_3D_SIZE 2\nDOMAIN_MIN 1 0 0"), "domain.cube").unwrap_err();
+        assert!(error.message.contains("DOMAIN_MAX"));
+    }
+
+    #[test]
+    fn a_neutral_grade_leaves_images_unchanged() {
+        let neutral: PostEffect = serde_json::from_str(r#"{"GRADE": {}}"#).unwrap();
+        let mut image = every_color();
+        neutral.apply(&mut image);
+        assert_eq!(image.into_raw(), every_color().into_raw());
+        // Without the shortcut the formula itself gives back every byte
+        for value in 0..=255u8 {
+            let color = value as f64 / 255.0;
+            for graded in grade([color, 1.0 - color, 0.5], 0.0, 1.0, 1.0).iter().zip([color, 1.0 - color, 0.5].iter()) {
+                assert!((graded.0 - graded.1).abs() < 1e-12);
+            }
+        }
+    }
+
+    #[test]
+    fn grades_move_brightness_contrast_and_saturation() {
+        let [r, g, b] = grade([0.5, 0.2, 0.1], 0.0, 1.0, 0.0);
+        assert!((r - g).abs() < 1e-12 && (g - b).abs() < 1e-12);
+        assert!((r - (0.2126 * 0.5 + 0.7152 * 0.2 + 0.0722 * 0.1)).abs() < 1e-12);
+        assert!((grade([MIDDLE_GRAY; 3], 0.0, 3.0, 1.0)[0] - MIDDLE_GRAY).abs() < 1e-12);
+        assert!((grade([0.5; 3], 0.0, 2.0, 1.0)[0] - 0.82).abs() < 1e-12);
+        assert!((grade([0.5; 3], 0.1, 1.0, 1.0)[0] - 0.6).abs() < 1e-12);
+        let mut image = RgbaImage::from_pixel(1, 1, Rgba([250, 100, 0, 77]));
+        PostEffect::GRADE { brightness: 0.2, contrast: 1.0, saturation: 1.0 }.apply(&mut image);
+        assert_eq!(image.get_pixel(0, 0), &Rgba([255, 151, 51, 77]));
+    }
+}
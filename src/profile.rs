@@ -0,0 +1,202 @@
+use std::fs;
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use crate::memory::{MemoryEstimate, format_size};
+use crate::texture_cache::TextureCache;
+
+#[allow(non_camel_case_types, clippy::upper_case_acronyms)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Stage {
+    LOAD,
+    PREPARE,
+    PHOTON_MAP,
+    RENDER,
+    PRIMARY,
+    SHADOW,
+    REFLECTION,
+    REFRACTION,
+    POST,
+    ENCODE,
+    ASSETS // Summed over the loader threads, it overlaps the prepare stage
+}
+
+const STAGE_COUNT: usize = 11;
+
+// Totals in nanoseconds, atomic so that threads sharing the scene can add to them
+#[derive(Debug, Default)]
+pub struct Profile {
+    started: Option<Instant>,
+    totals: [AtomicU64; STAGE_COUNT],
+    pub json_path: Option<String>,
+    pub memory: MemoryEstimate,
+    pub textures: Option<Arc<TextureCache>> // Its hits, misses and peak size are reported with the memory
+}
+
+impl Profile {
+    pub fn new(enabled: bool, json_path: Option<String>) -> Profile {
+        Profile {
+            started: if enabled { Some(Instant::now()) } else { None },
+            totals: Default::default(),
+            json_path,
+            memory: MemoryEstimate::default(),
+            textures: None
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.started.is_some()
+    }
+
+    pub fn start(&self) -> Option<Instant> {
+        if self.started.is_some() {
+            Some(Instant::now())
+        } else {
+            None
+        }
+    }
+
+    pub fn stop(&self, stage: Stage, start: Option<Instant>) {
+        if let Some(start) = start {
+            self.add(stage, start.elapsed());
+        }
+    }
+
+    // For time measured elsewhere, like on worker threads
+    pub fn add(&self, stage: Stage, duration: Duration) {
+        if self.is_enabled() {
+            self.totals[stage as usize].fetch_add(duration.as_nanos() as u64, Ordering::Relaxed);
+        }
+    }
+
+    pub fn total(&self, stage: Stage) -> Duration {
+        Duration::from_nanos(self.totals[stage as usize].load(Ordering::Relaxed))
+    }
+
+    fn rows(&self) -> Vec<(&'static str, Duration)> {
+        let render = self.total(Stage::RENDER);
+        let rays = self.total(Stage::PRIMARY) + self.total(Stage::SHADOW) + self.total(Stage::REFLECTION) + self.total(Stage::REFRACTION);
+        vec![
+            ("scene load", self.total(Stage::LOAD)),
+            ("prepare", self.total(Stage::PREPARE)),
+            ("photon map", self.total(Stage::PHOTON_MAP)),
+            ("primary rays", self.total(Stage::PRIMARY)),
+            ("shadow rays", self.total(Stage::SHADOW)),
+            ("reflection rays", self.total(Stage::REFLECTION)),
+            ("refraction rays", self.total(Stage::REFRACTION)),
+            ("shading", render.checked_sub(rays).unwrap_or_default()),
+            ("post", self.total(Stage::POST)),
+            ("encode", self.total(Stage::ENCODE))
+        ]
+    }
+
+    pub fn report(&self) -> io::Result<()> {
+        let wall = match self.started {
+            Some(started) => started.elapsed(),
+            None => return Ok(())
+        };
+        let rows = self.rows();
+        let measured: Duration = rows.iter().map(|(_, duration)| *duration).sum();
+        let wall_ms = wall.as_secs_f64() * 1000.0;
+
+        println!("{:<16} {:>12} {:>7}", "stage", "time (ms)", "%");
+        for (name, duration) in rows.iter().chain([("other", wall.checked_sub(measured).unwrap_or_default())].iter()) {
+            let ms = duration.as_secs_f64() * 1000.0;
+            println!("{:<16} {:>12.3} {:>7.1}", name, ms, if wall_ms > 0.0 { ms * 100.0 / wall_ms } else { 0.0 });
+        }
+        println!("{:<16} {:>12.3}", "total", wall_ms);
+        let assets_ms = self.total(Stage::ASSETS).as_secs_f64() * 1000.0;
+        if assets_ms > 0.0 {
+            println!("{:<16} {:>12.3}", "asset threads", assets_ms);
+        }
+        if !self.memory.items.is_empty() {
+            println!("{:<16} {:>12}", "memory", "estimate");
+            for (name, bytes) in self.memory.items.iter() {
+                println!("{:<16} {:>12}", name, format_size(*bytes));
+            }
+            println!("{:<16} {:>12}", "total", format_size(self.memory.total()));
+        }
+        let textures = self.textures.as_ref().map(|cache| cache.stats()).filter(|stats| stats.misses > 0);
+        if let Some(stats) = textures {
+            println!("texture cache    {} hits, {} misses, {} evictions, peak {}", stats.hits, stats.misses, stats.evictions, format_size(stats.peak_bytes));
+        }
+
+        if let Some(path) = &self.json_path {
+            let mut stages = serde_json::Map::new();
+            for (name, duration) in rows.iter() {
+                stages.insert(name.to_string(), serde_json::json!(duration.as_secs_f64() * 1000.0));
+            }
+            let mut memory = serde_json::Map::new();
+            for (name, bytes) in self.memory.items.iter() {
+                memory.insert(name.to_string(), serde_json::json!(bytes));
+            }
+            let mut json = serde_json::json!({ "wall_ms": wall_ms, "stages_ms": stages, "asset_threads_ms": assets_ms, "memory_bytes": memory });
+            if let Some(stats) = textures {
+                json["texture_cache"] = serde_json::json!({ "hits": stats.hits, "misses": stats.misses, "evictions": stats.evictions, "peak_bytes": stats.peak_bytes });
+            }
+            fs::write(path, serde_json::to_string_pretty(&json)?)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use crate::rendering::Scene;
+
+    fn is_sync<T: Sync>() {}
+
+    #[test]
+    fn scenes_can_be_shared_between_threads() {
+        is_sync::<Profile>();
+        is_sync::<Scene>();
+    }
+
+    #[test]
+    fn every_thread_adds_to_the_totals() {
+        let profile = Profile::new(true, None);
+        thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| {
+                    for _ in 0..100 {
+                        profile.add(Stage::SHADOW, Duration::from_micros(10));
+                    }
+                });
+            }
+        });
+        assert_eq!(profile.total(Stage::SHADOW), Duration::from_millis(8));
+        assert_eq!(profile.total(Stage::PRIMARY), Duration::ZERO);
+    }
+
+    #[test]
+    fn disabled_profiles_measure_nothing() {
+        let profile = Profile::new(false, None);
+        assert!(profile.start().is_none());
+        profile.add(Stage::RENDER, Duration::from_millis(5));
+        profile.stop(Stage::RENDER, Some(Instant::now()));
+        assert_eq!(profile.total(Stage::RENDER), Duration::ZERO);
+    }
+
+    #[test]
+    fn shading_is_the_render_time_outside_the_rays() {
+        let profile = Profile::new(true, None);
+        profile.add(Stage::RENDER, Duration::from_millis(10));
+        profile.add(Stage::PRIMARY, Duration::from_millis(3));
+        profile.add(Stage::SHADOW, Duration::from_millis(2));
+        profile.add(Stage::ASSETS, Duration::from_millis(40));
+        let rows = profile.rows();
+        assert_eq!(rows.iter().find(|(name, _)| *name == "shading").unwrap().1, Duration::from_millis(5));
+        assert!(rows.iter().all(|(_, duration)| *duration < Duration::from_millis(40)));
+    }
+
+    #[test]
+    fn worker_threads_loading_assets_are_counted() {
+        let mut scene: Scene = serde_json::from_str(include_str!("../test_scene/mesh.json")).unwrap();
+        scene.profile = Profile::new(true, None);
+        scene.prepare().unwrap();
+        assert!(scene.profile.total(Stage::ASSETS) > Duration::ZERO);
+    }
+}
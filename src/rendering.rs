@@ -1,10 +1,37 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::fmt;
 use serde::{Serialize, Deserialize};
-use crate::shape::{Shape, Ray, Hit, Point};
+use serde::de;
+use crate::shape::{Shape, Ray, RayType, Hit, Point, tangent_axes};
 use crate::vertors::Vector3;
-use image::{ImageBuffer, RgbaImage, Rgba, Pixel, ImageError};
-use crate::traits::{Intersectable, LightEmitter};
+use image::{ImageBuffer, RgbaImage, Rgba, Pixel};
+use crate::traits::{Intersectable, LightEmitter, Recorder, SampleSource};
+use crate::photon::{Caustics, PhotonMap, build_photon_map};
+use crate::light_sampling::{self, LightSampling, LightSubset};
+use crate::sampler::Sampler;
+use crate::node::Node;
+use crate::transform::Matrix;
+use crate::profile::{Profile, Stage};
+use crate::limits::{Limit, Limits};
+use crate::post::PostEffect;
+use crate::output::{self, OutputConfig};
+use crate::assets::{self, Asset, AssetError};
+use crate::debug::NoRecorder;
+use crate::aabb::Aabb;
+use crate::stereo::Stereo;
+use crate::exposure::Exposure;
+use crate::lens::{DepthOfField, Lens};
+use crate::uv_projection::UvProjection;
+use crate::animation::CameraPath;
+use crate::environment::{Environment, Sky};
+use crate::ies::{IesFile, IesProfile};
+use crate::texture::{Texture, ImageTexture, BumpMap};
+use crate::texture_cache::TextureCache;
 
 pub const SHADOW_BIAS: f64 = 1e-13;
+// Irradiance below which a point light is out of range, a fraction of the last step of an 8 bit channel
+pub const NEGLIGIBLE_IRRADIANCE: f64 = 1.0 / 512.0;
 
 #[derive(Copy, Clone, Debug, Serialize, Deserialize)]
 pub struct Color {
@@ -23,6 +50,10 @@ impl Color {
         Color { r: 0, g: 0, b: 0, a: 255 }
     }
 
+    pub fn white() -> Color {
+        Color { r: 255, g: 255, b: 255, a: 255 }
+    }
+
     pub fn to_rgba(&self) -> Rgba<u8> {
         Rgba::from_channels(self.r, self.g, self.b, self.a)
     }
@@ -53,29 +84,477 @@ impl std::ops::AddAssign for Color {
     }
 }
 
-#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+// Shading result in floating point, 1.0 is full intensity once exposed
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct LinearColor {
+    pub r: f64,
+    pub g: f64,
+    pub b: f64,
+    pub a: f64
+}
+
+impl LinearColor {
+    pub fn new(r: f64, g: f64, b: f64, a: f64) -> LinearColor {
+        LinearColor { r, g, b, a }
+    }
+
+    pub fn from_color(color: Color) -> LinearColor {
+        LinearColor::new(color.r as f64 / 255.0, color.g as f64 / 255.0, color.b as f64 / 255.0, color.a as f64 / 255.0)
+    }
+
+    // Channels above full intensity are clipped
+    pub fn to_color(&self) -> Color {
+        let channel = |value: f64| (value.clamp(0.0, 1.0) * 255.0) as u8;
+        Color::new(channel(self.r), channel(self.g), channel(self.b), channel(self.a))
+    }
+}
+
+impl std::ops::Add for LinearColor {
+    type Output = LinearColor;
+
+    fn add(self, rhs: Self) -> LinearColor {
+        LinearColor::new(self.r + rhs.r, self.g + rhs.g, self.b + rhs.b, (self.a + rhs.a).min(1.0))
+    }
+}
+
+impl std::ops::Mul<f64> for LinearColor {
+    type Output = LinearColor;
+
+    fn mul(self, rhs: f64) -> LinearColor {
+        LinearColor::new(self.r * rhs, self.g * rhs, self.b * rhs, self.a * rhs)
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Material {
-    pub base_color: Color,
-    pub albedo: f64,
-    pub reflectiveness: f64
+    pub base_color: Texture, // A color, or a pattern like {"CHECKER": {...}}
+    pub albedo: Albedo, // Like 0.9, or [0.9, 0.2, 0.2] to diffuse each channel of the light differently
+    pub reflectiveness: f64,
+    #[serde(default = "Color::white")]
+    pub reflection_color: Color, // Filter over the reflected rays, like a warm yellow for gold or copper
+    #[serde(default = "Color::black")]
+    pub emissive: Color, // Glow added to every ray seeing the surface, scaled by the strength
+    #[serde(default)]
+    pub emissive_strength: f64,
+    #[serde(default)]
+    pub transparency: f64, // Share of the color seen through the surface, like glass or water
+    #[serde(default = "default_opacity")]
+    pub opacity: f64, // Share of the surface covering what is behind it without bending it, like tinted film or a fence
+    #[serde(default = "default_ior")]
+    pub ior: f64,
+    #[serde(default)]
+    pub fresnel: Fresnel,
+    #[serde(default)]
+    pub specular: f64, // Strength of the Phong highlights of the lights, in their color
+    #[serde(default = "default_shininess")]
+    pub shininess: f64, // Exponent of the highlights, the higher the tighter they are
+    #[serde(default)]
+    pub shading: Shading,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub normal_map: Option<ImageTexture>, // Tangent space normals tilting the shading normal, on shapes with texture coordinates
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bump_map: Option<BumpMap>, // Heights tilting the shading normal along their slopes, over the normal map
+    #[serde(skip)]
+    pub name: Option<String> // Scene material this one comes from, an element writes this name back instead of the material
+}
+
+// Model turning the light reaching a surface into the light it sends toward the view
+#[allow(non_camel_case_types)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum Shading {
+    #[default]
+    LAMBERT, // Diffuse light only, plus Phong highlights around the mirrored light direction with a specular strength
+    BLINN_PHONG, // Diffuse light plus highlights where the half vector between the light and the view meets the normal
+    // GGX highlights whose Fresnel reflectance goes from 4% for dielectrics to the base color for metals, the diffuse light
+    // gets what they do not reflect. The rougher, the wider and dimmer the highlights
+    PBR { metallic: f64, roughness: f64 }
+}
+
+// Reflectance at normal incidence of the dielectrics of the PBR shading, like plastic or stone
+const DIELECTRIC_F0: f64 = 0.04;
+
+// How the reflected share of a material depends on the angle of the incoming ray
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum Fresnel {
+    #[default]
+    NONE, // The reflectiveness at every angle, the reflection is added on top of the rest of the surface
+    DIELECTRIC, // Schlick's approximation with the reflectance at normal incidence given by the ior, like glass or water
+    F0(f64) // Schlick's approximation from this reflectance at normal incidence, like metals
+}
+
+/// Share of the light a material diffuses, for each of the red, green and blue channels.
+///
+/// ```
+/// use rust_raytracer::rendering::{Albedo, Material};
+///
+/// let material = |albedo: &str| serde_json::from_str::<Material>(&format!(r#"{{"base_color": {{"r": 255, "g": 255, "b": 255, "a": 255}}, "albedo": {}, "reflectiveness": 0.0}}"#, albedo));
+/// // A single number diffuses every channel alike and is written back as it was
+/// let gray = material("0.9").unwrap();
+/// assert_eq!(gray.albedo, Albedo::gray(0.9));
+/// assert_eq!(serde_json::to_value(&gray).unwrap()["albedo"], 0.9);
+///
+/// let reddish = material("[0.9, 0.2, 0.2]").unwrap();
+/// assert_eq!(reddish.albedo, Albedo([0.9, 0.2, 0.2]));
+/// assert_eq!(serde_json::to_value(&reddish).unwrap()["albedo"], serde_json::json!([0.9, 0.2, 0.2]));
+/// assert!(material("[0.9, 0.2]").is_err());
+/// ```
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Albedo(pub [f64; 3]);
+
+impl Albedo {
+    pub fn gray(albedo: f64) -> Albedo {
+        Albedo([albedo; 3])
+    }
+
+    pub fn is_gray(&self) -> bool {
+        self.0[0] == self.0[1] && self.0[1] == self.0[2]
+    }
+}
+
+impl fmt::Display for Albedo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_gray() {
+            write!(f, "{}", self.0[0])
+        } else {
+            write!(f, "[{}, {}, {}]", self.0[0], self.0[1], self.0[2])
+        }
+    }
+}
+
+// Written as a single number when every channel is the same, like the scene files before colored albedos
+impl Serialize for Albedo {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if self.is_gray() {
+            self.0[0].serialize(serializer)
+        } else {
+            self.0.serialize(serializer)
+        }
+    }
+}
+
+struct AlbedoVisitor;
+
+impl<'de> de::Visitor<'de> for AlbedoVisitor {
+    type Value = Albedo;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a number or an array of 3 numbers for the red, green and blue channels")
+    }
+
+    fn visit_f64<E: de::Error>(self, albedo: f64) -> Result<Albedo, E> {
+        Ok(Albedo::gray(albedo))
+    }
+
+    fn visit_u64<E: de::Error>(self, albedo: u64) -> Result<Albedo, E> {
+        Ok(Albedo::gray(albedo as f64))
+    }
+
+    fn visit_i64<E: de::Error>(self, albedo: i64) -> Result<Albedo, E> {
+        Ok(Albedo::gray(albedo as f64))
+    }
+
+    fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<Albedo, A::Error> {
+        let mut channels = [0.0; 3];
+        for (index, channel) in channels.iter_mut().enumerate() {
+            *channel = seq.next_element()?.ok_or_else(|| de::Error::invalid_length(index, &self))?;
+        }
+        if seq.next_element::<de::IgnoredAny>()?.is_some() {
+            return Err(de::Error::invalid_length(4, &self));
+        }
+        Ok(Albedo(channels))
+    }
+}
+
+impl<'de> Deserialize<'de> for Albedo {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Albedo, D::Error> {
+        deserializer.deserialize_any(AlbedoVisitor)
+    }
+}
+
+fn default_ior() -> f64 {
+    1.0
+}
+
+fn default_opacity() -> f64 {
+    1.0
+}
+
+fn default_shininess() -> f64 {
+    32.0
 }
 
 impl Material {
     pub fn new(base_color: Color, albedo: f64, reflectiveness: f64) -> Material {
-        Material { base_color, albedo, reflectiveness }
+        Material { base_color: Texture::SOLID_COLOR(base_color), albedo: Albedo::gray(albedo), reflectiveness, reflection_color: Color::white(), emissive: Color::black(), emissive_strength: 0.0, transparency: 0.0, opacity: default_opacity(), ior: default_ior(), fresnel: Fresnel::NONE, specular: 0.0, shininess: default_shininess(), shading: Shading::LAMBERT, normal_map: None, bump_map: None, name: None }
+    }
+
+    /// Placeholder for the scene material with this name, replaced by a copy of it when the scene is prepared.
+    ///
+    /// ```
+    /// use rust_raytracer::rendering::{Camera, Color, Material, Renderable, Scene};
+    /// use rust_raytracer::shape::{Shape, Sphere};
+    /// use rust_raytracer::vertors::Vector3;
+    ///
+    /// let ball = |x: f64| Shape::SPHERE(Sphere::new(Vector3::new(x, 0.0, -5.0), 1.0));
+    /// let elements = vec![Renderable::new(ball(-1.5), Material::named("chrome")), Renderable::new(ball(1.5), Material::named("chrome"))];
+    /// let mut scene = Scene::new(Camera::new(32, 24, 90.0), elements, Vec::new(), Color::black());
+    /// scene.materials.insert("chrome".to_string(), Material::new(Color::new(200, 200, 210, 255), 0.2, 0.8));
+    /// scene.prepare().unwrap();
+    /// assert!(scene.flat_elements.iter().all(|element| element.material.reflectiveness == 0.8));
+    ///
+    /// // The elements keep pointing to the library in the scene file
+    /// let json = serde_json::to_value(&scene).unwrap();
+    /// assert_eq!(json["elements"][1]["material"], "chrome");
+    ///
+    /// scene.elements.push(Renderable::new(ball(0.0), Material::named("gold")));
+    /// assert_eq!(scene.prepare().unwrap_err().to_string(), "element 2 uses material \"gold\" that is not in the scene materials");
+    /// ```
+    pub fn named(name: &str) -> Material {
+        Material { name: Some(name.to_string()), ..Material::new(Color::black(), 0.0, 0.0) }
+    }
+
+    pub fn is_emissive(&self) -> bool {
+        self.emissive_strength > 0.0 && (self.emissive.r > 0 || self.emissive.g > 0 || self.emissive.b > 0)
+    }
+
+    pub fn emission(&self) -> LinearColor {
+        let emissive = LinearColor::from_color(self.emissive);
+        LinearColor::new(emissive.r, emissive.g, emissive.b, 0.0) * self.emissive_strength
+    }
+
+    // Share of the light reflected toward a ray coming from the direction, which grows toward grazing angles with a Fresnel mode
+    pub fn reflectance(&self, direction: Vector3, hit: &Hit) -> f64 {
+        let f0 = match self.fresnel {
+            Fresnel::NONE => return self.reflectiveness,
+            Fresnel::DIELECTRIC => ((self.ior - 1.0) / (self.ior + 1.0)).powi(2),
+            Fresnel::F0(f0) => f0
+        };
+        let mut cosine = (-direction.normalize().dot(&hit.normal)).clamp(0.0, 1.0);
+        // Leaving a denser material, the angle on the outer side counts and past the critical angle everything is reflected
+        if self.fresnel == Fresnel::DIELECTRIC && !hit.front_face && self.ior > 1.0 {
+            let sin_outside_sq = self.ior * self.ior * (1.0 - cosine * cosine);
+            if sin_outside_sq > 1.0 {
+                return 1.0;
+            }
+            cosine = (1.0 - sin_outside_sq).sqrt();
+        }
+        f0 + (1.0 - f0) * (1.0 - cosine).powi(5)
+    }
+
+    /// Light a sample of `brightness` coming from `light_direction` sends toward `view_direction`, as the irradiance
+    /// for the diffuse color and the highlight for each channel of the light color, following the `shading` model.
+    ///
+    /// ```
+    /// use rust_raytracer::rendering::{Color, Material, Shading};
+    /// use rust_raytracer::vertors::Vector3;
+    ///
+    /// let up = Vector3::new(0.0, 1.0, 0.0);
+    /// let grazing = Vector3::new(1.0, 0.0, 0.0);
+    /// let white = Color::new(255, 255, 255, 255);
+    /// let matte = Material::new(white, 0.9, 0.0);
+    /// // All of the brightness reaches the surface at normal incidence and none of it at grazing angles
+    /// assert_eq!(matte.shade(up, up, up, 2.0, white), (2.0, [0.0; 3]));
+    /// assert_eq!(matte.shade(up, grazing, up, 2.0, white), (0.0, [0.0; 3]));
+    ///
+    /// // Both highlights reach the specular share of the brightness in the mirror direction and vanish at grazing angles
+    /// let phong = Material { specular: 0.5, shininess: 32.0, ..matte.clone() };
+    /// let blinn_phong = Material { shading: Shading::BLINN_PHONG, ..phong.clone() };
+    /// for material in [&phong, &blinn_phong].iter() {
+    ///     assert!((material.shade(up, up, up, 2.0, white).1[0] - 1.0).abs() < 1e-12);
+    ///     assert_eq!(material.shade(up, grazing, up, 2.0, white).1[0], 0.0);
+    /// }
+    /// // Away from it, the half vector highlights are wider for the same shininess
+    /// let view = Vector3::new(0.3, 1.0, 0.0).normalize();
+    /// assert!(blinn_phong.shade(up, up, view, 2.0, white).1[0] > phong.shade(up, up, view, 2.0, white).1[0]);
+    ///
+    /// // PBR highlights spread and dim with the roughness, those of metals take the base color
+    /// let gold = Color::new(255, 190, 80, 255);
+    /// let pbr = |metallic: f64, roughness: f64| Material { shading: Shading::PBR { metallic, roughness }, ..matte.clone() };
+    /// let (smooth, rough) = (pbr(1.0, 0.2), pbr(1.0, 0.8));
+    /// assert!(smooth.shade(up, up, up, 2.0, gold).1[0] > rough.shade(up, up, up, 2.0, gold).1[0]);
+    /// let aside = Vector3::new(0.6, 1.0, 0.0).normalize();
+    /// assert!(smooth.shade(up, up, aside, 2.0, gold).1[0] < rough.shade(up, up, aside, 2.0, gold).1[0]);
+    /// let (diffuse, highlight) = smooth.shade(up, up, up, 2.0, gold);
+    /// assert!(diffuse == 0.0 && highlight[0] > highlight[1] && highlight[1] > highlight[2]);
+    /// let (diffuse, highlight) = pbr(0.0, 0.2).shade(up, up, up, 2.0, gold);
+    /// assert!(diffuse > 0.0 && diffuse < 2.0 && highlight[0] == highlight[2]);
+    /// ```
+    pub fn shade(&self, normal: Vector3, light_direction: Vector3, view_direction: Vector3, brightness: f64, base_color: Color) -> (f64, [f64; 3]) {
+        let facing = normal.dot(&light_direction);
+        let diffuse = facing.max(0.0) * brightness;
+        if let Shading::PBR { metallic, roughness } = self.shading {
+            return pbr_shade(metallic, roughness, normal, light_direction, view_direction, brightness, base_color);
+        }
+        if self.specular <= 0.0 || facing <= 0.0 {
+            return (diffuse, [0.0; 3]);
+        }
+        let alignment = match self.shading {
+            Shading::BLINN_PHONG => normal.dot(&(light_direction + view_direction).normalize()),
+            _ => (normal * (2.0 * facing) - light_direction).dot(&view_direction)
+        };
+        let highlight = self.specular * alignment.max(0.0).powf(self.shininess) * brightness;
+        (diffuse, [highlight; 3])
+    }
+
+    /// Share of each channel of the reflected rays kept: the `reflection_color`, and the base color of metals.
+    ///
+    /// ```
+    /// use rust_raytracer::rendering::{Camera, Color, Material, Renderable, Scene, View, sample_pixel};
+    /// use rust_raytracer::shape::{Shape, Sphere};
+    /// use rust_raytracer::traits::Recorder;
+    /// use rust_raytracer::vertors::Vector3;
+    ///
+    /// struct Quiet;
+    /// impl Recorder for Quiet {}
+    ///
+    /// // A mirror in front of the camera only shows the sky behind the camera
+    /// let sky = Color::new(90, 160, 230, 255);
+    /// let mirror = |reflection_color: Color| {
+    ///     let material = Material { reflection_color, ..Material::new(Color::black(), 0.0, 1.0) };
+    ///     let ball = Renderable::new(Shape::SPHERE(Sphere::new(Vector3::new(0.0, 0.0, -5.0), 1.0)), material);
+    ///     let mut scene = Scene::new(Camera::new(1, 1, 10.0), vec![ball], Vec::new(), sky);
+    ///     scene.prepare().unwrap();
+    ///     sample_pixel(4, &scene, &View::camera(&scene.camera), 0, 0, 0, &mut Quiet)
+    /// };
+    /// let seen = |color: Color| (color.r, color.g, color.b);
+    /// assert_eq!(seen(mirror(Color::white())), seen(sky));
+    /// assert_eq!(seen(mirror(Color::new(255, 0, 0, 255))), (sky.r, 0, 0));
+    ///
+    /// let gold = Material { reflection_color: Color::new(255, 200, 120, 255), ..Material::new(Color::black(), 0.0, 1.0) };
+    /// assert_eq!(gold.reflection_tint(Color::black()), [1.0, 200.0 / 255.0, 120.0 / 255.0]);
+    /// ```
+    pub fn reflection_tint(&self, base_color: Color) -> [f64; 3] {
+        let filter = |channel: u8| channel as f64 / 255.0;
+        let mut tint = [filter(self.reflection_color.r), filter(self.reflection_color.g), filter(self.reflection_color.b)];
+        if let Shading::PBR { metallic, .. } = self.shading {
+            let metal = |channel: u8| 1.0 - metallic + metallic * channel as f64 / 255.0;
+            for (tint, channel) in tint.iter_mut().zip([base_color.r, base_color.g, base_color.b]) {
+                *tint *= metal(channel);
+            }
+        }
+        tint
+    }
+
+    // Normal the hit is shaded with, None when the material has no normal or bump map or the shape no texture directions
+    pub fn mapped_normal(&self, hit: &Hit) -> Option<Vector3> {
+        let normal = self.normal_map.as_ref().and_then(|normal_map| normal_map.normal_at(hit));
+        match &self.bump_map {
+            Some(bump_map) => bump_map.normal_at(hit, normal.unwrap_or(hit.normal)).or(normal),
+            None => normal
+        }
+    }
+
+    // Image textures of the material, loaded with the scene
+    pub fn images(&self) -> impl Iterator<Item = &ImageTexture> {
+        self.base_color.image().into_iter().chain(self.normal_map.as_ref()).chain(self.bump_map.as_ref().map(|bump_map| &bump_map.image))
+    }
+
+    pub fn images_mut(&mut self) -> impl Iterator<Item = &mut ImageTexture> {
+        self.base_color.image_mut().into_iter().chain(self.normal_map.as_mut()).chain(self.bump_map.as_mut().map(|bump_map| &mut bump_map.image))
+    }
+
+    // Base color at the hit, where vertex colors replace the texture
+    pub fn color_at(&self, hit: &Hit) -> Color {
+        hit.color.unwrap_or_else(|| self.base_color.color_at(hit))
+    }
+
+    // Fraction of each channel a refraction or shadow ray keeps when crossing the surface, tinted by the base color
+    pub fn transmission(&self, hit: &Hit) -> [f64; 3] {
+        let color = self.color_at(hit);
+        let tint = |channel: u8| self.transparency * (channel as f64) / 255.0;
+        [tint(color.r), tint(color.g), tint(color.b)]
+    }
+
+    // Fraction of each channel a shadow ray keeps, the uncovered share of a partly opaque surface lets all of the light through
+    pub fn shadow_transmission(&self, hit: &Hit) -> [f64; 3] {
+        let transmission = self.transmission(hit);
+        if self.opacity >= 1.0 {
+            return transmission;
+        }
+        transmission.map(|channel| 1.0 - self.opacity + self.opacity * channel)
     }
 }
 
-#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+// GGX distribution with Smith masking (Schlick's approximation with k = alpha / 2) and Schlick's Fresnel reflectance per channel.
+// Kept out of line: inlined in shade, it slows the light loop of the other models down
+#[inline(never)]
+fn pbr_shade(metallic: f64, roughness: f64, normal: Vector3, light_direction: Vector3, view_direction: Vector3, brightness: f64, base_color: Color) -> (f64, [f64; 3]) {
+    let facing = normal.dot(&light_direction);
+    let toward_view = normal.dot(&view_direction);
+    if facing <= 0.0 || toward_view <= 0.0 {
+        return (0.0, [0.0; 3]);
+    }
+    let half = (light_direction + view_direction).normalize();
+    let alpha = (roughness * roughness).max(1e-3); // A perfectly smooth surface would only reflect point lights along a single direction
+    let alpha_sq = alpha * alpha;
+    let along_half = normal.dot(&half).max(0.0);
+    let denominator = along_half * along_half * (alpha_sq - 1.0) + 1.0;
+    let distribution = alpha_sq / (std::f64::consts::PI * denominator * denominator);
+    let k = alpha / 2.0;
+    let masking = facing / (facing * (1.0 - k) + k) * toward_view / (toward_view * (1.0 - k) + k);
+    let grazing = (1.0 - half.dot(&view_direction).clamp(0.0, 1.0)).powi(5);
+    let fresnel = |f0: f64| f0 + (1.0 - f0) * grazing;
+    let f0 = |channel: u8| DIELECTRIC_F0 + (channel as f64 / 255.0 - DIELECTRIC_F0) * metallic;
+    // The lobe times the cosine of the light, which cancels the one in the denominator of the microfacet model
+    let lobe = distribution * masking / (4.0 * toward_view) * brightness;
+    let highlight = [lobe * fresnel(f0(base_color.r)), lobe * fresnel(f0(base_color.g)), lobe * fresnel(f0(base_color.b))];
+    // Metals have no diffuse light, dielectrics diffuse what their highlights do not reflect
+    let diffuse = facing * brightness * (1.0 - metallic) * (1.0 - fresnel(DIELECTRIC_F0));
+    (diffuse, highlight)
+}
+
+// Light made by the scene itself: the surface of an emissive element, whose shadow rays go through that element, or the sun of the sky
+#[derive(Clone, Debug)]
+pub struct Emitter {
+    pub element: Option<usize>,
+    pub light: Light
+}
+
+impl Emitter {
+    // Spheres and quads are sampled like sphere and area lights, radiating the power of a uniformly glowing surface
+    pub fn of(element: usize, renderable: &Renderable) -> Vec<Emitter> {
+        let material = &renderable.material;
+        if !material.is_emissive() {
+            return Vec::new();
+        }
+        let radiance = material.emissive_strength * std::f64::consts::PI;
+        let lights = match &renderable.shape {
+            Shape::SPHERE(sphere) => {
+                let area = 4.0 * std::f64::consts::PI * sphere.radius * sphere.radius;
+                vec![Light::SPHERE(SphereLight::new(sphere.origin, sphere.radius, radiance * area, material.emissive, default_area_samples()))]
+            },
+            // Quads glow on both sides
+            Shape::QUAD(quad) => {
+                let area = quad.u.cross(&quad.v).length();
+                vec![
+                    Light::AREA(AreaLight::new(quad.corner, quad.u, quad.v, radiance * area, material.emissive, default_area_samples())),
+                    Light::AREA(AreaLight::new(quad.corner, quad.v, quad.u, radiance * area, material.emissive, default_area_samples()))
+                ]
+            },
+            _ => Vec::new()
+        };
+        lights.into_iter().map(|light| Emitter { element: Some(element), light }).collect()
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct DirectionalLight {
     pub direction: Vector3,
     pub brightness: f64,
-    pub color: Color
+    pub color: Color,
+    #[serde(default)]
+    pub angular_radius: f64, // Degrees, the sun is about 0.27
+    #[serde(default = "default_area_samples")]
+    pub samples: u32,
+    #[serde(default = "default_true")]
+    pub cast_shadows: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String> // Referenced by the lights list of the elements it is linked to
 }
 
 impl DirectionalLight {
     pub fn new(direction: Vector3, brightness: f64, color: Color) -> DirectionalLight {
-        DirectionalLight { direction, brightness, color }
+        DirectionalLight { direction, brightness, color, angular_radius: 0.0, samples: default_area_samples(), cast_shadows: true, name: None }
     }
 }
 
@@ -88,25 +567,170 @@ impl LightEmitter for DirectionalLight {
         self.brightness
     }
 
+    fn get_power(&self) -> f64 {
+        self.brightness
+    }
+
     fn get_color(&self) -> Color {
         self.color
     }
 
     fn get_distance(&self, _point: Point) -> f64 {
-        std::f64::INFINITY
+        f64::INFINITY
+    }
+
+    fn casts_shadows(&self) -> bool {
+        self.cast_shadows
+    }
+
+    fn get_samples(&self) -> u32 {
+        if self.angular_radius > 0.0 { self.samples } else { 1 }
+    }
+
+    // Directions spread uniformly over the cone of the light disk, so shadows blur farther from their occluder
+    fn sample(&self, point: Point, u: f64, v: f64) -> (Vector3, f64, f64) {
+        let axis = self.get_direction(point);
+        if self.angular_radius <= 0.0 {
+            return (axis, f64::INFINITY, self.brightness);
+        }
+        let (tangent, bitangent) = tangent_axes(axis);
+        let cos_theta = 1.0 - u * (1.0 - self.angular_radius.to_radians().cos());
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        let angle = 2.0 * std::f64::consts::PI * v;
+        let direction = axis * cos_theta + tangent * (sin_theta * angle.cos()) + bitangent * (sin_theta * angle.sin());
+        (direction.normalize(), f64::INFINITY, self.brightness)
     }
 }
 
-#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+/// Meaning of the `brightness` of the lights, chosen for the whole scene by `light_units`.
+///
+/// Point, sphere and area lights give their total power, spread over the sphere (or the half space in front of
+/// an area light) and falling off with the square of the distance. Directional lights give the irradiance on a
+/// surface facing them. `LEGACY` spot lights shine like a point light of that power cut to a cone, `PHYSICAL`
+/// spot lights put all of it inside their cone, so widening the cone dims them like a real fixture.
+///
+/// ```
+/// use rust_raytracer::rendering::{Color, DirectionalLight, LightUnits, PointLight, SpotLight};
+/// use rust_raytracer::traits::LightEmitter;
+/// use rust_raytracer::vertors::Vector3;
+///
+/// let white = Color::new(255, 255, 255, 255);
+/// let pi = std::f64::consts::PI;
+/// // A point light of power 100 at distance 2 from a facing plane
+/// let point = PointLight::new(Vector3::new(0.0, 2.0, 0.0), 100.0, white);
+/// let irradiance = point.get_brightness(Vector3::zero());
+/// assert!((irradiance - 100.0 / (4.0 * pi * 4.0)).abs() < 1e-12);
+///
+/// let sun = DirectionalLight::new(Vector3::new(0.0, -1.0, 0.0), 3.0, white);
+/// assert_eq!(sun.get_brightness(Vector3::zero()), 3.0);
+///
+/// // All the power of a physical spot light goes through its cone of 60 degrees of half angle
+/// let spot = SpotLight { units: LightUnits::PHYSICAL, ..SpotLight::new(Vector3::new(0.0, 2.0, 0.0), Vector3::new(0.0, -1.0, 0.0), 60.0, 60.0, 100.0, white) };
+/// let cone = 2.0 * pi * (1.0 - 60.0_f64.to_radians().cos());
+/// assert!((spot.get_brightness(Vector3::zero()) - 100.0 / (cone * 4.0)).abs() < 1e-12);
+/// ```
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum LightUnits {
+    #[default]
+    LEGACY,
+    PHYSICAL
+}
+
+// How the brightness of a point light falls off with the distance, always spread over the sphere (4π)
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum Attenuation {
+    #[default]
+    QUADRATIC, // Physically correct inverse square
+    LINEAR,
+    NONE,
+    CUSTOM { constant: f64, linear: f64, quadratic: f64 }
+}
+
+impl Attenuation {
+    pub fn is_valid(&self) -> bool {
+        match *self {
+            Attenuation::CUSTOM { constant, linear, quadratic } => constant >= 0.0 && linear >= 0.0 && quadratic >= 0.0 && constant + linear + quadratic > 0.0,
+            _ => true
+        }
+    }
+
+    pub fn factor(&self, distance: f64) -> f64 {
+        match *self {
+            Attenuation::QUADRATIC => distance * distance,
+            Attenuation::LINEAR => distance,
+            Attenuation::NONE => 1.0,
+            Attenuation::CUSTOM { constant, linear, quadratic } => constant + linear * distance + quadratic * distance * distance
+        }
+    }
+}
+
+// Intensity of an IES profile toward the point relative to its peak, its horizontal angles turn from a fixed tangent of the axis
+fn ies_factor(profile: Option<&IesProfile>, axis: Vector3, toward: Vector3) -> f64 {
+    let profile = match profile {
+        Some(profile) => profile,
+        None => return 1.0
+    };
+    let (axis, toward) = (axis.normalize(), toward.normalize());
+    let (tangent, bitangent) = tangent_axes(axis);
+    let vertical = toward.dot(&axis).clamp(-1.0, 1.0).acos().to_degrees();
+    let horizontal = toward.dot(&bitangent).atan2(toward.dot(&tangent)).to_degrees();
+    profile.intensity(vertical, horizontal)
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct PointLight {
     pub position: Point,
     pub brightness: f64,
-    pub color: Color
+    pub color: Color,
+    #[serde(default)]
+    pub attenuation: Attenuation,
+    #[serde(default = "default_true")]
+    pub cast_shadows: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ies_file: Option<String>, // Intensity distribution with its nadir straight down
+    #[serde(skip)]
+    pub ies_profile: Option<Arc<IesProfile>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_distance: Option<f64>, // Where the light stops, by default where it falls below NEGLIGIBLE_IRRADIANCE
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>
 }
 
 impl PointLight {
     pub fn new(position: Point, brightness: f64, color: Color) -> PointLight {
-        PointLight { position, brightness, color }
+        PointLight { position, brightness, color, attenuation: Attenuation::default(), cast_shadows: true, ies_file: None, ies_profile: None, max_distance: None, name: None }
+    }
+
+    // Distance at which the attenuated brightness drops to NEGLIGIBLE_IRRADIANCE, the light never ends without falloff
+    pub fn range(&self) -> f64 {
+        if let Some(max_distance) = self.max_distance {
+            return max_distance;
+        }
+        let factor = self.brightness / (4.0 * std::f64::consts::PI * NEGLIGIBLE_IRRADIANCE);
+        match self.attenuation {
+            Attenuation::QUADRATIC => factor.sqrt(),
+            Attenuation::LINEAR => factor,
+            Attenuation::NONE => f64::INFINITY,
+            Attenuation::CUSTOM { constant, linear, quadratic } => {
+                if quadratic > 0.0 {
+                    (-linear + (linear * linear + 4.0 * quadratic * (factor - constant)).max(0.0).sqrt()) / (2.0 * quadratic)
+                } else if linear > 0.0 {
+                    ((factor - constant) / linear).max(0.0)
+                } else {
+                    f64::INFINITY
+                }
+            }
+        }
+    }
+
+    // Fades the light out over the last tenth of its range so that it does not end on a hard circle
+    fn range_factor(&self, distance: f64) -> f64 {
+        let range = self.range();
+        if !range.is_finite() {
+            return 1.0;
+        }
+        let t = ((range - distance) / (0.1 * range)).clamp(0.0, 1.0);
+        t * t * (3.0 - 2.0 * t)
     }
 }
 
@@ -116,8 +740,17 @@ impl LightEmitter for PointLight {
     }
 
     fn get_brightness(&self, point: Point) -> f64 {
-        let light_distance_sq = (self.position - point).length_sq();
-        self.brightness / (4.0 * std::f64::consts::PI * light_distance_sq)
+        let profile = ies_factor(self.ies_profile.as_deref(), Vector3::new(0.0, -1.0, 0.0), point - self.position);
+        let distance = (self.position - point).length();
+        if self.attenuation == Attenuation::QUADRATIC {
+            let light_distance_sq = (self.position - point).length_sq();
+            return self.range_factor(distance) * profile * self.brightness / (4.0 * std::f64::consts::PI * light_distance_sq);
+        }
+        self.range_factor(distance) * profile * self.brightness / (4.0 * std::f64::consts::PI * self.attenuation.factor(distance))
+    }
+
+    fn get_power(&self) -> f64 {
+        self.brightness
     }
 
     fn get_color(&self) -> Color {
@@ -127,162 +760,1636 @@ impl LightEmitter for PointLight {
     fn get_distance(&self, point: Point) -> f64 {
         (self.position - point).length()
     }
+
+    fn casts_shadows(&self) -> bool {
+        self.cast_shadows
+    }
+
+    fn get_range(&self) -> Option<f64> {
+        Some(self.range())
+    }
 }
 
-#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
-pub enum Light {
-    POINT(PointLight),
-    DIRECTIONAL(DirectionalLight)
+// Point light limited to a cone, full brightness inside the inner angle fading out to nothing at the outer one
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SpotLight {
+    pub position: Point,
+    pub direction: Vector3,
+    pub inner_angle: f64, // Degrees from the direction
+    pub outer_angle: f64,
+    pub brightness: f64,
+    pub color: Color,
+    #[serde(default = "default_true")]
+    pub cast_shadows: bool,
+    #[serde(skip)]
+    pub units: LightUnits, // Copied from the scene when it is prepared
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ies_file: Option<String>, // Intensity distribution with its nadir along the direction, still cut by the cone
+    #[serde(skip)]
+    pub ies_profile: Option<Arc<IesProfile>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>
 }
 
-impl LightEmitter for Light {
-    fn get_direction(&self, point: Point) -> Vector3 {
-        match self {
-            Light::POINT(l) => l.get_direction(point),
-            Light::DIRECTIONAL(l) => l.get_direction(point),
+impl SpotLight {
+    pub fn new(position: Point, direction: Vector3, inner_angle: f64, outer_angle: f64, brightness: f64, color: Color) -> SpotLight {
+        SpotLight { position, direction, inner_angle, outer_angle, brightness, color, cast_shadows: true, units: LightUnits::default(), ies_file: None, ies_profile: None, name: None }
+    }
+
+    // Smoothstep between the cosines of the outer and inner angles, a hard edge when they are equal
+    pub fn cone_factor(&self, point: Point) -> f64 {
+        let cos_angle = (point - self.position).normalize().dot(&self.direction.normalize());
+        let cos_inner = self.inner_angle.to_radians().cos();
+        let cos_outer = self.outer_angle.to_radians().cos();
+        if cos_angle >= cos_inner {
+            return 1.0;
+        }
+        if cos_angle <= cos_outer {
+            return 0.0;
         }
+        let t = (cos_angle - cos_outer) / (cos_inner - cos_outer);
+        t * t * (3.0 - 2.0 * t)
+    }
+}
+
+impl LightEmitter for SpotLight {
+    fn get_direction(&self, point: Point) -> Vector3 {
+        (self.position - point).normalize()
     }
 
     fn get_brightness(&self, point: Point) -> f64 {
-        match self {
-            Light::POINT(l) => l.get_brightness(point),
-            Light::DIRECTIONAL(l) => l.get_brightness(point),
-        }
+        let light_distance_sq = (self.position - point).length_sq();
+        let solid_angle = match self.units {
+            LightUnits::LEGACY => 4.0 * std::f64::consts::PI,
+            LightUnits::PHYSICAL => 2.0 * std::f64::consts::PI * (1.0 - self.outer_angle.to_radians().cos())
+        };
+        let profile = ies_factor(self.ies_profile.as_deref(), self.direction, point - self.position);
+        profile * self.cone_factor(point) * self.brightness / (solid_angle * light_distance_sq)
+    }
+
+    fn get_power(&self) -> f64 {
+        self.brightness
     }
 
     fn get_color(&self) -> Color {
-        match self {
-            Light::POINT(l) => l.get_color(),
-            Light::DIRECTIONAL(l) => l.get_color(),
-        }
+        self.color
     }
 
     fn get_distance(&self, point: Point) -> f64 {
-        match self {
-            Light::POINT(l) => l.get_distance(point),
-            Light::DIRECTIONAL(l) => l.get_distance(point),
-        }
+        (self.position - point).length()
     }
-}
 
-#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
-pub struct Renderable {
-    pub shape: Shape,
-    pub material: Material
+    fn casts_shadows(&self) -> bool {
+        self.cast_shadows
+    }
 }
 
-impl Renderable {
-    pub fn new(shape: Shape, material: Material) -> Renderable {
-        Renderable { shape, material }
-    }
+fn default_area_samples() -> u32 {
+    16
 }
 
-#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
-pub struct Camera {
-    pub width: u32,
-    pub height: u32,
-    pub fov: f64,
+// Rectangle lighting the side its u x v normal points to, for soft shadows
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AreaLight {
+    pub corner: Point,
+    pub u: Vector3, // Edges from the corner
+    pub v: Vector3,
+    pub brightness: f64,
+    pub color: Color,
+    #[serde(default = "default_area_samples")]
+    pub samples: u32,
+    #[serde(default = "default_true")]
+    pub cast_shadows: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>
 }
 
-impl Camera {
-    pub fn new(width: u32, height: u32, fov: f64) -> Camera {
-        Camera { width, height, fov }
+impl AreaLight {
+    pub fn new(corner: Point, u: Vector3, v: Vector3, brightness: f64, color: Color, samples: u32) -> AreaLight {
+        AreaLight { corner, u, v, brightness, color, samples, cast_shadows: true, name: None }
     }
 
-    pub fn compute_prime_ray(&self, pixel_x_screen_space: u32, pixel_y_screen_space: u32) -> Ray {
-        assert!(self.width >= self.height);
-        assert!(pixel_x_screen_space <= self.width);
-        assert!(pixel_y_screen_space <= self.height);
-        let fov_adjustment = (self.fov.to_radians() / 2.0).tan();
-        let aspect_ratio = (self.width as f64) / (self.height as f64);
-        let dir_x = (((pixel_x_screen_space as f64 + 0.5) / self.width as f64) * 2.0 - 1.0) * aspect_ratio * fov_adjustment;
-        let dir_y = (1.0 - ((pixel_y_screen_space as f64 + 0.5) / self.height as f64) * 2.0) * fov_adjustment;
+    pub fn center(&self) -> Point {
+        self.corner + self.u * 0.5 + self.v * 0.5
+    }
 
-        Ray::new(Vector3::zero(), Vector3::new(dir_x, dir_y, -1.0).normalize())
+    // Lambertian emitter, brightest along its normal and dark behind
+    fn brightness_from(&self, position: Point, point: Point) -> f64 {
+        let offset = point - position;
+        let cos_emission = self.u.cross(&self.v).normalize().dot(&offset.normalize()).max(0.0);
+        self.brightness * cos_emission / (std::f64::consts::PI * offset.length_sq())
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct Scene {
-    pub camera: Camera,
-    pub elements: Vec<Renderable>,
-    pub lights: Vec<Light>,
-    pub sky_color: Color
-}
+impl LightEmitter for AreaLight {
+    fn get_direction(&self, point: Point) -> Vector3 {
+        (self.center() - point).normalize()
+    }
 
-impl Scene {
-    pub fn new(camera: Camera, elements: Vec<Renderable>, lights: Vec<Light>, sky_color: Color) -> Scene {
-        Scene { camera, elements, lights, sky_color }
+    fn get_brightness(&self, point: Point) -> f64 {
+        self.brightness_from(self.center(), point)
     }
 
-    pub fn trace(&self, ray: &Ray) -> Option<(Renderable, Hit)> {
-        let mut min_distance = std::f64::MAX;
-        let mut object: Option<(Renderable, Hit)> = None;
-        for &renderable in self.elements.iter() {
-            if let Some(hit) = renderable.shape.intersect(ray) {
-                if min_distance > hit.distance {
-                    min_distance = hit.distance;
-                    object = Some((renderable, hit));
-                }
-            }
-        }
-        object
+    fn get_power(&self) -> f64 {
+        self.brightness
     }
 
-    pub fn get_color(&self, ray: &Ray, hit_obj: Option<(Renderable, Hit)>, depth: u8, max_depth: u8) -> Color {
-        if let Some((renderable, hit)) = hit_obj {
-            if depth >= max_depth {
-                return Color::black();
-            }
-            let mut color_r: f64 = 0.0;
-            let mut color_g: f64 = 0.0;
-            let mut color_b: f64 = 0.0;
-            let amount_reflected = renderable.material.albedo / std::f64::consts::PI;
-            for light in self.lights.iter() {
-                let light_direction = light.get_direction(hit.point);
-                let mut light_brightness = light.get_brightness(hit.point);
-                let light_ray = Ray::new(hit.point + (hit.normal * SHADOW_BIAS), light_direction);
-                if let Some((_, hit_light)) = self.trace(&light_ray) {
-                    if (hit_light.distance <= light.get_distance(hit.point)) {
-                        light_brightness = 0.0;
-                    }
-                }
-                let light_power = (hit.normal.dot(&light_direction)).max(0.0) * light_brightness;
-                color_r += (((light.get_color().r as f64) / 255.0) * light_power * amount_reflected) * ((renderable.material.base_color.r as f64) / 255.0);
-                color_g += (((light.get_color().g as f64) / 255.0) * light_power * amount_reflected) * ((renderable.material.base_color.g as f64) / 255.0);
-                color_b += (((light.get_color().b as f64) / 255.0) * light_power * amount_reflected) * ((renderable.material.base_color.b as f64) / 255.0);
-            }
-            color_r = color_r.min(1.0).max(0.0);
-            color_g = color_g.min(1.0).max(0.0);
-            color_b = color_b.min(1.0).max(0.0);
-
-            if renderable.material.reflectiveness > 0.0 {
-                let reflection_ray = Ray::compute_reflection_ray(hit.normal, ray.direction, hit.point);
-                color_r = color_r * (1.0 - renderable.material.reflectiveness);
-                color_g = color_g * (1.0 - renderable.material.reflectiveness);
-                color_b = color_b * (1.0 - renderable.material.reflectiveness);
-                let mut color = Color::new((color_r * 255.0) as u8, (color_g * 255.0) as u8, (color_b * 255.0) as u8, 255);
-                let new_obj = self.trace(&reflection_ray);
-                color += self.get_color(&reflection_ray, new_obj, depth + 1, max_depth);
-                color
-            } else {
-                Color::new((color_r * 255.0) as u8, (color_g * 255.0) as u8, (color_b * 255.0) as u8, 255)
-            }
-        } else {
-            self.sky_color
-        }
+    fn get_color(&self) -> Color {
+        self.color
+    }
+
+    fn get_distance(&self, point: Point) -> f64 {
+        (self.center() - point).length()
+    }
+
+    fn casts_shadows(&self) -> bool {
+        self.cast_shadows
+    }
+
+    fn get_samples(&self) -> u32 {
+        self.samples
+    }
+
+    fn sample(&self, point: Point, u: f64, v: f64) -> (Vector3, f64, f64) {
+        let position = self.corner + self.u * u + self.v * v;
+        let offset = position - point;
+        (offset.normalize(), offset.length(), self.brightness_from(position, point))
     }
 }
 
-pub fn render(nb_pass: u8, scene: Scene, output_path: String) -> Result<(), ImageError> {
-    let mut image: RgbaImage = ImageBuffer::new(scene.camera.width, scene.camera.height);
-    for pixel_x in 0..scene.camera.width {
-        for pixel_y in 0..scene.camera.height {
-            let ray = Ray::compute_prime_ray(pixel_x, pixel_y, scene.camera);
-            let object = scene.trace(&ray);
-            let color = scene.get_color(&ray, object, 0, nb_pass);
-            image.put_pixel(pixel_x, pixel_y, color.to_rgba())
-        }
+// Point light with a radius for soft shadows, a radius of 0 is exactly a point light
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SphereLight {
+    pub center: Point,
+    pub radius: f64,
+    pub brightness: f64,
+    pub color: Color,
+    #[serde(default = "default_area_samples")]
+    pub samples: u32,
+    #[serde(default = "default_true")]
+    pub cast_shadows: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>
+}
+
+impl SphereLight {
+    pub fn new(center: Point, radius: f64, brightness: f64, color: Color, samples: u32) -> SphereLight {
+        SphereLight { center, radius, brightness, color, samples, cast_shadows: true, name: None }
+    }
+}
+
+impl LightEmitter for SphereLight {
+    fn get_direction(&self, point: Point) -> Vector3 {
+        (self.center - point).normalize()
     }
-    image.save(output_path)
-}
\ No newline at end of file
+
+    fn get_brightness(&self, point: Point) -> f64 {
+        let light_distance_sq = (self.center - point).length_sq();
+        self.brightness / (4.0 * std::f64::consts::PI * light_distance_sq)
+    }
+
+    fn get_power(&self) -> f64 {
+        self.brightness
+    }
+
+    fn get_color(&self) -> Color {
+        self.color
+    }
+
+    fn get_distance(&self, point: Point) -> f64 {
+        (self.center - point).length()
+    }
+
+    fn casts_shadows(&self) -> bool {
+        self.cast_shadows
+    }
+
+    fn get_samples(&self) -> u32 {
+        if self.radius > 0.0 { self.samples } else { 1 }
+    }
+
+    // Uniform over the hemisphere facing the point, the brightness still falls off with the distance to the center
+    fn sample(&self, point: Point, u: f64, v: f64) -> (Vector3, f64, f64) {
+        let axis = (point - self.center).normalize();
+        let (tangent, bitangent) = tangent_axes(axis);
+        let (sin_phi, cos_phi) = (2.0 * std::f64::consts::PI * v).sin_cos();
+        let sin_theta = (1.0 - u * u).max(0.0).sqrt();
+        let position = self.center + (axis * u + tangent * (sin_theta * cos_phi) + bitangent * (sin_theta * sin_phi)) * self.radius;
+        let offset = position - point;
+        (offset.normalize(), offset.length(), self.get_brightness(point))
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum Light {
+    POINT(PointLight),
+    DIRECTIONAL(DirectionalLight),
+    SPOT(SpotLight),
+    AREA(AreaLight),
+    SPHERE(SphereLight)
+}
+
+impl Light {
+    pub fn transformed(&self, matrix: &Matrix) -> Light {
+        match self {
+            Light::POINT(l) => Light::POINT(PointLight { position: matrix.transform_point(l.position), ..l.clone() }),
+            Light::DIRECTIONAL(l) => Light::DIRECTIONAL(DirectionalLight { direction: matrix.transform_vector(l.direction).normalize(), ..l.clone() }),
+            Light::SPOT(l) => Light::SPOT(SpotLight { position: matrix.transform_point(l.position), direction: matrix.transform_vector(l.direction).normalize(), ..l.clone() }),
+            Light::AREA(l) => Light::AREA(AreaLight { corner: matrix.transform_point(l.corner), u: matrix.transform_vector(l.u), v: matrix.transform_vector(l.v), ..l.clone() }),
+            Light::SPHERE(l) => Light::SPHERE(SphereLight { center: matrix.transform_point(l.center), radius: l.radius * matrix.uniform_scale(), ..l.clone() }),
+        }
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        match self {
+            Light::POINT(l) => l.name.as_deref(),
+            Light::DIRECTIONAL(l) => l.name.as_deref(),
+            Light::SPOT(l) => l.name.as_deref(),
+            Light::AREA(l) => l.name.as_deref(),
+            Light::SPHERE(l) => l.name.as_deref()
+        }
+    }
+
+    pub fn ies_file(&self) -> Option<&str> {
+        match self {
+            Light::POINT(l) => l.ies_file.as_deref(),
+            Light::SPOT(l) => l.ies_file.as_deref(),
+            _ => None
+        }
+    }
+
+    pub fn set_ies_profile(&mut self, profile: Arc<IesProfile>) {
+        match self {
+            Light::POINT(l) => l.ies_profile = Some(profile),
+            Light::SPOT(l) => l.ies_profile = Some(profile),
+            _ => ()
+        }
+    }
+}
+
+impl LightEmitter for Light {
+    fn get_direction(&self, point: Point) -> Vector3 {
+        match self {
+            Light::POINT(l) => l.get_direction(point),
+            Light::DIRECTIONAL(l) => l.get_direction(point),
+            Light::SPOT(l) => l.get_direction(point),
+            Light::AREA(l) => l.get_direction(point),
+            Light::SPHERE(l) => l.get_direction(point),
+        }
+    }
+
+    fn get_brightness(&self, point: Point) -> f64 {
+        match self {
+            Light::POINT(l) => l.get_brightness(point),
+            Light::DIRECTIONAL(l) => l.get_brightness(point),
+            Light::SPOT(l) => l.get_brightness(point),
+            Light::AREA(l) => l.get_brightness(point),
+            Light::SPHERE(l) => l.get_brightness(point),
+        }
+    }
+
+    fn get_power(&self) -> f64 {
+        match self {
+            Light::POINT(l) => l.get_power(),
+            Light::DIRECTIONAL(l) => l.get_power(),
+            Light::SPOT(l) => l.get_power(),
+            Light::AREA(l) => l.get_power(),
+            Light::SPHERE(l) => l.get_power(),
+        }
+    }
+
+    fn get_color(&self) -> Color {
+        match self {
+            Light::POINT(l) => l.get_color(),
+            Light::DIRECTIONAL(l) => l.get_color(),
+            Light::SPOT(l) => l.get_color(),
+            Light::AREA(l) => l.get_color(),
+            Light::SPHERE(l) => l.get_color(),
+        }
+    }
+
+    fn get_distance(&self, point: Point) -> f64 {
+        match self {
+            Light::POINT(l) => l.get_distance(point),
+            Light::DIRECTIONAL(l) => l.get_distance(point),
+            Light::SPOT(l) => l.get_distance(point),
+            Light::AREA(l) => l.get_distance(point),
+            Light::SPHERE(l) => l.get_distance(point),
+        }
+    }
+
+    fn casts_shadows(&self) -> bool {
+        match self {
+            Light::POINT(l) => l.casts_shadows(),
+            Light::DIRECTIONAL(l) => l.casts_shadows(),
+            Light::SPOT(l) => l.casts_shadows(),
+            Light::AREA(l) => l.casts_shadows(),
+            Light::SPHERE(l) => l.casts_shadows()
+        }
+    }
+
+    fn get_range(&self) -> Option<f64> {
+        match self {
+            Light::POINT(l) => l.get_range(),
+            _ => None
+        }
+    }
+
+    fn get_samples(&self) -> u32 {
+        match self {
+            Light::DIRECTIONAL(l) => l.get_samples(),
+            Light::AREA(l) => l.get_samples(),
+            Light::SPHERE(l) => l.get_samples(),
+            _ => 1
+        }
+    }
+
+    fn sample(&self, point: Point, u: f64, v: f64) -> (Vector3, f64, f64) {
+        match self {
+            Light::POINT(l) => l.sample(point, u, v),
+            Light::DIRECTIONAL(l) => l.sample(point, u, v),
+            Light::SPOT(l) => l.sample(point, u, v),
+            Light::AREA(l) => l.sample(point, u, v),
+            Light::SPHERE(l) => l.sample(point, u, v),
+        }
+    }
+}
+
+fn default_true() -> bool {
+    true
+}
+
+// Linear move over the frame, the element is translated by start at time 0 and by end at time 1
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct Motion {
+    #[serde(default = "Vector3::zero")]
+    pub start: Vector3,
+    pub end: Vector3
+}
+
+impl Motion {
+    pub fn offset(&self, time: f64) -> Vector3 {
+        self.start + (self.end - self.start) * time
+    }
+
+    pub fn transformed(&self, matrix: &Matrix) -> Motion {
+        Motion { start: matrix.transform_vector(self.start), end: matrix.transform_vector(self.end) }
+    }
+}
+
+// An element material is written inline or as the name of one of the scene materials
+mod material_or_name {
+    use std::fmt;
+    use serde::{Serialize, Deserialize, Serializer, Deserializer};
+    use serde::de::{self, MapAccess, Visitor};
+    use super::Material;
+
+    pub fn serialize<S: Serializer>(material: &Material, serializer: S) -> Result<S::Ok, S::Error> {
+        match &material.name {
+            Some(name) => name.serialize(serializer),
+            None => material.serialize(serializer)
+        }
+    }
+
+    struct MaterialVisitor;
+
+    impl<'de> Visitor<'de> for MaterialVisitor {
+        type Value = Material;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a material or the name of one of the scene materials")
+        }
+
+        fn visit_str<E: de::Error>(self, name: &str) -> Result<Material, E> {
+            Ok(Material::named(name))
+        }
+
+        // Through the derived deserializer, to keep its messages about the fields of inline materials
+        fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Material, A::Error> {
+            Material::deserialize(de::value::MapAccessDeserializer::new(map))
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Material, D::Error> {
+        deserializer.deserialize_any(MaterialVisitor)
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Renderable {
+    pub shape: Shape,
+    #[serde(with = "material_or_name")]
+    pub material: Material, // Inline, or like "chrome" for a scene material
+    #[serde(default = "default_true")]
+    pub visible_to_camera: bool,
+    #[serde(default = "default_true")]
+    pub visible_in_reflections: bool,
+    #[serde(default = "default_true")]
+    pub casts_shadows: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub motion: Option<Motion>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lights: Option<Vec<String>>, // Names of the only lights shading the element, every light by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>, // For the camera focus_on
+    #[serde(default, skip_serializing_if = "UvProjection::is_none")]
+    pub uv_projection: UvProjection // Replaces the texture coordinates of the shape
+}
+
+impl Renderable {
+    pub fn new(shape: Shape, material: Material) -> Renderable {
+        Renderable { shape, material, visible_to_camera: true, visible_in_reflections: true, casts_shadows: true, motion: None, lights: None, name: None, uv_projection: UvProjection::default() }
+    }
+
+    pub fn transformed(&self, matrix: &Matrix) -> Renderable {
+        Renderable { shape: self.shape.transformed(matrix), motion: self.motion.map(|motion| motion.transformed(matrix)), uv_projection: self.uv_projection.transformed(matrix), ..self.clone() }
+    }
+
+    // Linked elements ignore unnamed lights, like emissive elements and the sun
+    pub fn is_lit_by(&self, light: &Light) -> bool {
+        match &self.lights {
+            None => true,
+            Some(names) => light.name().is_some_and(|name| names.iter().any(|linked| linked == name))
+        }
+    }
+
+    // Moving elements are met where they are at the time of the ray
+    pub fn intersect(&self, ray: &Ray) -> Option<Hit> {
+        match &self.motion {
+            None => self.shape.intersect(ray).map(|hit| {
+                let point = hit.point;
+                self.uv_projection.apply(hit, point)
+            }),
+            Some(motion) => {
+                let offset = motion.offset(ray.time);
+                let hit = self.shape.intersect(&Ray { origin: ray.origin - offset, ..*ray })?;
+                // Projected where the element starts so that the texture moves with it
+                let point = hit.point;
+                Some(Hit { point: hit.point + offset, ..self.uv_projection.apply(hit, point) })
+            }
+        }
+    }
+
+    // Bounds swept by the whole move
+    pub fn bounding_box(&self) -> Option<Aabb> {
+        let aabb = self.shape.bounding_box()?;
+        match &self.motion {
+            None => Some(aabb),
+            Some(motion) => Some(aabb.translated(motion.start).union(&aabb.translated(motion.end)))
+        }
+    }
+
+    pub fn is_visible(&self, ray_type: RayType) -> bool {
+        match ray_type {
+            RayType::PRIMARY => self.visible_to_camera,
+            RayType::REFLECTION | RayType::REFRACTION => self.visible_in_reflections,
+            RayType::SHADOW => self.casts_shadows
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum Projection {
+    #[default]
+    PERSPECTIVE,
+    PANORAMIC // Equirectangular view of the whole sphere around the camera, for 2:1 images
+}
+
+// Exporters write matrices with some rounding, but scales, shears or mirrors would distort the view
+const CAMERA_MATRIX_TOLERANCE: f64 = 1e-3;
+
+fn matrix_basis(m: &[[f64; 4]; 4]) -> Result<(Vector3, Vector3, Vector3), String> {
+    if m[3] != [0.0, 0.0, 0.0, 1.0] {
+        return Err(format!("the camera matrix last row must be [0, 0, 0, 1], not {:?}", m[3]));
+    }
+    let column = |index: usize| Vector3::new(m[0][index], m[1][index], m[2][index]);
+    let (right, up, back) = (column(0), column(1), column(2));
+    if Matrix::new(*m).inverse().is_none() {
+        return Err("the camera matrix cannot be inverted".to_string());
+    }
+    let lengths = [right.length(), up.length(), back.length()];
+    if lengths.iter().any(|length| (length - 1.0).abs() > CAMERA_MATRIX_TOLERANCE) {
+        return Err(format!("the camera matrix scales its axes (lengths {:.4}, {:.4}, {:.4}), only rotations and translations are allowed", lengths[0], lengths[1], lengths[2]));
+    }
+    if right.dot(&up).abs().max(right.dot(&back).abs()).max(up.dot(&back).abs()) > CAMERA_MATRIX_TOLERANCE {
+        return Err("the camera matrix axes are not perpendicular, only rotations and translations are allowed".to_string());
+    }
+    if right.cross(&up).dot(&back) < 0.0 {
+        return Err("the camera matrix mirrors the view, only rotations and translations are allowed".to_string());
+    }
+    Ok((right, up, -back))
+}
+
+fn default_up() -> Vector3 {
+    Vector3::new(0.0, 1.0, 0.0)
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Camera {
+    pub width: u32,
+    pub height: u32,
+    pub fov: f64,
+    #[serde(default = "Vector3::zero")]
+    pub position: Point,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub look_at: Option<Point>, // Looks down -z when missing
+    #[serde(default = "default_up")]
+    pub up: Vector3,
+    #[serde(default)]
+    pub shutter_open: f64, // Frame times the shutter is open between, equal for no motion blur
+    #[serde(default)]
+    pub shutter_close: f64,
+    #[serde(default)]
+    pub roll: f64, // Degrees, positive turns the camera counterclockwise around the view direction
+    #[serde(default)]
+    pub shift_x: f64, // Lens shift in image widths, moves the framing without turning the view so verticals stay parallel
+    #[serde(default)]
+    pub shift_y: f64, // In image heights, positive moves the framing up
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub matrix: Option<[[f64; 4]; 4]>, // Camera to world, looking down -z, replaces the position, look_at, up and roll
+    #[serde(default)]
+    pub projection: Projection,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stereo: Option<Stereo>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exposure: Option<Exposure>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub depth_of_field: Option<DepthOfField>,
+    #[serde(skip)]
+    pub frame: Option<(Vector3, Vector3, Vector3)> // Basis checked and kept by prepare so the rays do not rebuild it
+}
+
+impl Camera {
+    pub fn new(width: u32, height: u32, fov: f64) -> Camera {
+        Camera { width, height, fov, position: Vector3::zero(), look_at: None, up: default_up(), roll: 0.0, shift_x: 0.0, shift_y: 0.0, matrix: None, shutter_open: 0.0, shutter_close: 0.0, projection: Projection::default(), stereo: None, exposure: None, depth_of_field: None, frame: None }
+    }
+
+    pub fn looking_at(self, position: Point, look_at: Point, up: Vector3) -> Camera {
+        Camera { position, look_at: Some(look_at), up, matrix: None, frame: None, ..self }.framed()
+    }
+
+    // Placed by the columns of a camera to world matrix, the axes must be orthonormal
+    pub fn with_basis(self, position: Point, right: Vector3, up: Vector3, forward: Vector3) -> Camera {
+        Camera { matrix: Some([
+            [right.x, up.x, -forward.x, position.x],
+            [right.y, up.y, -forward.y, position.y],
+            [right.z, up.z, -forward.z, position.z],
+            [0.0, 0.0, 0.0, 1.0]
+        ]), frame: None, ..self }.framed()
+    }
+
+    // Checks the basis once and keeps it for the rays, placing the camera again must frame it again
+    pub fn prepare(&mut self) -> Result<(), String> {
+        self.frame = Some(self.basis()?);
+        Ok(())
+    }
+
+    fn framed(self) -> Camera {
+        Camera { frame: self.basis().ok(), ..self }
+    }
+
+    pub fn origin(&self) -> Point {
+        match self.matrix {
+            Some(m) => Vector3::new(m[0][3], m[1][3], m[2][3]),
+            None => self.position
+        }
+    }
+
+    // Right, up and forward axes of the view
+    pub fn basis(&self) -> Result<(Vector3, Vector3, Vector3), String> {
+        if let Some(m) = self.matrix {
+            return matrix_basis(&m);
+        }
+        let forward = match self.look_at {
+            Some(look_at) if (look_at - self.position).length_sq() == 0.0 => return Err("the camera look_at is at its position".to_string()),
+            Some(look_at) => (look_at - self.position).normalize(),
+            None => Vector3::new(0.0, 0.0, -1.0)
+        };
+        let right = forward.cross(&self.up);
+        if right.length_sq() <= 1e-12 * self.up.length_sq() {
+            return Err("the camera up is zero or parallel to the view direction".to_string());
+        }
+        let right = right.normalize();
+        let up = right.cross(&forward);
+        let (sin, cos) = self.roll.to_radians().sin_cos();
+        Ok((right * cos + up * sin, up * cos - right * sin, forward))
+    }
+
+    // Only once prepared and focused, a camera without an aperture keeps the pinhole rays
+    pub fn lens(&self) -> Option<Lens> {
+        let depth_of_field = self.depth_of_field.as_ref().filter(|depth_of_field| depth_of_field.aperture > 0.0)?;
+        let (right, up, forward) = self.frame?;
+        Some(Lens { radius: depth_of_field.aperture * 0.5, focus: depth_of_field.focus?, right, up, forward })
+    }
+
+    pub fn exposure_scale(&self) -> f64 {
+        self.exposure.map_or(1.0, |exposure| exposure.scale())
+    }
+
+    pub fn compute_prime_ray(&self, pixel_x_screen_space: u32, pixel_y_screen_space: u32) -> Ray {
+        self.compute_sample_ray(pixel_x_screen_space, pixel_y_screen_space, 0.5, 0.5)
+    }
+
+    pub fn compute_sample_ray(&self, pixel_x_screen_space: u32, pixel_y_screen_space: u32, offset_x: f64, offset_y: f64) -> Ray {
+        debug_assert!(pixel_x_screen_space < self.width);
+        debug_assert!(pixel_y_screen_space < self.height);
+        let (dir_x, dir_y, dir_z) = match self.projection {
+            Projection::PERSPECTIVE => {
+                // The fov spans the shorter side of the image, vertical in landscape and horizontal in portrait
+                let fov_adjustment = (self.fov.to_radians() / 2.0).tan();
+                let (aspect_x, aspect_y) = if self.width >= self.height {
+                    ((self.width as f64) / (self.height as f64), 1.0)
+                } else {
+                    (1.0, (self.height as f64) / (self.width as f64))
+                };
+                // The lens shift slides the image plane across the view, a shift of 1 frames the next image over
+                let dir_x = (((pixel_x_screen_space as f64 + offset_x) / self.width as f64) * 2.0 - 1.0 + self.shift_x * 2.0) * aspect_x * fov_adjustment;
+                let dir_y = (1.0 - ((pixel_y_screen_space as f64 + offset_y) / self.height as f64) * 2.0 + self.shift_y * 2.0) * aspect_y * fov_adjustment;
+                (dir_x, dir_y, 1.0)
+            },
+            Projection::PANORAMIC => {
+                // Longitude 0 is the view direction and the image wraps around behind the camera. The rows are sampled
+                // inside their pixels, never exactly on a pole, so the top and bottom rows still spread around it
+                let longitude = (((pixel_x_screen_space as f64 + offset_x) / self.width as f64) * 2.0 - 1.0) * std::f64::consts::PI;
+                let latitude = (0.5 - (pixel_y_screen_space as f64 + offset_y) / self.height as f64) * std::f64::consts::PI;
+                (longitude.sin() * latitude.cos(), latitude.sin(), longitude.cos() * latitude.cos())
+            }
+        };
+        // Scenes are checked when prepared, a degenerate basis can only be met before that and keeps the default view
+        let (right, up, forward) = self.frame.unwrap_or_else(|| {
+            self.basis().unwrap_or((Vector3::new(1.0, 0.0, 0.0), Vector3::new(0.0, 1.0, 0.0), Vector3::new(0.0, 0.0, -1.0)))
+        });
+
+        Ray::new(self.origin(), (right * dir_x + up * dir_y + forward * dir_z).normalize())
+    }
+}
+
+fn default_samples_per_pixel() -> u32 {
+    1
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Scene {
+    pub camera: Camera,
+    pub elements: Vec<Renderable>,
+    pub lights: Vec<Light>,
+    #[serde(default)]
+    pub sky_color: Sky,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub environment: Option<Environment>,
+    #[serde(default)]
+    pub nodes: Vec<Node>,
+    #[serde(default)]
+    pub geometries: BTreeMap<String, Shape>,
+    #[serde(default)]
+    pub materials: BTreeMap<String, Material>, // Shared by the elements naming them
+    #[serde(default)]
+    pub light_units: LightUnits,
+    #[serde(default)]
+    pub light_sampling: LightSampling,
+    #[serde(default)]
+    pub light_subset: Option<LightSubset>,
+    #[serde(default)]
+    pub caustics: Caustics,
+    #[serde(default)]
+    pub post: Vec<PostEffect>,
+    #[serde(default)]
+    pub output: OutputConfig,
+    #[serde(default)]
+    pub sampler: Sampler,
+    #[serde(default = "default_samples_per_pixel")]
+    pub samples_per_pixel: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub camera_path: Option<CameraPath>,
+    #[serde(default)]
+    pub seed: u64,
+    #[serde(skip)]
+    pub flat_elements: Vec<Renderable>, // The elements then those of the nodes in world space, resolved and loaded by prepare
+    #[serde(skip)]
+    pub flat_lights: Vec<Light>, // The lights then those of the nodes in world space
+    #[serde(skip)]
+    pub emitters: Vec<Emitter>,
+    #[serde(skip)]
+    pub photon_map: Option<PhotonMap>,
+    #[serde(skip)]
+    pub light_powers: Vec<f64>, // Emitted power of every light in the same units, for POWER_WEIGHTED
+    #[serde(skip)]
+    pub profile: Profile,
+    #[serde(skip)]
+    pub limits: Limits, // Checked on the files read when the scene is prepared
+    #[serde(skip)]
+    pub textures: Arc<TextureCache>,
+    #[serde(skip)]
+    pub verbose: bool // Prints every asset once it is loaded, and the photons of the caustics
+}
+
+impl Scene {
+    pub fn new(camera: Camera, elements: Vec<Renderable>, lights: Vec<Light>, sky_color: Color) -> Scene {
+        Scene { camera, elements, lights, sky_color: Sky::COLOR(sky_color), environment: None, nodes: Vec::new(), geometries: BTreeMap::new(), materials: BTreeMap::new(), light_units: LightUnits::default(), light_sampling: LightSampling::default(), light_subset: None, caustics: Caustics::default(), post: Vec::new(), output: OutputConfig::default(), sampler: Sampler::default(), samples_per_pixel: default_samples_per_pixel(), camera_path: None, seed: 0, flat_elements: Vec::new(), flat_lights: Vec::new(), emitters: Vec::new(), photon_map: None, light_powers: Vec::new(), profile: Profile::default(), limits: Limits::default(), textures: Arc::default(), verbose: false }
+    }
+
+    pub fn prepare(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.camera.prepare()?;
+        if self.camera.shutter_close < self.camera.shutter_open {
+            return Err(format!("the camera shutter closes at {} before it opens at {}", self.camera.shutter_close, self.camera.shutter_open).into());
+        }
+        if let Some(exposure) = &self.camera.exposure {
+            exposure.validate()?;
+        }
+        if let Some(depth_of_field) = &self.camera.depth_of_field {
+            depth_of_field.validate(&self.camera)?;
+        }
+        if let Some(path) = &self.camera_path {
+            path.validate(&self.camera)?;
+        }
+        if let Some(environment) = &self.environment {
+            environment.validate()?;
+        }
+        // The elements, lights and nodes stay as the file wrote them so that the scene serializes back with its hierarchy,
+        // rendering goes through copies with the nodes flattened into world space
+        self.flat_elements = self.elements.clone();
+        self.flat_lights = self.lights.clone();
+        for node in self.nodes.iter() {
+            node.flatten(&Matrix::identity(), &mut self.flat_elements, &mut self.flat_lights);
+        }
+        for (index, renderable) in self.flat_elements.iter_mut().enumerate() {
+            renderable.uv_projection.prepare().map_err(|message| format!("element {} {}", index, message))?;
+            if let Some(name) = renderable.material.name.take() {
+                let material = self.materials.get(&name)
+                    .ok_or_else(|| format!("element {} uses material \"{}\" that is not in the scene materials", index, name))?;
+                renderable.material = Material { name: Some(name), ..material.clone() };
+            }
+        }
+        for (index, renderable) in self.flat_elements.iter().enumerate() {
+            if !(0.0..=1.0).contains(&renderable.material.transparency) {
+                return Err(format!("element {} transparency must be between 0 and 1, not {}", index, renderable.material.transparency).into());
+            }
+            if !(0.0..=1.0).contains(&renderable.material.opacity) {
+                return Err(format!("element {} opacity must be between 0 and 1, not {}", index, renderable.material.opacity).into());
+            }
+            if renderable.material.ior <= 0.0 || renderable.material.ior.is_nan() {
+                return Err(format!("element {} ior must be positive, not {}", index, renderable.material.ior).into());
+            }
+            if renderable.material.albedo.0.iter().any(|albedo| !(0.0..=1.0).contains(albedo)) {
+                return Err(format!("element {} albedo must be between 0 and 1 in every channel, not {}", index, renderable.material.albedo).into());
+            }
+            renderable.material.base_color.validate().map_err(|message| format!("element {} {}", index, message))?;
+            for image in renderable.material.normal_map.iter().chain(renderable.material.bump_map.as_ref().map(|bump_map| &bump_map.image)) {
+                image.validate().map_err(|message| format!("element {} {}", index, message))?;
+            }
+            if renderable.material.specular < 0.0 || renderable.material.shininess < 0.0 {
+                return Err(format!("element {} specular and shininess must not be negative, not {} and {}", index, renderable.material.specular, renderable.material.shininess).into());
+            }
+            if let Fresnel::F0(f0) = renderable.material.fresnel {
+                if !(0.0..=1.0).contains(&f0) {
+                    return Err(format!("element {} fresnel F0 must be between 0 and 1, not {}", index, f0).into());
+                }
+            }
+            if let Shading::PBR { metallic, roughness } = renderable.material.shading {
+                if !(0.0..=1.0).contains(&metallic) || !(0.0..=1.0).contains(&roughness) {
+                    return Err(format!("element {} PBR metallic and roughness must be between 0 and 1, not {} and {}", index, metallic, roughness).into());
+                }
+            }
+            if let Some(missing) = renderable.lights.iter().flatten().find(|name| !self.flat_lights.iter().any(|light| light.name() == Some(name.as_str()))) {
+                return Err(format!("element {} is linked to a light named \"{}\" that is not in the scene", index, missing).into());
+            }
+        }
+        for (index, light) in self.flat_lights.iter_mut().enumerate() {
+            if let Light::SPOT(spot) = light {
+                spot.units = self.light_units;
+            }
+            match light {
+                Light::SPOT(spot) if self.light_units == LightUnits::PHYSICAL && spot.outer_angle <= 0.0 => {
+                    return Err(format!("spot light {} outer_angle must be positive with physical light units", index).into());
+                },
+                Light::POINT(point) if self.light_units == LightUnits::PHYSICAL && point.attenuation != Attenuation::QUADRATIC => {
+                    return Err(format!("point light {} attenuation must be QUADRATIC with physical light units", index).into());
+                },
+                Light::DIRECTIONAL(directional) if !(0.0..90.0).contains(&directional.angular_radius) => {
+                    return Err(format!("directional light {} angular_radius must be between 0 and 90 degrees, not {}", index, directional.angular_radius).into());
+                },
+                Light::SPOT(spot) if !(spot.inner_angle >= 0.0 && spot.inner_angle <= spot.outer_angle && spot.outer_angle <= 180.0) => {
+                    return Err(format!("spot light {} angles must be 0 <= inner_angle <= outer_angle <= 180, not {} and {}", index, spot.inner_angle, spot.outer_angle).into());
+                },
+                Light::AREA(area) if area.u.cross(&area.v).length_sq() == 0.0 => {
+                    return Err(format!("area light {} edges u and v are zero or parallel", index).into());
+                },
+                Light::POINT(point) if point.max_distance.is_some_and(|max_distance| max_distance <= 0.0 || max_distance.is_nan()) => {
+                    return Err(format!("point light {} max_distance must be positive", index).into());
+                },
+                Light::POINT(point) if !point.attenuation.is_valid() => {
+                    return Err(format!("point light {} attenuation coefficients must not be negative and one of them must be positive", index).into());
+                },
+                Light::SPHERE(sphere) if sphere.radius < 0.0 || sphere.radius.is_nan() => {
+                    return Err(format!("sphere light {} radius must not be negative, not {}", index, sphere.radius).into());
+                },
+                _ => ()
+            }
+        }
+        self.emitters = self.flat_elements.iter().enumerate().flat_map(|(index, renderable)| Emitter::of(index, renderable)).collect();
+        self.emitters.extend(self.environment.iter().filter_map(Environment::sun).map(|light| Emitter { element: None, light }));
+        // Geometries are loaded once here and shared by their instances, the scene keeps their description
+        let mut geometries: Vec<(String, Shape)> = self.geometries.iter().map(|(name, shape)| (name.clone(), shape.clone())).collect();
+        if geometries.iter().any(|(_, shape)| !shape.instances().is_empty()) {
+            return Err("geometries cannot contain instances".into());
+        }
+        // IES files are loaded once for all the lights sharing them
+        let mut ies_files: Vec<&str> = self.flat_lights.iter().filter_map(Light::ies_file).collect();
+        ies_files.sort_unstable();
+        ies_files.dedup();
+        let mut ies_files: Vec<IesFile> = ies_files.into_iter().map(IesFile::new).collect();
+        // Image textures are only decoded when a ray samples them, their headers are read now so that every missing or
+        // broken file is reported before the render
+        let mut texture_files: Vec<&str> = self.flat_elements.iter().flat_map(|renderable| renderable.material.images()).map(|image| image.file.as_str()).collect();
+        texture_files.sort_unstable();
+        texture_files.dedup();
+        let unreadable: Vec<String> = texture_files.iter()
+            .filter_map(|file| image::image_dimensions(file).err().map(|e| format!("{}: {}", file, e)))
+            .collect();
+        if !unreadable.is_empty() {
+            return Err(AssetError::new(unreadable).into());
+        }
+        let heightfields = self.flat_elements.iter().map(|renderable| &renderable.shape)
+            .chain(geometries.iter().map(|(_, shape)| shape))
+            .flat_map(|shape| shape.heightfields())
+            .map(|heightfield| heightfield.file.as_str());
+        let environment = match &self.environment {
+            Some(Environment::IMAGE(image)) => Some(image.file.as_str()),
+            _ => None
+        };
+        for file in texture_files.iter().copied().chain(heightfields).chain(environment) {
+            self.limits.check_image(file)?;
+        }
+        let shapes = self.flat_elements.iter_mut()
+            .map(|renderable| &mut renderable.shape)
+            .chain(geometries.iter_mut().map(|(_, shape)| shape))
+            .flat_map(|shape| shape.assets_mut());
+        let mut assets: Vec<&mut dyn Asset> = self.environment.iter_mut().map(|environment| environment as &mut dyn Asset)
+            .chain(self.post.iter_mut().map(|effect| effect as &mut dyn Asset))
+            .chain(ies_files.iter_mut().map(|file| file as &mut dyn Asset))
+            .chain(shapes)
+            .collect();
+        let durations = assets::load_all(&mut assets)?;
+        self.profile.add(Stage::ASSETS, durations.iter().sum());
+        if self.verbose {
+            for (asset, duration) in assets.iter().zip(durations.iter()) {
+                if self.profile.is_enabled() {
+                    println!("{}, loaded in {:.3} ms", asset.describe(), duration.as_secs_f64() * 1000.0);
+                } else {
+                    println!("{}", asset.describe());
+                }
+            }
+        }
+        for mesh in self.flat_elements.iter().map(|renderable| &renderable.shape).chain(geometries.iter().map(|(_, shape)| shape)).flat_map(|shape| shape.meshes()) {
+            self.limits.check(Limit::TRIANGLES, mesh.indices.len() as u64).map_err(|e| format!("{}: {}", mesh.file, e))?;
+        }
+        let profiles: BTreeMap<String, Arc<IesProfile>> = ies_files.into_iter()
+            .filter_map(|IesFile { file, profile }| profile.map(|profile| (file, Arc::new(profile))))
+            .collect();
+        for light in self.flat_lights.iter_mut() {
+            if let Some(profile) = light.ies_file().and_then(|file| profiles.get(file)) {
+                let profile = Arc::clone(profile);
+                light.set_ies_profile(profile);
+            }
+        }
+        for image in self.flat_elements.iter_mut().flat_map(|renderable| renderable.material.images_mut()) {
+            image.cache = Some(Arc::clone(&self.textures));
+        }
+        let shared: BTreeMap<String, Arc<Shape>> = geometries.into_iter().map(|(name, shape)| (name, Arc::new(shape))).collect();
+        for instance in self.flat_elements.iter_mut().flat_map(|renderable| renderable.shape.instances_mut()) {
+            match shared.get(&instance.description.reference) {
+                Some(geometry) => instance.geometry = Some(Arc::clone(geometry)),
+                None => return Err(format!("instance of unknown geometry \"{}\"", instance.description.reference).into())
+            }
+        }
+        self.light_powers = light_sampling::light_powers(&self.flat_lights, self.bounding_box());
+        self.focus()?;
+        Ok(())
+    }
+
+    // Resolves the distance in focus from the elements once they are loaded, again whenever the camera moves
+    pub fn focus(&mut self) -> Result<(), String> {
+        let focus = match &self.camera.depth_of_field {
+            Some(depth_of_field) => depth_of_field.resolve(self)?,
+            None => return Ok(())
+        };
+        if let Some(depth_of_field) = self.camera.depth_of_field.as_mut() {
+            depth_of_field.focus = Some(focus);
+        }
+        Ok(())
+    }
+
+    pub fn metadata(&self) -> Vec<(String, String)> {
+        let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+        if let Ok(mut value) = serde_json::to_value(self) {
+            if let Some(object) = value.as_object_mut() {
+                object.remove("seed");
+                object.remove("samples_per_pixel");
+                object.remove("output");
+            }
+            for byte in value.to_string().bytes() {
+                hash = (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3);
+            }
+        }
+        vec![
+            ("Software".to_string(), "rust_raytracer".to_string()),
+            ("Samples".to_string(), self.samples_per_pixel.max(1).to_string()),
+            ("Seed".to_string(), self.seed.to_string()),
+            ("SceneHash".to_string(), format!("{:016x}", hash))
+        ]
+    }
+
+    // Bounds of every bounded element, planes are left out
+    pub fn bounding_box(&self) -> Option<Aabb> {
+        self.flat_elements.iter()
+            .filter_map(|renderable| renderable.bounding_box())
+            .fold(None, |scene: Option<Aabb>, aabb| Some(scene.map_or(aabb, |scene| scene.union(&aabb))))
+    }
+
+    pub fn trace(&self, ray: &Ray, ray_type: RayType) -> Option<(&Renderable, Hit)> {
+        self.trace_recorded(ray, ray_type, &mut NoRecorder)
+    }
+
+    pub fn trace_recorded(&self, ray: &Ray, ray_type: RayType, recorder: &mut dyn Recorder) -> Option<(&Renderable, Hit)> {
+        let mut min_distance = ray.t_max;
+        let mut object: Option<(&Renderable, Hit)> = None;
+        // The range ends at the closest hit so far so that farther shapes stop early, except for the debug output listing every candidate
+        let recording = recorder.is_recording();
+        for (index, renderable) in self.flat_elements.iter().enumerate().filter(|(_, renderable)| renderable.is_visible(ray_type)) {
+            let limited = if recording { *ray } else { ray.with_range(ray.t_min, min_distance) };
+            if let Some(hit) = renderable.intersect(&limited) {
+                recorder.candidate(index, &hit);
+                if min_distance > hit.distance {
+                    min_distance = hit.distance;
+                    object = Some((renderable, hit));
+                }
+            }
+        }
+        object.map(|(renderable, hit)| (renderable, hit.facing(ray.direction)))
+    }
+
+    // Shadow rays toward the surface of an emissive element go through that element
+    pub fn trace_shadow(&self, ray: &Ray, ignored: Option<usize>) -> Option<(&Renderable, Hit)> {
+        let ignored = match ignored {
+            Some(ignored) => ignored,
+            None => return self.trace(ray, RayType::SHADOW)
+        };
+        self.flat_elements.iter().enumerate()
+            .filter(|(index, renderable)| *index != ignored && renderable.is_visible(RayType::SHADOW))
+            .filter_map(|(_, renderable)| renderable.intersect(ray).map(|hit| (renderable, hit)))
+            .min_by(|(_, a), (_, b)| a.distance.partial_cmp(&b.distance).unwrap_or(std::cmp::Ordering::Equal))
+    }
+
+    // Light left along a shadow ray after going through every element on its way, with the distance of the first one
+    pub fn shadow_transmittance(&self, ray: &Ray, ignored: Option<usize>) -> ([f64; 3], Option<f64>) {
+        let mut transmittance = [1.0, 1.0, 1.0];
+        let mut first_hit = None;
+        let mut remaining = *ray;
+        while let Some((renderable, hit)) = self.trace_shadow(&remaining, ignored) {
+            first_hit.get_or_insert(hit.distance);
+            let transmission = hit.material.as_ref().unwrap_or(&renderable.material).shadow_transmission(&hit);
+            for (channel, factor) in transmittance.iter_mut().zip(transmission.iter()) {
+                *channel *= factor;
+            }
+            if transmittance.iter().all(|channel| *channel <= 0.0) {
+                return ([0.0, 0.0, 0.0], first_hit);
+            }
+            remaining = remaining.with_range(hit.distance + 1e-9 * hit.distance.abs().max(1.0), ray.t_max);
+        }
+        (transmittance, first_hit)
+    }
+
+    /// Light coming back along the ray from the hit, the background when there is none, before the exposure.
+    ///
+    /// ```
+    /// use rust_raytracer::rendering::{Camera, Color, Material, Renderable, Scene, View, sample_pixel};
+    /// use rust_raytracer::shape::{Quad, Ray, Shape, Sphere};
+    /// use rust_raytracer::traits::Recorder;
+    /// use rust_raytracer::vertors::Vector3;
+    ///
+    /// struct Quiet;
+    /// impl Recorder for Quiet {}
+    ///
+    /// let glowing = |color: Color| Material { emissive: color, emissive_strength: 1.0, ..Material::new(Color::black(), 0.0, 0.0) };
+    /// let pane = |opacity: f64| {
+    ///     let quad = Quad::new(Vector3::new(-1.0, -1.0, -3.0), Vector3::new(2.0, 0.0, 0.0), Vector3::new(0.0, 2.0, 0.0));
+    ///     Renderable::new(Shape::QUAD(quad), Material { opacity, ..glowing(Color::new(255, 0, 0, 255)) })
+    /// };
+    /// let white_ball = Renderable::new(Shape::SPHERE(Sphere::new(Vector3::new(0.0, 0.0, -6.0), 1.0)), glowing(Color::white()));
+    /// let sky = Color::new(90, 160, 230, 255);
+    /// let seen = |elements: Vec<Renderable>| {
+    ///     let mut scene = Scene::new(Camera::new(1, 1, 10.0), elements, Vec::new(), sky);
+    ///     scene.prepare().unwrap();
+    ///     let color = sample_pixel(4, &scene, &View::camera(&scene.camera), 0, 0, 0, &mut Quiet);
+    ///     (color.r, color.g, color.b)
+    /// };
+    /// // Half of a red pane in front of a white sphere shows a pink sphere
+    /// let (r, g, b) = seen(vec![pane(0.5), white_ball.clone()]);
+    /// assert!(r == 255 && g == b && g > 0 && g < 255);
+    /// // A clear pane shows the sky when nothing is behind it, an opaque one only itself
+    /// assert_eq!(seen(vec![pane(0.0)]), (sky.r, sky.g, sky.b));
+    /// assert_eq!(seen(vec![pane(1.0), white_ball]), (255, 0, 0));
+    ///
+    /// // Shadows keep the uncovered share of the light
+    /// let mut scene = Scene::new(Camera::new(1, 1, 10.0), vec![pane(0.25)], Vec::new(), sky);
+    /// scene.prepare().unwrap();
+    /// let (transmittance, _) = scene.shadow_transmittance(&Ray::new(Vector3::zero(), Vector3::new(0.0, 0.0, -1.0)), None);
+    /// assert_eq!(transmittance, [0.75; 3]);
+    /// ```
+    pub fn get_color(&self, ray: &Ray, hit_obj: Option<(&Renderable, Hit)>, depth: u8, max_depth: u8, rng: &mut dyn SampleSource, recorder: &mut dyn Recorder) -> LinearColor {
+        if let Some((renderable, mut hit)) = hit_obj {
+            if depth >= max_depth {
+                return LinearColor::new(0.0, 0.0, 0.0, 1.0);
+            }
+            // Normal maps only tilt the shading normal, rays leaving the hit are still offset along the geometric one
+            if let Some(normal) = hit.material.as_ref().unwrap_or(&renderable.material).mapped_normal(&hit) {
+                hit.normal = normal;
+            }
+            recorder.hit(renderable, &hit);
+            let mut color_r: f64 = 0.0;
+            let mut color_g: f64 = 0.0;
+            let mut color_b: f64 = 0.0;
+            let material = hit.material.as_ref().unwrap_or(&renderable.material);
+            let amount_reflected = material.albedo.0.map(|albedo| albedo / std::f64::consts::PI);
+            let base_color = material.color_at(&hit);
+            let view_direction = -ray.direction.normalize();
+            // Emissive elements and the sun light every point, outside of the light sampling strategy
+            let selected = self.light_sampling.select(&self.flat_lights, &self.light_powers, hit.point, &self.light_subset, rng).into_iter().map(|(light, weight)| (light, weight, None));
+            let emitters = self.emitters.iter().map(|emitter| (&emitter.light, 1.0, emitter.element));
+            for (light, weight, element) in selected.chain(emitters).filter(|(light, _, _)| renderable.is_lit_by(light)) {
+                // Lights out of range are skipped before any brightness or shadow ray is computed
+                if light.get_range().is_some_and(|range| light.get_distance(hit.point) >= range) {
+                    continue;
+                }
+                let light_direction = light.get_direction(hit.point);
+                let brightness = light.get_brightness(hit.point) * weight;
+                // Extended lights average stratified shadow rays over their surface, a single one aims at the center
+                let samples = light.get_samples().max(1);
+                let columns = (samples as f64).sqrt().ceil() as u32;
+                let rows = samples.div_ceil(columns);
+                let mut light_power = [0.0, 0.0, 0.0];
+                let mut specular_power = [0.0, 0.0, 0.0];
+                let mut blocked_at = None;
+                let shadow_start = self.profile.start();
+                for index in 0..samples {
+                    let (u, v) = if samples == 1 {
+                        (0.5, 0.5)
+                    } else {
+                        ((((index % columns) as f64) + rng.next_f64()) / columns as f64, (((index / columns) as f64) + rng.next_f64()) / rows as f64)
+                    };
+                    let (sample_direction, sample_distance, sample_brightness) = light.sample(hit.point, u, v);
+                    // Fill lights without shadows skip the occlusion test entirely
+                    let transmittance = if light.casts_shadows() {
+                        let light_ray = Ray::new(hit.shadow_origin(), sample_direction).with_range(0.0, sample_distance).with_time(ray.time);
+                        let (transmittance, first_hit) = self.shadow_transmittance(&light_ray, element);
+                        if let Some(distance) = first_hit {
+                            blocked_at.get_or_insert(distance);
+                        }
+                        if transmittance.iter().all(|channel| *channel <= 0.0) {
+                            continue;
+                        }
+                        transmittance
+                    } else {
+                        [1.0, 1.0, 1.0]
+                    };
+                    let (diffuse, highlight) = material.shade(hit.normal, sample_direction, view_direction, sample_brightness, base_color);
+                    let power = diffuse * weight / samples as f64;
+                    for channel in 0..3 {
+                        light_power[channel] += power * transmittance[channel];
+                        specular_power[channel] += highlight[channel] * weight / samples as f64 * transmittance[channel];
+                    }
+                }
+                self.profile.stop(Stage::SHADOW, shadow_start);
+                // Highlights take the color of the light, only metals tint them
+                let contribution = [
+                    (((light.get_color().r as f64) / 255.0) * light_power[0] * amount_reflected[0]) * ((base_color.r as f64) / 255.0) + ((light.get_color().r as f64) / 255.0) * specular_power[0],
+                    (((light.get_color().g as f64) / 255.0) * light_power[1] * amount_reflected[1]) * ((base_color.g as f64) / 255.0) + ((light.get_color().g as f64) / 255.0) * specular_power[1],
+                    (((light.get_color().b as f64) / 255.0) * light_power[2] * amount_reflected[2]) * ((base_color.b as f64) / 255.0) + ((light.get_color().b as f64) / 255.0) * specular_power[2]
+                ];
+                color_r += contribution[0];
+                color_g += contribution[1];
+                color_b += contribution[2];
+                if recorder.is_recording() {
+                    let index = self.flat_lights.iter().position(|candidate| std::ptr::eq(candidate, light))
+                        .or_else(|| self.emitters.iter().position(|emitter| std::ptr::eq(&emitter.light, light)).map(|position| self.flat_lights.len() + position))
+                        .unwrap_or(0);
+                    recorder.light(index, light_direction, brightness, blocked_at, contribution);
+                }
+            }
+            if let Some(photon_map) = &self.photon_map {
+                let caustic = photon_map.irradiance(hit.point, hit.normal);
+                recorder.caustic(caustic);
+                color_r += caustic[0] * amount_reflected[0] * ((base_color.r as f64) / 255.0);
+                color_g += caustic[1] * amount_reflected[1] * ((base_color.g as f64) / 255.0);
+                color_b += caustic[2] * amount_reflected[2] * ((base_color.b as f64) / 255.0);
+            }
+            if let Some(environment) = &self.environment {
+                let ambient = self.ambient(environment, &hit, ray.time, rng);
+                color_r += ambient[0] * amount_reflected[0] * ((base_color.r as f64) / 255.0);
+                color_g += ambient[1] * amount_reflected[1] * ((base_color.g as f64) / 255.0);
+                color_b += ambient[2] * amount_reflected[2] * ((base_color.b as f64) / 255.0);
+            }
+            let mut color = LinearColor::new(color_r, color_g, color_b, 1.0);
+            let reflectance = material.reflectance(ray.direction, &hit);
+            // A fully reflective surface hides whatever is seen through it
+            if material.transparency > 0.0 && reflectance < 1.0 {
+                // Total internal reflection keeps the ray inside the material
+                let (ray_type, transmitted_ray) = match Ray::compute_refraction_ray(&hit, ray.direction, material.ior) {
+                    Some(refraction_ray) => (RayType::REFRACTION, refraction_ray),
+                    None => (RayType::REFLECTION, Ray::compute_reflection_ray(&hit, ray.direction))
+                };
+                let transmitted_ray = transmitted_ray.with_time(ray.time);
+                recorder.enter(ray_type, &transmitted_ray);
+                let refraction_start = self.profile.start();
+                let new_obj = self.trace_recorded(&transmitted_ray, ray_type, recorder);
+                self.profile.stop(Stage::REFRACTION, refraction_start);
+                let transmitted = self.get_color(&transmitted_ray, new_obj, depth + 1, max_depth, rng, recorder);
+                recorder.leave(self.exposed(transmitted));
+                let transmission = material.transmission(&hit);
+                color = color * (1.0 - material.transparency)
+                    + LinearColor::new(transmitted.r * transmission[0], transmitted.g * transmission[1], transmitted.b * transmission[2], transmitted.a * material.transparency);
+            }
+            if reflectance > 0.0 {
+                let reflection_ray = Ray::compute_reflection_ray(&hit, ray.direction).with_time(ray.time);
+                color = color * (1.0 - reflectance);
+                recorder.enter(RayType::REFLECTION, &reflection_ray);
+                let reflection_start = self.profile.start();
+                let new_obj = self.trace_recorded(&reflection_ray, RayType::REFLECTION, recorder);
+                self.profile.stop(Stage::REFLECTION, reflection_start);
+                let reflected = self.get_color(&reflection_ray, new_obj, depth + 1, max_depth, rng, recorder);
+                recorder.leave(self.exposed(reflected));
+                let tint = material.reflection_tint(base_color);
+                let reflected = LinearColor::new(reflected.r * tint[0], reflected.g * tint[1], reflected.b * tint[2], reflected.a);
+                color = if material.fresnel == Fresnel::NONE { color + reflected } else { color + reflected * reflectance };
+            }
+            if material.is_emissive() {
+                color = color + material.emission();
+            }
+            if material.opacity < 1.0 {
+                // The rest of the surface shows what is behind it, the sky when nothing is
+                let behind_ray = Ray::compute_pass_through_ray(&hit, ray.direction).with_time(ray.time);
+                recorder.enter(RayType::REFRACTION, &behind_ray);
+                let behind_start = self.profile.start();
+                let new_obj = self.trace_recorded(&behind_ray, RayType::REFRACTION, recorder);
+                self.profile.stop(Stage::REFRACTION, behind_start);
+                let behind = self.get_color(&behind_ray, new_obj, depth + 1, max_depth, rng, recorder);
+                recorder.leave(self.exposed(behind));
+                color = color * material.opacity + behind * (1.0 - material.opacity);
+            }
+            color
+        } else {
+            self.background(ray.direction)
+        }
+    }
+
+    // Irradiance from the unblocked part of the environment, cosine weighted directions only need to be averaged and scaled by pi
+    fn ambient(&self, environment: &Environment, hit: &Hit, time: f64, rng: &mut dyn SampleSource) -> [f64; 3] {
+        let samples = environment.ambient_samples();
+        let mut irradiance = [0.0; 3];
+        if samples == 0 {
+            return irradiance;
+        }
+        let (tangent, bitangent) = tangent_axes(hit.normal);
+        for _ in 0..samples {
+            let (radius, angle) = (rng.next_f64().sqrt(), 2.0 * std::f64::consts::PI * rng.next_f64());
+            let height = (1.0 - radius * radius).max(0.0).sqrt();
+            let direction = (tangent * (radius * angle.cos()) + bitangent * (radius * angle.sin()) + hit.normal * height).normalize();
+            let ambient_ray = Ray::new(hit.shadow_origin(), direction).with_time(time);
+            if self.trace(&ambient_ray, RayType::SHADOW).is_some() {
+                continue;
+            }
+            let radiance = environment.ambient_radiance(direction);
+            irradiance[0] += radiance.r * std::f64::consts::PI / samples as f64;
+            irradiance[1] += radiance.g * std::f64::consts::PI / samples as f64;
+            irradiance[2] += radiance.b * std::f64::consts::PI / samples as f64;
+        }
+        irradiance
+    }
+
+    pub fn background(&self, direction: Vector3) -> LinearColor {
+        self.environment.as_ref().map_or_else(|| self.sky_color.radiance(direction), |environment| environment.radiance(direction))
+    }
+
+    // Scales by the camera exposure, the alpha is coverage and is kept
+    pub fn exposure(&self, color: LinearColor) -> LinearColor {
+        LinearColor { a: color.a, ..color * self.camera.exposure_scale() }
+    }
+
+    // Exposed and quantized, for the recorders
+    pub fn exposed(&self, color: LinearColor) -> Color {
+        self.exposure(color).to_color()
+    }
+}
+
+pub fn prepare_caustics(nb_pass: u8, scene: &mut Scene) {
+    scene.photon_map = None;
+    if scene.caustics.enabled {
+        let photon_start = scene.profile.start();
+        let photon_map = build_photon_map(scene, nb_pass);
+        scene.profile.stop(Stage::PHOTON_MAP, photon_start);
+        if scene.verbose {
+            println!("Caustic photons stored: {}", photon_map.photon_count());
+        }
+        scene.photon_map = Some(photon_map);
+    }
+}
+
+pub struct View {
+    pub width: u32,
+    pub height: u32,
+    pub stream: u64,
+    pub ray: Box<dyn Fn(u32, u32, f64, f64) -> Ray>,
+    pub lens: Option<Lens> // Depth of field of the camera views
+}
+
+impl View {
+    pub fn new(width: u32, height: u32, stream: u64, ray: Box<dyn Fn(u32, u32, f64, f64) -> Ray>) -> View {
+        View { width, height, stream, ray, lens: None }
+    }
+
+    pub fn camera(camera: &Camera) -> View {
+        let lens = camera.lens();
+        let camera = camera.clone();
+        View { lens, ..View::new(camera.width, camera.height, 0, Box::new(move |x, y, offset_x, offset_y| camera.compute_sample_ray(x, y, offset_x, offset_y))) }
+    }
+}
+
+pub fn sample_pixel(nb_pass: u8, scene: &Scene, view: &View, pixel_x: u32, pixel_y: u32, index: u32, recorder: &mut dyn Recorder) -> Color {
+    sample_radiance(nb_pass, scene, view, pixel_x, pixel_y, index, recorder).to_color()
+}
+
+// Exposed color of one sample before it is quantized, samples are averaged in floating point
+pub fn sample_radiance(nb_pass: u8, scene: &Scene, view: &View, pixel_x: u32, pixel_y: u32, index: u32, recorder: &mut dyn Recorder) -> LinearColor {
+    let pixel = (((pixel_y as u64) << 32) | (pixel_x as u64)) ^ (view.stream << 56);
+    let mut stream = scene.sampler.stream(scene.seed, pixel, index);
+    let ray = if scene.samples_per_pixel > 1 || index > 0 {
+        let offset_x = stream.next_f64();
+        let offset_y = stream.next_f64();
+        (view.ray)(pixel_x, pixel_y, offset_x, offset_y)
+    } else {
+        (view.ray)(pixel_x, pixel_y, 0.5, 0.5)
+    };
+    // Only an open shutter draws a time, so that scenes without motion blur keep their samples
+    let camera = &scene.camera;
+    let ray = if camera.shutter_close > camera.shutter_open {
+        ray.with_time(camera.shutter_open + (camera.shutter_close - camera.shutter_open) * stream.next_f64())
+    } else {
+        ray.with_time(camera.shutter_open)
+    };
+    // Likewise only a lens draws a point of its aperture
+    let ray = match &view.lens {
+        Some(lens) => lens.ray(ray, stream.next_f64(), stream.next_f64()),
+        None => ray
+    };
+    recorder.enter(RayType::PRIMARY, &ray);
+    let primary_start = scene.profile.start();
+    let object = scene.trace_recorded(&ray, RayType::PRIMARY, recorder);
+    scene.profile.stop(Stage::PRIMARY, primary_start);
+    let color = scene.exposure(scene.get_color(&ray, object, 0, nb_pass, &mut stream, recorder));
+    recorder.leave(color.to_color());
+    color
+}
+
+// Linear sums of the exposed samples, bright samples keep their weight instead of being clipped one by one
+pub fn sum_samples(nb_pass: u8, scene: &Scene, view: &View, pixel_x: u32, pixel_y: u32, first_sample: u32) -> [f64; 4] {
+    let mut sum = [0.0; 4];
+    for index in first_sample..first_sample + scene.samples_per_pixel.max(1) {
+        let color = sample_radiance(nb_pass, scene, view, pixel_x, pixel_y, index, &mut NoRecorder);
+        sum[0] += color.r;
+        sum[1] += color.g;
+        sum[2] += color.b;
+        sum[3] += color.a;
+    }
+    sum
+}
+
+// Quantized once, from the mean of the linear sums
+pub fn average(sum: &[f64; 4], samples: u32) -> Color {
+    let samples = samples.max(1) as f64;
+    LinearColor::new(sum[0] / samples, sum[1] / samples, sum[2] / samples, sum[3] / samples).to_color()
+}
+
+pub fn render_view(nb_pass: u8, scene: &Scene, view: &View) -> RgbaImage {
+    let render_start = scene.profile.start();
+    let mut image: RgbaImage = ImageBuffer::new(view.width, view.height);
+    for pixel_x in 0..view.width {
+        for pixel_y in 0..view.height {
+            let sum = sum_samples(nb_pass, scene, view, pixel_x, pixel_y, 0);
+            image.put_pixel(pixel_x, pixel_y, average(&sum, scene.samples_per_pixel).to_rgba());
+        }
+    }
+    scene.profile.stop(Stage::RENDER, render_start);
+    image
+}
+
+pub fn apply_post(scene: &Scene, image: &mut RgbaImage) {
+    let post_start = scene.profile.start();
+    for effect in scene.post.iter() {
+        effect.apply(image);
+    }
+    scene.profile.stop(Stage::POST, post_start);
+}
+
+pub fn render_image(nb_pass: u8, scene: &mut Scene) -> RgbaImage {
+    prepare_caustics(nb_pass, scene);
+    let mut image = render_view(nb_pass, scene, &View::camera(&scene.camera));
+    apply_post(scene, &mut image);
+    image
+}
+
+pub fn render(nb_pass: u8, mut scene: Scene, output_path: String) -> Result<(), Box<dyn std::error::Error>> {
+    let image = render_image(nb_pass, &mut scene);
+    let encode_start = scene.profile.start();
+    output::write(&image, &output_path, &scene.output, &scene.metadata())?;
+    scene.profile.stop(Stage::ENCODE, encode_start);
+    scene.profile.report()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shape::{Plane, Sphere};
+
+    #[test]
+    fn prepared_camera_casts_the_rays_of_its_basis() {
+        let placed = Camera { roll: 30.0, ..Camera::new(64, 48, 60.0).looking_at(Vector3::new(1.0, 2.0, 3.0), Vector3::new(-1.0, 0.5, -2.0), Vector3::new(0.0, 1.0, 0.0)) };
+        let mut prepared = placed.clone();
+        prepared.prepare().unwrap();
+        let unprepared = Camera { frame: None, ..placed };
+        for (x, y) in [(0, 0), (31, 17), (63, 47)] {
+            let (expected, found) = (unprepared.compute_sample_ray(x, y, 0.25, 0.75), prepared.compute_sample_ray(x, y, 0.25, 0.75));
+            assert!((expected.direction - found.direction).length() < 1e-12);
+            assert!((expected.origin - found.origin).length() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn preparing_checks_the_camera_basis() {
+        let mut camera = Camera::new(64, 48, 60.0).looking_at(Vector3::zero(), Vector3::new(0.0, 5.0, 0.0), Vector3::new(0.0, 1.0, 0.0));
+        assert!(camera.frame.is_none());
+        assert!(camera.prepare().is_err());
+    }
+
+    // Columns and rows the dark ball covers on the white sky
+    fn ball_extent(width: u32, height: u32, center: Point) -> (u32, u32) {
+        let ball = Renderable::new(Shape::SPHERE(Sphere::new(center, 1.0)), Material::new(Color::black(), 0.0, 0.0));
+        let mut scene = Scene::new(Camera::new(width, height, 60.0), vec![ball], Vec::new(), Color::white());
+        scene.prepare().unwrap();
+        let image = render_image(1, &mut scene);
+        let dark = |x: u32, y: u32| image.get_pixel(x, y)[0] < 128;
+        let columns = (0..width).filter(|&x| (0..height).any(|y| dark(x, y))).count() as u32;
+        let rows = (0..height).filter(|&y| (0..width).any(|x| dark(x, y))).count() as u32;
+        (columns, rows)
+    }
+
+    #[test]
+    fn landscape_and_portrait_images_keep_the_ball_round() {
+        let landscape = ball_extent(800, 600, Vector3::new(0.0, 0.0, -5.0));
+        let portrait = ball_extent(600, 800, Vector3::new(0.0, 0.0, -5.0));
+        assert!(landscape.0.abs_diff(landscape.1) <= 1, "{:?}", landscape);
+        assert!(portrait.0.abs_diff(portrait.1) <= 1, "{:?}", portrait);
+        // The fov spans the shorter side in both, the ball covers as many pixels
+        assert!(landscape.0.abs_diff(portrait.0) <= 1, "{:?} {:?}", landscape, portrait);
+        // Off center along the longer side the perspective stretches it as much in both
+        let wide = ball_extent(800, 600, Vector3::new(2.0, 0.0, -5.0));
+        let tall = ball_extent(600, 800, Vector3::new(0.0, 2.0, -5.0));
+        assert!(wide.0.abs_diff(tall.1) <= 1 && wide.1.abs_diff(tall.0) <= 1, "{:?} {:?}", wide, tall);
+    }
+
+    fn assert_same_vector(found: Vector3, expected: Vector3) {
+        assert!((found - expected).length() < 1e-12, "{:?} instead of {:?}", found, expected);
+    }
+
+    #[test]
+    fn no_roll_keeps_the_basis_of_the_view() {
+        let (right, up, forward) = Camera::new(64, 48, 60.0).basis().unwrap();
+        assert_same_vector(right, Vector3::new(1.0, 0.0, 0.0));
+        assert_same_vector(up, Vector3::new(0.0, 1.0, 0.0));
+        assert_same_vector(forward, Vector3::new(0.0, 0.0, -1.0));
+        // Looking down and to the side, right stays level and up leans back
+        let camera = Camera::new(64, 48, 60.0).looking_at(Vector3::new(0.0, 4.0, 0.0), Vector3::new(4.0, 0.0, 0.0), Vector3::new(0.0, 1.0, 0.0));
+        let (right, up, forward) = camera.basis().unwrap();
+        let half = std::f64::consts::FRAC_1_SQRT_2;
+        assert_same_vector(forward, Vector3::new(half, -half, 0.0));
+        assert_same_vector(right, Vector3::new(0.0, 0.0, 1.0));
+        assert_same_vector(up, Vector3::new(half, half, 0.0));
+        let (rolled_right, rolled_up, rolled_forward) = Camera { roll: 0.0, ..camera }.basis().unwrap();
+        assert_same_vector(rolled_right, right);
+        assert_same_vector(rolled_up, up);
+        assert_same_vector(rolled_forward, forward);
+    }
+
+    #[test]
+    fn a_quarter_roll_turns_up_into_screen_right() {
+        let camera = Camera::new(64, 48, 60.0).looking_at(Vector3::new(1.0, 2.0, 3.0), Vector3::new(-1.0, 0.5, -2.0), Vector3::new(0.0, 1.0, 0.0));
+        let (right, up, forward) = camera.basis().unwrap();
+        let rolled = Camera { roll: 90.0, ..camera };
+        let (rolled_right, rolled_up, rolled_forward) = rolled.basis().unwrap();
+        assert_same_vector(rolled_right, up);
+        assert_same_vector(rolled_up, -right);
+        assert_same_vector(rolled_forward, forward);
+        // What was straight above the center of the image is now straight to its right
+        let mut prepared = rolled;
+        prepared.prepare().unwrap();
+        let toward_right = prepared.compute_prime_ray(63, 24).direction;
+        assert!(toward_right.dot(&up) > 0.5 && toward_right.dot(&right).abs() < 0.05, "{:?}", toward_right);
+    }
+
+    #[test]
+    fn point_lights_end_at_their_range_without_a_step() {
+        let lamp = PointLight { max_distance: Some(10.0), ..PointLight::new(Vector3::zero(), 1000.0, Color::white()) };
+        let at = |distance: f64| lamp.get_brightness(Vector3::new(0.0, 0.0, distance));
+        for distance in [10.0, 10.0 + 1e-12, 10.5, 1e6] {
+            assert_eq!(at(distance), 0.0);
+        }
+        // Untouched before the last tenth, then no jump anywhere through the fade and around its two edges
+        assert_eq!(at(8.99), 1000.0 / (4.0 * std::f64::consts::PI * 8.99 * 8.99));
+        let steps: Vec<f64> = (0..=30_000).map(|step| at(8.5 + step as f64 * 1e-4)).collect();
+        assert!(steps.windows(2).all(|pair| (pair[1] - pair[0]).abs() < 1e-3), "the fade jumps");
+        assert!(at(10.0 - 1e-4) < 1e-6);
+        // Without a max_distance the light ends where it falls below NEGLIGIBLE_IRRADIANCE
+        let automatic = PointLight::new(Vector3::zero(), 1000.0, Color::white());
+        assert!((1000.0 / (4.0 * std::f64::consts::PI * automatic.range().powi(2)) - NEGLIGIBLE_IRRADIANCE).abs() < 1e-15);
+        assert_eq!(automatic.get_brightness(Vector3::new(0.0, automatic.range(), 0.0)), 0.0);
+    }
+
+    // The floor out of the range of a lamp looks like it does without the lamp
+    #[test]
+    fn lights_add_nothing_beyond_their_range() {
+        let floor = Renderable::new(Shape::PLANE(Plane::new(Vector3::zero(), Vector3::new(0.0, 1.0, 0.0))), Material::new(Color::white(), 0.8, 0.2));
+        let sun = Light::DIRECTIONAL(DirectionalLight::new(Vector3::new(0.3, -1.0, 0.2), 1.0, Color::white()));
+        let lamp = Light::POINT(PointLight { max_distance: Some(4.0), ..PointLight::new(Vector3::new(0.0, 2.0, 0.0), 500.0, Color::white()) });
+        let seen = |lights: Vec<Light>, x: f64| {
+            let mut scene = Scene::new(Camera::new(4, 4, 60.0), vec![floor.clone()], lights, Color::black());
+            scene.prepare().unwrap();
+            let ray = Ray::new(Vector3::new(x, 5.0, 5.0), (Vector3::new(x, 0.0, 0.0) - Vector3::new(x, 5.0, 5.0)).normalize());
+            let color = scene.get_color(&ray, scene.trace(&ray, RayType::PRIMARY), 0, 4, &mut crate::random::Rng::new(1), &mut NoRecorder);
+            (color.r, color.g, color.b)
+        };
+        assert_eq!(seen(vec![sun.clone(), lamp.clone()], 3.5), seen(vec![sun.clone()], 3.5));
+        assert_eq!(seen(vec![sun.clone(), lamp.clone()], 20.0), seen(vec![sun.clone()], 20.0));
+        assert!(seen(vec![sun.clone(), lamp], 1.0).0 > seen(vec![sun], 1.0).0);
+    }
+
+    // 50 short range lamps: cargo test --release rendering::tests::light_range_benchmark -- --ignored --nocapture
+    // times the scene with its ranges and with every lamp reaching the whole scene
+    #[test]
+    #[ignore]
+    fn light_range_benchmark() {
+        use std::collections::HashMap;
+        use std::time::Instant;
+        let parse = || crate::parse_scene(include_str!("../test_scene/light_range.json"), &HashMap::new(), None).unwrap();
+        let (ranged, mut unbounded) = (parse(), parse());
+        for light in unbounded.lights.iter_mut() {
+            if let Light::POINT(point) = light {
+                point.max_distance = Some(f64::INFINITY);
+            }
+        }
+        let run = |mut scene: Scene| {
+            scene.prepare().unwrap();
+            let start = Instant::now();
+            let image = render_view(4, &scene, &View::camera(&scene.camera));
+            (start.elapsed(), image)
+        };
+        let (with_ranges, cut) = run(ranged);
+        let (without, full) = run(unbounded);
+        // Cutting a light off only ever takes light away
+        assert!(cut.pixels().zip(full.pixels()).all(|(cut, full)| (0..3).all(|channel| cut[channel] <= full[channel])));
+        println!("50 point lights: {:?} reaching everywhere, {:?} with their ranges, {:.2}x", without, with_ranges, without.as_secs_f64() / with_ranges.as_secs_f64());
+    }
+
+    // A ball on a floor under an area light, every sample draws another point of the light
+    fn soft_shadow_scene(samples_per_pixel: u32, seed: u64) -> Scene {
+        let camera = Camera::new(16, 12, 60.0).looking_at(Vector3::new(0.0, 2.0, 4.0), Vector3::zero(), Vector3::new(0.0, 1.0, 0.0));
+        let floor = Renderable::new(Shape::PLANE(Plane::new(Vector3::zero(), Vector3::new(0.0, 1.0, 0.0))), Material::new(Color::white(), 0.8, 0.0));
+        let ball = Renderable::new(Shape::SPHERE(Sphere::new(Vector3::new(0.0, 0.7, 0.0), 0.7)), Material::new(Color::new(200, 80, 80, 255), 0.8, 0.0));
+        let lamp = Light::AREA(AreaLight::new(Vector3::new(-1.0, 3.0, -1.0), Vector3::new(2.0, 0.0, 0.0), Vector3::new(0.0, 0.0, 2.0), 400.0, Color::white(), 1));
+        let mut scene = Scene::new(camera, vec![floor, ball], vec![lamp], Color::new(40, 40, 60, 255));
+        scene.samples_per_pixel = samples_per_pixel;
+        scene.seed = seed;
+        scene.prepare().unwrap();
+        scene
+    }
+
+    fn linear_image(scene: &Scene) -> Vec<[f64; 4]> {
+        let view = View::camera(&scene.camera);
+        (0..view.height).flat_map(|y| (0..view.width).map(move |x| (x, y)))
+            .map(|(x, y)| sum_samples(4, scene, &view, x, y, 0).map(|channel| channel / scene.samples_per_pixel as f64))
+            .collect()
+    }
+
+    #[test]
+    fn samples_are_averaged_before_they_are_quantized() {
+        // Half the samples on a light twice as bright as white and half on black average to white, not to gray
+        assert_eq!(average(&[2.0, 1.0, 0.5, 2.0], 2).to_rgba(), Color::new(255, 127, 63, 255).to_rgba());
+        assert_eq!(average(&[0.0; 4], 0).to_rgba(), Color::new(0, 0, 0, 0).to_rgba());
+    }
+
+    #[test]
+    fn same_seed_renders_the_same_image() {
+        let scene = soft_shadow_scene(4, 11);
+        let view = View::camera(&scene.camera);
+        assert_eq!(render_view(4, &scene, &view).into_raw(), render_view(4, &scene, &view).into_raw());
+        assert_eq!(linear_image(&scene), linear_image(&soft_shadow_scene(4, 11)));
+        assert_ne!(linear_image(&scene), linear_image(&soft_shadow_scene(4, 12)));
+    }
+
+    #[test]
+    fn more_samples_converge_to_the_reference() {
+        let reference = linear_image(&soft_shadow_scene(1024, 1));
+        let error = |samples: u32| {
+            let image = linear_image(&soft_shadow_scene(samples, 2));
+            image.iter().zip(reference.iter()).map(|(pixel, expected)| (0..3).map(|channel| (pixel[channel] - expected[channel]).powi(2)).sum::<f64>()).sum::<f64>()
+        };
+        let (few, many) = (error(2), error(64));
+        assert!(few > 0.0);
+        assert!(many < few / 8.0, "{} samples off by {}, {} by {}", 2, few, 64, many);
+    }
+
+    #[test]
+    fn halton_samples_converge_faster_than_random_ones() {
+        let reference = linear_image(&soft_shadow_scene(1024, 1));
+        let error = |sampler: Sampler, seed: u64| {
+            let mut scene = soft_shadow_scene(16, seed);
+            scene.sampler = sampler;
+            let image = linear_image(&scene);
+            image.iter().zip(reference.iter()).map(|(pixel, expected)| (0..3).map(|channel| (pixel[channel] - expected[channel]).powi(2)).sum::<f64>()).sum::<f64>()
+        };
+        // Summed over a few seeds so that one lucky random render does not decide
+        let (random, halton): (f64, f64) = (2..6).map(|seed| (error(Sampler::RANDOM, seed), error(Sampler::HALTON, seed))).fold((0.0, 0.0), |(random, halton), (a, b)| (random + a, halton + b));
+        assert!(halton < random / 2.0, "halton off by {}, random by {}", halton, random);
+    }
+
+    // A ball over a floor in front of a mirror, lit from above between the two so that the mirror shows its lit side
+    fn flagged_scene(ball: Option<Renderable>) -> Scene {
+        let camera = Camera::new(16, 12, 60.0);
+        let floor = Renderable::new(Shape::PLANE(Plane::new(Vector3::zero(), Vector3::new(0.0, 1.0, 0.0))), Material::new(Color::white(), 0.8, 0.0));
+        let mirror = Renderable::new(Shape::PLANE(Plane::new(Vector3::new(0.0, 0.0, -3.0), Vector3::new(0.0, 0.0, 1.0))), Material::new(Color::white(), 0.0, 1.0));
+        let lamp = Light::POINT(PointLight::new(Vector3::new(0.0, 4.0, -2.0), 2000.0, Color::white()));
+        let mut scene = Scene::new(camera, vec![floor, mirror].into_iter().chain(ball).collect(), vec![lamp], Color::new(40, 40, 60, 255));
+        scene.prepare().unwrap();
+        scene
+    }
+
+    fn ball(flag: fn(&mut Renderable)) -> Option<Renderable> {
+        let mut ball = Renderable::new(Shape::SPHERE(Sphere::new(Vector3::new(0.0, 1.0, 0.0), 0.5)), Material::new(Color::new(255, 0, 0, 255), 0.8, 0.0));
+        flag(&mut ball);
+        Some(ball)
+    }
+
+    fn seen_from(scene: &Scene, origin: Point, target: Point) -> (f64, f64, f64) {
+        let ray = Ray::new(origin, (target - origin).normalize());
+        let color = scene.get_color(&ray, scene.trace(&ray, RayType::PRIMARY), 0, 4, &mut crate::random::Rng::new(1), &mut NoRecorder);
+        (color.r, color.g, color.b)
+    }
+
+    // Straight at the ball, with the mirror behind it
+    fn head_on(scene: &Scene) -> (f64, f64, f64) {
+        seen_from(scene, Vector3::new(0.0, 1.0, 5.0), Vector3::new(0.0, 1.0, 0.0))
+    }
+
+    // The floor in the middle of the shadow of the ball, seen from the side past it
+    fn under(scene: &Scene) -> (f64, f64, f64) {
+        seen_from(scene, Vector3::new(2.0, 1.0, 2.0 / 3.0), Vector3::new(0.0, 0.0, 2.0 / 3.0))
+    }
+
+    #[test]
+    fn elements_hidden_from_the_camera_still_show_in_mirrors_and_cast_shadows() {
+        let (empty, hidden) = (flagged_scene(None), flagged_scene(ball(|ball| ball.visible_to_camera = false)));
+        let ray = Ray::new(Vector3::new(0.0, 1.0, 5.0), Vector3::new(0.0, 0.0, -1.0));
+        assert!((hidden.trace(&ray, RayType::PRIMARY).unwrap().1.distance - 8.0).abs() < 1e-9);
+        assert!(hidden.trace(&ray, RayType::REFLECTION).is_some_and(|(_, hit)| (hit.distance - 4.5).abs() < 1e-9));
+        // The mirror shows the red ball instead of the sky behind the camera
+        let (reflected, sky) = (head_on(&hidden), head_on(&empty));
+        assert!(reflected.0 > 0.0 && reflected.1 == 0.0 && reflected.2 == 0.0, "{:?}", reflected);
+        assert!(sky.1 > 0.0 && sky.2 > 0.0, "{:?}", sky);
+        assert!(under(&hidden).0 < under(&empty).0 / 10.0);
+    }
+
+    #[test]
+    fn elements_hidden_from_reflections_still_show_to_the_camera_and_cast_shadows() {
+        let (empty, hidden) = (flagged_scene(None), flagged_scene(ball(|ball| ball.visible_in_reflections = false)));
+        let ray = Ray::new(Vector3::new(0.0, 1.0, 5.0), Vector3::new(0.0, 0.0, -1.0));
+        assert!((hidden.trace(&ray, RayType::PRIMARY).unwrap().1.distance - 4.5).abs() < 1e-9);
+        assert!(hidden.trace(&ray, RayType::REFLECTION).is_some_and(|(_, hit)| (hit.distance - 8.0).abs() < 1e-9));
+        assert!(hidden.trace(&ray, RayType::REFRACTION).is_some_and(|(_, hit)| (hit.distance - 8.0).abs() < 1e-9));
+        // Seen from the mirror the ball is gone, the floor under it is still dark
+        let hidden_camera = flagged_scene(ball(|ball| { ball.visible_to_camera = false; ball.visible_in_reflections = false; }));
+        assert_eq!(head_on(&hidden_camera), head_on(&empty));
+        assert!(under(&hidden).0 < under(&empty).0 / 10.0);
+    }
+
+    #[test]
+    fn elements_without_shadows_let_the_light_through() {
+        let (empty, unshadowed) = (flagged_scene(None), flagged_scene(ball(|ball| ball.casts_shadows = false)));
+        let shadowed = flagged_scene(ball(|_| ()));
+        assert!(unshadowed.trace(&Ray::new(Vector3::new(0.0, 0.1, 0.0), Vector3::new(0.0, 1.0, 0.0)), RayType::SHADOW).is_none());
+        assert_eq!(under(&unshadowed), under(&empty));
+        assert!(under(&shadowed).0 < under(&empty).0 / 10.0);
+        // The ball itself looks the same, in front of the camera and in the mirror
+        assert_eq!(head_on(&unshadowed), head_on(&shadowed));
+        let ray = Ray::new(Vector3::new(0.0, 1.0, 5.0), Vector3::new(0.0, 0.0, -1.0));
+        assert!(unshadowed.trace(&ray, RayType::REFLECTION).is_some_and(|(_, hit)| (hit.distance - 4.5).abs() < 1e-9));
+    }
+}
@@ -0,0 +1,222 @@
+use serde::{Serialize, Deserialize};
+use crate::random::Rng;
+use crate::traits::SampleSource;
+
+// Samples are stratified in runs of this many, whatever the number of samples per pixel, so that renders
+// accumulated over several runs draw the same samples as a single longer one
+const STRATA: u32 = 16;
+const GRID: u32 = 4; // Columns and rows of the strata on the pixel
+
+const PRIMES: [u64; 32] = [2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53, 59, 61, 67, 71, 73, 79, 83, 89, 97, 101, 103, 107, 109, 113, 127, 131];
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum Sampler {
+    #[default]
+    RANDOM,
+    STRATIFIED,
+    HALTON
+}
+
+fn hash(seed: u64, pixel: u64, dimension: u32) -> u64 {
+    Rng::new(seed ^ pixel.wrapping_mul(0x9E37_79B9_7F4A_7C15) ^ (dimension as u64).wrapping_mul(0xD6E8_FEB8_6659_FD93)).next_u64()
+}
+
+fn unit(value: u64) -> f64 {
+    (value >> 11) as f64 * (1.0 / (1u64 << 53) as f64)
+}
+
+fn radical_inverse(mut index: u64, base: u64) -> f64 {
+    let inverse_base = 1.0 / base as f64;
+    let mut factor = inverse_base;
+    let mut result = 0.0;
+    while index > 0 {
+        result += (index % base) as f64 * factor;
+        index /= base;
+        factor *= inverse_base;
+    }
+    result
+}
+
+// Kensler's hash based permutation, shuffles 0..count without storing a table
+fn permute(mut index: u32, count: u32, seed: u32) -> u32 {
+    let mut mask = count.wrapping_sub(1);
+    mask |= mask >> 1;
+    mask |= mask >> 2;
+    mask |= mask >> 4;
+    mask |= mask >> 8;
+    mask |= mask >> 16;
+    loop {
+        index ^= seed;
+        index = index.wrapping_mul(0xe170_893d);
+        index ^= seed >> 16;
+        index ^= (index & mask) >> 4;
+        index ^= seed >> 8;
+        index = index.wrapping_mul(0x0929_eb3f);
+        index ^= seed >> 23;
+        index ^= (index & mask) >> 1;
+        index = index.wrapping_mul(1 | seed >> 27);
+        index = index.wrapping_mul(0x6935_fa69);
+        index ^= (index & mask) >> 11;
+        index = index.wrapping_mul(0x74dc_b303);
+        index ^= (index & mask) >> 2;
+        index = index.wrapping_mul(0x9e50_1cc3);
+        index ^= (index & mask) >> 2;
+        index = index.wrapping_mul(0xc860_a3df);
+        index &= mask;
+        index ^= index >> 5;
+        if index < count {
+            break;
+        }
+    }
+    index.wrapping_add(seed) % count
+}
+
+// Cell of the grid for the sample of a run, in the order of the first two Sobol dimensions: the first two samples
+// are in different halves of the pixel, the first four in different quarters and the sixteen cover every cell
+fn sobol_cell(sample: u32) -> (u32, u32) {
+    let mut row = 0u32;
+    let mut direction = 1u32 << 31;
+    let mut bits = sample;
+    while bits != 0 {
+        if bits & 1 != 0 {
+            row ^= direction;
+        }
+        bits >>= 1;
+        direction ^= direction >> 1;
+    }
+    (sample.reverse_bits() >> 30, row >> 30)
+}
+
+impl Sampler {
+    pub fn sample(&self, seed: u64, pixel: u64, index: u32, dimension: u32) -> f64 {
+        let scramble = hash(seed, pixel, dimension);
+        let (run, position) = ((index / STRATA) as u64, index % STRATA);
+        match self {
+            Sampler::RANDOM => unit(hash(scramble, index as u64, dimension)),
+            Sampler::STRATIFIED if dimension < 2 => {
+                // Flipping the same bits of every column and row keeps the halves and quarters apart
+                let flip = hash(seed, pixel ^ run.wrapping_mul(0xA24B_AED4_963E_E407), 0);
+                let (column, row) = sobol_cell(position);
+                let jitter = unit(hash(scramble, index as u64, dimension));
+                if dimension == 0 {
+                    ((column ^ (flip as u32 % GRID)) as f64 + jitter) / GRID as f64
+                } else {
+                    ((row ^ ((flip >> 32) as u32 % GRID)) as f64 + jitter) / GRID as f64
+                }
+            },
+            Sampler::STRATIFIED => {
+                let stratum = permute(position, STRATA, hash(scramble, run, dimension) as u32);
+                (stratum as f64 + unit(hash(scramble, index as u64, dimension))) / STRATA as f64
+            },
+            // Past the table the bases grow so large that the first samples of a pixel line up, and wrapping around
+            // would give the later dimensions the same points as the first ones, they are drawn at random instead
+            Sampler::HALTON if dimension as usize >= PRIMES.len() => unit(hash(scramble, index as u64, dimension)),
+            Sampler::HALTON => {
+                let value = radical_inverse(index as u64, PRIMES[dimension as usize]) + unit(scramble);
+                value - value.floor()
+            }
+        }
+    }
+
+    pub fn stream(&self, seed: u64, pixel: u64, index: u32) -> SampleStream {
+        match self {
+            Sampler::RANDOM => SampleStream::RANDOM(Rng::new(pixel ^ seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) ^ (index as u64).wrapping_mul(0xD6E8_FEB8_6659_FD93))),
+            _ => SampleStream::SEQUENCE { sampler: *self, seed, pixel, index, dimension: 0 }
+        }
+    }
+}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Copy, Clone, Debug)]
+pub enum SampleStream {
+    RANDOM(Rng),
+    SEQUENCE {
+        sampler: Sampler,
+        seed: u64,
+        pixel: u64,
+        index: u32,
+        dimension: u32
+    }
+}
+
+impl SampleSource for SampleStream {
+    fn next_f64(&mut self) -> f64 {
+        match self {
+            SampleStream::RANDOM(rng) => rng.next_f64(),
+            SampleStream::SEQUENCE { sampler, seed, pixel, index, dimension } => {
+                let value = sampler.sample(*seed, *pixel, *index, *dimension);
+                *dimension += 1;
+                value
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cell(sampler: Sampler, pixel: u64, index: u32) -> (u32, u32) {
+        let (x, y) = (sampler.sample(3, pixel, index, 0), sampler.sample(3, pixel, index, 1));
+        ((x * GRID as f64) as u32, (y * GRID as f64) as u32)
+    }
+
+    #[test]
+    fn sobol_cells_cover_the_grid() {
+        let mut cells: Vec<(u32, u32)> = (0..STRATA).map(sobol_cell).collect();
+        cells.sort_unstable();
+        cells.dedup();
+        assert_eq!(cells.len(), STRATA as usize);
+    }
+
+    #[test]
+    fn every_run_of_stratified_samples_covers_the_pixel() {
+        for pixel in [0, 7, 1 << 40] {
+            for run in 0..3 {
+                let mut cells: Vec<(u32, u32)> = (run * STRATA..(run + 1) * STRATA).map(|index| cell(Sampler::STRATIFIED, pixel, index)).collect();
+                // The first four samples of a run are in different quarters
+                let mut quarters: Vec<(u32, u32)> = cells[..4].iter().map(|&(column, row)| (column / 2, row / 2)).collect();
+                quarters.sort_unstable();
+                quarters.dedup();
+                assert_eq!(quarters.len(), 4);
+                cells.sort_unstable();
+                cells.dedup();
+                assert_eq!(cells.len(), STRATA as usize);
+            }
+        }
+    }
+
+    #[test]
+    fn every_run_of_stratified_samples_covers_the_other_dimensions() {
+        for dimension in 2..6 {
+            let mut strata: Vec<u32> = (STRATA..2 * STRATA).map(|index| (Sampler::STRATIFIED.sample(3, 5, index, dimension) * STRATA as f64) as u32).collect();
+            strata.sort_unstable();
+            assert_eq!(strata, (0..STRATA).collect::<Vec<u32>>());
+        }
+    }
+
+    #[test]
+    fn samples_stay_in_the_unit_interval() {
+        for sampler in [Sampler::RANDOM, Sampler::STRATIFIED, Sampler::HALTON] {
+            for index in 0..64 {
+                for dimension in 0..8 {
+                    let value = sampler.sample(1, 2, index, dimension);
+                    assert!((0.0..1.0).contains(&value), "{:?} gave {}", sampler, value);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn halton_dimensions_past_the_primes_are_not_shifted_copies_of_the_first_ones() {
+        let table = PRIMES.len() as u32;
+        for dimension in 0..4 {
+            let shifts: Vec<f64> = (0..16).map(|index| {
+                let shift = Sampler::HALTON.sample(1, 2, index, dimension + table) - Sampler::HALTON.sample(1, 2, index, dimension);
+                shift - shift.floor()
+            }).collect();
+            assert!(shifts.iter().any(|shift| (shift - shifts[0]).abs() > 1e-6), "dimension {} repeats", dimension + table);
+        }
+    }
+}
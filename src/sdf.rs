@@ -0,0 +1,152 @@
+use serde::{Serialize, Deserialize};
+use crate::shape::{Ray, Hit, Point, intersect_in_object_space};
+use crate::vertors::Vector3;
+use crate::traits::Intersectable;
+use crate::transform::Matrix;
+use crate::aabb::Aabb;
+
+#[allow(non_camel_case_types, clippy::upper_case_acronyms)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum SdfNode {
+    SPHERE { center: Point, radius: f64 },
+    BOX {
+        center: Point,
+        half_size: Vector3,
+        #[serde(default)]
+        rounding: f64
+    },
+    UNION { left: Box<SdfNode>, right: Box<SdfNode> },
+    SMOOTH_UNION { left: Box<SdfNode>, right: Box<SdfNode>, smoothness: f64 },
+    SUBTRACTION { left: Box<SdfNode>, right: Box<SdfNode> },
+    INTERSECTION { left: Box<SdfNode>, right: Box<SdfNode> }
+}
+
+impl SdfNode {
+    // Distance to the surface, negative inside. Operators may underestimate it, which only slows the marching down
+    pub fn distance(&self, point: Point) -> f64 {
+        match self {
+            SdfNode::SPHERE { center, radius } => (point - *center).length() - radius,
+            SdfNode::BOX { center, half_size, rounding } => {
+                let local = point - *center;
+                let q = Vector3::new(local.x.abs() - half_size.x + rounding, local.y.abs() - half_size.y + rounding, local.z.abs() - half_size.z + rounding);
+                let outside = Vector3::new(q.x.max(0.0), q.y.max(0.0), q.z.max(0.0)).length();
+                outside + q.x.max(q.y).max(q.z).min(0.0) - rounding
+            },
+            SdfNode::UNION { left, right } => left.distance(point).min(right.distance(point)),
+            SdfNode::SMOOTH_UNION { left, right, smoothness } => {
+                let (a, b) = (left.distance(point), right.distance(point));
+                if *smoothness <= 0.0 {
+                    return a.min(b);
+                }
+                // Polynomial smooth minimum, the blend digs at most smoothness / 4 below the plain union
+                let h = (smoothness - (a - b).abs()).max(0.0) / smoothness;
+                a.min(b) - h * h * smoothness * 0.25
+            },
+            SdfNode::SUBTRACTION { left, right } => left.distance(point).max(-right.distance(point)),
+            SdfNode::INTERSECTION { left, right } => left.distance(point).max(right.distance(point))
+        }
+    }
+
+    pub fn bounds(&self) -> Option<Aabb> {
+        match self {
+            SdfNode::SPHERE { center, radius } => Some(Aabb::around(*center, Vector3::new(*radius, *radius, *radius))),
+            SdfNode::BOX { center, half_size, .. } => Some(Aabb::around(*center, *half_size)),
+            SdfNode::UNION { left, right } => match (left.bounds(), right.bounds()) {
+                (Some(left), Some(right)) => Some(left.union(&right)),
+                (left, right) => left.or(right)
+            },
+            SdfNode::SMOOTH_UNION { left, right, smoothness } => match (left.bounds(), right.bounds()) {
+                (Some(left), Some(right)) => Some(left.union(&right).expanded(smoothness.max(0.0) * 0.25)),
+                (left, right) => left.or(right)
+            },
+            SdfNode::SUBTRACTION { left, .. } => left.bounds(),
+            SdfNode::INTERSECTION { left, right } => left.bounds()?.intersection(&right.bounds()?)
+        }
+    }
+}
+
+fn default_max_steps() -> u32 {
+    256
+}
+
+fn default_epsilon() -> f64 {
+    1e-4
+}
+
+fn no_placement() -> Option<Matrix> {
+    Some(Matrix::identity())
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Sdf {
+    pub node: SdfNode,
+    #[serde(default = "default_max_steps")]
+    pub max_steps: u32,
+    #[serde(default = "default_epsilon")]
+    pub epsilon: f64,
+    #[serde(skip, default = "Matrix::identity")]
+    pub to_world: Matrix,
+    #[serde(skip, default = "no_placement")]
+    pub to_object: Option<Matrix>
+}
+
+impl Sdf {
+    // Node transforms are kept as matrices, the field is evaluated in its own space
+    pub fn transformed(&self, matrix: &Matrix) -> Sdf {
+        let to_world = *matrix * self.to_world;
+        Sdf { node: self.node.clone(), max_steps: self.max_steps, epsilon: self.epsilon, to_world, to_object: to_world.inverse() }
+    }
+
+    pub fn normal(&self, point: Point) -> Vector3 {
+        let h = self.epsilon;
+        let difference = |offset: Vector3| self.node.distance(point + offset) - self.node.distance(point - offset);
+        Vector3::new(difference(Vector3::new(h, 0.0, 0.0)), difference(Vector3::new(0.0, h, 0.0)), difference(Vector3::new(0.0, 0.0, h))).normalize()
+    }
+
+    // Sphere tracing inside the bounds: each step moves by the distance to the surface, which cannot cross it.
+    // Rays leaving the surface (shadows, reflections, crossings) first step off it before a hit counts
+    fn march(&self, ray: &Ray) -> Option<Hit> {
+        let (enter, exit) = self.node.bounds()?.expanded(self.epsilon).range(ray)?;
+        let mut distance = enter;
+        let mut leaving = enter <= ray.t_min;
+        for _ in 0..self.max_steps {
+            if distance > exit {
+                return None;
+            }
+            let field = self.node.distance(ray.origin + ray.direction * distance).abs();
+            if field >= self.epsilon {
+                leaving = false;
+                distance += field;
+            } else if leaving {
+                distance += self.epsilon;
+            } else {
+                let point = ray.origin + ray.direction * distance;
+                return Some(Hit::new(distance, point, self.normal(point)));
+            }
+        }
+        None // Out of steps, most likely grazing the surface
+    }
+}
+
+impl Intersectable for Sdf {
+    fn intersect(&self, ray: &Ray) -> Option<Hit> {
+        intersect_in_object_space(&SdfSpace(self), self.to_object.as_ref()?, ray)
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        Some(self.node.bounds()?.transformed(&self.to_world))
+    }
+}
+
+// The field in its own space, intersected once the ray is brought there
+struct SdfSpace<'a>(&'a Sdf);
+
+impl<'a> Intersectable for SdfSpace<'a> {
+    fn intersect(&self, ray: &Ray) -> Option<Hit> {
+        self.0.march(ray)
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        self.0.node.bounds()
+    }
+}
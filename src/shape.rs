@@ -1,47 +1,169 @@
 use serde::{Serialize, Deserialize};
 use crate::vertors::Vector3;
-use crate::rendering::{Camera, SHADOW_BIAS};
+use crate::rendering::{Camera, Color, Material, SHADOW_BIAS};
 use crate::traits::Intersectable;
+use crate::transform::Matrix;
+use crate::polynomial;
+use crate::mesh::Mesh;
+use crate::heightfield::Heightfield;
+use crate::sdf::Sdf;
+use crate::metaballs::Metaballs;
+use crate::aabb::Aabb;
+use crate::assets::Asset;
+use std::convert::TryFrom;
 use std::mem::swap;
+use std::sync::Arc;
+use std::fmt;
 
 pub type Point = Vector3;
 
 #[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct RayDescription {
+    pub origin: Point,
+    pub direction: Vector3,
+    pub t_min: f64,
+    pub t_max: f64,
+    #[serde(default)]
+    pub time: f64
+}
+
+// Read rays go through Ray::new like the others, the inverse direction and signs are not written
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+#[serde(from = "RayDescription", into = "RayDescription")]
 pub struct Ray {
     pub origin: Point,
-    pub direction: Vector3
+    pub direction: Vector3,
+    pub t_min: f64,
+    pub t_max: f64,
+    pub time: f64, // Within the frame, from 0 to 1, where moving elements are met
+    pub inverse_direction: Vector3, // Infinite along the axes the ray is parallel to, with the sign of the zero
+    pub sign: [usize; 3] // 1 along the axes the ray goes down, picks which side of a box it enters from
+}
+
+impl From<RayDescription> for Ray {
+    fn from(description: RayDescription) -> Ray {
+        Ray::new(description.origin, description.direction).with_range(description.t_min, description.t_max).with_time(description.time)
+    }
+}
+
+impl From<Ray> for RayDescription {
+    fn from(ray: Ray) -> RayDescription {
+        RayDescription { origin: ray.origin, direction: ray.direction, t_min: ray.t_min, t_max: ray.t_max, time: ray.time }
+    }
 }
 
 impl Ray {
+    // Every ray goes through here so that the inverse direction and signs box tests use match the direction
     pub fn new(origin: Point, direction: Vector3) -> Ray {
-        Ray { origin, direction }
+        let inverse_direction = Vector3::new(1.0 / direction.x, 1.0 / direction.y, 1.0 / direction.z);
+        let sign = [(inverse_direction.x < 0.0) as usize, (inverse_direction.y < 0.0) as usize, (inverse_direction.z < 0.0) as usize];
+        Ray { origin, direction, t_min: 0.0, t_max: f64::INFINITY, time: 0.0, inverse_direction, sign }
+    }
+
+    pub fn with_range(self, t_min: f64, t_max: f64) -> Ray {
+        Ray { t_min, t_max, ..self }
+    }
+
+    pub fn with_time(self, time: f64) -> Ray {
+        Ray { time, ..self }
+    }
+
+    pub fn in_range(&self, distance: f64) -> bool {
+        distance >= self.t_min && distance <= self.t_max
     }
 
-    pub fn compute_prime_ray(x: u32, y: u32, camera: Camera) -> Ray {
+    pub fn compute_prime_ray(x: u32, y: u32, camera: &Camera) -> Ray {
         camera.compute_prime_ray(x, y)
     }
 
-    pub fn compute_reflection_ray(normal: Vector3, old_direction: Vector3, point: Point) -> Ray {
-        Ray {
-            origin: point + (normal * SHADOW_BIAS),
-            direction: old_direction - (normal * 2.0 * old_direction.dot(&normal))
+    pub fn compute_reflection_ray(hit: &Hit, old_direction: Vector3) -> Ray {
+        Ray::new(hit.point + (hit.geometric_normal * SHADOW_BIAS), old_direction - (hit.normal * 2.0 * old_direction.dot(&hit.normal)))
+    }
+
+    // Snell's law, entering the material on front faces and leaving it on back faces. None on total internal reflection
+    pub fn compute_refraction_ray(hit: &Hit, old_direction: Vector3, ior: f64) -> Option<Ray> {
+        let direction = old_direction.normalize();
+        let eta = if hit.front_face { 1.0 / ior } else { ior };
+        let cos_incident = (-direction.dot(&hit.normal)).clamp(0.0, 1.0);
+        let sin_refracted_sq = eta * eta * (1.0 - cos_incident * cos_incident);
+        if sin_refracted_sq > 1.0 {
+            return None;
         }
+        let refracted = direction * eta + hit.normal * (eta * cos_incident - (1.0 - sin_refracted_sq).sqrt());
+        Some(Ray::new(hit.point - (hit.geometric_normal * SHADOW_BIAS), refracted))
+    }
+
+    // Same direction from the other side of the surface, to see what a partly opaque surface lets through
+    pub fn compute_pass_through_ray(hit: &Hit, old_direction: Vector3) -> Ray {
+        Ray::new(hit.point - (hit.geometric_normal * SHADOW_BIAS), old_direction)
     }
 }
 
-#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum RayType {
+    PRIMARY,
+    REFLECTION,
+    REFRACTION,
+    SHADOW
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Hit {
     pub distance: f64,
     pub point: Point,
-    pub normal: Vector3
+    pub normal: Vector3,
+    pub geometric_normal: Vector3, // Normal of the surface itself, rays leaving it are offset along this one
+    pub color: Option<Color>, // Replaces the material base color, from mesh vertex colors
+    pub material: Option<Material>, // Replaces the element material, from the materials of OBJ files
+    pub front_face: bool, // False when the ray came from behind the surface, e.g. from inside a sphere
+    pub uv: (f64, f64), // Texture coordinates, (0, 0) for shapes without a parametrization
+    pub tangents: Option<(Vector3, Vector3)>, // Directions u and v grow along on the surface, used by normal maps
+    pub shadow_offset: f64 // Extra lift of shadow rays along the geometric normal, up to the smooth surface flat triangles stand for
 }
 
 impl Hit {
     pub fn new(distance: f64, point: Point, normal: Vector3) -> Hit {
-        Hit { distance, point, normal }
+        Hit { distance, point, normal, geometric_normal: normal, color: None, material: None, front_face: true, uv: (0.0, 0.0), tangents: None, shadow_offset: 0.0 }
+    }
+
+    pub fn with_uv(self, u: f64, v: f64) -> Hit {
+        Hit { uv: (u, v), ..self }
+    }
+
+    pub fn with_tangents(self, tangent: Vector3, bitangent: Vector3) -> Hit {
+        Hit { tangents: Some((tangent, bitangent)), ..self }
+    }
+
+    // Shapes return outward normals, shading and the offsets of the rays leaving the hit need them on the side of the ray
+    pub fn facing(self, direction: Vector3) -> Hit {
+        if self.geometric_normal.dot(&direction) <= 0.0 {
+            return self;
+        }
+        Hit { normal: -self.normal, geometric_normal: -self.geometric_normal, front_face: false, shadow_offset: 0.0, ..self }
+    }
+
+    // Where shadow and ambient occlusion rays leave the surface from
+    pub fn shadow_origin(&self) -> Point {
+        self.point + self.geometric_normal * (self.shadow_offset + SHADOW_BIAS)
     }
 }
 
+// Longitude and latitude of a unit direction: u turns from 0 to 1 around the y axis starting at +x,
+// v goes from 0 at the +y pole to 1 at the -y pole
+pub fn spherical_uv(direction: Vector3) -> (f64, f64) {
+    let longitude = (-direction.z).atan2(direction.x).rem_euclid(2.0 * std::f64::consts::PI);
+    // Just before the seam the longitude can round up to a full turn, which is the seam itself
+    let u = longitude / (2.0 * std::f64::consts::PI);
+    (if u < 1.0 { u } else { 0.0 }, direction.y.clamp(-1.0, 1.0).acos() / std::f64::consts::PI)
+}
+
+// Two directions spanning the plane of the normal, used to give flat shapes texture coordinates
+pub fn tangent_axes(normal: Vector3) -> (Vector3, Vector3) {
+    let reference = if normal.y.abs() < 0.9 { Vector3::new(0.0, 1.0, 0.0) } else { Vector3::new(0.0, 0.0, 1.0) };
+    let tangent = normal.cross(&reference).normalize();
+    (tangent, normal.normalize().cross(&tangent))
+}
+
 #[derive(Copy, Clone, Debug, Serialize, Deserialize)]
 pub struct Sphere {
     pub origin: Point,
@@ -52,6 +174,10 @@ impl Sphere {
     pub fn new(origin: Point, radius: f64) -> Sphere {
         Sphere { origin, radius }
     }
+
+    pub fn transformed(&self, matrix: &Matrix) -> Sphere {
+        Sphere { origin: matrix.transform_point(self.origin), radius: self.radius * matrix.uniform_scale() }
+    }
 }
 
 impl Intersectable for Sphere {
@@ -70,58 +196,1623 @@ impl Intersectable for Sphere {
             swap(&mut intersect_0, &mut intersect_1);
         }
 
-        if intersect_0 < 0.0 && intersect_1 < 0.0 {
-            None
-        } else if intersect_0 >= 0.0 {
-            let hit_point = ray.origin + ray.direction * intersect_0;
-            let normal = (hit_point - self.origin).normalize();
-            Some(Hit::new(intersect_0, hit_point, normal))
+        let distance = if ray.in_range(intersect_0) {
+            intersect_0
+        } else if ray.in_range(intersect_1) {
+            intersect_1
         } else {
-            let hit_point = ray.origin + ray.direction * intersect_1;
-            let normal = (hit_point - self.origin).normalize();
-            Some(Hit::new(intersect_1, hit_point, normal))
-        }
+            return None;
+        };
+        let hit_point = ray.origin + ray.direction * distance;
+        let normal = (hit_point - self.origin).normalize();
+        let (u, v) = spherical_uv(normal);
+        // u turns toward -z from +x and v goes down, both vanish at the poles
+        let tangent = Vector3::new(normal.z, 0.0, -normal.x);
+        Some(Hit::new(distance, hit_point, normal).with_uv(u, v).with_tangents(tangent, tangent.cross(&normal)))
 
     }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        let radius = self.radius.abs();
+        Some(Aabb::around(self.origin, Vector3::new(radius, radius, radius)))
+    }
+}
+
+fn default_true() -> bool {
+    true
 }
 
 #[derive(Copy, Clone, Debug, Serialize, Deserialize)]
 pub struct Plane {
     pub point: Point,
-    pub normal: Vector3
+    pub normal: Vector3,
+    #[serde(default = "default_true")]
+    pub two_sided: bool
 }
 
 impl Plane {
     pub fn new(point: Point, normal: Vector3) -> Plane {
-        Plane { point, normal }
+        Plane { point, normal, two_sided: true }
+    }
+
+    pub fn transformed(&self, matrix: &Matrix) -> Plane {
+        Plane { point: matrix.transform_point(self.point), normal: matrix.transform_vector(self.normal).normalize(), two_sided: self.two_sided }
     }
 }
 
 impl Intersectable for Plane {
+    // A one sided plane is only seen by rays going along its normal, a two sided one returns the normal facing the ray
     fn intersect(&self, ray: &Ray) -> Option<Hit> {
         let denom = self.normal.dot(&ray.direction);
-        if denom > 0.0 {
-            let origin_to_plane = self.point - ray.origin;
-            let distance = origin_to_plane.dot(&self.normal) / denom;
-            if distance >= 0.0 {
-                return Some(Hit::new(distance, ray.origin + ray.direction * distance, -self.normal));
+        if denom.abs() < 1e-12 || (!self.two_sided && denom < 0.0) {
+            return None;
+        }
+        let origin_to_plane = self.point - ray.origin;
+        let distance = origin_to_plane.dot(&self.normal) / denom;
+        if !ray.in_range(distance) {
+            return None;
+        }
+        let normal = if denom > 0.0 { -self.normal } else { self.normal };
+        // World units along two axes of the plane, both sides share them
+        let hit_point = ray.origin + ray.direction * distance;
+        let (tangent, bitangent) = tangent_axes(self.normal);
+        let from_point = hit_point - self.point;
+        Some(Hit::new(distance, hit_point, normal.normalize()).with_uv(from_point.dot(&tangent), from_point.dot(&bitangent)).with_tangents(tangent, bitangent))
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        None
+    }
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct Triangle {
+    pub a: Point,
+    pub b: Point,
+    pub c: Point
+}
+
+impl Triangle {
+    pub fn new(a: Point, b: Point, c: Point) -> Triangle {
+        Triangle { a, b, c }
+    }
+
+    pub fn transformed(&self, matrix: &Matrix) -> Triangle {
+        Triangle { a: matrix.transform_point(self.a), b: matrix.transform_point(self.b), c: matrix.transform_point(self.c) }
+    }
+}
+
+impl Intersectable for Triangle {
+    fn intersect(&self, ray: &Ray) -> Option<Hit> {
+        let edge_1 = self.b - self.a;
+        let edge_2 = self.c - self.a;
+        let p = ray.direction.cross(&edge_2);
+        let determinant = edge_1.dot(&p);
+        if determinant.abs() < 1e-12 {
+            return None; // Parallel to the triangle or degenerate triangle
+        }
+        let inverse_determinant = 1.0 / determinant;
+        let origin_to_a = ray.origin - self.a;
+        let u = origin_to_a.dot(&p) * inverse_determinant;
+        if !(-1e-9..=1.0 + 1e-9).contains(&u) {
+            return None;
+        }
+        let q = origin_to_a.cross(&edge_1);
+        let v = ray.direction.dot(&q) * inverse_determinant;
+        if v < -1e-9 || u + v > 1.0 + 1e-9 {
+            return None;
+        }
+        let distance = edge_2.dot(&q) * inverse_determinant;
+        if !ray.in_range(distance) {
+            return None;
+        }
+        // Outward on the side the vertices turn counterclockwise, Hit::facing turns it toward the ray
+        let normal = edge_1.cross(&edge_2).normalize();
+        // The barycentric weights of b and c
+        Some(Hit::new(distance, ray.origin + ray.direction * distance, normal).with_uv(u, v).with_tangents(edge_1, edge_2))
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        Aabb::from_points(vec![self.a, self.b, self.c])
+    }
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct Cylinder {
+    pub base: Point,
+    pub axis: Vector3,
+    pub radius: f64,
+    pub height: f64
+}
+
+impl Cylinder {
+    pub fn new(base: Point, axis: Vector3, radius: f64, height: f64) -> Cylinder {
+        Cylinder { base, axis, radius, height }
+    }
+
+    pub fn transformed(&self, matrix: &Matrix) -> Cylinder {
+        let scale = matrix.uniform_scale();
+        Cylinder { base: matrix.transform_point(self.base), axis: matrix.transform_vector(self.axis).normalize(), radius: self.radius * scale, height: self.height * scale }
+    }
+}
+
+impl Intersectable for Cylinder {
+    fn intersect(&self, ray: &Ray) -> Option<Hit> {
+        let axis = self.axis.normalize();
+        let origin = ray.origin - self.base;
+        let direction_along = ray.direction.dot(&axis);
+        let origin_along = origin.dot(&axis);
+        let mut nearest: Option<(f64, Vector3)> = None;
+        let mut keep = |distance: f64, normal: Vector3| {
+            if ray.in_range(distance) && nearest.is_none_or(|(best, _)| distance < best) {
+                nearest = Some((distance, normal));
+            }
+        };
+
+        let direction_across = ray.direction - axis * direction_along;
+        let origin_across = origin - axis * origin_along;
+        let a = direction_across.dot(&direction_across);
+        if a > 1e-12 {
+            let b = 2.0 * origin_across.dot(&direction_across);
+            let c = origin_across.dot(&origin_across) - self.radius * self.radius;
+            let discriminant = b * b - 4.0 * a * c;
+            if discriminant >= 0.0 {
+                let root = discriminant.sqrt();
+                for distance in [(-b - root) / (2.0 * a), (-b + root) / (2.0 * a)] {
+                    let along = origin_along + direction_along * distance;
+                    if along >= 0.0 && along <= self.height {
+                        keep(distance, (origin_across + direction_across * distance).normalize());
+                    }
+                }
             }
         }
+
+        if direction_along.abs() > 1e-12 {
+            for (cap_along, normal) in [(0.0, -axis), (self.height, axis)] {
+                let distance = (cap_along - origin_along) / direction_along;
+                let across = origin_across + direction_across * distance;
+                if across.dot(&across) <= self.radius * self.radius {
+                    keep(distance, normal);
+                }
+            }
+        }
+
+        nearest.map(|(distance, normal)| Hit::new(distance, ray.origin + ray.direction * distance, normal))
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        let top = self.base + self.axis.normalize() * self.height;
+        Some(Aabb::around_disk(self.base, self.axis, self.radius).union(&Aabb::around_disk(top, self.axis, self.radius)))
+    }
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct InfiniteCylinder {
+    pub axis_point: Point,
+    pub axis_dir: Vector3,
+    pub radius: f64
+}
+
+impl InfiniteCylinder {
+    pub fn new(axis_point: Point, axis_dir: Vector3, radius: f64) -> InfiniteCylinder {
+        InfiniteCylinder { axis_point, axis_dir, radius }
+    }
+
+    pub fn transformed(&self, matrix: &Matrix) -> InfiniteCylinder {
+        InfiniteCylinder { axis_point: matrix.transform_point(self.axis_point), axis_dir: matrix.transform_vector(self.axis_dir).normalize(), radius: self.radius * matrix.uniform_scale() }
+    }
+}
+
+impl Intersectable for InfiniteCylinder {
+    // Same side surface as the capped cylinder without its ends, u turns around the axis and v is the distance along it
+    fn intersect(&self, ray: &Ray) -> Option<Hit> {
+        let axis = self.axis_dir.normalize();
+        let origin = ray.origin - self.axis_point;
+        let direction_across = ray.direction - axis * ray.direction.dot(&axis);
+        let origin_across = origin - axis * origin.dot(&axis);
+        let roots = polynomial::solve_quadratic([
+            origin_across.dot(&origin_across) - self.radius * self.radius,
+            2.0 * origin_across.dot(&direction_across),
+            direction_across.dot(&direction_across)
+        ]);
+        let distance = roots.into_iter().filter(|distance| ray.in_range(*distance)).min_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal))?;
+        let hit_point = ray.origin + ray.direction * distance;
+        let from_axis = hit_point - self.axis_point;
+        let normal = (from_axis - axis * from_axis.dot(&axis)).normalize();
+        let (tangent, bitangent) = tangent_axes(axis);
+        let angle = normal.dot(&bitangent).atan2(normal.dot(&tangent)).rem_euclid(2.0 * std::f64::consts::PI);
+        Some(Hit::new(distance, hit_point, normal).with_uv(angle / (2.0 * std::f64::consts::PI), from_axis.dot(&axis)).with_tangents(axis.cross(&normal), axis))
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
         None
     }
 }
 
 #[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct Cone {
+    pub apex: Point,
+    pub axis: Vector3,
+    pub half_angle: f64,
+    pub height: f64
+}
+
+impl Cone {
+    pub fn new(apex: Point, axis: Vector3, half_angle: f64, height: f64) -> Cone {
+        Cone { apex, axis, half_angle, height }
+    }
+
+    pub fn transformed(&self, matrix: &Matrix) -> Cone {
+        Cone { apex: matrix.transform_point(self.apex), axis: matrix.transform_vector(self.axis).normalize(), half_angle: self.half_angle, height: self.height * matrix.uniform_scale() }
+    }
+}
+
+impl Intersectable for Cone {
+    fn intersect(&self, ray: &Ray) -> Option<Hit> {
+        let axis = self.axis.normalize();
+        let cos_squared = self.half_angle.to_radians().cos().powi(2);
+        let origin = ray.origin - self.apex;
+        let direction_along = ray.direction.dot(&axis);
+        let origin_along = origin.dot(&axis);
+        let mut nearest: Option<(f64, Vector3)> = None;
+        let mut keep = |distance: f64, normal: Vector3| {
+            if ray.in_range(distance) && nearest.is_none_or(|(best, _)| distance < best) {
+                nearest = Some((distance, normal));
+            }
+        };
+        let side_normal = |distance: f64| {
+            let to_point = origin + ray.direction * distance;
+            let normal = to_point * cos_squared - axis * to_point.dot(&axis);
+            if normal.length_sq() > 1e-24 { normal.normalize() } else { -axis }
+        };
+
+        let a = direction_along * direction_along - cos_squared;
+        let b = 2.0 * (direction_along * origin_along - cos_squared * ray.direction.dot(&origin));
+        let c = origin_along * origin_along - cos_squared * origin.dot(&origin);
+        let roots = if a.abs() > 1e-12 {
+            let discriminant = b * b - 4.0 * a * c;
+            if discriminant >= 0.0 {
+                let root = discriminant.sqrt();
+                vec![(-b - root) / (2.0 * a), (-b + root) / (2.0 * a)]
+            } else {
+                Vec::new()
+            }
+        } else if b.abs() > 1e-12 {
+            vec![-c / b]
+        } else {
+            Vec::new()
+        };
+        for distance in roots {
+            let along = origin_along + direction_along * distance;
+            if along >= 0.0 && along <= self.height {
+                keep(distance, side_normal(distance));
+            }
+        }
+
+        if direction_along.abs() > 1e-12 {
+            let distance = (self.height - origin_along) / direction_along;
+            let across = origin + ray.direction * distance - axis * self.height;
+            let radius = self.height * self.half_angle.to_radians().tan();
+            if across.dot(&across) <= radius * radius {
+                keep(distance, axis);
+            }
+        }
+
+        nearest.map(|(distance, normal)| Hit::new(distance, ray.origin + ray.direction * distance, normal))
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        let base = self.apex + self.axis.normalize() * self.height;
+        let radius = self.height * self.half_angle.to_radians().tan();
+        Some(Aabb::around_disk(base, self.axis, radius).union(&Aabb::new(self.apex, self.apex)))
+    }
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct Torus {
+    pub center: Point,
+    pub axis: Vector3,
+    pub major_radius: f64,
+    pub minor_radius: f64
+}
+
+impl Torus {
+    pub fn new(center: Point, axis: Vector3, major_radius: f64, minor_radius: f64) -> Torus {
+        Torus { center, axis, major_radius, minor_radius }
+    }
+
+    pub fn transformed(&self, matrix: &Matrix) -> Torus {
+        let scale = matrix.uniform_scale();
+        Torus { center: matrix.transform_point(self.center), axis: matrix.transform_vector(self.axis).normalize(), major_radius: self.major_radius * scale, minor_radius: self.minor_radius * scale }
+    }
+}
+
+impl Intersectable for Torus {
+    fn intersect(&self, ray: &Ray) -> Option<Hit> {
+        let axis = self.axis.normalize();
+        let major_squared = self.major_radius * self.major_radius;
+        let bounds = self.major_radius + self.minor_radius;
+
+        // Start the quartic from the bounding sphere so far away rays keep their precision
+        let to_origin = ray.origin - self.center;
+        let projection = to_origin.dot(&ray.direction);
+        let discriminant = projection * projection - (to_origin.dot(&to_origin) - bounds * bounds);
+        if discriminant < 0.0 {
+            return None;
+        }
+        let exit = -projection + discriminant.sqrt();
+        if exit < ray.t_min {
+            return None;
+        }
+        let shift = (-projection - discriminant.sqrt()).max(ray.t_min.min(0.0));
+
+        let origin = to_origin + ray.direction * shift;
+        let m = ray.direction.dot(&ray.direction);
+        let n = origin.dot(&ray.direction);
+        let k = origin.dot(&origin) + major_squared - self.minor_radius * self.minor_radius;
+        let origin_along = origin.dot(&axis);
+        let direction_along = ray.direction.dot(&axis);
+        let roots = polynomial::solve_quartic([
+            k * k - 4.0 * major_squared * (origin.dot(&origin) - origin_along * origin_along),
+            4.0 * n * k - 8.0 * major_squared * (n - direction_along * origin_along),
+            2.0 * m * k + 4.0 * n * n - 4.0 * major_squared * (m - direction_along * direction_along),
+            4.0 * m * n,
+            m * m
+        ]);
+        let distance = roots.into_iter()
+            .map(|root| root + shift)
+            .filter(|distance| *distance > ray.t_min.min(0.0) + 1e-7 && ray.in_range(*distance))
+            .min_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal))?;
+
+        let hit_point = ray.origin + ray.direction * distance;
+        let local = hit_point - self.center;
+        let across = local - axis * local.dot(&axis);
+        let ring = if across.length_sq() > 1e-24 { across.normalize() * self.major_radius } else { across };
+        Some(Hit::new(distance, hit_point, (local - ring).normalize()))
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        Some(Aabb::around_disk(self.center, self.axis, self.major_radius).expanded(self.minor_radius))
+    }
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct Ellipsoid {
+    pub center: Point,
+    pub radii: Vector3,
+    #[serde(skip, default = "Matrix::identity")]
+    pub orientation: Matrix
+}
+
+impl Ellipsoid {
+    pub fn new(center: Point, radii: Vector3) -> Ellipsoid {
+        Ellipsoid { center, radii, orientation: Matrix::identity() }
+    }
+
+    pub fn transformed(&self, matrix: &Matrix) -> Ellipsoid {
+        Ellipsoid { center: matrix.transform_point(self.center), radii: self.radii * matrix.uniform_scale(), orientation: matrix.rotation() * self.orientation }
+    }
+}
+
+impl Intersectable for Ellipsoid {
+    fn intersect(&self, ray: &Ray) -> Option<Hit> {
+        // Intersect the unit sphere in the space where the semi-axes have a length of 1
+        let to_local = self.orientation.transpose();
+        let scale_down = |v: Vector3| Vector3::new(v.x / self.radii.x, v.y / self.radii.y, v.z / self.radii.z);
+        let origin = scale_down(to_local.transform_vector(ray.origin - self.center));
+        let direction = scale_down(to_local.transform_vector(ray.direction));
+        let a = direction.dot(&direction);
+        if a < 1e-24 {
+            return None; // Degenerate ellipsoid
+        }
+        let b = origin.dot(&direction);
+        let c = origin.dot(&origin) - 1.0;
+        let discriminant = b * b - a * c;
+        if discriminant < 0.0 {
+            return None;
+        }
+        let root = discriminant.sqrt();
+        let distance = [(-b - root) / a, (-b + root) / a].iter().copied().find(|distance| ray.in_range(*distance))?;
+
+        // Normals go back with the inverse transpose of the scaling, so they are divided by the radii again
+        let local_normal = scale_down(origin + direction * distance);
+        let normal = self.orientation.transform_vector(local_normal).normalize();
+        Some(Hit::new(distance, ray.origin + ray.direction * distance, normal))
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        // Each axis of the box gets the length of the rotated and scaled semi-axes projected on it
+        let m = self.orientation.m;
+        let extent = |row: usize| ((m[row][0] * self.radii.x).powi(2) + (m[row][1] * self.radii.y).powi(2) + (m[row][2] * self.radii.z).powi(2)).sqrt();
+        Some(Aabb::around(self.center, Vector3::new(extent(0), extent(1), extent(2))))
+    }
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct Capsule {
+    pub a: Point,
+    pub b: Point,
+    pub radius: f64
+}
+
+impl Capsule {
+    pub fn new(a: Point, b: Point, radius: f64) -> Capsule {
+        Capsule { a, b, radius }
+    }
+
+    pub fn transformed(&self, matrix: &Matrix) -> Capsule {
+        Capsule { a: matrix.transform_point(self.a), b: matrix.transform_point(self.b), radius: self.radius * matrix.uniform_scale() }
+    }
+}
+
+impl Intersectable for Capsule {
+    fn intersect(&self, ray: &Ray) -> Option<Hit> {
+        let length = (self.b - self.a).length();
+        let axis = (self.b - self.a).normalize(); // Zero when both ends coincide, the caps then make a whole sphere
+        let origin = ray.origin - self.a;
+        let direction_along = ray.direction.dot(&axis);
+        let origin_along = origin.dot(&axis);
+        let mut nearest: Option<(f64, Vector3)> = None;
+        let mut keep = |distance: f64, normal: Vector3| {
+            if ray.in_range(distance) && nearest.is_none_or(|(best, _)| distance < best) {
+                nearest = Some((distance, normal));
+            }
+        };
+
+        let direction_across = ray.direction - axis * direction_along;
+        let origin_across = origin - axis * origin_along;
+        let a = direction_across.dot(&direction_across);
+        if length > 0.0 && a > 1e-12 {
+            let b = 2.0 * origin_across.dot(&direction_across);
+            let c = origin_across.dot(&origin_across) - self.radius * self.radius;
+            let discriminant = b * b - 4.0 * a * c;
+            if discriminant >= 0.0 {
+                let root = discriminant.sqrt();
+                for distance in [(-b - root) / (2.0 * a), (-b + root) / (2.0 * a)] {
+                    let along = origin_along + direction_along * distance;
+                    if along >= 0.0 && along <= length {
+                        keep(distance, (origin_across + direction_across * distance).normalize());
+                    }
+                }
+            }
+        }
+
+        // Each cap only keeps the half of its sphere that is outside the body
+        for (center, outside) in [(self.a, -1.0), (self.b, 1.0)] {
+            let to_center = ray.origin - center;
+            let b = to_center.dot(&ray.direction);
+            let c = to_center.dot(&to_center) - self.radius * self.radius;
+            let discriminant = b * b - c;
+            if discriminant < 0.0 {
+                continue;
+            }
+            let root = discriminant.sqrt();
+            for distance in [-b - root, -b + root] {
+                let from_center = to_center + ray.direction * distance;
+                if from_center.dot(&axis) * outside >= 0.0 {
+                    keep(distance, from_center.normalize());
+                }
+            }
+        }
+
+        nearest.map(|(distance, normal)| Hit::new(distance, ray.origin + ray.direction * distance, normal))
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        Some(Aabb::new(self.a, self.a).union(&Aabb::new(self.b, self.b)).expanded(self.radius))
+    }
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct Disk {
+    pub center: Point,
+    pub normal: Vector3,
+    pub radius: f64
+}
+
+impl Disk {
+    pub fn new(center: Point, normal: Vector3, radius: f64) -> Disk {
+        Disk { center, normal, radius }
+    }
+
+    pub fn transformed(&self, matrix: &Matrix) -> Disk {
+        Disk { center: matrix.transform_point(self.center), normal: matrix.transform_vector(self.normal).normalize(), radius: self.radius * matrix.uniform_scale() }
+    }
+}
+
+impl Intersectable for Disk {
+    fn intersect(&self, ray: &Ray) -> Option<Hit> {
+        let normal = self.normal.normalize();
+        let denom = normal.dot(&ray.direction);
+        if denom.abs() < 1e-12 {
+            return None; // Parallel to the disk
+        }
+        let distance = (self.center - ray.origin).dot(&normal) / denom;
+        if !ray.in_range(distance) {
+            return None;
+        }
+        let hit_point = ray.origin + ray.direction * distance;
+        let from_center = hit_point - self.center;
+        if from_center.dot(&from_center) > self.radius * self.radius {
+            return None;
+        }
+        Some(Hit::new(distance, hit_point, if denom > 0.0 { -normal } else { normal }))
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        Some(Aabb::around_disk(self.center, self.normal, self.radius))
+    }
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct Quad {
+    pub corner: Point,
+    pub u: Vector3,
+    pub v: Vector3
+}
+
+impl Quad {
+    pub fn new(corner: Point, u: Vector3, v: Vector3) -> Quad {
+        Quad { corner, u, v }
+    }
+
+    pub fn transformed(&self, matrix: &Matrix) -> Quad {
+        Quad { corner: matrix.transform_point(self.corner), u: matrix.transform_vector(self.u), v: matrix.transform_vector(self.v) }
+    }
+}
+
+impl Intersectable for Quad {
+    fn intersect(&self, ray: &Ray) -> Option<Hit> {
+        let plane_normal = self.u.cross(&self.v);
+        let normal_length_sq = plane_normal.length_sq();
+        if normal_length_sq < 1e-24 {
+            return None; // Degenerate quad
+        }
+        let denom = plane_normal.dot(&ray.direction);
+        if denom.abs() < 1e-12 * normal_length_sq.sqrt() {
+            return None; // Parallel to the quad
+        }
+        let distance = (self.corner - ray.origin).dot(&plane_normal) / denom;
+        if !ray.in_range(distance) {
+            return None;
+        }
+        let hit_point = ray.origin + ray.direction * distance;
+        let from_corner = hit_point - self.corner;
+        // Parametric coordinates along the edges, from the plane normal so that u and v do not need to be orthogonal
+        let u = plane_normal.dot(&from_corner.cross(&self.v)) / normal_length_sq;
+        let v = plane_normal.dot(&self.u.cross(&from_corner)) / normal_length_sq;
+        if !(0.0..=1.0).contains(&u) || !(0.0..=1.0).contains(&v) {
+            return None;
+        }
+        let normal = plane_normal.normalize();
+        Some(Hit::new(distance, hit_point, if denom > 0.0 { -normal } else { normal }).with_uv(u, v).with_tangents(self.u, self.v))
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        Aabb::from_points(vec![self.corner, self.corner + self.u, self.corner + self.v, self.corner + self.u + self.v])
+    }
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct OrientedBoxDescription {
+    pub center: Point,
+    pub half_size: Vector3,
+    #[serde(default)]
+    pub axes: Option<[Vector3; 3]>,
+    #[serde(default = "Vector3::zero")]
+    pub rotate: Vector3 // In degrees like transforms, only used without axes
+}
+
+// The axes are made orthonormal once when the scene is read, intersections rely on it
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+#[serde(try_from = "OrientedBoxDescription", into = "OrientedBoxDescription")]
+pub struct OrientedBox {
+    pub center: Point,
+    pub axes: [Vector3; 3],
+    pub half_size: Vector3
+}
+
+impl TryFrom<OrientedBoxDescription> for OrientedBox {
+    type Error = String;
+
+    // Gram-Schmidt: the first axis keeps its direction, the second loses its part along it and the third is rebuilt
+    fn try_from(description: OrientedBoxDescription) -> Result<OrientedBox, String> {
+        let [x, y, z] = description.axes.unwrap_or_else(|| {
+            let rotation = object_to_world(Vector3::zero(), description.rotate, unit_scale());
+            [Vector3::new(1.0, 0.0, 0.0), Vector3::new(0.0, 1.0, 0.0), Vector3::new(0.0, 0.0, 1.0)].map(|axis| rotation.transform_vector(axis))
+        });
+        let x_axis = x.normalize();
+        let y_axis = (y - x_axis * y.dot(&x_axis)).normalize();
+        let z_axis = x_axis.cross(&y_axis);
+        if ![x_axis, y_axis].iter().all(|axis| axis.length().is_finite() && axis.length() > 0.5) || z.length_sq() < 1e-24 {
+            return Err("oriented box axes must be non-zero and not parallel".to_string());
+        }
+        if description.half_size.x < 0.0 || description.half_size.y < 0.0 || description.half_size.z < 0.0 {
+            return Err("oriented box half_size must not be negative".to_string());
+        }
+        // A left-handed third axis only mirrors the box, which is symmetric
+        Ok(OrientedBox { center: description.center, axes: [x_axis, y_axis, z_axis], half_size: description.half_size })
+    }
+}
+
+impl From<OrientedBox> for OrientedBoxDescription {
+    fn from(oriented: OrientedBox) -> OrientedBoxDescription {
+        OrientedBoxDescription { center: oriented.center, half_size: oriented.half_size, axes: Some(oriented.axes), rotate: Vector3::zero() }
+    }
+}
+
+impl OrientedBox {
+    pub fn transformed(&self, matrix: &Matrix) -> OrientedBox {
+        OrientedBox {
+            center: matrix.transform_point(self.center),
+            axes: self.axes.map(|axis| matrix.transform_vector(axis).normalize()),
+            half_size: self.half_size * matrix.uniform_scale()
+        }
+    }
+
+    fn local(&self, vector: Vector3) -> Vector3 {
+        Vector3::new(vector.dot(&self.axes[0]), vector.dot(&self.axes[1]), vector.dot(&self.axes[2]))
+    }
+}
+
+impl Intersectable for OrientedBox {
+    // The slab test in the frame of the box, distances are kept since the axes are orthonormal
+    fn intersect(&self, ray: &Ray) -> Option<Hit> {
+        let local = Ray::new(self.local(ray.origin - self.center), self.local(ray.direction)).with_range(f64::NEG_INFINITY, f64::INFINITY);
+        let (enter, exit) = Aabb::around(Vector3::zero(), self.half_size).range(&local)?;
+        let distance = [enter, exit].iter().copied().find(|distance| ray.in_range(*distance))?;
+
+        // The face hit is the one the point is relatively closest to, uv spans it from 0 to 1
+        let point = local.origin + local.direction * distance;
+        let relative = [point.x / self.half_size.x, point.y / self.half_size.y, point.z / self.half_size.z];
+        let face = (0..3).max_by(|&a, &b| relative[a].abs().partial_cmp(&relative[b].abs()).unwrap_or(std::cmp::Ordering::Equal)).unwrap_or(0);
+        let normal = self.axes[face] * relative[face].signum();
+        let (u, v) = ((relative[(face + 1) % 3] + 1.0) * 0.5, (relative[(face + 2) % 3] + 1.0) * 0.5);
+        Some(Hit::new(distance, ray.origin + ray.direction * distance, normal).with_uv(u, v).with_tangents(self.axes[(face + 1) % 3], self.axes[(face + 2) % 3]))
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        let corners = (0..8).map(|corner| {
+            let sign = |bit: usize| if corner & (1 << bit) == 0 { -1.0 } else { 1.0 };
+            self.center + self.axes[0] * (self.half_size.x * sign(0)) + self.axes[1] * (self.half_size.y * sign(1)) + self.axes[2] * (self.half_size.z * sign(2))
+        });
+        Aabb::from_points(corners)
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PolygonDescription {
+    pub vertices: Vec<Point>
+}
+
+// Checked once when the scene is read: repeated vertices are dropped, the rest must be coplanar and convex
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(try_from = "PolygonDescription", into = "PolygonDescription")]
+pub struct Polygon {
+    pub vertices: Vec<Point>,
+    pub normal: Vector3
+}
+
+impl TryFrom<PolygonDescription> for Polygon {
+    type Error = String;
+
+    fn try_from(description: PolygonDescription) -> Result<Polygon, String> {
+        let points = &description.vertices;
+        let size = points.iter().flat_map(|a| points.iter().map(move |b| (*a - *b).length())).fold(0.0, f64::max);
+        let tolerance = size * 1e-6;
+        // Indices in the file are kept for the errors
+        let mut kept: Vec<usize> = Vec::with_capacity(points.len());
+        for (index, point) in points.iter().enumerate() {
+            if kept.last().is_none_or(|&last| (*point - points[last]).length() > tolerance) {
+                kept.push(index);
+            }
+        }
+        while kept.len() > 1 && (points[kept[0]] - points[kept[kept.len() - 1]]).length() <= tolerance {
+            kept.pop();
+        }
+        if kept.len() < 3 {
+            return Err(format!("a polygon needs at least 3 distinct vertices, found {}", kept.len()));
+        }
+        let vertices: Vec<Point> = kept.iter().map(|&index| points[index]).collect();
+
+        // Newell's method averages the normal over every edge, so no single corner decides it
+        let normal = vertices.iter().zip(vertices.iter().cycle().skip(1))
+            .fold(Vector3::zero(), |normal, (a, b)| normal + Vector3::new((a.y - b.y) * (a.z + b.z), (a.z - b.z) * (a.x + b.x), (a.x - b.x) * (a.y + b.y)));
+        if normal.length() <= tolerance * size {
+            return Err("the polygon vertices are collinear".to_string());
+        }
+        let normal = normal.normalize();
+        // The plane of the first two vertices and the next one off their line, the first vertex away from it is reported
+        let third = (2..vertices.len()).find(|&index| (vertices[1] - vertices[0]).cross(&(vertices[index] - vertices[0])).length() > tolerance * size).unwrap_or(2);
+        let plane_normal = (vertices[1] - vertices[0]).cross(&(vertices[third] - vertices[0])).normalize();
+        for (vertex, &index) in vertices.iter().zip(kept.iter()) {
+            let distance = (*vertex - vertices[0]).dot(&plane_normal);
+            if distance.abs() > tolerance {
+                return Err(format!("polygon vertex {} is {:.6} away from the plane of vertices {}, {} and {}", index, distance.abs(), kept[0], kept[1], kept[third]));
+            }
+        }
+        // Every corner turns the same way, and all of them together only once around (a star turns twice)
+        let count = vertices.len();
+        let mut turned = 0.0;
+        for corner in 0..count {
+            let incoming = vertices[corner] - vertices[(corner + count - 1) % count];
+            let outgoing = vertices[(corner + 1) % count] - vertices[corner];
+            let turn = incoming.cross(&outgoing).dot(&normal);
+            if turn < -tolerance * size {
+                return Err(format!("polygon is not convex at vertex {}", kept[corner]));
+            }
+            turned += turn.atan2(incoming.dot(&outgoing));
+            if turned > 2.0 * std::f64::consts::PI + 1e-6 {
+                return Err(format!("polygon edges cross each other, it winds around more than once at vertex {}", kept[corner]));
+            }
+        }
+        Ok(Polygon { vertices, normal })
+    }
+}
+
+impl From<Polygon> for PolygonDescription {
+    fn from(polygon: Polygon) -> PolygonDescription {
+        PolygonDescription { vertices: polygon.vertices }
+    }
+}
+
+impl Polygon {
+    pub fn transformed(&self, matrix: &Matrix) -> Polygon {
+        Polygon { vertices: self.vertices.iter().map(|vertex| matrix.transform_point(*vertex)).collect(), normal: matrix.transform_vector(self.normal).normalize() }
+    }
+}
+
+impl Intersectable for Polygon {
+    // Inside when on the inner side of every edge, points on an edge count as inside
+    fn intersect(&self, ray: &Ray) -> Option<Hit> {
+        let denom = self.normal.dot(&ray.direction);
+        if denom.abs() < 1e-12 {
+            return None;
+        }
+        let distance = (self.vertices[0] - ray.origin).dot(&self.normal) / denom;
+        if !ray.in_range(distance) {
+            return None;
+        }
+        let hit_point = ray.origin + ray.direction * distance;
+        let edges = self.vertices.iter().zip(self.vertices.iter().cycle().skip(1));
+        for (a, b) in edges {
+            let edge = *b - *a;
+            if edge.cross(&(hit_point - *a)).dot(&self.normal) < -1e-9 * edge.length_sq() {
+                return None;
+            }
+        }
+        // World units along two axes of the polygon from its first vertex, like planes
+        let (tangent, bitangent) = tangent_axes(self.normal);
+        let from_first = hit_point - self.vertices[0];
+        let normal = if denom > 0.0 { -self.normal } else { self.normal };
+        Some(Hit::new(distance, hit_point, normal).with_uv(from_first.dot(&tangent), from_first.dot(&bitangent)).with_tangents(tangent, bitangent))
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        Aabb::from_points(self.vertices.iter().copied())
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ConvexPolyhedronDescription {
+    pub planes: Vec<Plane>
+}
+
+// The solid behind every plane, opposite to their normals. Its corners are found when the scene is read,
+// which rejects unbounded and empty sets and gives the bounding box
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(try_from = "ConvexPolyhedronDescription", into = "ConvexPolyhedronDescription")]
+pub struct ConvexPolyhedron {
+    pub planes: Vec<Plane>,
+    pub corners: Vec<Point>
+}
+
+impl TryFrom<ConvexPolyhedronDescription> for ConvexPolyhedron {
+    type Error = String;
+
+    fn try_from(description: ConvexPolyhedronDescription) -> Result<ConvexPolyhedron, String> {
+        let mut planes = description.planes;
+        for (index, plane) in planes.iter_mut().enumerate() {
+            if plane.normal.length_sq() < 1e-24 {
+                return Err(format!("convex polyhedron plane {} has a zero normal", index));
+            }
+            plane.normal = plane.normal.normalize();
+        }
+        let scale = planes.iter().map(|plane| plane.point.length()).fold(1.0, f64::max);
+        let tolerance = scale * 1e-9;
+        let outside = |point: Point| planes.iter().any(|plane| (point - plane.point).dot(&plane.normal) > tolerance);
+
+        // A direction no plane faces leads out forever, when there is one it is along an edge of two planes
+        let normals: Vec<Vector3> = planes.iter().map(|plane| plane.normal).collect();
+        let edges: Vec<Vector3> = normals.iter().enumerate()
+            .flat_map(|(i, a)| normals[i + 1..].iter().map(move |b| a.cross(b)))
+            .filter(|edge| edge.length_sq() > 1e-18)
+            .collect();
+        let escapes = |direction: Vector3| normals.iter().all(|normal| normal.dot(&direction) <= 1e-9);
+        if planes.len() < 4 || edges.is_empty() || edges.iter().any(|edge| escapes(*edge) || escapes(-*edge)) {
+            return Err("convex polyhedron is unbounded, its planes must enclose a volume".to_string());
+        }
+
+        // Corners are where three planes meet inside all the others
+        let mut corners: Vec<Point> = Vec::new();
+        for i in 0..planes.len() {
+            for j in i + 1..planes.len() {
+                for k in j + 1..planes.len() {
+                    let (a, b, c) = (&planes[i], &planes[j], &planes[k]);
+                    let determinant = a.normal.dot(&b.normal.cross(&c.normal));
+                    if determinant.abs() < 1e-12 {
+                        continue;
+                    }
+                    let corner = (b.normal.cross(&c.normal) * a.normal.dot(&a.point)
+                        + c.normal.cross(&a.normal) * b.normal.dot(&b.point)
+                        + a.normal.cross(&b.normal) * c.normal.dot(&c.point)) * (1.0 / determinant);
+                    if !outside(corner) {
+                        corners.push(corner);
+                    }
+                }
+            }
+        }
+        if corners.is_empty() {
+            return Err("convex polyhedron is empty, no point is behind all of its planes".to_string());
+        }
+        Ok(ConvexPolyhedron { planes, corners })
+    }
+}
+
+impl From<ConvexPolyhedron> for ConvexPolyhedronDescription {
+    fn from(polyhedron: ConvexPolyhedron) -> ConvexPolyhedronDescription {
+        ConvexPolyhedronDescription { planes: polyhedron.planes }
+    }
+}
+
+impl ConvexPolyhedron {
+    pub fn transformed(&self, matrix: &Matrix) -> ConvexPolyhedron {
+        ConvexPolyhedron {
+            planes: self.planes.iter().map(|plane| plane.transformed(matrix)).collect(),
+            corners: self.corners.iter().map(|corner| matrix.transform_point(*corner)).collect()
+        }
+    }
+}
+
+impl Intersectable for ConvexPolyhedron {
+    // Each plane clips the ray: the last one it enters gives the entry, the first one it leaves the exit
+    fn intersect(&self, ray: &Ray) -> Option<Hit> {
+        let mut enter = (f64::NEG_INFINITY, None);
+        let mut exit = (f64::INFINITY, None);
+        for plane in self.planes.iter() {
+            let denom = plane.normal.dot(&ray.direction);
+            let behind = (plane.point - ray.origin).dot(&plane.normal);
+            if denom.abs() < 1e-12 {
+                if behind < 0.0 {
+                    return None; // Parallel and outside of this plane
+                }
+                continue;
+            }
+            let distance = behind / denom;
+            if denom < 0.0 && distance > enter.0 {
+                enter = (distance, Some(plane.normal));
+            } else if denom > 0.0 && distance < exit.0 {
+                exit = (distance, Some(plane.normal));
+            }
+            if enter.0 > exit.0 {
+                return None;
+            }
+        }
+        let (distance, normal) = [enter, exit].iter().copied().find(|(distance, _)| ray.in_range(*distance))?;
+        let normal = normal?;
+        let hit_point = ray.origin + ray.direction * distance;
+        // Like planes, world units along two axes of the face
+        let (tangent, bitangent) = tangent_axes(normal);
+        Some(Hit::new(distance, hit_point, normal).with_uv(hit_point.dot(&tangent), hit_point.dot(&bitangent)).with_tangents(tangent, bitangent))
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        Aabb::from_points(self.corners.iter().copied())
+    }
+}
+
+fn unit_scale() -> Vector3 {
+    Vector3::new(1.0, 1.0, 1.0)
+}
+
+fn object_to_world(translate: Vector3, rotate: Vector3, scale: Vector3) -> Matrix {
+    Matrix::translation(translate)
+        * Matrix::rotation_z(rotate.z)
+        * Matrix::rotation_y(rotate.y)
+        * Matrix::rotation_x(rotate.x)
+        * Matrix::scaling(scale)
+}
+
+pub fn intersect_in_object_space<T: Intersectable + ?Sized>(shape: &T, to_object: &Matrix, ray: &Ray) -> Option<Hit> {
+    let direction = to_object.transform_vector(ray.direction);
+    let stretch = direction.length(); // Object space distances are this many times the world ones
+    if stretch < 1e-24 {
+        return None;
+    }
+    let local_ray = Ray::new(to_object.transform_point(ray.origin), direction * (1.0 / stretch))
+        .with_range(ray.t_min * stretch, ray.t_max * stretch);
+    let local_hit = shape.intersect(&local_ray)?;
+    let distance = local_hit.distance / stretch;
+    let to_world_normal = |normal: Vector3| to_object.transpose().transform_vector(normal).normalize();
+    let to_world_tangent = |tangent: Vector3| world_tangent(to_object, local_hit.geometric_normal, tangent);
+    Some(Hit {
+        distance,
+        point: ray.origin + ray.direction * distance,
+        normal: to_world_normal(local_hit.normal),
+        geometric_normal: to_world_normal(local_hit.geometric_normal),
+        tangents: local_hit.tangents.map(|(tangent, bitangent)| (to_world_tangent(tangent), to_world_tangent(bitangent))),
+        // Distances along the normal scale by the inverse of how much the transpose stretches it
+        shadow_offset: local_hit.shadow_offset / to_object.transpose().transform_vector(local_hit.geometric_normal).length(),
+        ..local_hit
+    })
+}
+
+// Tangents go to the world through the inverse of to_object. Its transpose maps the normal and the normal crossed with the tangent,
+// which are both orthogonal to the tangent, so the image of the tangent is orthogonal to both of theirs
+fn world_tangent(to_object: &Matrix, normal: Vector3, tangent: Vector3) -> Vector3 {
+    let transposed = to_object.transpose();
+    let (x, y, z) = (to_object.transform_vector(Vector3::new(1.0, 0.0, 0.0)), to_object.transform_vector(Vector3::new(0.0, 1.0, 0.0)), to_object.transform_vector(Vector3::new(0.0, 0.0, 1.0)));
+    let determinant = x.dot(&y.cross(&z));
+    let normal = normal.normalize();
+    transposed.transform_vector(normal).cross(&transposed.transform_vector(normal.cross(&tangent))) * (-1.0 / determinant)
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TransformedDescription {
+    #[serde(default = "Vector3::zero")]
+    pub translate: Vector3,
+    #[serde(default = "Vector3::zero")]
+    pub rotate: Vector3,
+    #[serde(default = "unit_scale")]
+    pub scale: Vector3,
+    pub shape: Box<Shape>
+}
+
+// The matrices are built once from the description, node transforms are then composed into them
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(from = "TransformedDescription", into = "TransformedDescription")]
+pub struct Transformed {
+    pub description: TransformedDescription,
+    pub to_world: Matrix,
+    pub to_object: Option<Matrix>
+}
+
+impl From<TransformedDescription> for Transformed {
+    fn from(description: TransformedDescription) -> Transformed {
+        let to_world = object_to_world(description.translate, description.rotate, description.scale);
+        Transformed { description, to_world, to_object: to_world.inverse() }
+    }
+}
+
+impl From<Transformed> for TransformedDescription {
+    fn from(transformed: Transformed) -> TransformedDescription {
+        transformed.description
+    }
+}
+
+impl Transformed {
+    pub fn new(translate: Vector3, rotate: Vector3, scale: Vector3, shape: Shape) -> Transformed {
+        Transformed::from(TransformedDescription { translate, rotate, scale, shape: Box::new(shape) })
+    }
+
+    pub fn transformed(&self, matrix: &Matrix) -> Transformed {
+        let to_world = *matrix * self.to_world;
+        Transformed { description: self.description.clone(), to_world, to_object: to_world.inverse() }
+    }
+}
+
+impl Intersectable for Transformed {
+    fn intersect(&self, ray: &Ray) -> Option<Hit> {
+        intersect_in_object_space(self.description.shape.as_ref(), self.to_object.as_ref()?, ray)
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        Some(self.description.shape.bounding_box()?.transformed(&self.to_world))
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct InstanceDescription {
+    #[serde(rename = "ref")]
+    pub reference: String,
+    #[serde(default = "Vector3::zero")]
+    pub translate: Vector3,
+    #[serde(default = "Vector3::zero")]
+    pub rotate: Vector3,
+    #[serde(default = "unit_scale")]
+    pub scale: Vector3
+}
+
+// Places a geometry of the scene geometries, shared by every instance once the scene is prepared
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(from = "InstanceDescription", into = "InstanceDescription")]
+pub struct Instance {
+    pub description: InstanceDescription,
+    pub geometry: Option<Arc<Shape>>,
+    pub to_world: Matrix,
+    pub to_object: Option<Matrix>
+}
+
+impl From<InstanceDescription> for Instance {
+    fn from(description: InstanceDescription) -> Instance {
+        let to_world = object_to_world(description.translate, description.rotate, description.scale);
+        Instance { description, geometry: None, to_world, to_object: to_world.inverse() }
+    }
+}
+
+impl From<Instance> for InstanceDescription {
+    fn from(instance: Instance) -> InstanceDescription {
+        instance.description
+    }
+}
+
+impl Instance {
+    pub fn new(reference: String, translate: Vector3, rotate: Vector3, scale: Vector3) -> Instance {
+        Instance::from(InstanceDescription { reference, translate, rotate, scale })
+    }
+
+    pub fn transformed(&self, matrix: &Matrix) -> Instance {
+        let to_world = *matrix * self.to_world;
+        Instance { description: self.description.clone(), geometry: self.geometry.clone(), to_world, to_object: to_world.inverse() }
+    }
+}
+
+impl Intersectable for Instance {
+    fn intersect(&self, ray: &Ray) -> Option<Hit> {
+        intersect_in_object_space(self.geometry.as_deref()?, self.to_object.as_ref()?, ray)
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        Some(self.geometry.as_ref()?.bounding_box()?.transformed(&self.to_world))
+    }
+}
+
+// Shapes written by library users, shared between the clones of the scene. They cannot be read from or written to a scene file
+#[derive(Clone)]
+pub struct CustomShape(pub Arc<dyn Intersectable + Send + Sync>);
+
+impl CustomShape {
+    pub fn new<T: Intersectable + Send + Sync + 'static>(shape: T) -> CustomShape {
+        CustomShape(Arc::new(shape))
+    }
+
+    // The shape only knows its world placement, node transforms wrap it instead
+    pub fn transformed(&self, matrix: &Matrix) -> Transformed {
+        Transformed {
+            description: TransformedDescription { translate: Vector3::zero(), rotate: Vector3::zero(), scale: unit_scale(), shape: Box::new(Shape::CUSTOM(self.clone())) },
+            to_world: *matrix,
+            to_object: matrix.inverse()
+        }
+    }
+}
+
+impl fmt::Debug for CustomShape {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "CustomShape")
+    }
+}
+
+impl Intersectable for CustomShape {
+    fn intersect(&self, ray: &Ray) -> Option<Hit> {
+        self.0.intersect(ray)
+    }
+
+    fn intersect_all(&self, ray: &Ray) -> Vec<Hit> {
+        self.0.intersect_all(ray)
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        self.0.bounding_box()
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum CsgOperation {
+    UNION,
+    INTERSECTION,
+    DIFFERENCE
+}
+
+impl CsgOperation {
+    fn contains(&self, in_left: bool, in_right: bool) -> bool {
+        match self {
+            CsgOperation::UNION => in_left || in_right,
+            CsgOperation::INTERSECTION => in_left && in_right,
+            CsgOperation::DIFFERENCE => in_left && !in_right
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Csg {
+    pub operation: CsgOperation,
+    pub left: Box<Shape>,
+    pub right: Box<Shape>
+}
+
+impl Csg {
+    pub fn new(operation: CsgOperation, left: Shape, right: Shape) -> Csg {
+        Csg { operation, left: Box::new(left), right: Box::new(right) }
+    }
+
+    pub fn transformed(&self, matrix: &Matrix) -> Csg {
+        Csg { operation: self.operation, left: Box::new(self.left.transformed(matrix)), right: Box::new(self.right.transformed(matrix)) }
+    }
+}
+
+impl Intersectable for Csg {
+    fn intersect(&self, ray: &Ray) -> Option<Hit> {
+        self.intersect_all(ray).into_iter().next()
+    }
+
+    // The children are crossed along the whole line so that whether the ray starts inside them is known,
+    // and a crossing is kept when it changes whether the point is inside the combined shape
+    fn intersect_all(&self, ray: &Ray) -> Vec<Hit> {
+        let line = ray.with_range(f64::NEG_INFINITY, f64::INFINITY);
+        let mut crossings: Vec<(Hit, bool)> = self.left.intersect_all(&line).into_iter().map(|hit| (hit, true))
+            .chain(self.right.intersect_all(&line).into_iter().map(|hit| (hit, false)))
+            .collect();
+        crossings.sort_by(|(a, _), (b, _)| a.distance.partial_cmp(&b.distance).unwrap_or(std::cmp::Ordering::Equal));
+
+        let mut in_left = false;
+        let mut in_right = false;
+        let mut hits = Vec::new();
+        for (mut hit, from_left) in crossings {
+            let was_inside = self.operation.contains(in_left, in_right);
+            if from_left {
+                in_left = !in_left;
+            } else {
+                in_right = !in_right;
+            }
+            if was_inside == self.operation.contains(in_left, in_right) || !ray.in_range(hit.distance) {
+                continue;
+            }
+            if !from_left && self.operation == CsgOperation::DIFFERENCE {
+                hit.normal = -hit.normal; // The surface carved by the right shape faces into it
+                hit.geometric_normal = -hit.geometric_normal;
+            }
+            hits.push(hit);
+        }
+        hits
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        let left = self.left.bounding_box();
+        let right = self.right.bounding_box();
+        match self.operation {
+            CsgOperation::UNION => Some(left?.union(&right?)),
+            CsgOperation::INTERSECTION => match (left, right) {
+                (Some(left), Some(right)) => left.intersection(&right),
+                (left, right) => left.or(right)
+            },
+            CsgOperation::DIFFERENCE => left
+        }
+    }
+}
+
+// Groups share the element material, the closest child hit wins and each next child only looks in front of it
+fn intersect_group(shapes: &[Shape], ray: &Ray) -> Option<Hit> {
+    let mut closest: Option<Hit> = None;
+    for shape in shapes {
+        let remaining = ray.with_range(ray.t_min, closest.as_ref().map_or(ray.t_max, |hit| hit.distance));
+        if let Some(hit) = shape.intersect(&remaining) {
+            if closest.as_ref().is_none_or(|closest| hit.distance < closest.distance) {
+                closest = Some(hit);
+            }
+        }
+    }
+    closest
+}
+
+fn intersect_all_group(shapes: &[Shape], ray: &Ray) -> Vec<Hit> {
+    let mut hits: Vec<Hit> = shapes.iter().flat_map(|shape| shape.intersect_all(ray)).collect();
+    hits.sort_by(|a, b| a.distance.partial_cmp(&b.distance).unwrap_or(std::cmp::Ordering::Equal));
+    hits
+}
+
+// A single unbounded child leaves the whole group unbounded
+fn group_bounding_box(shapes: &[Shape]) -> Option<Aabb> {
+    let mut bounds: Option<Aabb> = None;
+    for shape in shapes {
+        let aabb = shape.bounding_box()?;
+        bounds = Some(bounds.map_or(aabb, |bounds| bounds.union(&aabb)));
+    }
+    bounds
+}
+
+#[allow(non_camel_case_types)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum Shape {
     SPHERE(Sphere),
-    PLANE(Plane)
+    PLANE(Plane),
+    TRIANGLE(Triangle),
+    CYLINDER(Cylinder),
+    INFINITE_CYLINDER(InfiniteCylinder),
+    CONE(Cone),
+    TORUS(Torus),
+    DISK(Disk),
+    QUAD(Quad),
+    ORIENTED_BOX(OrientedBox),
+    POLYGON(Polygon),
+    CONVEX_POLYHEDRON(ConvexPolyhedron),
+    MESH(Mesh),
+    HEIGHTFIELD(Heightfield),
+    ELLIPSOID(Ellipsoid),
+    CAPSULE(Capsule),
+    SDF(Sdf),
+    METABALLS(Metaballs),
+    CSG(Csg),
+    TRANSFORM(Transformed),
+    INSTANCE(Instance),
+    GROUP(Vec<Shape>),
+    #[serde(skip)]
+    CUSTOM(CustomShape)
+}
+
+impl Shape {
+    pub fn transformed(&self, matrix: &Matrix) -> Shape {
+        match self {
+            Shape::SPHERE(s) => Shape::SPHERE(s.transformed(matrix)),
+            Shape::PLANE(p) => Shape::PLANE(p.transformed(matrix)),
+            Shape::TRIANGLE(t) => Shape::TRIANGLE(t.transformed(matrix)),
+            Shape::CYLINDER(c) => Shape::CYLINDER(c.transformed(matrix)),
+            Shape::INFINITE_CYLINDER(c) => Shape::INFINITE_CYLINDER(c.transformed(matrix)),
+            Shape::CONE(c) => Shape::CONE(c.transformed(matrix)),
+            Shape::TORUS(t) => Shape::TORUS(t.transformed(matrix)),
+            Shape::DISK(d) => Shape::DISK(d.transformed(matrix)),
+            Shape::QUAD(q) => Shape::QUAD(q.transformed(matrix)),
+            Shape::ORIENTED_BOX(b) => Shape::ORIENTED_BOX(b.transformed(matrix)),
+            Shape::POLYGON(p) => Shape::POLYGON(p.transformed(matrix)),
+            Shape::CONVEX_POLYHEDRON(p) => Shape::CONVEX_POLYHEDRON(p.transformed(matrix)),
+            Shape::MESH(m) => Shape::MESH(m.transformed(matrix)),
+            Shape::HEIGHTFIELD(h) => Shape::HEIGHTFIELD(h.transformed(matrix)),
+            Shape::ELLIPSOID(e) => Shape::ELLIPSOID(e.transformed(matrix)),
+            Shape::CAPSULE(c) => Shape::CAPSULE(c.transformed(matrix)),
+            Shape::SDF(s) => Shape::SDF(s.transformed(matrix)),
+            Shape::METABALLS(m) => Shape::METABALLS(m.transformed(matrix)),
+            Shape::CSG(c) => Shape::CSG(c.transformed(matrix)),
+            Shape::TRANSFORM(t) => Shape::TRANSFORM(t.transformed(matrix)),
+            Shape::INSTANCE(i) => Shape::INSTANCE(i.transformed(matrix)),
+            Shape::GROUP(g) => Shape::GROUP(g.iter().map(|shape| shape.transformed(matrix)).collect()),
+            Shape::CUSTOM(c) => Shape::TRANSFORM(c.transformed(matrix))
+        }
+    }
+
+    pub fn meshes(&self) -> Vec<&Mesh> {
+        match self {
+            Shape::MESH(m) => vec![m],
+            Shape::CSG(c) => c.left.meshes().into_iter().chain(c.right.meshes()).collect(),
+            Shape::TRANSFORM(t) => t.description.shape.meshes(),
+            Shape::GROUP(g) => g.iter().flat_map(|shape| shape.meshes()).collect(),
+            _ => Vec::new()
+        }
+    }
+
+    pub fn heightfields(&self) -> Vec<&Heightfield> {
+        match self {
+            Shape::HEIGHTFIELD(h) => vec![h],
+            Shape::CSG(c) => c.left.heightfields().into_iter().chain(c.right.heightfields()).collect(),
+            Shape::TRANSFORM(t) => t.description.shape.heightfields(),
+            Shape::GROUP(g) => g.iter().flat_map(|shape| shape.heightfields()).collect(),
+            _ => Vec::new()
+        }
+    }
+
+    // Files read when the scene is prepared
+    pub fn assets_mut(&mut self) -> Vec<&mut dyn Asset> {
+        match self {
+            Shape::MESH(m) => vec![m],
+            Shape::HEIGHTFIELD(h) => vec![h],
+            Shape::CSG(c) => c.left.assets_mut().into_iter().chain(c.right.assets_mut()).collect(),
+            Shape::TRANSFORM(t) => t.description.shape.assets_mut(),
+            Shape::GROUP(g) => g.iter_mut().flat_map(|shape| shape.assets_mut()).collect(),
+            _ => Vec::new()
+        }
+    }
+
+    pub fn instances(&self) -> Vec<&Instance> {
+        match self {
+            Shape::INSTANCE(i) => vec![i],
+            Shape::CSG(c) => c.left.instances().into_iter().chain(c.right.instances()).collect(),
+            Shape::TRANSFORM(t) => t.description.shape.instances(),
+            Shape::GROUP(g) => g.iter().flat_map(|shape| shape.instances()).collect(),
+            _ => Vec::new()
+        }
+    }
+
+    pub fn instances_mut(&mut self) -> Vec<&mut Instance> {
+        match self {
+            Shape::INSTANCE(i) => vec![i],
+            Shape::CSG(c) => c.left.instances_mut().into_iter().chain(c.right.instances_mut()).collect(),
+            Shape::TRANSFORM(t) => t.description.shape.instances_mut(),
+            Shape::GROUP(g) => g.iter_mut().flat_map(|shape| shape.instances_mut()).collect(),
+            _ => Vec::new()
+        }
+    }
 }
 
 impl Intersectable for Shape {
     fn intersect(&self, ray: &Ray) -> Option<Hit> {
         match self {
             Shape::SPHERE(s) => s.intersect(ray),
-            Shape::PLANE(p) => p.intersect(ray)
+            Shape::PLANE(p) => p.intersect(ray),
+            Shape::TRIANGLE(t) => t.intersect(ray),
+            Shape::CYLINDER(c) => c.intersect(ray),
+            Shape::INFINITE_CYLINDER(c) => c.intersect(ray),
+            Shape::CONE(c) => c.intersect(ray),
+            Shape::TORUS(t) => t.intersect(ray),
+            Shape::DISK(d) => d.intersect(ray),
+            Shape::QUAD(q) => q.intersect(ray),
+            Shape::ORIENTED_BOX(b) => b.intersect(ray),
+            Shape::POLYGON(p) => p.intersect(ray),
+            Shape::CONVEX_POLYHEDRON(p) => p.intersect(ray),
+            Shape::MESH(m) => m.intersect(ray),
+            Shape::HEIGHTFIELD(h) => h.intersect(ray),
+            Shape::ELLIPSOID(e) => e.intersect(ray),
+            Shape::CAPSULE(c) => c.intersect(ray),
+            Shape::SDF(s) => s.intersect(ray),
+            Shape::METABALLS(m) => m.intersect(ray),
+            Shape::CSG(c) => c.intersect(ray),
+            Shape::TRANSFORM(t) => t.intersect(ray),
+            Shape::INSTANCE(i) => i.intersect(ray),
+            Shape::GROUP(g) => intersect_group(g, ray),
+            Shape::CUSTOM(c) => c.intersect(ray)
+        }
+    }
+
+    fn intersect_all(&self, ray: &Ray) -> Vec<Hit> {
+        match self {
+            Shape::SPHERE(s) => s.intersect_all(ray),
+            Shape::PLANE(p) => p.intersect_all(ray),
+            Shape::TRIANGLE(t) => t.intersect_all(ray),
+            Shape::CYLINDER(c) => c.intersect_all(ray),
+            Shape::INFINITE_CYLINDER(c) => c.intersect_all(ray),
+            Shape::CONE(c) => c.intersect_all(ray),
+            Shape::TORUS(t) => t.intersect_all(ray),
+            Shape::DISK(d) => d.intersect_all(ray),
+            Shape::QUAD(q) => q.intersect_all(ray),
+            Shape::ORIENTED_BOX(b) => b.intersect_all(ray),
+            Shape::POLYGON(p) => p.intersect_all(ray),
+            Shape::CONVEX_POLYHEDRON(p) => p.intersect_all(ray),
+            Shape::MESH(m) => m.intersect_all(ray),
+            Shape::HEIGHTFIELD(h) => h.intersect_all(ray),
+            Shape::ELLIPSOID(e) => e.intersect_all(ray),
+            Shape::CAPSULE(c) => c.intersect_all(ray),
+            Shape::SDF(s) => s.intersect_all(ray),
+            Shape::METABALLS(m) => m.intersect_all(ray),
+            Shape::CSG(c) => c.intersect_all(ray),
+            Shape::TRANSFORM(t) => t.intersect_all(ray),
+            Shape::INSTANCE(i) => i.intersect_all(ray),
+            Shape::GROUP(g) => intersect_all_group(g, ray),
+            Shape::CUSTOM(c) => c.intersect_all(ray)
+        }
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        match self {
+            Shape::SPHERE(s) => s.bounding_box(),
+            Shape::PLANE(p) => p.bounding_box(),
+            Shape::TRIANGLE(t) => t.bounding_box(),
+            Shape::CYLINDER(c) => c.bounding_box(),
+            Shape::INFINITE_CYLINDER(c) => c.bounding_box(),
+            Shape::CONE(c) => c.bounding_box(),
+            Shape::TORUS(t) => t.bounding_box(),
+            Shape::DISK(d) => d.bounding_box(),
+            Shape::QUAD(q) => q.bounding_box(),
+            Shape::ORIENTED_BOX(b) => b.bounding_box(),
+            Shape::POLYGON(p) => p.bounding_box(),
+            Shape::CONVEX_POLYHEDRON(p) => p.bounding_box(),
+            Shape::MESH(m) => m.bounding_box(),
+            Shape::HEIGHTFIELD(h) => h.bounding_box(),
+            Shape::ELLIPSOID(e) => e.bounding_box(),
+            Shape::CAPSULE(c) => c.bounding_box(),
+            Shape::SDF(s) => s.bounding_box(),
+            Shape::METABALLS(m) => m.bounding_box(),
+            Shape::CSG(c) => c.bounding_box(),
+            Shape::TRANSFORM(t) => t.bounding_box(),
+            Shape::INSTANCE(i) => i.bounding_box(),
+            Shape::GROUP(g) => group_bounding_box(g),
+            Shape::CUSTOM(c) => c.bounding_box()
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Every outward normal met from inside a closed shape is a back face, turned toward the ray
+    fn assert_back_face(shape: &dyn Intersectable, origin: Point, direction: Vector3) {
+        let ray = Ray::new(origin, direction);
+        let hit = shape.intersect(&ray).unwrap().facing(ray.direction);
+        assert!(!hit.front_face, "front face from inside along {:?}", direction);
+        assert!(hit.normal.dot(&ray.direction) < 0.0);
+        assert!(hit.geometric_normal.dot(&ray.direction) < 0.0);
+    }
+
+    fn directions() -> Vec<Vector3> {
+        vec![Vector3::new(1.0, 0.0, 0.0), Vector3::new(0.0, -1.0, 0.0), Vector3::new(0.0, 0.0, 1.0), Vector3::new(-1.0, 2.0, -3.0).normalize()]
+    }
+
+    #[test]
+    fn rays_from_inside_a_sphere_see_back_faces() {
+        let sphere = Sphere::new(Vector3::new(1.0, 2.0, 3.0), 2.0);
+        for direction in directions() {
+            assert_back_face(&sphere, Vector3::new(1.5, 2.0, 3.0), direction);
+        }
+    }
+
+    #[test]
+    fn rays_from_outside_a_sphere_see_front_faces() {
+        let sphere = Sphere::new(Vector3::zero(), 1.0);
+        let ray = Ray::new(Vector3::new(0.0, 0.0, 5.0), Vector3::new(0.0, 0.0, -1.0));
+        let hit = sphere.intersect(&ray).unwrap().facing(ray.direction);
+        assert!(hit.front_face);
+        assert!(hit.normal.z > 0.99);
+    }
+
+    #[test]
+    fn rays_from_inside_closed_shapes_see_back_faces() {
+        let unit_axes = [Vector3::new(1.0, 0.0, 0.0), Vector3::new(0.0, 1.0, 0.0), Vector3::new(0.0, 0.0, 1.0)];
+        let shapes = vec![
+            Shape::ORIENTED_BOX(OrientedBox { center: Vector3::zero(), axes: unit_axes, half_size: Vector3::new(1.0, 2.0, 3.0) }),
+            Shape::ELLIPSOID(Ellipsoid::new(Vector3::zero(), Vector3::new(1.0, 2.0, 3.0))),
+            Shape::CAPSULE(Capsule::new(Vector3::new(0.0, -1.0, 0.0), Vector3::new(0.0, 1.0, 0.0), 1.0)),
+            Shape::CYLINDER(Cylinder::new(Vector3::new(0.0, -1.0, 0.0), Vector3::new(0.0, 1.0, 0.0), 1.0, 2.0))
+        ];
+        for shape in &shapes {
+            for direction in directions() {
+                assert_back_face(shape, Vector3::new(0.1, 0.2, -0.1), direction);
+            }
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn read_rays_compute_their_inverse_direction_and_signs() {
+        let ray: Ray = serde_json::from_str(r#"{"origin": {"x": 1.0, "y": 2.0, "z": 3.0}, "direction": {"x": 2.0, "y": -0.5, "z": 0.0}, "t_min": 0.1, "t_max": 5.0}"#).unwrap();
+        assert_eq!((ray.inverse_direction.x, ray.inverse_direction.y, ray.inverse_direction.z), (0.5, -2.0, f64::INFINITY));
+        assert_eq!(ray.sign, [0, 1, 0]);
+        assert_eq!((ray.t_min, ray.t_max, ray.time), (0.1, 5.0, 0.0));
+        // Only what Ray::new is given is written
+        let written = serde_json::to_value(ray.with_time(0.5)).unwrap();
+        assert!(written.get("sign").is_none() && written.get("inverse_direction").is_none());
+        let read: Ray = serde_json::from_value(written).unwrap();
+        assert_eq!((read.sign, read.time, read.inverse_direction.y), ([0, 1, 0], 0.5, -2.0));
+    }
+
+    // Distance from the point to the tube, along the normal
+    fn off_torus(torus: &Torus, point: Point) -> f64 {
+        let along = point.dot(&torus.axis);
+        let across = (point - torus.axis * along).length();
+        ((across - torus.major_radius).powi(2) + along * along).sqrt() - torus.minor_radius
+    }
+
+    fn assert_on_torus(torus: &Torus, ray: &Ray) {
+        let hit = torus.intersect(ray).unwrap_or_else(|| panic!("{:?} misses", ray));
+        assert!(hit.distance.is_finite() && hit.normal.x.is_finite() && hit.normal.y.is_finite() && hit.normal.z.is_finite(), "{:?} gave {:?}", ray, hit);
+        assert!((hit.normal.length() - 1.0).abs() < 1e-9);
+        assert!(off_torus(torus, hit.point).abs() < 1e-7, "{:?} hits {:?}", ray, hit.point);
+    }
+
+    #[test]
+    fn rays_grazing_the_torus_hit_without_nan() {
+        let torus = Torus::new(Vector3::zero(), Vector3::new(0.0, 1.0, 0.0), 2.0, 0.5);
+        for below in [1e-2, 1e-4, 1e-6, 1e-8] {
+            // Over the top of the tube, and along the outer rim
+            assert_on_torus(&torus, &Ray::new(Vector3::new(-10.0, 0.5 - below, 0.0), Vector3::new(1.0, 0.0, 0.0)));
+            assert_on_torus(&torus, &Ray::new(Vector3::new(2.5 - below, 0.0, -10.0), Vector3::new(0.0, 0.0, 1.0)));
+        }
+        assert!(torus.intersect(&Ray::new(Vector3::new(-10.0, 0.5 + 1e-6, 0.0), Vector3::new(1.0, 0.0, 0.0))).is_none());
+        assert!(torus.intersect(&Ray::new(Vector3::new(2.5 + 1e-6, 0.0, -10.0), Vector3::new(0.0, 0.0, 1.0))).is_none());
+    }
+
+    // Every row across the tube hits, up to the ones that graze it
+    #[test]
+    fn rows_across_the_torus_have_no_gaps() {
+        let torus = Torus::new(Vector3::zero(), Vector3::new(0.0, 1.0, 0.0), 2.0, 0.5);
+        for row in 0..=1200 {
+            let height = -0.6 + row as f64 * 0.001;
+            let ray = Ray::new(Vector3::new(-10.0, height, 0.3), Vector3::new(1.0, 0.0, 0.0));
+            if height.abs() < 0.5 - 1e-9 {
+                assert_on_torus(&torus, &ray);
+            } else if height.abs() > 0.5 + 1e-9 {
+                assert!(torus.intersect(&ray).is_none(), "row {} hits", row);
+            }
+        }
+    }
+
+    #[test]
+    fn far_away_rays_hit_the_torus_where_near_ones_do() {
+        let torus = Torus::new(Vector3::new(1.0, -2.0, 3.0), Vector3::new(0.0, 0.0, 1.0), 2.0, 0.5);
+        let direction = Vector3::new(0.1, -0.2, -1.0).normalize();
+        let target = torus.center + Vector3::new(2.0, 0.25, 0.0);
+        let near = torus.intersect(&Ray::new(target - direction * 5.0, direction)).unwrap().point;
+        for far in [1e2, 1e4, 1e6] {
+            let hit = torus.intersect(&Ray::new(target - direction * far, direction)).unwrap_or_else(|| panic!("missed from {}", far));
+            assert!((hit.point - near).length() < 1e-9, "{} away hits {:?} instead of {:?}", far, hit.point, near);
+        }
+    }
+
+    #[test]
+    fn spherical_uvs_stay_in_the_unit_square() {
+        let sphere = Sphere::new(Vector3::new(1.0, 2.0, 3.0), 2.0);
+        for step in 0..=64 {
+            for turn in 0..64 {
+                let (latitude, longitude) = (step as f64 / 64.0 * std::f64::consts::PI, turn as f64 / 64.0 * 2.0 * std::f64::consts::PI);
+                let direction = Vector3::new(latitude.sin() * longitude.cos(), latitude.cos(), -latitude.sin() * longitude.sin());
+                let (u, v) = spherical_uv(direction);
+                assert!((0.0..1.0).contains(&u) && (0.0..=1.0).contains(&v), "{:?} gave {:?}", direction, (u, v));
+                let (hit_u, hit_v) = sphere.intersect(&Ray::new(sphere.origin + direction * 5.0, -direction)).unwrap().uv;
+                assert!((0.0..1.0).contains(&hit_u) && (0.0..=1.0).contains(&hit_v), "{:?} hit {:?}", direction, (hit_u, hit_v));
+            }
+        }
+        // Right before the seam the longitude rounds up to a full turn
+        assert_eq!(spherical_uv(Vector3::new(1.0, 0.0, 1e-17)).0, 0.0);
+    }
+
+    // Across the seam at +x u wraps from just under 1 to just over 0
+    #[test]
+    fn spherical_uvs_wrap_continuously_across_the_seam() {
+        for offset in [1e-3, 1e-6, 1e-9, 1e-12] {
+            let (before, _) = spherical_uv(Vector3::new(1.0, 0.3, offset).normalize());
+            let (after, _) = spherical_uv(Vector3::new(1.0, 0.3, -offset).normalize());
+            assert!(before > 0.5 && after < 0.5, "{} {}", before, after);
+            assert!((1.0 - before) + after < offset, "{} jumps from {} to {}", offset, before, after);
+        }
+        // And turns the same way all around
+        let longitudes: Vec<f64> = (0..16).map(|turn| spherical_uv(Vector3::new((turn as f64 * 0.4).cos(), 0.0, -(turn as f64 * 0.4).sin())).0).collect();
+        assert!(longitudes.windows(2).all(|pair| pair[1] > pair[0]), "{:?}", longitudes);
+    }
+
+    #[test]
+    fn spherical_uvs_are_finite_at_the_poles() {
+        assert_eq!(spherical_uv(Vector3::new(0.0, 1.0, 0.0)), (0.0, 0.0));
+        assert_eq!(spherical_uv(Vector3::new(0.0, -1.0, 0.0)), (0.0, 1.0));
+        // Rays straight down onto the top of the sphere and up into its bottom
+        let sphere = Sphere::new(Vector3::zero(), 1.0);
+        let top = sphere.intersect(&Ray::new(Vector3::new(0.0, 5.0, 0.0), Vector3::new(0.0, -1.0, 0.0))).unwrap();
+        let bottom = sphere.intersect(&Ray::new(Vector3::new(0.0, -5.0, 0.0), Vector3::new(0.0, 1.0, 0.0))).unwrap();
+        for (u, v) in [top.uv, bottom.uv] {
+            assert!(u.is_finite() && v.is_finite() && (0.0..1.0).contains(&u));
+        }
+        assert!(top.uv.1 < 1e-9 && bottom.uv.1 > 1.0 - 1e-9);
+    }
+
+    fn polygon(points: &[(f64, f64, f64)]) -> Result<Polygon, String> {
+        Polygon::try_from(PolygonDescription { vertices: points.iter().map(|&(x, y, z)| Vector3::new(x, y, z)).collect() })
+    }
+
+    #[test]
+    fn valid_polygons_are_accepted() {
+        // A regular pentagon on the floor, wound toward +y, with its first vertex repeated
+        let mut points: Vec<(f64, f64, f64)> = (0..5).map(|corner| {
+            let angle = corner as f64 / 5.0 * 2.0 * std::f64::consts::PI;
+            (angle.cos(), 0.0, -angle.sin())
+        }).collect();
+        points.insert(1, points[0]);
+        let pentagon = polygon(&points).unwrap();
+        assert_eq!(pentagon.vertices.len(), 5);
+        assert!((pentagon.normal - Vector3::new(0.0, 1.0, 0.0)).length() < 1e-12);
+        let hit = pentagon.intersect(&Ray::new(Vector3::new(0.2, 1.0, 0.1), Vector3::new(0.0, -1.0, 0.0))).unwrap();
+        assert!((hit.distance - 1.0).abs() < 1e-12);
+        assert!(pentagon.intersect(&Ray::new(Vector3::new(0.9, 1.0, 0.6), Vector3::new(0.0, -1.0, 0.0))).is_none());
+    }
+
+    #[test]
+    fn non_coplanar_polygons_name_the_vertex_off_the_plane() {
+        let error = polygon(&[(0.0, 0.0, 0.0), (1.0, 0.0, 0.0), (1.0, 1.0, 0.0), (0.0, 1.0, 0.25)]).unwrap_err();
+        assert_eq!(error, "polygon vertex 3 is 0.250000 away from the plane of vertices 0, 1 and 2");
+        // Indices are those of the file, counting the dropped duplicates
+        let error = polygon(&[(0.0, 0.0, 0.0), (0.0, 0.0, 0.0), (1.0, 0.0, 0.0), (1.0, 1.0, 0.0), (0.5, 1.5, 0.0), (0.0, 1.0, -0.5)]).unwrap_err();
+        assert_eq!(error, "polygon vertex 5 is 0.500000 away from the plane of vertices 0, 2 and 3");
+    }
+
+    #[test]
+    fn non_convex_polygons_name_the_vertex_turning_back() {
+        // An arrow head whose notch at vertex 2 points inward
+        let error = polygon(&[(0.0, 0.0, 0.0), (2.0, 0.0, 0.0), (1.0, 0.5, 0.0), (2.0, 2.0, 0.0), (0.0, 2.0, 0.0)]).unwrap_err();
+        assert_eq!(error, "polygon is not convex at vertex 2");
+        // Scene files report it through the shape
+        let error = serde_json::from_str::<Shape>(r#"{"POLYGON": {"vertices": [{"x": 0, "y": 0, "z": 0}, {"x": 2, "y": 0, "z": 0}, {"x": 1, "y": 0.5, "z": 0}, {"x": 2, "y": 2, "z": 0}, {"x": 0, "y": 2, "z": 0}]}}"#).unwrap_err();
+        assert!(error.to_string().contains("not convex at vertex 2"), "{}", error);
+    }
+}
@@ -0,0 +1,89 @@
+use std::error;
+use serde::{Serialize, Deserialize};
+use image::{ImageBuffer, RgbaImage, Rgba, GenericImage};
+use crate::rendering::{self, Scene, Camera, View};
+use crate::output;
+use crate::profile::Stage;
+
+#[allow(non_camel_case_types)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum StereoLayout {
+    #[default]
+    SIDE_BY_SIDE, // Left eye on the left half of a double width image
+    ANAGLYPH // Red from the left eye, green and blue from the right one
+}
+
+fn default_eye_separation() -> f64 {
+    0.065
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct Stereo {
+    #[serde(default = "default_eye_separation")]
+    pub eye_separation: f64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub convergence: Option<f64>, // Distance the eyes turn toward, parallel eyes when missing
+    #[serde(default)]
+    pub layout: StereoLayout
+}
+
+impl Default for Stereo {
+    fn default() -> Self {
+        Stereo { eye_separation: default_eye_separation(), convergence: None, layout: StereoLayout::default() }
+    }
+}
+
+impl Stereo {
+    // Cameras moved by half the separation along the right axis, both turned toward the same point when converging
+    pub fn eyes(&self, camera: &Camera) -> Result<(Camera, Camera), String> {
+        let (right, up, forward) = camera.basis()?;
+        let origin = camera.origin();
+        let eye = |side: f64| {
+            let position = origin + right * (side * self.eye_separation * 0.5);
+            let camera = Camera { stereo: None, ..camera.clone() };
+            match self.convergence {
+                Some(distance) => {
+                    let eye_forward = (origin + forward * distance - position).normalize();
+                    let eye_right = eye_forward.cross(&up).normalize();
+                    camera.with_basis(position, eye_right, eye_right.cross(&eye_forward), eye_forward)
+                },
+                None => camera.with_basis(position, right, up, forward)
+            }
+        };
+        Ok((eye(-1.0), eye(1.0)))
+    }
+}
+
+pub fn side_by_side(left: &RgbaImage, right: &RgbaImage) -> RgbaImage {
+    let mut image: RgbaImage = ImageBuffer::from_pixel(left.width() * 2, left.height(), Rgba([0, 0, 0, 0]));
+    let _ = image.copy_from(left, 0, 0);
+    let _ = image.copy_from(right, left.width(), 0);
+    image
+}
+
+pub fn anaglyph(left: &RgbaImage, right: &RgbaImage) -> RgbaImage {
+    ImageBuffer::from_fn(left.width(), left.height(), |x, y| {
+        let (left, right) = (left.get_pixel(x, y), right.get_pixel(x, y));
+        Rgba([left[0], right[1], right[2], left[3].max(right[3])])
+    })
+}
+
+pub fn render(nb_pass: u8, mut scene: Scene, output_path: String) -> Result<(), Box<dyn error::Error>> {
+    let stereo = scene.camera.stereo.unwrap_or_default();
+    let (left, right) = stereo.eyes(&scene.camera)?;
+    rendering::prepare_caustics(nb_pass, &mut scene);
+    let images: Vec<RgbaImage> = [left, right].iter().map(|eye| {
+        let mut image = rendering::render_view(nb_pass, &scene, &View::camera(eye));
+        rendering::apply_post(&scene, &mut image);
+        image
+    }).collect();
+    let image = match stereo.layout {
+        StereoLayout::SIDE_BY_SIDE => side_by_side(&images[0], &images[1]),
+        StereoLayout::ANAGLYPH => anaglyph(&images[0], &images[1])
+    };
+    let encode_start = scene.profile.start();
+    output::write(&image, &output_path, &scene.output, &scene.metadata())?;
+    scene.profile.stop(Stage::ENCODE, encode_start);
+    scene.profile.report()?;
+    Ok(())
+}
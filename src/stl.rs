@@ -0,0 +1,137 @@
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::error;
+use std::fmt;
+use std::fs;
+use crate::shape::Point;
+use crate::vertors::Vector3;
+
+// Vertices closer than this along every axis are merged, binary files repeat them for every facet
+const WELD_DISTANCE: f64 = 1e-6;
+
+#[derive(Debug, Clone)]
+pub struct StlError {
+    pub file: String,
+    pub line: usize,
+    pub message: String
+}
+
+impl StlError {
+    pub fn new(file: &str, line: usize, message: String) -> StlError {
+        StlError { file: file.to_string(), line, message }
+    }
+}
+
+impl fmt::Display for StlError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.line > 0 {
+            write!(f, "{}:{}: {}", self.file, self.line, self.message)
+        } else {
+            write!(f, "{}: {}", self.file, self.message)
+        }
+    }
+}
+
+impl error::Error for StlError {}
+
+#[derive(Clone, Debug, Default)]
+pub struct Stl {
+    pub vertices: Vec<Point>,
+    pub indices: Vec<[usize; 3]>,
+    pub normals: Vec<Vector3>,
+    welded: HashMap<[i64; 3], usize>
+}
+
+impl Stl {
+    pub fn load(file: &str) -> Result<Stl, StlError> {
+        let content = fs::read(file).map_err(|e| StlError::new(file, 0, e.to_string()))?;
+        Stl::parse(&content, file)
+    }
+
+    // Binary files may also start with "solid", so their size is checked first
+    pub fn parse(content: &[u8], file: &str) -> Result<Stl, StlError> {
+        let stl = if content.len() >= 84 && content.len() == 84 + 50 * u32::from_le_bytes(content[80..84].try_into().unwrap()) as usize {
+            Stl::parse_binary(content)
+        } else if content.starts_with(b"solid") {
+            let text = std::str::from_utf8(content).map_err(|_| StlError::new(file, 0, "ASCII file is not valid UTF-8".to_string()))?;
+            Stl::parse_ascii(text, file)?
+        } else {
+            return Err(StlError::new(file, 0, "neither an ASCII nor a binary STL file".to_string()));
+        };
+        if stl.indices.is_empty() {
+            return Err(StlError::new(file, 0, "no facets".to_string()));
+        }
+        Ok(stl)
+    }
+
+    fn parse_binary(content: &[u8]) -> Stl {
+        let mut stl = Stl::default();
+        for facet in content[84..].chunks_exact(50) {
+            let mut values = [0.0; 12];
+            for (value, bytes) in values.iter_mut().zip(facet.chunks_exact(4)) {
+                *value = f32::from_le_bytes(bytes.try_into().unwrap()) as f64;
+            }
+            let vector = |index: usize| Vector3::new(values[index], values[index + 1], values[index + 2]);
+            stl.add_facet(vector(0), [vector(3), vector(6), vector(9)]);
+        }
+        stl
+    }
+
+    fn parse_ascii(content: &str, file: &str) -> Result<Stl, StlError> {
+        let mut stl = Stl::default();
+        let mut normal = Vector3::zero();
+        let mut corners = Vec::with_capacity(3);
+        for (index, line) in content.lines().enumerate() {
+            let line_number = index + 1;
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            match parts.first() {
+                Some(&"facet") => {
+                    normal = parse_vector(parts.get(2..).unwrap_or(&[]), file, line_number)?;
+                    corners.clear();
+                },
+                Some(&"vertex") => corners.push(parse_vector(&parts[1..], file, line_number)?),
+                Some(&"endfacet") => {
+                    if corners.len() != 3 {
+                        return Err(StlError::new(file, line_number, format!("a facet needs 3 vertices, found {}", corners.len())));
+                    }
+                    stl.add_facet(normal, [corners[0], corners[1], corners[2]]);
+                },
+                _ => {} // solid, outer loop and their end markers only structure the file
+            }
+        }
+        Ok(stl)
+    }
+
+    // Exporters often write zero normals, those are rebuilt from the vertices
+    fn add_facet(&mut self, normal: Vector3, corners: [Point; 3]) {
+        let length = normal.length();
+        let normal = if length.is_finite() && length > 1e-12 {
+            normal * (1.0 / length)
+        } else {
+            (corners[1] - corners[0]).cross(&(corners[2] - corners[0])).normalize()
+        };
+        let indices = [self.weld(corners[0]), self.weld(corners[1]), self.weld(corners[2])];
+        self.indices.push(indices);
+        self.normals.push(normal);
+    }
+
+    fn weld(&mut self, vertex: Point) -> usize {
+        let key = [(vertex.x / WELD_DISTANCE).round() as i64, (vertex.y / WELD_DISTANCE).round() as i64, (vertex.z / WELD_DISTANCE).round() as i64];
+        let vertices = &mut self.vertices;
+        *self.welded.entry(key).or_insert_with(|| {
+            vertices.push(vertex);
+            vertices.len() - 1
+        })
+    }
+}
+
+fn parse_vector(parts: &[&str], file: &str, line: usize) -> Result<Vector3, StlError> {
+    if parts.len() < 3 {
+        return Err(StlError::new(file, line, format!("expected 3 coordinates, found {}", parts.len())));
+    }
+    let mut values = [0.0; 3];
+    for (value, part) in values.iter_mut().zip(parts.iter()) {
+        *value = part.parse().map_err(|_| StlError::new(file, line, format!("invalid number \"{}\"", part)))?;
+    }
+    Ok(Vector3::new(values[0], values[1], values[2]))
+}
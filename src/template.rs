@@ -0,0 +1,223 @@
+use std::error;
+use std::fmt;
+use std::collections::HashMap;
+use serde::{Serialize, Deserialize};
+use serde_json::{Map, Value};
+use crate::rendering::Renderable;
+use crate::transform::Transform;
+
+#[derive(Debug, Clone)]
+pub struct TemplateError {
+    pub path: String,
+    pub message: String
+}
+
+impl TemplateError {
+    pub fn new(path: String, message: String) -> TemplateError {
+        TemplateError { path, message }
+    }
+}
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+impl error::Error for TemplateError {}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Template {
+    #[serde(flatten)]
+    pub renderable: Renderable,
+    #[serde(default)]
+    pub transform: Transform
+}
+
+impl Template {
+    pub fn instantiate(&self) -> Renderable {
+        self.renderable.transformed(&self.transform.to_matrix())
+    }
+}
+
+// Merged into the template as written, the optional fields it leaves out can be set too
+fn merge(target: &mut Value, overrides: &Value) {
+    match (target, overrides) {
+        (Value::Object(target), Value::Object(overrides)) => {
+            for (key, value) in overrides.iter() {
+                match target.get_mut(key) {
+                    Some(field) => merge(field, value),
+                    None => {
+                        target.insert(key.clone(), value.clone());
+                    }
+                }
+            }
+        },
+        (target, overrides) => *target = overrides.clone()
+    }
+}
+
+// Unknown fields are ignored when parsing, an overridden field missing from the parsed template was misspelled
+fn check_overrides(parsed: &Value, overrides: &Value, path: &str) -> Result<(), TemplateError> {
+    if let Value::Object(overrides) = overrides {
+        for (key, value) in overrides.iter() {
+            let field_path = format!("{}.{}", path, key);
+            match parsed.get(key) {
+                Some(field) => check_overrides(field, value, &field_path)?,
+                None if value.is_null() => (),
+                None => return Err(TemplateError::new(field_path, "field does not exist in the template".to_string()))
+            }
+        }
+    }
+    Ok(())
+}
+
+fn parse_templates(templates: &Value) -> Result<HashMap<String, Value>, TemplateError> {
+    let templates = match templates {
+        Value::Object(templates) => templates,
+        _ => return Err(TemplateError::new("templates".to_string(), "expected an object of named templates".to_string()))
+    };
+    let mut parsed = HashMap::new();
+    for (name, definition) in templates.iter() {
+        let path = format!("templates.{}", name);
+        serde_json::from_value::<Template>(definition.clone()).map_err(|e| TemplateError::new(path, e.to_string()))?;
+        parsed.insert(name.clone(), definition.clone());
+    }
+    Ok(parsed)
+}
+
+fn expand_element(element: &Map<String, Value>, templates: &HashMap<String, Value>, path: &str) -> Result<Value, TemplateError> {
+    for key in element.keys() {
+        if key != "template" && key != "override" {
+            return Err(TemplateError::new(format!("{}.{}", path, key), "only \"template\" and \"override\" are allowed on a template instance".to_string()));
+        }
+    }
+    let name = match element.get("template") {
+        Some(Value::String(name)) => name,
+        _ => return Err(TemplateError::new(format!("{}.template", path), "expected a template name".to_string()))
+    };
+    let mut merged = match templates.get(name) {
+        Some(template) => template.clone(),
+        None => return Err(TemplateError::new(format!("{}.template", path), format!("unknown template \"{}\"", name)))
+    };
+    if let Some(overrides) = element.get("override") {
+        merge(&mut merged, overrides);
+    }
+    let template: Template = serde_json::from_value(merged)
+        .map_err(|e| TemplateError::new(format!("{}.override", path), e.to_string()))?;
+    if let Some(overrides) = element.get("override") {
+        let parsed = serde_json::to_value(&template).map_err(|e| TemplateError::new(path.to_string(), e.to_string()))?;
+        check_overrides(&parsed, overrides, &format!("{}.override", path))?;
+    }
+    serde_json::to_value(template.instantiate())
+        .map_err(|e| TemplateError::new(path.to_string(), e.to_string()))
+}
+
+pub fn expand_templates(scene: &mut Value) -> Result<(), TemplateError> {
+    let scene = match scene {
+        Value::Object(scene) => scene,
+        _ => return Ok(())
+    };
+    let templates = match scene.remove("templates") {
+        Some(templates) => parse_templates(&templates)?,
+        None => return Ok(())
+    };
+    if let Some(Value::Array(elements)) = scene.get_mut("elements") {
+        for (index, element) in elements.iter_mut().enumerate() {
+            let expanded = match element {
+                Value::Object(object) if object.contains_key("template") => expand_element(object, &templates, &format!("elements[{}]", index))?,
+                _ => continue
+            };
+            *element = expanded;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn scene(element: Value) -> Value {
+        json!({
+            "templates": {
+                "ball": {
+                    "shape": {"SPHERE": {"origin": {"x": 0.0, "y": 0.0, "z": 0.0}, "radius": 0.5}},
+                    "material": {"base_color": {"r": 200, "g": 20, "b": 20, "a": 255}, "albedo": 0.3, "reflectiveness": 0.2},
+                    "transform": {"translate": {"x": 1.0, "y": 2.0, "z": -5.0}}
+                }
+            },
+            "elements": [element]
+        })
+    }
+
+    fn expanded(element: Value) -> Result<Value, TemplateError> {
+        let mut scene = scene(element);
+        expand_templates(&mut scene)?;
+        assert!(scene.get("templates").is_none());
+        Ok(scene["elements"][0].clone())
+    }
+
+    #[test]
+    fn instances_are_moved_by_the_template_transform() {
+        let element = expanded(json!({"template": "ball"})).unwrap();
+        assert_eq!(element["shape"]["SPHERE"]["origin"], json!({"x": 1.0, "y": 2.0, "z": -5.0}));
+        assert_eq!(element["shape"]["SPHERE"]["radius"], json!(0.5));
+        assert_eq!(element["material"]["reflectiveness"], json!(0.2));
+    }
+
+    #[test]
+    fn overrides_keep_the_fields_they_do_not_name() {
+        let element = expanded(json!({"template": "ball", "override": {
+            "material": {"reflectiveness": 0.9},
+            "transform": {"translate": {"x": -1.0}, "scale": 2.0}
+        }})).unwrap();
+        assert_eq!(element["material"]["reflectiveness"], json!(0.9));
+        assert_eq!(element["material"]["albedo"], json!(0.3));
+        assert_eq!(element["material"]["base_color"], json!({"r": 200, "g": 20, "b": 20, "a": 255}));
+        assert_eq!(element["shape"]["SPHERE"]["origin"], json!({"x": -1.0, "y": 2.0, "z": -5.0}));
+        assert_eq!(element["shape"]["SPHERE"]["radius"], json!(1.0));
+    }
+
+    #[test]
+    fn overrides_set_optional_fields_the_template_leaves_out() {
+        let element = expanded(json!({"template": "ball", "override": {
+            "lights": ["key"],
+            "motion": {"end": {"x": 0.0, "y": 1.0, "z": 0.0}},
+            "casts_shadows": false
+        }})).unwrap();
+        assert_eq!(element["lights"], json!(["key"]));
+        assert_eq!(element["motion"]["end"], json!({"x": 0.0, "y": 1.0, "z": 0.0}));
+        assert_eq!(element["casts_shadows"], json!(false));
+        let element = expanded(json!({"template": "ball", "override": {"lights": null}})).unwrap();
+        assert!(element.get("lights").is_none());
+    }
+
+    #[test]
+    fn misspelled_overrides_are_refused_with_their_path() {
+        let error = expanded(json!({"template": "ball", "override": {"material": {"reflectivness": 0.9}}})).unwrap_err();
+        assert_eq!(error.path, "elements[0].override.material.reflectivness");
+        let error = expanded(json!({"template": "ball", "override": {"shadows": false}})).unwrap_err();
+        assert_eq!(error.path, "elements[0].override.shadows");
+        let error = expanded(json!({"template": "ball", "override": {"material": {"albedo": "high"}}})).unwrap_err();
+        assert_eq!(error.path, "elements[0].override");
+    }
+
+    #[test]
+    fn instances_must_name_a_known_template() {
+        assert_eq!(expanded(json!({"template": "cube"})).unwrap_err().path, "elements[0].template");
+        assert_eq!(expanded(json!({"template": 3})).unwrap_err().path, "elements[0].template");
+        assert_eq!(expanded(json!({"template": "ball", "material": "chrome"})).unwrap_err().path, "elements[0].material");
+        let mut scene = json!({"templates": [], "elements": []});
+        assert_eq!(expand_templates(&mut scene).unwrap_err().path, "templates");
+        let mut scene = json!({"templates": {"broken": {"material": "chrome"}}, "elements": []});
+        assert_eq!(expand_templates(&mut scene).unwrap_err().path, "templates.broken");
+    }
+
+    #[test]
+    fn elements_without_a_template_are_left_alone() {
+        let element = json!({"shape": {"SPHERE": {"origin": {"x": 0.0, "y": 0.0, "z": 0.0}, "radius": 1.0}}, "material": "chrome"});
+        assert_eq!(expanded(element.clone()).unwrap(), element);
+    }
+}
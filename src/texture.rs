@@ -0,0 +1,719 @@
+use std::error;
+use std::sync::Arc;
+use serde::{Serialize, Deserialize};
+use crate::rendering::Color;
+use crate::shape::{Hit, Point};
+use crate::vertors::Vector3;
+use crate::noise;
+use crate::texture_cache::TextureCache;
+
+// Keeps surfaces lying exactly on a cell border, like a plane at y = 0, from flipping between two cells with rounding
+const CELL_BIAS: f64 = 1e-9;
+
+// Coordinates a texture is evaluated from
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum TextureMapping {
+    #[default]
+    WORLD, // The hit point, so the pattern stays put on the surface whatever the camera does
+    UV // The texture coordinates of the shape
+}
+
+fn default_transform_scale() -> [f64; 2] {
+    [1.0, 1.0]
+}
+
+// Placement of a texture over the texture coordinates of the shape: turned by rotation degrees counterclockwise around
+// the middle of the texture, repeated scale times along u and v from there, then shifted by offset
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TextureTransform {
+    #[serde(default = "default_transform_scale")]
+    pub scale: [f64; 2],
+    #[serde(default)]
+    pub offset: [f64; 2],
+    #[serde(default)]
+    pub rotation: f64
+}
+
+impl TextureTransform {
+    /// Texture coordinates the texture is read at for the texture coordinates `(u, v)` of the shape, before the texture
+    /// wraps them.
+    ///
+    /// ```
+    /// use rust_raytracer::texture::TextureTransform;
+    ///
+    /// let near = |(u, v): (f64, f64), (expected_u, expected_v): (f64, f64)| (u - expected_u).abs() < 1e-12 && (v - expected_v).abs() < 1e-12;
+    /// // The middle of the texture stays put whatever the rotation and the scale
+    /// let turned = TextureTransform { scale: [10.0, 10.0], offset: [0.0, 0.0], rotation: 45.0 };
+    /// assert!(near(turned.apply(0.5, 0.5), (0.5, 0.5)));
+    /// // Tiling repeats the texture around it, the offset shifts it
+    /// let tiled = TextureTransform { scale: [4.0, 2.0], offset: [0.25, 0.0], rotation: 0.0 };
+    /// assert!(near(tiled.apply(0.75, 0.75), (1.75, 1.0)));
+    /// // A quarter turn brings what was right of the middle above it, v going down the image
+    /// let quarter = TextureTransform { scale: [1.0, 1.0], offset: [0.0, 0.0], rotation: 90.0 };
+    /// assert!(near(quarter.apply(0.5, 0.25), (0.75, 0.5)));
+    /// ```
+    pub fn apply(&self, u: f64, v: f64) -> (f64, f64) {
+        let (sin, cos) = self.rotation.to_radians().sin_cos();
+        let (u, v) = (u - 0.5, v - 0.5);
+        // Reading the texture turned clockwise shows it turned counterclockwise, with v going down
+        let (u, v) = (u * cos - v * sin, u * sin + v * cos);
+        (u * self.scale[0] + 0.5 + self.offset[0], v * self.scale[1] + 0.5 + self.offset[1])
+    }
+
+    // Tilt of a normal or bump map given right and up the texture, turned to right and up the texture coordinates of the
+    // shape. It keeps its length so the relief is as strong whatever the tiling
+    fn tilt(&self, right: f64, up: f64) -> (f64, f64) {
+        let (sin, cos) = self.rotation.to_radians().sin_cos();
+        // Slopes follow the transposed derivatives of the texture coordinates, v going down
+        let (u, v) = (right * self.scale[0], -up * self.scale[1]);
+        let (u, v) = (u * cos + v * sin, -u * sin + v * cos);
+        let length = (u * u + v * v).sqrt();
+        if length == 0.0 {
+            return (right, up);
+        }
+        let factor = (right * right + up * up).sqrt() / length;
+        (u * factor, -v * factor)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.scale.iter().chain(self.offset.iter()).any(|value| !value.is_finite()) || !self.rotation.is_finite() || self.scale.contains(&0.0) {
+            return Err(format!("texture transform scale must not be zero, and scale, offset and rotation must be finite, not {:?}, {:?} and {}", self.scale, self.offset, self.rotation));
+        }
+        Ok(())
+    }
+}
+
+// Alternating cells of two colors, cubes of side scale in world space or squares of side scale in uv space
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct Checker {
+    pub color_a: Color,
+    pub color_b: Color,
+    pub scale: f64,
+    #[serde(default)]
+    pub mapping: TextureMapping,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transform: Option<TextureTransform> // Over the texture coordinates, with the UV mapping only
+}
+
+impl Checker {
+    pub fn color_at(&self, hit: &Hit) -> Color {
+        let cell = |coordinate: f64| (coordinate / self.scale + CELL_BIAS).floor() as i64;
+        let parity = match self.mapping {
+            TextureMapping::WORLD => cell(hit.point.x) + cell(hit.point.y) + cell(hit.point.z),
+            TextureMapping::UV => {
+                let (u, v) = transformed_uv(&self.transform, hit);
+                cell(u) + cell(v)
+            }
+        };
+        if parity.rem_euclid(2) == 0 { self.color_a } else { self.color_b }
+    }
+}
+
+fn transformed_uv(transform: &Option<TextureTransform>, hit: &Hit) -> (f64, f64) {
+    transformed(transform, hit.uv)
+}
+
+fn transformed(transform: &Option<TextureTransform>, (u, v): (f64, f64)) -> (f64, f64) {
+    match transform {
+        Some(transform) => transform.apply(u, v),
+        None => (u, v)
+    }
+}
+
+// Where an image texture is read from
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum ImageMapping {
+    #[default]
+    UV, // The texture coordinates of the shape
+    TRIPLANAR // The hit point projected on the world planes facing x, y and z, one image per scene unit, for shapes without texture coordinates
+}
+
+fn default_sharpness() -> f64 {
+    4.0
+}
+
+// Texture coordinates of a hit on one of the triplanar planes, with the directions they grow along
+#[derive(Copy, Clone, Debug)]
+struct Planar {
+    weight: f64,
+    uv: (f64, f64),
+    tangents: (Vector3, Vector3)
+}
+
+// Planes facing x, y and z weighted by how much the normal faces them, raised to the sharpness so that the blend only
+// spreads near the edges. Seen from outside every plane shows the image upright and not mirrored
+fn triplanar(hit: &Hit, sharpness: f64) -> [Planar; 3] {
+    let (point, normal) = (hit.point, hit.normal);
+    let weights = [normal.x, normal.y, normal.z].map(|component| component.abs().powf(sharpness));
+    let total: f64 = weights.iter().sum();
+    let weight = |axis: usize| if total > 0.0 { weights[axis] / total } else { 1.0 / 3.0 };
+    let side = |component: f64| if component < 0.0 { -1.0 } else { 1.0 };
+    [
+        Planar { weight: weight(0), uv: (-side(normal.x) * point.z, -point.y), tangents: (Vector3::new(0.0, 0.0, -side(normal.x)), Vector3::new(0.0, -1.0, 0.0)) },
+        Planar { weight: weight(1), uv: (point.x, side(normal.y) * point.z), tangents: (Vector3::new(1.0, 0.0, 0.0), Vector3::new(0.0, 0.0, side(normal.y))) },
+        Planar { weight: weight(2), uv: (side(normal.z) * point.x, -point.y), tangents: (Vector3::new(side(normal.z), 0.0, 0.0), Vector3::new(0.0, -1.0, 0.0)) }
+    ]
+}
+
+// Weighted normals of the planes, None when one of them has none
+fn blend_normals(normals: impl Iterator<Item = Option<(f64, Vector3)>>) -> Option<Vector3> {
+    let mut sum = Vector3::zero();
+    for normal in normals {
+        let (weight, normal) = normal?;
+        sum = sum + normal * weight;
+    }
+    if sum.length_sq() < 1e-24 {
+        return None;
+    }
+    Some(sum.normalize())
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum TextureFilter {
+    NEAREST, // The pixel under the texture coordinates, crisp pixel art
+    #[default]
+    BILINEAR // Blend of the four pixels around the texture coordinates
+}
+
+// How texture coordinates outside of [0, 1] reach the image
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum TextureWrap {
+    #[default]
+    REPEAT, // Tiles the image
+    CLAMP // Stretches its border pixels
+}
+
+// Pixels of an image texture, row by row from the top of the image
+#[derive(Clone, Debug, Default)]
+pub struct TexturePixels {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<[u8; 4]>
+}
+
+impl TexturePixels {
+    pub fn load(file: &str) -> Result<TexturePixels, Box<dyn error::Error>> {
+        let image = image::open(file)?.to_rgba();
+        let (width, height) = (image.width() as usize, image.height() as usize);
+        Ok(TexturePixels { width, height, pixels: image.pixels().map(|pixel| pixel.0).collect() })
+    }
+
+    fn pixel(&self, column: usize, row: usize) -> [u8; 4] {
+        self.pixels[row * self.width + column]
+    }
+}
+
+// Image laid over the texture coordinates of the shape, u going right and v going down the image
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ImageTexture {
+    pub file: String,
+    #[serde(default)]
+    pub filter: TextureFilter,
+    #[serde(default)]
+    pub wrap: TextureWrap,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transform: Option<TextureTransform>,
+    #[serde(default)]
+    pub mapping: ImageMapping,
+    #[serde(default = "default_sharpness")]
+    pub sharpness: f64, // Of the TRIPLANAR blend, higher keeps each plane sharp closer to the edges
+    #[serde(skip)]
+    pub image: Option<Arc<TexturePixels>>, // Pixels given up front, read instead of the cache
+    #[serde(skip)]
+    pub cache: Option<Arc<TextureCache>> // Loads the file the first time it is sampled, shared by every material using it
+}
+
+impl ImageTexture {
+    // Pixel coordinate along an axis of the given size, wrapped into the image
+    fn wrap(&self, pixel: i64, size: usize) -> usize {
+        match self.wrap {
+            TextureWrap::REPEAT => pixel.rem_euclid(size as i64) as usize,
+            TextureWrap::CLAMP => pixel.clamp(0, size as i64 - 1) as usize
+        }
+    }
+
+    // None until the scene is prepared
+    fn pixels(&self) -> Option<Arc<TexturePixels>> {
+        match (&self.image, &self.cache) {
+            (Some(image), _) => Some(Arc::clone(image)),
+            (None, Some(cache)) => Some(cache.get(&self.file)),
+            (None, None) => None
+        }
+    }
+
+    // Channels of the image at the texture coordinates, from 0 to 255 and not rounded so filtered slopes stay smooth.
+    // None until the image is loaded
+    fn texel(&self, u: f64, v: f64) -> Option<[f64; 4]> {
+        let image = self.pixels()?;
+        self.texel_of(&image, u, v)
+    }
+
+    fn texel_of(&self, image: &TexturePixels, u: f64, v: f64) -> Option<[f64; 4]> {
+        if image.width == 0 || image.height == 0 {
+            return None;
+        }
+        let (u, v) = match self.wrap {
+            TextureWrap::REPEAT => (u.rem_euclid(1.0), v.rem_euclid(1.0)),
+            TextureWrap::CLAMP => (u.clamp(0.0, 1.0), v.clamp(0.0, 1.0))
+        };
+        let (x, y) = (u * image.width as f64, v * image.height as f64);
+        match self.filter {
+            TextureFilter::NEAREST => {
+                let pixel = image.pixel(self.wrap(x.floor() as i64, image.width), self.wrap(y.floor() as i64, image.height));
+                Some(pixel.map(|channel| channel as f64))
+            },
+            TextureFilter::BILINEAR => {
+                // Between the centers of the four nearest pixels
+                let (x, y) = (x - 0.5, y - 0.5);
+                let (left, top) = (x.floor(), y.floor());
+                let (tx, ty) = (x - left, y - top);
+                let (left, top) = (left as i64, top as i64);
+                let columns = [self.wrap(left, image.width), self.wrap(left + 1, image.width)];
+                let rows = [self.wrap(top, image.height), self.wrap(top + 1, image.height)];
+                let mut channels = [0.0; 4];
+                for (channel, value) in channels.iter_mut().enumerate() {
+                    let texel = |column: usize, row: usize| image.pixel(columns[column], rows[row])[channel] as f64;
+                    let upper = texel(0, 0) * (1.0 - tx) + texel(1, 0) * tx;
+                    let lower = texel(0, 1) * (1.0 - tx) + texel(1, 1) * tx;
+                    *value = upper * (1.0 - ty) + lower * ty;
+                }
+                Some(channels)
+            }
+        }
+    }
+
+    // Where the texture is read for the hit, before wrapping
+    pub fn uv_at(&self, hit: &Hit) -> (f64, f64) {
+        transformed_uv(&self.transform, hit)
+    }
+
+    // Tilt of a normal or bump map right and up the texture coordinates of the shape
+    fn tilt(&self, right: f64, up: f64) -> (f64, f64) {
+        match &self.transform {
+            Some(transform) => transform.tilt(right, up),
+            None => (right, up)
+        }
+    }
+
+    // Planes of a TRIPLANAR texture that the hit faces
+    fn planes(&self, hit: &Hit) -> impl Iterator<Item = Planar> {
+        IntoIterator::into_iter(triplanar(hit, self.sharpness)).filter(|planar| planar.weight > 0.0)
+    }
+
+    pub fn color_at(&self, hit: &Hit) -> Color {
+        let channels = match self.mapping {
+            ImageMapping::UV => {
+                let (u, v) = self.uv_at(hit);
+                self.texel(u, v)
+            },
+            ImageMapping::TRIPLANAR => self.pixels().and_then(|image| {
+                let mut channels = [0.0; 4];
+                for planar in self.planes(hit) {
+                    let (u, v) = transformed(&self.transform, planar.uv);
+                    let texel = self.texel_of(&image, u, v)?;
+                    for (channel, value) in channels.iter_mut().zip(texel.iter()) {
+                        *channel += value * planar.weight;
+                    }
+                }
+                Some(channels)
+            })
+        };
+        match channels {
+            Some(channels) => {
+                let [r, g, b, a] = channels.map(|channel| channel.round().clamp(0.0, 255.0) as u8);
+                Color::new(r, g, b, a)
+            },
+            None => Color::black()
+        }
+    }
+
+    // Gray level of the image at the texture coordinates, from 0 for black to 1 for white
+    pub fn height_at(&self, u: f64, v: f64) -> Option<f64> {
+        let image = self.pixels()?;
+        self.height_of(&image, u, v)
+    }
+
+    fn height_of(&self, image: &TexturePixels, u: f64, v: f64) -> Option<f64> {
+        let [r, g, b, _] = self.texel_of(image, u, v)?;
+        Some((0.2126 * r + 0.7152 * g + 0.0722 * b) / 255.0)
+    }
+
+    // Slopes of the gray levels along u and v, per unit of texture coordinates, from the heights one pixel around
+    pub fn slopes_at(&self, u: f64, v: f64) -> Option<(f64, f64)> {
+        let image = self.pixels()?;
+        let (du, dv) = (1.0 / image.width as f64, 1.0 / image.height as f64);
+        let height = |u: f64, v: f64| self.height_of(&image, u, v);
+        let along_u = (height(u + du, v)? - height(u - du, v)?) / (2.0 * du);
+        let along_v = (height(u, v + dv)? - height(u, v - dv)?) / (2.0 * dv);
+        Some((along_u, along_v))
+    }
+
+    // Shading normal from a normal map laid out like OpenGL ones: red goes right, green goes up the image and blue leaves the surface
+    // With the TRIPLANAR mapping every plane tilts the normal in its own directions, then the normals are blended
+    pub fn normal_at(&self, hit: &Hit) -> Option<Vector3> {
+        let component = |channel: f64| channel.round().clamp(0.0, 255.0) / 255.0 * 2.0 - 1.0;
+        match self.mapping {
+            ImageMapping::UV => {
+                let (u, v) = self.uv_at(hit);
+                let channels = self.texel(u, v)?;
+                let (right, up) = self.tilt(component(channels[0]), component(channels[1]));
+                tangent_space_normal(hit, hit.normal, right, up, component(channels[2]))
+            },
+            ImageMapping::TRIPLANAR => {
+                let image = self.pixels()?;
+                blend_normals(self.planes(hit).map(|planar| {
+                    let (u, v) = transformed(&self.transform, planar.uv);
+                    let channels = self.texel_of(&image, u, v)?;
+                    let (right, up) = self.tilt(component(channels[0]), component(channels[1]));
+                    tangent_frame_normal(planar.tangents, hit.front_face, hit.normal, right, up, component(channels[2])).map(|normal| (planar.weight, normal))
+                }))
+            }
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.mapping == ImageMapping::TRIPLANAR && !(self.sharpness >= 0.0 && self.sharpness.is_finite()) {
+            return Err(format!("texture sharpness must not be negative, not {}", self.sharpness));
+        }
+        self.transform.as_ref().map_or(Ok(()), TextureTransform::validate)
+    }
+}
+
+// Grayscale heights whose slopes tilt the shading normal. The strength is the height of white in texture coordinate units
+// (the width of the image is 1), so the relief follows the texture whatever the size of the shape. Negative strengths dig it
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BumpMap {
+    #[serde(flatten)]
+    pub image: ImageTexture,
+    pub strength: f64
+}
+
+impl BumpMap {
+    /// Shading normal at the hit, tilted away from `normal` against the slopes of the heights.
+    ///
+    /// ```
+    /// use std::sync::Arc;
+    /// use rust_raytracer::shape::Hit;
+    /// use rust_raytracer::texture::{BumpMap, ImageMapping, ImageTexture, TexturePixels, TextureFilter, TextureWrap};
+    /// use rust_raytracer::vertors::Vector3;
+    ///
+    /// // A ramp going up by 16 gray levels per pixel to the right: 16 * 16 / 255 per unit of u
+    /// let pixels = (0..16 * 16).map(|index| { let level = (index % 16) as u8 * 16; [level, level, level, 255] }).collect();
+    /// let ramp = TexturePixels { width: 16, height: 16, pixels };
+    /// let image = ImageTexture { file: "ramp.png".to_string(), filter: TextureFilter::BILINEAR, wrap: TextureWrap::CLAMP, transform: None, mapping: ImageMapping::UV, sharpness: 4.0, image: Some(Arc::new(ramp)), cache: None };
+    /// let (along_u, along_v) = image.slopes_at(0.5, 0.5).unwrap();
+    /// assert!((along_u - 256.0 / 255.0).abs() < 1e-9 && along_v.abs() < 1e-9);
+    ///
+    /// // On a floor with u along x, the 45 degree slope of this strength tilts the normal halfway toward -x
+    /// let up = Vector3::new(0.0, 1.0, 0.0);
+    /// let hit = Hit::new(1.0, Vector3::zero(), up).with_uv(0.5, 0.5).with_tangents(Vector3::new(1.0, 0.0, 0.0), Vector3::new(0.0, 0.0, 1.0));
+    /// let bump_map = BumpMap { image, strength: 255.0 / 256.0 };
+    /// let normal = bump_map.normal_at(&hit, up).unwrap();
+    /// let expected = Vector3::new(-1.0, 1.0, 0.0).normalize();
+    /// assert!((normal - expected).length() < 1e-9);
+    /// ```
+    pub fn normal_at(&self, hit: &Hit, normal: Vector3) -> Option<Vector3> {
+        // v goes down the image, heights growing along it slope down toward its top
+        let tilt = |(along_u, along_v): (f64, f64)| self.image.tilt(-self.strength * along_u, self.strength * along_v);
+        match self.image.mapping {
+            ImageMapping::UV => {
+                let (u, v) = self.image.uv_at(hit);
+                let (right, up) = tilt(self.image.slopes_at(u, v)?);
+                tangent_space_normal(hit, normal, right, up, 1.0)
+            },
+            ImageMapping::TRIPLANAR => blend_normals(self.image.planes(hit).map(|planar| {
+                let (u, v) = transformed(&self.image.transform, planar.uv);
+                let (right, up) = tilt(self.image.slopes_at(u, v)?);
+                tangent_frame_normal(planar.tangents, hit.front_face, normal, right, up, 1.0).map(|normal| (planar.weight, normal))
+            }))
+        }
+    }
+}
+
+// Shading normal at the hit from its components right and up the image and along the normal, in the frame the texture
+// directions of the shape make around it. Back faces see the relief from behind. None where the shape has no texture directions
+pub fn tangent_space_normal(hit: &Hit, normal: Vector3, right: f64, up: f64, out: f64) -> Option<Vector3> {
+    tangent_frame_normal(hit.tangents?, hit.front_face, normal, right, up, out)
+}
+
+fn tangent_frame_normal((tangent, bitangent): (Vector3, Vector3), front_face: bool, normal: Vector3, right: f64, up: f64, out: f64) -> Option<Vector3> {
+    let right_axis = (tangent - normal * normal.dot(&tangent)).normalize();
+    let up_axis = -bitangent; // v goes down the image
+    let up_axis = (up_axis - normal * normal.dot(&up_axis) - right_axis * right_axis.dot(&up_axis)).normalize();
+    if right_axis.length_sq() == 0.0 || up_axis.length_sq() == 0.0 {
+        return None;
+    }
+    let side = if front_face { 1.0 } else { -1.0 };
+    let mapped = (right_axis * right + up_axis * up) * side + normal * out;
+    if mapped.length_sq() < 1e-24 {
+        return None;
+    }
+    Some(mapped.normalize())
+}
+
+// What a noise texture draws between its two colors
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum NoiseKind {
+    #[default]
+    PERLIN, // Smooth blotches, like clouds
+    TURBULENCE, // Absolute values of the octaves, with creases where the noise crosses 0, like smoke or fire
+    MARBLE, // Stripes along x bent by the turbulence
+    WORLEY // Distance to scattered feature points, cells like stones, scales or cracked mud
+}
+
+// Seeded noise of the hit point in world space, so it needs no texture coordinates. Each octave adds the noise at a higher
+// frequency with a lower amplitude
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct Noise {
+    #[serde(default)]
+    pub kind: NoiseKind,
+    pub color_a: Color,
+    pub color_b: Color,
+    #[serde(default = "default_frequency")]
+    pub frequency: f64, // Lattice cells per world unit of the first octave
+    #[serde(default = "default_octaves")]
+    pub octaves: u32,
+    #[serde(default = "default_lacunarity")]
+    pub lacunarity: f64, // Frequency factor from an octave to the next
+    #[serde(default = "default_gain")]
+    pub gain: f64, // Amplitude factor from an octave to the next
+    #[serde(default)]
+    pub seed: u64
+}
+
+fn default_frequency() -> f64 {
+    1.0
+}
+
+fn default_octaves() -> u32 {
+    4
+}
+
+fn default_lacunarity() -> f64 {
+    2.0
+}
+
+fn default_gain() -> f64 {
+    0.5
+}
+
+// Number of stripe periods the turbulence can shift marble veins by
+const MARBLE_DISTORTION: f64 = 2.0;
+
+impl Noise {
+    // Octaves summed with their amplitudes, divided by the sum of the amplitudes
+    fn octaves_at(&self, point: Point) -> f64 {
+        let (mut frequency, mut amplitude) = (self.frequency, 1.0);
+        let (mut total, mut weight) = (0.0, 0.0);
+        for octave in 0..self.octaves {
+            // Each octave has its own lattice so that they do not line up at the origin
+            let seed = self.seed.wrapping_add(octave as u64);
+            let value = match self.kind {
+                NoiseKind::PERLIN => noise::perlin(point * frequency, seed),
+                NoiseKind::TURBULENCE | NoiseKind::MARBLE => noise::perlin(point * frequency, seed).abs(),
+                NoiseKind::WORLEY => noise::worley(point * frequency, seed)
+            };
+            total += value * amplitude;
+            weight += amplitude;
+            frequency *= self.lacunarity;
+            amplitude *= self.gain;
+        }
+        if weight > 0.0 { total / weight } else { 0.0 }
+    }
+
+    // Position between the two colors, from 0 to 1
+    pub fn value_at(&self, point: Point) -> f64 {
+        let value = self.octaves_at(point);
+        let blend = match self.kind {
+            NoiseKind::PERLIN => 0.5 + 0.5 * value,
+            NoiseKind::TURBULENCE | NoiseKind::WORLEY => value,
+            NoiseKind::MARBLE => 0.5 + 0.5 * (std::f64::consts::PI * (point.x * self.frequency + MARBLE_DISTORTION * value)).sin()
+        };
+        blend.clamp(0.0, 1.0)
+    }
+
+    pub fn color_at(&self, hit: &Hit) -> Color {
+        let t = self.value_at(hit.point);
+        let mix = |a: u8, b: u8| (a as f64 + (b as f64 - a as f64) * t).round() as u8;
+        Color::new(mix(self.color_a.r, self.color_b.r), mix(self.color_a.g, self.color_b.g), mix(self.color_a.b, self.color_b.b), mix(self.color_a.a, self.color_b.a))
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.frequency <= 0.0 || self.frequency.is_nan() || self.lacunarity <= 0.0 || self.lacunarity.is_nan() {
+            return Err(format!("noise frequency and lacunarity must be positive, not {} and {}", self.frequency, self.lacunarity));
+        }
+        if !(1..=16).contains(&self.octaves) {
+            return Err(format!("noise octaves must be between 1 and 16, not {}", self.octaves));
+        }
+        if self.gain < 0.0 || self.gain.is_nan() {
+            return Err(format!("noise gain must be positive or zero, not {}", self.gain));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum Pattern {
+    CHECKER(Checker),
+    IMAGE(ImageTexture),
+    NOISE(Noise)
+}
+
+// Color of a material over its surface, a bare color is the same everywhere
+#[allow(non_camel_case_types)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Texture {
+    SOLID_COLOR(Color),
+    PATTERN(Pattern)
+}
+
+impl Texture {
+    pub fn color_at(&self, hit: &Hit) -> Color {
+        match self {
+            Texture::SOLID_COLOR(color) => *color,
+            Texture::PATTERN(Pattern::CHECKER(checker)) => checker.color_at(hit),
+            Texture::PATTERN(Pattern::IMAGE(image)) => image.color_at(hit),
+            Texture::PATTERN(Pattern::NOISE(noise)) => noise.color_at(hit)
+        }
+    }
+
+    pub fn image(&self) -> Option<&ImageTexture> {
+        match self {
+            Texture::PATTERN(Pattern::IMAGE(image)) => Some(image),
+            _ => None
+        }
+    }
+
+    pub fn image_mut(&mut self) -> Option<&mut ImageTexture> {
+        match self {
+            Texture::PATTERN(Pattern::IMAGE(image)) => Some(image),
+            _ => None
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            Texture::PATTERN(Pattern::CHECKER(checker)) if checker.scale <= 0.0 || checker.scale.is_nan() => {
+                Err(format!("checker scale must be positive, not {}", checker.scale))
+            },
+            Texture::PATTERN(Pattern::CHECKER(checker)) if checker.transform.is_some() && checker.mapping != TextureMapping::UV => {
+                Err("checker transform needs the UV mapping".to_string())
+            },
+            Texture::PATTERN(Pattern::CHECKER(checker)) => checker.transform.as_ref().map_or(Ok(()), TextureTransform::validate),
+            Texture::PATTERN(Pattern::IMAGE(image)) => image.validate(),
+            Texture::PATTERN(Pattern::NOISE(noise)) => noise.validate(),
+            _ => Ok(())
+        }
+    }
+}
+
+impl From<Color> for Texture {
+    fn from(color: Color) -> Texture {
+        Texture::SOLID_COLOR(color)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use crate::rendering;
+
+    // 16 x 16 image whose red grows along the columns and green along the rows, read without filtering
+    fn grid(mapping: ImageMapping) -> ImageTexture {
+        let pixels = (0..16 * 16).map(|index| [(index % 16) as u8 * 16, (index / 16) as u8 * 16, 0, 255]).collect();
+        let image = TexturePixels { width: 16, height: 16, pixels };
+        ImageTexture { file: "grid.png".to_string(), filter: TextureFilter::NEAREST, wrap: TextureWrap::REPEAT, transform: None, mapping, sharpness: default_sharpness(), image: Some(Arc::new(image)), cache: None }
+    }
+
+    fn hit(point: Point, normal: Vector3) -> Hit {
+        Hit::new(1.0, point, normal.normalize())
+    }
+
+    // Column and row of the grid read at the hit
+    fn cell(texture: &ImageTexture, hit: &Hit) -> (u8, u8) {
+        let color = texture.color_at(hit);
+        (color.r / 16, color.g / 16)
+    }
+
+    #[test]
+    fn faces_along_the_axes_read_their_own_plane() {
+        let texture = grid(ImageMapping::TRIPLANAR);
+        // Seen from outside u goes right and v goes down on every plane
+        assert_eq!(cell(&texture, &hit(Vector3::new(1.0, -0.5, -0.25), Vector3::new(1.0, 0.0, 0.0))), (4, 8));
+        assert_eq!(cell(&texture, &hit(Vector3::new(-1.0, -0.5, -0.25), Vector3::new(-1.0, 0.0, 0.0))), (12, 8));
+        assert_eq!(cell(&texture, &hit(Vector3::new(0.25, 1.0, 0.75), Vector3::new(0.0, 1.0, 0.0))), (4, 12));
+        assert_eq!(cell(&texture, &hit(Vector3::new(0.25, -1.0, 0.75), Vector3::new(0.0, -1.0, 0.0))), (4, 4));
+        assert_eq!(cell(&texture, &hit(Vector3::new(0.25, -0.5, 1.0), Vector3::new(0.0, 0.0, 1.0))), (4, 8));
+        assert_eq!(cell(&texture, &hit(Vector3::new(0.25, -0.5, -1.0), Vector3::new(0.0, 0.0, -1.0))), (12, 8));
+        // The texture coordinates of the shape are ignored
+        let with_uv = hit(Vector3::new(0.25, -0.5, 1.0), Vector3::new(0.0, 0.0, 1.0)).with_uv(0.9, 0.9);
+        assert_eq!(cell(&texture, &with_uv), (4, 8));
+        assert_eq!(cell(&grid(ImageMapping::UV), &with_uv), (14, 14));
+    }
+
+    #[test]
+    fn slanted_faces_blend_the_planes_by_their_normal() {
+        let texture = grid(ImageMapping::TRIPLANAR);
+        // Halfway between the x and y planes, which read columns 4 and 8
+        let point = Vector3::new(0.5, 0.5, -0.25);
+        let color = texture.color_at(&hit(point, Vector3::new(1.0, 1.0, 0.0)));
+        assert_eq!((color.r, color.g), (96, 160));
+        // A sharper blend leans toward the plane the surface faces the most
+        let leaning = hit(point, Vector3::new(1.0, 0.8, 0.0));
+        let soft = texture.color_at(&leaning);
+        let sharp = ImageTexture { sharpness: 16.0, ..texture.clone() }.color_at(&leaning);
+        assert!(sharp.r < soft.r && soft.r < 96);
+        assert_eq!(ImageTexture { sharpness: 1000.0, ..texture }.color_at(&leaning).r, 64);
+    }
+
+    #[test]
+    fn triplanar_normal_maps_tilt_every_plane_in_its_own_directions() {
+        let flat = TexturePixels { width: 1, height: 1, pixels: vec![[128, 128, 255, 255]] };
+        let right = TexturePixels { width: 1, height: 1, pixels: vec![[218, 128, 218, 255]] };
+        let map = |pixels: TexturePixels| ImageTexture { image: Some(Arc::new(pixels)), ..grid(ImageMapping::TRIPLANAR) };
+        let (flat, right) = (map(flat), map(right));
+        for normal in [Vector3::new(1.0, 0.0, 0.0), Vector3::new(0.0, -1.0, 0.0), Vector3::new(0.6, 0.8, 0.0), Vector3::new(-0.3, 0.5, -0.8)].iter() {
+            // Shapes without texture directions still get the relief, a flat map keeps their normal
+            let hit = hit(Vector3::zero(), *normal);
+            assert!(grid(ImageMapping::UV).normal_at(&hit).is_none());
+            assert!((flat.normal_at(&hit).unwrap() - hit.normal).length() < 0.01);
+        }
+        // Right is toward -z on the plane facing x and toward x on the one facing z
+        let facing_x = right.normal_at(&hit(Vector3::zero(), Vector3::new(1.0, 0.0, 0.0))).unwrap();
+        assert!(facing_x.z < -0.5 && facing_x.x > 0.5 && facing_x.y.abs() < 0.01);
+        let facing_z = right.normal_at(&hit(Vector3::zero(), Vector3::new(0.0, 0.0, 1.0))).unwrap();
+        assert!(facing_z.x > 0.5 && facing_z.z > 0.5 && facing_z.y.abs() < 0.01);
+    }
+
+    #[test]
+    fn triplanar_bump_maps_follow_the_heights_on_every_plane() {
+        // Heights growing to the right of the image
+        let pixels = (0..16 * 16).map(|index| { let level = (index % 16) as u8 * 16; [level, level, level, 255] }).collect();
+        let image = ImageTexture { image: Some(Arc::new(TexturePixels { width: 16, height: 16, pixels })), filter: TextureFilter::BILINEAR, ..grid(ImageMapping::TRIPLANAR) };
+        let bump_map = BumpMap { image, strength: 0.5 };
+        let normal = Vector3::new(0.0, 0.0, 1.0);
+        let tilted = bump_map.normal_at(&hit(Vector3::new(0.5, -0.5, 1.0), normal), normal).unwrap();
+        assert!(tilted.x < -0.1 && tilted.y.abs() < 1e-9);
+        let normal = Vector3::new(1.0, 0.0, 0.0);
+        let tilted = bump_map.normal_at(&hit(Vector3::new(1.0, -0.5, -0.5), normal), normal).unwrap();
+        assert!(tilted.z > 0.1 && tilted.y.abs() < 1e-9);
+    }
+
+    #[test]
+    fn carved_shapes_match_their_golden_render() {
+        // Left with the texture coordinates of the box and the sphere, right with the TRIPLANAR mapping
+        let mut scene = crate::parse_scene(include_str!("../test_scene/triplanar.json"), &HashMap::new(), None).unwrap();
+        scene.camera.width = 160;
+        scene.camera.height = 90;
+        scene.prepare().unwrap();
+        let image = rendering::render_image(1, &mut scene);
+        let golden = image::open("test_scene/golden/triplanar.png").unwrap().to_rgba();
+        assert_eq!(image.dimensions(), golden.dimensions());
+        let differences = image.pixels().zip(golden.pixels())
+            .filter(|(pixel, expected)| pixel.0.iter().zip(expected.0.iter()).any(|(a, b)| (*a as i32 - *b as i32).abs() > 2))
+            .count();
+        assert_eq!(differences, 0);
+    }
+}
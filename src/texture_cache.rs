@@ -0,0 +1,235 @@
+use std::collections::HashMap;
+use std::mem::size_of;
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+use crate::texture::TexturePixels;
+
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64, // Every load, the first one of a file and the ones after it was evicted
+    pub evictions: u64,
+    pub bytes: u64,
+    pub peak_bytes: u64
+}
+
+struct Entry {
+    pixels: Arc<TexturePixels>,
+    last_use: AtomicU64 // Tick of the clock of the cache, bumped under the read lock
+}
+
+fn pixel_bytes(pixels: &TexturePixels) -> u64 {
+    (pixels.pixels.len() * size_of::<[u8; 4]>()) as u64
+}
+
+// Decoded image textures by file, loaded the first time a ray samples them and shared by every material using the file.
+// Above the budget the least recently sampled ones are dropped and loaded again when needed. Samplers keep their Arc, so
+// a texture dropped while a thread reads it stays alive until that thread is done
+#[derive(Default)]
+pub struct TextureCache {
+    budget: Option<u64>, // Decoded bytes, unlimited when None
+    entries: RwLock<HashMap<String, Entry>>,
+    clock: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+    bytes: AtomicU64,
+    peak_bytes: AtomicU64
+}
+
+impl TextureCache {
+    pub fn new(budget: Option<u64>) -> TextureCache {
+        TextureCache { budget, ..TextureCache::default() }
+    }
+
+    pub fn budget(&self) -> Option<u64> {
+        self.budget
+    }
+
+    pub fn get(&self, file: &str) -> Arc<TexturePixels> {
+        self.get_with(file, || TexturePixels::load(file).map_err(|e| e.to_string()))
+    }
+
+    // A file that cannot be read any more gets an empty image, which samples as black, so it is reported once
+    pub fn get_with(&self, file: &str, load: impl FnOnce() -> Result<TexturePixels, String>) -> Arc<TexturePixels> {
+        let tick = self.clock.fetch_add(1, Ordering::Relaxed);
+        if let Some(entry) = self.entries.read().unwrap_or_else(|e| e.into_inner()).get(file) {
+            entry.last_use.store(tick, Ordering::Relaxed);
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Arc::clone(&entry.pixels);
+        }
+        // Decoded outside of the lock so that the other threads keep sampling the loaded textures
+        let pixels = load().unwrap_or_else(|e| {
+            eprintln!("warning: texture {}: {}", file, e);
+            TexturePixels::default()
+        });
+        let mut entries = self.entries.write().unwrap_or_else(|e| e.into_inner());
+        // Another thread may have loaded it meanwhile
+        if let Some(entry) = entries.get(file) {
+            entry.last_use.store(tick, Ordering::Relaxed);
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Arc::clone(&entry.pixels);
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let pixels = Arc::new(pixels);
+        let mut bytes = self.bytes.load(Ordering::Relaxed) + pixel_bytes(&pixels);
+        entries.insert(file.to_string(), Entry { pixels: Arc::clone(&pixels), last_use: AtomicU64::new(tick) });
+        if let Some(budget) = self.budget {
+            while bytes > budget {
+                let oldest = entries.iter()
+                    .filter(|(name, _)| name.as_str() != file)
+                    .min_by_key(|(_, entry)| entry.last_use.load(Ordering::Relaxed))
+                    .map(|(name, _)| name.clone());
+                match oldest.and_then(|name| entries.remove(&name)) {
+                    Some(evicted) => {
+                        bytes -= pixel_bytes(&evicted.pixels);
+                        self.evictions.fetch_add(1, Ordering::Relaxed);
+                    },
+                    None => break // A single texture above the budget is still kept
+                }
+            }
+        }
+        self.bytes.store(bytes, Ordering::Relaxed);
+        self.peak_bytes.fetch_max(bytes, Ordering::Relaxed);
+        pixels
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            bytes: self.bytes.load(Ordering::Relaxed),
+            peak_bytes: self.peak_bytes.load(Ordering::Relaxed)
+        }
+    }
+}
+
+impl std::fmt::Debug for TextureCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("TextureCache").field("budget", &self.budget).field("stats", &self.stats()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::thread;
+    use crate::rendering::{self, Scene};
+    use crate::vertors::Vector3;
+
+    // Square image of a single color, size x size pixels of 4 bytes
+    fn tiny(size: usize, level: u8) -> Result<TexturePixels, String> {
+        Ok(TexturePixels { width: size, height: size, pixels: vec![[level, level, level, 255]; size * size] })
+    }
+
+    #[test]
+    fn files_are_loaded_once_and_shared() {
+        let cache = TextureCache::new(None);
+        let mut loads = 0;
+        let first = cache.get_with("bricks.png", || { loads += 1; tiny(2, 10) });
+        let second = cache.get_with("bricks.png", || { loads += 1; tiny(2, 20) });
+        assert_eq!(loads, 1);
+        assert!(Arc::ptr_eq(&first, &second));
+        assert_eq!(second.pixels[0][0], 10);
+        assert_eq!(cache.stats(), CacheStats { hits: 1, misses: 1, evictions: 0, bytes: 16, peak_bytes: 16 });
+    }
+
+    #[test]
+    fn the_least_recently_used_textures_are_evicted_above_the_budget() {
+        let cache = TextureCache::new(Some(40));
+        cache.get_with("a.png", || tiny(2, 1)); // 16 bytes
+        cache.get_with("b.png", || tiny(2, 2));
+        cache.get_with("a.png", || tiny(2, 1)); // b is now the oldest
+        cache.get_with("c.png", || tiny(2, 3));
+        let stats = cache.stats();
+        assert_eq!((stats.evictions, stats.bytes, stats.peak_bytes), (1, 32, 32));
+        let mut reloaded = false;
+        cache.get_with("a.png", || { reloaded = true; tiny(2, 1) });
+        assert!(!reloaded);
+        cache.get_with("b.png", || { reloaded = true; tiny(2, 2) });
+        assert!(reloaded);
+        assert_eq!(cache.stats(), CacheStats { hits: 2, misses: 4, evictions: 2, bytes: 32, peak_bytes: 32 });
+    }
+
+    #[test]
+    fn textures_above_the_budget_are_still_kept() {
+        let cache = TextureCache::new(Some(10));
+        cache.get_with("small.png", || tiny(1, 1));
+        let large = cache.get_with("large.png", || tiny(4, 2));
+        assert_eq!(large.width, 4);
+        assert_eq!(cache.stats().bytes, 64);
+        assert_eq!(cache.stats().evictions, 1);
+    }
+
+    #[test]
+    fn unreadable_files_sample_as_empty_images_once() {
+        let cache = TextureCache::new(None);
+        assert_eq!(cache.get_with("missing.png", || Err("not found".to_string())).width, 0);
+        assert_eq!(cache.get("missing.png").width, 0);
+        assert_eq!(cache.stats().misses, 1);
+    }
+
+    #[test]
+    fn threads_share_a_single_load() {
+        let cache = TextureCache::new(Some(64));
+        let loads = AtomicU64::new(0);
+        thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| {
+                    for index in 0..100 {
+                        let file = format!("{}.png", index % 3);
+                        let pixels = cache.get_with(&file, || { loads.fetch_add(1, Ordering::Relaxed); tiny(2, (index % 3) as u8) });
+                        assert_eq!(pixels.pixels[0][0], (index % 3) as u8);
+                    }
+                });
+            }
+        });
+        let stats = cache.stats();
+        assert_eq!(stats.hits + stats.misses, 800);
+        assert_eq!(stats.misses, 3);
+        assert!(loads.load(Ordering::Relaxed) >= 3);
+        assert_eq!(stats.bytes, 48);
+    }
+
+    fn textured_scene(budget: Option<u64>) -> Scene {
+        let mut scene = crate::parse_scene(include_str!("../test_scene/texture_transform.json"), &HashMap::new(), None).unwrap();
+        scene.camera.width = 80;
+        scene.camera.height = 45;
+        scene.textures = Arc::new(TextureCache::new(budget));
+        scene.prepare().unwrap();
+        scene
+    }
+
+    #[test]
+    fn renders_are_the_same_whatever_the_budget() {
+        let mut unlimited = textured_scene(None);
+        let mut starved = textured_scene(Some(1));
+        let expected = rendering::render_image(1, &mut unlimited);
+        assert_eq!(rendering::render_image(1, &mut starved).into_raw(), expected.into_raw());
+        assert_eq!((unlimited.textures.stats().misses, unlimited.textures.stats().evictions), (2, 0));
+        assert!(starved.textures.stats().evictions > 0);
+        assert!(starved.textures.stats().peak_bytes < unlimited.textures.stats().peak_bytes);
+    }
+
+    #[test]
+    fn textures_out_of_view_are_never_loaded() {
+        let mut scene = textured_scene(None);
+        scene.camera.look_at = Some(Vector3::new(0.0, 0.0, 5.0));
+        scene.camera.prepare().unwrap();
+        rendering::render_image(1, &mut scene);
+        assert_eq!(scene.textures.stats(), CacheStats::default());
+    }
+
+    #[test]
+    fn missing_files_are_reported_when_the_scene_is_prepared() {
+        let content = include_str!("../test_scene/texture_transform.json")
+            .replace("textures/planet.png", "textures/missing_planet.png")
+            .replace("textures/bricks_normal.png", "textures/missing_bricks.png");
+        let mut scene = crate::parse_scene(&content, &HashMap::new(), None).unwrap();
+        let error = scene.prepare().unwrap_err().to_string();
+        assert!(error.starts_with("2 asset(s) failed to load"), "{}", error);
+        assert!(error.contains("missing_planet.png") && error.contains("missing_bricks.png"));
+    }
+}
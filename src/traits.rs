@@ -1,14 +1,71 @@
-use crate::shape::{Ray, Hit, Point};
+use crate::shape::{Ray, RayType, Hit, Point};
 use crate::vertors::Vector3;
-use crate::rendering::Color;
+use crate::rendering::{Color, Renderable};
+use crate::aabb::Aabb;
+
+const MAX_CROSSINGS: usize = 256;
 
 pub trait Intersectable {
     fn intersect(&self, ray: &Ray) -> Option<Hit>;
+    // None for shapes without bounds, like planes
+    fn bounding_box(&self) -> Option<Aabb>;
+
+    // Every surface crossing in the ray range by increasing distance, found by moving t_min past each hit
+    fn intersect_all(&self, ray: &Ray) -> Vec<Hit> {
+        let mut hits: Vec<Hit> = Vec::new();
+        let mut remaining = *ray;
+        while let Some(hit) = self.intersect(&remaining) {
+            let distance = hit.distance;
+            hits.push(hit);
+            if hits.len() >= MAX_CROSSINGS {
+                break;
+            }
+            remaining = ray.with_range(distance + 1e-9 * distance.abs().max(1.0), ray.t_max);
+        }
+        hits
+    }
 }
 
 pub trait LightEmitter {
     fn get_direction(&self, point: Point) -> Vector3;
     fn get_brightness(&self, point: Point) -> f64;
+    fn get_power(&self) -> f64;
     fn get_color(&self) -> Color;
     fn get_distance(&self, point: Point) -> f64;
-}
\ No newline at end of file
+
+    // Lights that do not cast shadows are never tested for occlusion
+    fn casts_shadows(&self) -> bool {
+        true
+    }
+
+    // Distance beyond which the light is skipped, None when it reaches everywhere
+    fn get_range(&self) -> Option<f64> {
+        None
+    }
+
+    // Shadow rays shading a point, spread over the light surface for lights with an extent
+    fn get_samples(&self) -> u32 {
+        1
+    }
+
+    // Direction, distance and brightness from the point toward the light surface at (u, v) in [0, 1)²
+    fn sample(&self, point: Point, _u: f64, _v: f64) -> (Vector3, f64, f64) {
+        (self.get_direction(point), self.get_distance(point), self.get_brightness(point))
+    }
+}
+
+pub trait Recorder {
+    fn is_recording(&self) -> bool {
+        false
+    }
+    fn enter(&mut self, _ray_type: RayType, _ray: &Ray) {}
+    fn candidate(&mut self, _element: usize, _hit: &Hit) {}
+    fn hit(&mut self, _renderable: &Renderable, _hit: &Hit) {}
+    fn light(&mut self, _light: usize, _direction: Vector3, _brightness: f64, _blocked_at: Option<f64>, _contribution: [f64; 3]) {}
+    fn caustic(&mut self, _irradiance: [f64; 3]) {}
+    fn leave(&mut self, _color: Color) {}
+}
+
+pub trait SampleSource {
+    fn next_f64(&mut self) -> f64;
+}
@@ -0,0 +1,182 @@
+use std::ops;
+use serde::{Serialize, Deserialize};
+use crate::vertors::Vector3;
+use crate::shape::Point;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Matrix {
+    pub m: [[f64; 4]; 4]
+}
+
+impl Matrix {
+    pub fn new(m: [[f64; 4]; 4]) -> Matrix {
+        Matrix { m }
+    }
+
+    pub fn identity() -> Matrix {
+        Matrix { m: [
+            [1.0, 0.0, 0.0, 0.0],
+            [0.0, 1.0, 0.0, 0.0],
+            [0.0, 0.0, 1.0, 0.0],
+            [0.0, 0.0, 0.0, 1.0]
+        ] }
+    }
+
+    pub fn translation(offset: Vector3) -> Matrix {
+        let mut matrix = Matrix::identity();
+        matrix.m[0][3] = offset.x;
+        matrix.m[1][3] = offset.y;
+        matrix.m[2][3] = offset.z;
+        matrix
+    }
+
+    pub fn scaling(factor: Vector3) -> Matrix {
+        let mut matrix = Matrix::identity();
+        matrix.m[0][0] = factor.x;
+        matrix.m[1][1] = factor.y;
+        matrix.m[2][2] = factor.z;
+        matrix
+    }
+
+    pub fn rotation_x(degrees: f64) -> Matrix {
+        let (sin, cos) = degrees.to_radians().sin_cos();
+        let mut matrix = Matrix::identity();
+        matrix.m[1][1] = cos;
+        matrix.m[1][2] = -sin;
+        matrix.m[2][1] = sin;
+        matrix.m[2][2] = cos;
+        matrix
+    }
+
+    pub fn rotation_y(degrees: f64) -> Matrix {
+        let (sin, cos) = degrees.to_radians().sin_cos();
+        let mut matrix = Matrix::identity();
+        matrix.m[0][0] = cos;
+        matrix.m[0][2] = sin;
+        matrix.m[2][0] = -sin;
+        matrix.m[2][2] = cos;
+        matrix
+    }
+
+    pub fn rotation_z(degrees: f64) -> Matrix {
+        let (sin, cos) = degrees.to_radians().sin_cos();
+        let mut matrix = Matrix::identity();
+        matrix.m[0][0] = cos;
+        matrix.m[0][1] = -sin;
+        matrix.m[1][0] = sin;
+        matrix.m[1][1] = cos;
+        matrix
+    }
+
+    pub fn transform_point(&self, point: Point) -> Point {
+        Vector3::new(
+            self.m[0][0] * point.x + self.m[0][1] * point.y + self.m[0][2] * point.z + self.m[0][3],
+            self.m[1][0] * point.x + self.m[1][1] * point.y + self.m[1][2] * point.z + self.m[1][3],
+            self.m[2][0] * point.x + self.m[2][1] * point.y + self.m[2][2] * point.z + self.m[2][3]
+        )
+    }
+
+    pub fn transform_vector(&self, vector: Vector3) -> Vector3 {
+        Vector3::new(
+            self.m[0][0] * vector.x + self.m[0][1] * vector.y + self.m[0][2] * vector.z,
+            self.m[1][0] * vector.x + self.m[1][1] * vector.y + self.m[1][2] * vector.z,
+            self.m[2][0] * vector.x + self.m[2][1] * vector.y + self.m[2][2] * vector.z
+        )
+    }
+
+    pub fn uniform_scale(&self) -> f64 {
+        Vector3::new(self.m[0][0], self.m[1][0], self.m[2][0]).length()
+    }
+
+    pub fn rotation(&self) -> Matrix {
+        let scale = self.uniform_scale();
+        let mut rotation = Matrix::identity();
+        for row in 0..3 {
+            for column in 0..3 {
+                rotation.m[row][column] = if scale != 0.0 { self.m[row][column] / scale } else { 0.0 };
+            }
+        }
+        rotation
+    }
+
+    // Gauss-Jordan elimination with partial pivoting, None when the matrix is singular (e.g. a zero scale)
+    pub fn inverse(&self) -> Option<Matrix> {
+        let mut m = self.m;
+        let mut inverse = Matrix::identity().m;
+        for column in 0..4 {
+            let pivot = (column..4).max_by(|&a, &b| m[a][column].abs().partial_cmp(&m[b][column].abs()).unwrap_or(std::cmp::Ordering::Equal))?;
+            if m[pivot][column].abs() < 1e-12 {
+                return None;
+            }
+            m.swap(column, pivot);
+            inverse.swap(column, pivot);
+            let factor = 1.0 / m[column][column];
+            for k in 0..4 {
+                m[column][k] *= factor;
+                inverse[column][k] *= factor;
+            }
+            for row in 0..4 {
+                if row != column {
+                    let scale = m[row][column];
+                    for k in 0..4 {
+                        m[row][k] -= scale * m[column][k];
+                        inverse[row][k] -= scale * inverse[column][k];
+                    }
+                }
+            }
+        }
+        Some(Matrix { m: inverse })
+    }
+
+    pub fn transpose(&self) -> Matrix {
+        let mut transposed = Matrix::identity();
+        for row in 0..4 {
+            for column in 0..4 {
+                transposed.m[row][column] = self.m[column][row];
+            }
+        }
+        transposed
+    }
+}
+
+impl ops::Mul for Matrix {
+    type Output = Self;
+
+    fn mul(self, rhs: Self) -> Self {
+        let mut result = [[0.0; 4]; 4];
+        for (row, result_row) in result.iter_mut().enumerate() {
+            for (column, value) in result_row.iter_mut().enumerate() {
+                *value = (0..4).map(|k| self.m[row][k] * rhs.m[k][column]).sum();
+            }
+        }
+        Matrix { m: result }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Transform {
+    pub translate: Vector3,
+    pub rotate: Vector3,
+    pub scale: f64
+}
+
+impl Transform {
+    pub fn new(translate: Vector3, rotate: Vector3, scale: f64) -> Transform {
+        Transform { translate, rotate, scale }
+    }
+
+    pub fn to_matrix(self) -> Matrix {
+        Matrix::translation(self.translate)
+            * Matrix::rotation_z(self.rotate.z)
+            * Matrix::rotation_y(self.rotate.y)
+            * Matrix::rotation_x(self.rotate.x)
+            * Matrix::scaling(Vector3::new(self.scale, self.scale, self.scale))
+    }
+}
+
+impl Default for Transform {
+    fn default() -> Transform {
+        Transform { translate: Vector3::zero(), rotate: Vector3::zero(), scale: 1.0 }
+    }
+}
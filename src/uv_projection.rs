@@ -0,0 +1,225 @@
+use serde::{Serialize, Deserialize};
+use crate::shape::{Hit, Point, spherical_uv};
+use crate::transform::{Matrix, Transform};
+use crate::vertors::Vector3;
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum ProjectionMode {
+    #[default]
+    NONE, // The texture coordinates of the shape
+    PLANAR, // Straight along the axis, the square from -0.5 to 0.5 across it covers the image
+    CYLINDRICAL, // Around the axis, u turning once around it and v going down one unit along it
+    SPHERICAL // Longitude and latitude around the center, the poles on the axis, like the sphere texture coordinates
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum Axis {
+    X,
+    #[default]
+    Y,
+    Z
+}
+
+impl Axis {
+    // Coordinates with the axis along y, seen from the positive side of the axis for the planar projection: x goes
+    // right the image and z down it
+    fn turned_to_y(self, point: Point) -> Vector3 {
+        match self {
+            Axis::X => Vector3::new(-point.z, point.x, -point.y),
+            Axis::Y => point,
+            Axis::Z => Vector3::new(point.x, point.z, -point.y)
+        }
+    }
+
+    fn turned_back(self, vector: Vector3) -> Vector3 {
+        match self {
+            Axis::X => Vector3::new(vector.y, -vector.z, -vector.x),
+            Axis::Y => vector,
+            Axis::Z => Vector3::new(vector.x, -vector.z, vector.y)
+        }
+    }
+}
+
+// Texture coordinates computed again from the hit point in the space of the projection, placed by the transform (and
+// the nodes around the element) over the scene
+#[derive(Copy, Clone, Debug, Default, Serialize, Deserialize)]
+pub struct UvProjection {
+    pub mode: ProjectionMode,
+    #[serde(default)]
+    pub axis: Axis,
+    #[serde(default)]
+    pub transform: Transform,
+    #[serde(skip)]
+    parent: Option<Matrix>, // Nodes the element was in, kept when they are flattened
+    #[serde(skip)]
+    to_object: Option<Matrix> // Inverse of the placement, kept by prepare
+}
+
+impl UvProjection {
+    pub fn new(mode: ProjectionMode, axis: Axis, transform: Transform) -> UvProjection {
+        UvProjection { mode, axis, transform, parent: None, to_object: None }
+    }
+
+    pub fn is_none(&self) -> bool {
+        self.mode == ProjectionMode::NONE
+    }
+
+    pub fn transformed(&self, matrix: &Matrix) -> UvProjection {
+        UvProjection { parent: Some(*matrix * self.parent.unwrap_or_else(Matrix::identity)), to_object: None, ..*self }
+    }
+
+    fn placement(&self) -> Matrix {
+        self.parent.unwrap_or_else(Matrix::identity) * self.transform.to_matrix()
+    }
+
+    pub fn prepare(&mut self) -> Result<(), String> {
+        if self.is_none() {
+            return Ok(());
+        }
+        self.to_object = Some(self.placement().inverse().ok_or("the uv_projection transform cannot be inverted, its scale must not be zero")?);
+        Ok(())
+    }
+
+    // Texture coordinates and the directions they grow along at a point given in the space of the element, None for NONE
+    pub fn project(&self, point: Point) -> Option<((f64, f64), (Vector3, Vector3))> {
+        let to_object = match self.mode {
+            ProjectionMode::NONE => return None,
+            _ => self.to_object.or_else(|| self.placement().inverse())?
+        };
+        let local = self.axis.turned_to_y(to_object.transform_point(point));
+        let (uv, tangent, bitangent) = match self.mode {
+            ProjectionMode::PLANAR => ((local.x + 0.5, local.z + 0.5), Vector3::new(1.0, 0.0, 0.0), Vector3::new(0.0, 0.0, 1.0)),
+            ProjectionMode::CYLINDRICAL => {
+                let longitude = (-local.z).atan2(local.x).rem_euclid(2.0 * std::f64::consts::PI);
+                ((longitude / (2.0 * std::f64::consts::PI), 0.5 - local.y), Vector3::new(local.z, 0.0, -local.x), Vector3::new(0.0, -1.0, 0.0))
+            },
+            _ => {
+                let direction = if local.length_sq() > 0.0 { local.normalize() } else { Vector3::new(0.0, 1.0, 0.0) };
+                let tangent = Vector3::new(direction.z, 0.0, -direction.x);
+                (spherical_uv(direction), tangent, tangent.cross(&direction))
+            }
+        };
+        // Directions in the space of the projection turn back like normals, the placement being a similarity
+        let to_world = |vector: Vector3| to_object.transpose().transform_vector(self.axis.turned_back(vector));
+        Some((uv, (to_world(tangent), to_world(bitangent))))
+    }
+
+    pub fn apply(&self, hit: Hit, point: Point) -> Hit {
+        match self.project(point) {
+            Some(((u, v), (tangent, bitangent))) => hit.with_uv(u, v).with_tangents(tangent, bitangent),
+            None => hit
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn near(actual: (f64, f64), expected: (f64, f64)) -> bool {
+        (actual.0 - expected.0).abs() < 1e-9 && (actual.1 - expected.1).abs() < 1e-9
+    }
+
+    fn uv(projection: &UvProjection, point: Point) -> (f64, f64) {
+        projection.project(point).unwrap().0
+    }
+
+    fn projection(mode: ProjectionMode, axis: Axis) -> UvProjection {
+        let mut projection = UvProjection::new(mode, axis, Transform::default());
+        projection.prepare().unwrap();
+        projection
+    }
+
+    #[test]
+    fn none_keeps_the_texture_coordinates_of_the_shape() {
+        let none = projection(ProjectionMode::NONE, Axis::Y);
+        assert!(none.project(Vector3::new(1.0, 2.0, 3.0)).is_none());
+        let hit = Hit::new(1.0, Vector3::new(1.0, 2.0, 3.0), Vector3::new(0.0, 1.0, 0.0)).with_uv(0.3, 0.7);
+        let kept = none.apply(hit, Vector3::new(1.0, 2.0, 3.0));
+        assert_eq!(kept.uv, (0.3, 0.7));
+        assert!(kept.tangents.is_none());
+    }
+
+    #[test]
+    fn planar_projections_look_along_their_axis() {
+        let down = projection(ProjectionMode::PLANAR, Axis::Y);
+        assert!(near(uv(&down, Vector3::zero()), (0.5, 0.5)));
+        // Seen from above the image is upright, its top toward -z, whatever the height
+        assert!(near(uv(&down, Vector3::new(0.5, 3.0, -0.5)), (1.0, 0.0)));
+        assert!(near(uv(&down, Vector3::new(-0.5, -7.0, 0.5)), (0.0, 1.0)));
+        let front = projection(ProjectionMode::PLANAR, Axis::Z);
+        assert!(near(uv(&front, Vector3::new(0.25, 0.5, 9.0)), (0.75, 0.0)));
+        let side = projection(ProjectionMode::PLANAR, Axis::X);
+        assert!(near(uv(&side, Vector3::new(9.0, 0.5, -0.25)), (0.75, 0.0)));
+        let (_, (tangent, bitangent)) = side.project(Vector3::zero()).unwrap();
+        assert!((tangent - Vector3::new(0.0, 0.0, -1.0)).length() < 1e-12 && (bitangent - Vector3::new(0.0, -1.0, 0.0)).length() < 1e-12);
+    }
+
+    #[test]
+    fn cylindrical_projections_turn_around_their_axis() {
+        let cylinder = projection(ProjectionMode::CYLINDRICAL, Axis::Y);
+        assert!(near(uv(&cylinder, Vector3::new(1.0, 0.0, 0.0)), (0.0, 0.5)));
+        assert!(near(uv(&cylinder, Vector3::new(0.0, 0.25, -2.0)), (0.25, 0.25)));
+        assert!(near(uv(&cylinder, Vector3::new(-3.0, -0.5, 0.0)), (0.5, 1.0)));
+        assert!(near(uv(&cylinder, Vector3::new(0.0, 0.0, 1.0)), (0.75, 0.5)));
+        let (_, (tangent, bitangent)) = cylinder.project(Vector3::new(1.0, 0.0, 0.0)).unwrap();
+        assert!((tangent - Vector3::new(0.0, 0.0, -1.0)).length() < 1e-12 && (bitangent - Vector3::new(0.0, -1.0, 0.0)).length() < 1e-12);
+        // Lying along x, v goes down the image toward -x
+        let lying = projection(ProjectionMode::CYLINDRICAL, Axis::X);
+        assert!(near(uv(&lying, Vector3::new(0.25, 0.0, -1.0)), (0.0, 0.25)));
+    }
+
+    #[test]
+    fn spherical_projections_match_the_sphere_coordinates() {
+        let sphere = projection(ProjectionMode::SPHERICAL, Axis::Y);
+        assert!(near(uv(&sphere, Vector3::new(0.0, 2.0, 0.0)), (0.0, 0.0)));
+        assert!(near(uv(&sphere, Vector3::new(3.0, 0.0, 0.0)), (0.0, 0.5)));
+        assert!(near(uv(&sphere, Vector3::new(0.0, 0.0, -1.0)), (0.25, 0.5)));
+        assert!((uv(&sphere, Vector3::new(0.0, -1.0, 0.0)).1 - 1.0).abs() < 1e-9);
+        let direction = Vector3::new(0.3, -0.4, 0.5).normalize();
+        assert!(near(uv(&sphere, direction * 4.0), spherical_uv(direction)));
+        // With the poles on z the north pole is toward +z
+        let turned = projection(ProjectionMode::SPHERICAL, Axis::Z);
+        assert!(near(uv(&turned, Vector3::new(0.0, 0.0, 1.0)), (0.0, 0.0)));
+    }
+
+    #[test]
+    fn the_transform_and_the_nodes_place_the_projection() {
+        let mut placed = UvProjection::new(ProjectionMode::PLANAR, Axis::Y, Transform::new(Vector3::new(2.0, 0.0, 0.0), Vector3::zero(), 2.0));
+        placed.prepare().unwrap();
+        assert!(near(uv(&placed, Vector3::new(2.0, 5.0, 0.0)), (0.5, 0.5)));
+        assert!(near(uv(&placed, Vector3::new(3.0, 0.0, 1.0)), (1.0, 1.0)));
+        let mut moved = placed.transformed(&Matrix::translation(Vector3::new(0.0, 0.0, -10.0)));
+        moved.prepare().unwrap();
+        assert!(near(uv(&moved, Vector3::new(3.0, 0.0, -9.0)), (1.0, 1.0)));
+        // Turned a quarter around y the image top faces -x
+        let mut turned = UvProjection::new(ProjectionMode::PLANAR, Axis::Y, Transform::new(Vector3::zero(), Vector3::new(0.0, 90.0, 0.0), 1.0));
+        turned.prepare().unwrap();
+        assert!(near(uv(&turned, Vector3::new(-0.5, 0.0, 0.0)), (0.5, 0.0)));
+        let (_, (tangent, _)) = turned.project(Vector3::zero()).unwrap();
+        assert!((tangent - Vector3::new(0.0, 0.0, -1.0)).length() < 1e-12);
+        let mut flat = UvProjection::new(ProjectionMode::CYLINDRICAL, Axis::Y, Transform::new(Vector3::zero(), Vector3::zero(), 0.0));
+        assert!(flat.prepare().is_err());
+    }
+
+    #[test]
+    fn elements_replace_the_texture_coordinates_of_their_shape() {
+        use crate::rendering::{Color, Material, Motion, Renderable};
+        use crate::shape::{Ray, Shape, Sphere};
+        let center = Vector3::new(1.0, 2.0, -5.0);
+        let sphere = Renderable::new(Shape::SPHERE(Sphere::new(center, 1.5)), Material::new(Color::white(), 0.5, 0.0));
+        let ray = Ray::new(Vector3::zero(), (Vector3::new(1.4, 2.9, -4.0) - Vector3::zero()).normalize());
+        let intrinsic = sphere.intersect(&ray).unwrap();
+        // Centered on the sphere the spherical projection gives back its own coordinates
+        let mut projected = Renderable { uv_projection: UvProjection::new(ProjectionMode::SPHERICAL, Axis::Y, Transform::new(center, Vector3::zero(), 1.0)), ..sphere.clone() };
+        projected.uv_projection.prepare().unwrap();
+        assert!(near(projected.intersect(&ray).unwrap().uv, intrinsic.uv));
+        // Moving elements carry their projection along
+        let planar = UvProjection::new(ProjectionMode::PLANAR, Axis::Z, Transform::new(center, Vector3::zero(), 4.0));
+        let mut moving = Renderable { uv_projection: planar, motion: Some(Motion { start: Vector3::zero(), end: Vector3::new(2.0, 0.0, 0.0) }), ..sphere };
+        moving.uv_projection.prepare().unwrap();
+        let later = Ray { origin: ray.origin + Vector3::new(2.0, 0.0, 0.0), time: 1.0, ..ray };
+        assert!(near(moving.intersect(&later).unwrap().uv, moving.intersect(&ray).unwrap().uv));
+    }
+}
@@ -0,0 +1,201 @@
+use std::error;
+use std::fmt;
+use std::collections::HashMap;
+
+#[derive(Debug, Clone)]
+pub struct VariableError {
+    pub message: String
+}
+
+impl VariableError {
+    pub fn new(message: String) -> VariableError {
+        VariableError { message }
+    }
+}
+
+impl fmt::Display for VariableError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl error::Error for VariableError {}
+
+#[derive(Copy, Clone, Debug)]
+struct Segment {
+    output_start: usize,
+    output_len: usize,
+    source_start: usize,
+    source_len: usize
+}
+
+#[derive(Clone, Debug)]
+pub struct Substitution {
+    pub text: String,
+    segments: Vec<Segment>
+}
+
+pub fn line_column(text: &str, offset: usize) -> (usize, usize) {
+    let before = &text[..offset.min(text.len())];
+    let line = before.matches('\n').count() + 1;
+    let line_start = before.rfind('\n').map(|index| index + 1).unwrap_or(0);
+    (line, offset - line_start + 1)
+}
+
+fn offset_of(text: &str, line: usize, column: usize) -> usize {
+    let mut offset = 0;
+    for _ in 1..line {
+        match text[offset..].find('\n') {
+            Some(index) => offset += index + 1,
+            None => return text.len()
+        }
+    }
+    (offset + column.saturating_sub(1)).min(text.len())
+}
+
+impl Substitution {
+    pub fn source_offset(&self, output_offset: usize) -> usize {
+        let mut shift: isize = 0;
+        for segment in self.segments.iter() {
+            if output_offset < segment.output_start {
+                break;
+            }
+            if output_offset < segment.output_start + segment.output_len {
+                return segment.source_start;
+            }
+            shift += segment.source_len as isize - segment.output_len as isize;
+        }
+        (output_offset as isize + shift) as usize
+    }
+
+    pub fn source_position(&self, source: &str, line: usize, column: usize) -> (usize, usize) {
+        let output_offset = offset_of(&self.text, line, column);
+        line_column(source, self.source_offset(output_offset))
+    }
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '.'
+}
+
+pub fn substitute(source: &str, variables: &HashMap<String, String>) -> Result<Substitution, VariableError> {
+    let mut text = String::with_capacity(source.len());
+    let mut segments = Vec::new();
+    let mut missing = Vec::new();
+    let mut rest = source;
+    let mut source_offset = 0;
+
+    while let Some(index) = rest.find('$') {
+        text.push_str(&rest[..index]);
+        let start = source_offset + index;
+        let after = &rest[index..];
+        if let Some(escaped) = after.strip_prefix("$${") {
+            segments.push(Segment { output_start: text.len(), output_len: 2, source_start: start, source_len: 3 });
+            text.push_str("${");
+            rest = escaped;
+            source_offset = start + 3;
+            continue;
+        }
+        if !after.starts_with("${") {
+            text.push('$');
+            rest = &after[1..];
+            source_offset = start + 1;
+            continue;
+        }
+        let end = match after.find('}') {
+            Some(end) => end,
+            None => {
+                let (line, column) = line_column(source, start);
+                return Err(VariableError::new(format!("unterminated placeholder at line {} column {}", line, column)));
+            }
+        };
+        let placeholder = &after[2..end];
+        let (name, default) = match placeholder.find(":-") {
+            Some(split) => (&placeholder[..split], Some(&placeholder[split + 2..])),
+            None => (placeholder, None)
+        };
+        if name.is_empty() || !name.chars().all(is_name_char) {
+            let (line, column) = line_column(source, start);
+            return Err(VariableError::new(format!("invalid placeholder \"${{{}}}\" at line {} column {}", placeholder, line, column)));
+        }
+        let value = match (variables.get(name), default) {
+            (Some(value), _) => value.as_str(),
+            (None, Some(default)) => default,
+            (None, None) => {
+                let (line, column) = line_column(source, start);
+                missing.push(format!("{} (line {} column {})", name, line, column));
+                ""
+            }
+        };
+        segments.push(Segment { output_start: text.len(), output_len: value.len(), source_start: start, source_len: end + 1 });
+        text.push_str(value);
+        rest = &after[end + 1..];
+        source_offset = start + end + 1;
+    }
+    text.push_str(rest);
+
+    if !missing.is_empty() {
+        return Err(VariableError::new(format!("unset scene variables without a default: {}", missing.join(", "))));
+    }
+    Ok(Substitution { text, segments })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn variables(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect()
+    }
+
+    fn substituted(source: &str, pairs: &[(&str, &str)]) -> String {
+        substitute(source, &variables(pairs)).unwrap().text
+    }
+
+    #[test]
+    fn placeholders_are_replaced_by_their_value() {
+        assert_eq!(substituted("{\"fov\": ${fov}, \"seed\": ${render.seed}}", &[("fov", "70"), ("render.seed", "3")]), "{\"fov\": 70, \"seed\": 3}");
+        assert_eq!(substituted("${a}${a}", &[("a", "xy")]), "xyxy");
+        assert_eq!(substituted("no placeholders", &[("a", "1")]), "no placeholders");
+    }
+
+    #[test]
+    fn defaults_apply_to_unset_variables_only() {
+        assert_eq!(substituted("${fov:-60}", &[]), "60");
+        assert_eq!(substituted("${fov:-60}", &[("fov", "90")]), "90");
+        assert_eq!(substituted("\"${name:-}\"", &[]), "\"\"");
+        assert_eq!(substituted("${url:-a:-b}", &[]), "a:-b");
+    }
+
+    #[test]
+    fn escaped_placeholders_and_lone_dollars_are_kept() {
+        assert_eq!(substituted("$${fov} costs $5", &[("fov", "70")]), "${fov} costs $5");
+        assert_eq!(substituted("$$${fov}", &[("fov", "70")]), "$${fov}");
+    }
+
+    #[test]
+    fn every_unset_variable_is_reported() {
+        let error = substitute("{\n  \"a\": ${first},\n  \"b\": ${second}\n}", &HashMap::new()).unwrap_err();
+        assert!(error.message.contains("first (line 2 column 8)"), "{}", error);
+        assert!(error.message.contains("second (line 3 column 8)"), "{}", error);
+    }
+
+    #[test]
+    fn malformed_placeholders_are_refused() {
+        assert!(substitute("${fov", &HashMap::new()).unwrap_err().message.contains("unterminated"));
+        assert!(substitute("${}", &HashMap::new()).unwrap_err().message.contains("invalid placeholder"));
+        assert!(substitute("${a b}", &HashMap::new()).unwrap_err().message.contains("invalid placeholder"));
+    }
+
+    #[test]
+    fn positions_in_the_output_map_back_to_the_source() {
+        let source = "{\"width\": ${width},\n\"fov\": $${x} oops}";
+        let substitution = substitute(source, &variables(&[("width", "1920")])).unwrap();
+        assert_eq!(substitution.text, "{\"width\": 1920,\n\"fov\": ${x} oops}");
+        // Inside a value, errors point at its placeholder
+        assert_eq!(substitution.source_position(source, 1, 13), (1, 11));
+        // After it, the length difference is taken back
+        assert_eq!(substitution.source_position(source, 1, 15), (1, 19));
+        assert_eq!(substitution.source_position(source, 2, 13), (2, 14));
+    }
+}
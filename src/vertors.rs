@@ -1,6 +1,5 @@
 use std::ops;
 use serde::{Serialize, Deserialize};
-use std::ops::Neg;
 
 #[derive(Copy, Clone, Serialize, Deserialize, Debug)]
 pub struct Vector3 {